@@ -1,5 +1,7 @@
 //! Build script ensuring `boot-manipulator` is built as an UEFI runtime driver.
 
 fn main() {
-    println!("cargo::rustc-link-arg=/subsystem:efi_runtime_driver");
+    if std::env::var("CARGO_CFG_TARGET_OS").is_ok_and(|os| os == "uefi") {
+        println!("cargo::rustc-link-arg=/subsystem:efi_runtime_driver");
+    }
 }
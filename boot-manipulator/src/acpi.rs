@@ -0,0 +1,444 @@
+//! Discovery of processors through the ACPI Multiple APIC Description Table (MADT).
+
+use core::fmt;
+
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+
+/// The signature of the Root System Description Pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The number of bytes of an ACPI 1.0 RSDP covered by its checksum.
+const RSDP_V1_LENGTH: usize = 20;
+/// The number of bytes of an ACPI 2.0+ RSDP.
+const RSDP_V2_LENGTH: usize = 36;
+
+/// The signature of the Extended System Description Table.
+const XSDT_SIGNATURE: &[u8; 4] = b"XSDT";
+/// The signature of the Multiple APIC Description Table.
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/// The size of the header shared by every system description table.
+const SDT_HEADER_LENGTH: usize = 36;
+/// The offset of the interrupt controller structures in the MADT.
+const MADT_ENTRIES_OFFSET: usize = 44;
+
+/// The MADT entry type describing a processor's local APIC.
+const ENTRY_LOCAL_APIC: u8 = 0;
+/// The MADT entry type describing a processor's local x2APIC.
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+/// The MADT entry type describing a processor's GIC CPU interface.
+const ENTRY_GICC: u8 = 0xB;
+
+/// The flag in a processor entry marking the processor as enabled.
+const PROCESSOR_ENABLED: u32 = 1;
+
+/// Returns the number of enabled processors described by the MADT the firmware publishes.
+///
+/// # Errors
+/// Returns an [`AcpiError`] if the RSDP cannot be found or a table along the way to the MADT is
+/// missing, malformed, or fails its checksum.
+pub fn processor_count() -> Result<usize, AcpiError> {
+    let rsdp = uefi::system::with_config_table(|entries| {
+        let find = |guid| entries.iter().find(|entry| entry.guid == guid);
+        find(ACPI2_GUID)
+            .or_else(|| find(ACPI_GUID))
+            .map(|entry| entry.address as u64)
+    })
+    .ok_or(AcpiError::MissingRsdp)?;
+
+    // SAFETY:
+    // The firmware publishes a valid RSDP in the configuration table, and UEFI identity maps all
+    // memory.
+    unsafe { processor_count_from(rsdp) }
+}
+
+/// Returns the number of enabled processors described by the MADT reachable from the RSDP at
+/// `rsdp`.
+///
+/// # Errors
+/// Returns an [`AcpiError`] if a table along the way to the MADT is missing, malformed, or fails
+/// its checksum.
+///
+/// # Safety
+/// `rsdp` must be the address of an RSDP, and every table it references must remain valid for
+/// the duration of the call.
+unsafe fn processor_count_from(rsdp: u64) -> Result<usize, AcpiError> {
+    // SAFETY:
+    // The caller guarantees that `rsdp` points to an RSDP.
+    let (root, entry_size) = unsafe { root_table(rsdp)? };
+    // SAFETY:
+    // The root table was validated by `root_table`.
+    let root = unsafe { system_description_table(root)? };
+
+    let entries = &root[SDT_HEADER_LENGTH..];
+    for entry in entries.chunks_exact(entry_size) {
+        let address = match *entry {
+            [a, b, c, d] => u64::from(u32::from_le_bytes([a, b, c, d])),
+            _ => read_u64(entry, 0).ok_or(AcpiError::Malformed(*XSDT_SIGNATURE))?,
+        };
+
+        // SAFETY:
+        // The root table only references system description tables.
+        match unsafe { system_description_table(address) } {
+            Ok(table) if table[..4] == *MADT_SIGNATURE => return count_enabled_processors(table),
+            Err(
+                error @ (AcpiError::InvalidChecksum(signature) | AcpiError::Malformed(signature)),
+            ) if signature == *MADT_SIGNATURE => return Err(error),
+            // Other tables are irrelevant, even if they are invalid.
+            _ => {}
+        }
+    }
+
+    Err(AcpiError::MissingMadt)
+}
+
+/// Counts the enabled processors described by the local APIC, local x2APIC, and GICC entries of
+/// `madt`.
+///
+/// # Errors
+/// Returns [`AcpiError::Malformed`] if an entry extends past the end of the table.
+pub fn count_enabled_processors(madt: &[u8]) -> Result<usize, AcpiError> {
+    let malformed = AcpiError::Malformed(*MADT_SIGNATURE);
+
+    let mut count = 0;
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset < madt.len() {
+        let kind = madt[offset];
+        let length = *madt.get(offset + 1).ok_or(malformed)? as usize;
+        let entry = madt.get(offset..offset + length).ok_or(malformed)?;
+        if length < 2 {
+            return Err(malformed);
+        }
+
+        let flags = match kind {
+            ENTRY_LOCAL_APIC => read_u32(entry, 4),
+            ENTRY_LOCAL_X2APIC => read_u32(entry, 8),
+            ENTRY_GICC => read_u32(entry, 12),
+            _ => None,
+        };
+        if flags.is_some_and(|flags| flags & PROCESSOR_ENABLED == PROCESSOR_ENABLED) {
+            count += 1;
+        }
+
+        offset += length;
+    }
+
+    Ok(count)
+}
+
+/// Validates the RSDP at `rsdp`, returning the address of the XSDT, or of the RSDT if there is
+/// none, along with the size of its entries.
+///
+/// # Safety
+/// `rsdp` must be the address of an RSDP.
+unsafe fn root_table(rsdp: u64) -> Result<(u64, usize), AcpiError> {
+    // SAFETY:
+    // The caller guarantees that `rsdp` points to an RSDP, which is at least as long as an
+    // ACPI 1.0 RSDP.
+    let v1 = unsafe { core::slice::from_raw_parts(rsdp as *const u8, RSDP_V1_LENGTH) };
+    if v1[..8] != *RSDP_SIGNATURE || !checksum_valid(v1) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    if v1[15] < 2 {
+        let rsdt = read_u32(v1, 16).ok_or(AcpiError::InvalidRsdp)?;
+        return Ok((u64::from(rsdt), 4));
+    }
+
+    // SAFETY:
+    // A revision of 2 or later identifies an ACPI 2.0+ RSDP, which is `RSDP_V2_LENGTH` bytes long.
+    let v2 = unsafe { core::slice::from_raw_parts(rsdp as *const u8, RSDP_V2_LENGTH) };
+    if !checksum_valid(v2) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    let xsdt = read_u64(v2, 24).ok_or(AcpiError::InvalidRsdp)?;
+    Ok((xsdt, 8))
+}
+
+/// Returns the system description table at `address` after validating its length and checksum.
+///
+/// # Safety
+/// `address` must be the address of a system description table that remains valid while the
+/// returned slice is in use.
+unsafe fn system_description_table(address: u64) -> Result<&'static [u8], AcpiError> {
+    // SAFETY:
+    // The caller guarantees that `address` points to a system description table, which begins
+    // with its header.
+    let header = unsafe { core::slice::from_raw_parts(address as *const u8, SDT_HEADER_LENGTH) };
+    let signature = [header[0], header[1], header[2], header[3]];
+    let length = read_u32(header, 4).ok_or(AcpiError::Malformed(signature))? as usize;
+    if length < SDT_HEADER_LENGTH {
+        return Err(AcpiError::Malformed(signature));
+    }
+
+    // SAFETY:
+    // The header reports that the table spans `length` bytes.
+    let table = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+    if !checksum_valid(table) {
+        return Err(AcpiError::InvalidChecksum(signature));
+    }
+
+    Ok(table)
+}
+
+/// Returns `true` if the bytes of `table` sum to zero, as required of every ACPI table.
+fn checksum_valid(table: &[u8]) -> bool {
+    table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Reads the little-endian `u32` at `offset` in `bytes`.
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads the little-endian `u64` at `offset` in `bytes`.
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let low = read_u32(bytes, offset)?;
+    let high = read_u32(bytes, offset + 4)?;
+    Some(u64::from(low) | (u64::from(high) << 32))
+}
+
+/// Various errors that can occur while locating and parsing the MADT.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The firmware does not publish an RSDP in its configuration table.
+    MissingRsdp,
+    /// The RSDP has an invalid signature or checksum.
+    InvalidRsdp,
+    /// The table with the given signature failed its checksum.
+    InvalidChecksum([u8; 4]),
+    /// The table with the given signature is truncated or otherwise malformed.
+    Malformed([u8; 4]),
+    /// The root table does not reference a MADT.
+    MissingMadt,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingRsdp => write!(f, "the firmware does not publish an RSDP"),
+            Self::InvalidRsdp => write!(f, "the RSDP is invalid"),
+            Self::InvalidChecksum(signature) => {
+                write!(
+                    f,
+                    "the {} table failed its checksum",
+                    signature_name(signature)
+                )
+            }
+            Self::Malformed(signature) => {
+                write!(f, "the {} table is malformed", signature_name(signature))
+            }
+            Self::MissingMadt => write!(f, "no MADT was found"),
+        }
+    }
+}
+
+/// Returns the signature of a table as text for display.
+fn signature_name(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sets the byte at `index` of `bytes` so that the first `length` bytes sum to zero.
+    fn fix_checksum(bytes: &mut [u8], index: usize, length: usize) {
+        bytes[index] = 0;
+        let sum = bytes[..length]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes[index] = sum.wrapping_neg();
+    }
+
+    /// Returns a system description table with `signature` and `body` and a valid checksum.
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut table = vec![0; SDT_HEADER_LENGTH];
+        table[..4].copy_from_slice(signature);
+        table[4..8].copy_from_slice(&((SDT_HEADER_LENGTH + body.len()) as u32).to_le_bytes());
+        table[8] = 1;
+        table.extend_from_slice(body);
+
+        let length = table.len();
+        fix_checksum(&mut table, 9, length);
+        table
+    }
+
+    /// Returns an ACPI 2.0 RSDP referencing the XSDT at `xsdt`.
+    fn rsdp_v2(xsdt: u64) -> Vec<u8> {
+        let mut rsdp = vec![0; RSDP_V2_LENGTH];
+        rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+        rsdp[15] = 2;
+        rsdp[20..24].copy_from_slice(&(RSDP_V2_LENGTH as u32).to_le_bytes());
+        rsdp[24..32].copy_from_slice(&xsdt.to_le_bytes());
+        fix_checksum(&mut rsdp, 8, RSDP_V1_LENGTH);
+        fix_checksum(&mut rsdp, 32, RSDP_V2_LENGTH);
+        rsdp
+    }
+
+    /// Returns an XSDT referencing `tables`.
+    fn xsdt(tables: &[&[u8]]) -> Vec<u8> {
+        let body = tables
+            .iter()
+            .flat_map(|table| (table.as_ptr() as u64).to_le_bytes())
+            .collect::<Vec<_>>();
+        table(XSDT_SIGNATURE, &body)
+    }
+
+    /// Returns a MADT body containing `entries` after the local interrupt controller address and
+    /// flags.
+    fn madt_body(entries: &[&[u8]]) -> Vec<u8> {
+        let mut body = vec![0; MADT_ENTRIES_OFFSET - SDT_HEADER_LENGTH];
+        for entry in entries {
+            body.extend_from_slice(entry);
+        }
+        body
+    }
+
+    /// Returns a processor entry of `kind` and `length` with `flags` at `flags_offset`.
+    fn processor_entry(kind: u8, length: u8, flags_offset: usize, flags: u32) -> Vec<u8> {
+        let mut entry = vec![0; usize::from(length)];
+        entry[0] = kind;
+        entry[1] = length;
+        entry[flags_offset..flags_offset + 4].copy_from_slice(&flags.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn counts_enabled_processors_of_every_kind() {
+        let local_apic = processor_entry(ENTRY_LOCAL_APIC, 8, 4, 1);
+        let disabled_apic = processor_entry(ENTRY_LOCAL_APIC, 8, 4, 0);
+        let x2apic = processor_entry(ENTRY_LOCAL_X2APIC, 16, 8, 1);
+        let gicc = processor_entry(ENTRY_GICC, 80, 12, 1);
+        let io_apic = processor_entry(1, 12, 4, 1);
+        let madt = table(
+            MADT_SIGNATURE,
+            &madt_body(&[&local_apic, &disabled_apic, &io_apic, &x2apic, &gicc]),
+        );
+
+        assert_eq!(count_enabled_processors(&madt), Ok(3));
+    }
+
+    #[test]
+    fn truncated_entries_are_malformed() {
+        let mut madt = table(
+            MADT_SIGNATURE,
+            &madt_body(&[&processor_entry(ENTRY_LOCAL_APIC, 8, 4, 1)]),
+        );
+        madt.truncate(madt.len() - 1);
+        assert_eq!(
+            count_enabled_processors(&madt),
+            Err(AcpiError::Malformed(*MADT_SIGNATURE))
+        );
+
+        let madt = table(MADT_SIGNATURE, &madt_body(&[&[0, 0]]));
+        assert_eq!(
+            count_enabled_processors(&madt),
+            Err(AcpiError::Malformed(*MADT_SIGNATURE))
+        );
+    }
+
+    #[test]
+    fn finds_the_madt_through_the_xsdt() {
+        let facp = table(b"FACP", &[0; 8]);
+        let madt = table(
+            MADT_SIGNATURE,
+            &madt_body(&[
+                &processor_entry(ENTRY_LOCAL_APIC, 8, 4, 1),
+                &processor_entry(ENTRY_LOCAL_APIC, 8, 4, 1),
+            ]),
+        );
+        let xsdt = xsdt(&[&facp, &madt]);
+        let rsdp = rsdp_v2(xsdt.as_ptr() as u64);
+
+        // SAFETY:
+        // `rsdp` and the tables it references are alive for the duration of the call.
+        let count = unsafe { processor_count_from(rsdp.as_ptr() as u64) };
+        assert_eq!(count, Ok(2));
+    }
+
+    #[test]
+    fn missing_madt_is_reported() {
+        let facp = table(b"FACP", &[0; 8]);
+        let xsdt = xsdt(&[&facp]);
+        let rsdp = rsdp_v2(xsdt.as_ptr() as u64);
+
+        // SAFETY:
+        // `rsdp` and the tables it references are alive for the duration of the call.
+        let count = unsafe { processor_count_from(rsdp.as_ptr() as u64) };
+        assert_eq!(count, Err(AcpiError::MissingMadt));
+    }
+
+    #[test]
+    fn corrupt_madt_checksum_is_reported() {
+        let mut madt = table(MADT_SIGNATURE, &madt_body(&[]));
+        madt[9] = madt[9].wrapping_add(1);
+        let xsdt = xsdt(&[&madt]);
+        let rsdp = rsdp_v2(xsdt.as_ptr() as u64);
+
+        // SAFETY:
+        // `rsdp` and the tables it references are alive for the duration of the call.
+        let count = unsafe { processor_count_from(rsdp.as_ptr() as u64) };
+        assert_eq!(count, Err(AcpiError::InvalidChecksum(*MADT_SIGNATURE)));
+    }
+
+    #[test]
+    fn rsdp_revision_selects_the_root_table() {
+        let mut v1 = rsdp_v2(0);
+        v1[15] = 0;
+        v1[16..20].copy_from_slice(&0x7FFE_0000u32.to_le_bytes());
+        fix_checksum(&mut v1, 8, RSDP_V1_LENGTH);
+        // SAFETY:
+        // `v1` holds an ACPI 1.0 RSDP.
+        let root = unsafe { root_table(v1.as_ptr() as u64) };
+        assert_eq!(root, Ok((0x7FFE_0000, 4)));
+
+        let v2 = rsdp_v2(0x1_2345_6000);
+        // SAFETY:
+        // `v2` holds an ACPI 2.0 RSDP.
+        let root = unsafe { root_table(v2.as_ptr() as u64) };
+        assert_eq!(root, Ok((0x1_2345_6000, 8)));
+    }
+
+    #[test]
+    fn invalid_rsdps_are_rejected() {
+        let mut bad_signature = rsdp_v2(0);
+        bad_signature[0] = b'X';
+        fix_checksum(&mut bad_signature, 8, RSDP_V1_LENGTH);
+        // SAFETY:
+        // `bad_signature` is `RSDP_V2_LENGTH` bytes long.
+        let root = unsafe { root_table(bad_signature.as_ptr() as u64) };
+        assert_eq!(root, Err(AcpiError::InvalidRsdp));
+
+        let mut bad_extended_checksum = rsdp_v2(0);
+        bad_extended_checksum[32] = bad_extended_checksum[32].wrapping_add(1);
+        // SAFETY:
+        // `bad_extended_checksum` is `RSDP_V2_LENGTH` bytes long.
+        let root = unsafe { root_table(bad_extended_checksum.as_ptr() as u64) };
+        assert_eq!(root, Err(AcpiError::InvalidRsdp));
+    }
+
+    #[test]
+    fn short_tables_are_malformed() {
+        let mut short = table(b"SSDT", &[]);
+        short[4..8].copy_from_slice(&8u32.to_le_bytes());
+        // SAFETY:
+        // `short` holds a complete table header.
+        let result = unsafe { system_description_table(short.as_ptr() as u64) };
+        assert_eq!(result, Err(AcpiError::Malformed(*b"SSDT")));
+    }
+
+    #[test]
+    fn errors_name_the_table() {
+        assert_eq!(
+            AcpiError::InvalidChecksum(*MADT_SIGNATURE).to_string(),
+            "the APIC table failed its checksum"
+        );
+        assert_eq!(
+            AcpiError::Malformed([0xFF; 4]).to_string(),
+            "the ???? table is malformed"
+        );
+    }
+}
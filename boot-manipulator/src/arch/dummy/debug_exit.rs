@@ -0,0 +1,14 @@
+//! Fallback test result signaling, which is not available on this architecture.
+
+/// The status reported to QEMU.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum QemuExitCode {
+    /// The run succeeded.
+    Success = 0x10,
+    /// The run failed.
+    Failure = 0x11,
+}
+
+/// Does nothing, as QEMU's `isa-debug-exit` device does not exist on this architecture.
+pub fn exit_qemu(_: QemuExitCode) {}
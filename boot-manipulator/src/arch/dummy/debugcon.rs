@@ -0,0 +1,36 @@
+//! Fallback debug console, which is never present.
+
+use crate::console::{Console, ReadError, WriteError};
+
+/// Console standing in for the debug console, which does not exist on this architecture.
+pub struct DebugconConsole;
+
+impl DebugconConsole {
+    /// Creates a new [`DebugconConsole`].
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// Returns `false`, as there is no debug console on this architecture.
+    pub fn probe(&mut self) -> bool {
+        false
+    }
+}
+
+impl Console for DebugconConsole {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn is_available(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<(), (WriteError, usize)> {
+        Err((WriteError::Unavailable, 0))
+    }
+}
@@ -0,0 +1,58 @@
+//! Fallback inspection, which cannot read anything on this architecture.
+
+use core::fmt;
+
+/// The maximum number of bytes passed to the callback of [`read_physical`] at once.
+pub const CHUNK_LENGTH: usize = 16;
+
+/// Writes that virtualization is unsupported to `f`.
+///
+/// # Errors
+/// Returns an error if writing to `f` fails.
+pub fn write_state(f: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(f, "technology: none")
+}
+
+/// Writes that there are no segment registers to `f`.
+///
+/// # Errors
+/// Returns an error if writing to `f` fails.
+pub fn write_registers(f: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(f, "no segment registers on this architecture")
+}
+
+/// Returns [`InspectError::Unsupported`], as there is no VMCS on this architecture.
+pub fn read_vmcs_field(_: u32) -> Result<u64, InspectError> {
+    Err(InspectError::Unsupported)
+}
+
+/// Returns [`InspectError::Unsupported`], as there are no MSRs on this architecture.
+pub fn read_msr(_: u32, _: bool) -> Result<u64, InspectError> {
+    Err(InspectError::Unsupported)
+}
+
+/// Returns [`InspectError::Unsupported`], as physical memory cannot be mapped on this
+/// architecture.
+pub fn read_physical(
+    _: u64,
+    _: u64,
+    _: bool,
+    _: &mut dyn FnMut(u64, &[u8]),
+) -> Result<(), InspectError> {
+    Err(InspectError::Unsupported)
+}
+
+/// The error returned by every inspection on this architecture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InspectError {
+    /// Inspection is not supported on this architecture.
+    Unsupported,
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not supported on this architecture")
+    }
+}
+
+impl core::error::Error for InspectError {}
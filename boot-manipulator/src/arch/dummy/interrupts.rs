@@ -0,0 +1,10 @@
+//! Fallback interrupt masking, which does nothing as no interrupts are handled on this
+//! architecture.
+
+/// Returns `false`, as interrupts are never masked on this architecture.
+pub fn disable_save() -> bool {
+    false
+}
+
+/// Does nothing, as interrupts are never masked on this architecture.
+pub fn restore(_: bool) {}
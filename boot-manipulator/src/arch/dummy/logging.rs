@@ -0,0 +1,46 @@
+//! Fallback serial console, which is never available.
+
+use crate::{
+    console::{Console, ReadError, WriteError},
+    spinlock::Spinlock,
+};
+
+/// Console standing in for a serial port, which is never available as there is no serial port
+/// driver on this architecture.
+pub struct SerialConsole;
+
+impl SerialConsole {
+    /// Creates a new [`SerialConsole`].
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// Does nothing, as there is no serial port driver on this architecture.
+    ///
+    /// # Errors
+    /// Never fails.
+    pub fn initialize(&mut self, _: u16, _: u32) -> Result<(), core::convert::Infallible> {
+        Ok(())
+    }
+}
+
+impl Console for SerialConsole {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn is_available(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        Err((ReadError::Unavailable, 0))
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<(), (WriteError, usize)> {
+        Err((WriteError::Unavailable, 0))
+    }
+}
+
+/// Does nothing, as there is no serial port driver on this architecture.
+pub fn force_write(_: &Spinlock<SerialConsole>, _: impl FnOnce(&mut dyn core::fmt::Write)) {}
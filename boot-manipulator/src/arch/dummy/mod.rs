@@ -0,0 +1,42 @@
+//! Fallback definitions for architectures without virtualization support.
+//!
+//! These allow `boot-manipulator` to be built and loaded on any architecture supported by UEFI,
+//! while reporting that virtualization is unsupported.
+
+#[cfg(feature = "test-exit")]
+pub mod debug_exit;
+#[cfg(feature = "debugcon")]
+pub mod debugcon;
+pub mod inspect;
+pub mod interrupts;
+#[cfg(feature = "serial-logging")]
+pub mod logging;
+pub mod nested;
+pub mod time;
+pub mod virtualization;
+
+/// Returns [`None`], as processors cannot be identified on this architecture.
+pub fn processor_id() -> Option<u32> {
+    None
+}
+
+/// Halts forever, as there is no architectural way to reset the processor.
+pub fn reset() -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Forwards to the intercepted `ExitBootServices` without taking control of the processor.
+///
+/// # Safety
+/// Must only be installed in place of `ExitBootServices` by
+/// [`exit_boot_services::install`][crate::exit_boot_services::install].
+pub unsafe extern "efiapi" fn exit_boot_services_handler(
+    image_handle: *mut core::ffi::c_void,
+    map_key: usize,
+) -> uefi::Status {
+    // SAFETY:
+    // The caller guarantees that this handler was installed in place of `ExitBootServices`.
+    unsafe { crate::exit_boot_services::forward(image_handle, map_key) }
+}
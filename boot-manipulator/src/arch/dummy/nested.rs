@@ -0,0 +1,12 @@
+//! Fallback parent hypervisor detection.
+
+use core::convert::Infallible;
+
+/// A hypervisor underneath `boot-manipulator`, which can never be identified on this
+/// architecture.
+pub type ParentHypervisor = Infallible;
+
+/// Returns `None`, as parent hypervisors cannot be detected on this architecture.
+pub fn detect() -> Option<ParentHypervisor> {
+    None
+}
@@ -0,0 +1,20 @@
+//! Fallback clock, which advances by a fixed amount on every read.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The amount the fake counter advances by on every read.
+const TICKS_PER_READ: u64 = 1_000;
+
+/// The value of the fake counter.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the current value of the fake counter, which deterministically advances by
+/// [`TICKS_PER_READ`] on every call.
+pub fn read_counter() -> u64 {
+    COUNTER.fetch_add(TICKS_PER_READ, Ordering::Relaxed)
+}
+
+/// Returns `true`, as the fake counter advances independently of power state.
+pub fn is_invariant() -> bool {
+    true
+}
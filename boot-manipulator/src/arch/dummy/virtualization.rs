@@ -0,0 +1,62 @@
+//! Fallback virtualization support, which is never available.
+
+use core::{convert::Infallible, fmt};
+
+use crate::frames::OutOfMemoryError;
+
+/// Returns `false`, as virtualization is not implemented for this architecture.
+pub fn is_supported() -> bool {
+    false
+}
+
+/// Does nothing, as virtualization is not implemented for this architecture.
+///
+/// # Errors
+/// Never fails.
+pub fn allocate_basic_memory() -> Result<(), OutOfMemoryError> {
+    Ok(())
+}
+
+/// Fails, as virtualization is not implemented for this architecture.
+///
+/// # Errors
+/// Always returns [`Unsupported`].
+pub fn enable_support() -> Result<(), Unsupported> {
+    Err(Unsupported)
+}
+
+/// Fails, as virtualization is not implemented for this architecture.
+///
+/// # Errors
+/// Always returns [`Unsupported`].
+pub fn setup_virtual_machine_state() -> Result<(), Unsupported> {
+    Err(Unsupported)
+}
+
+/// Fails, as virtualization is not implemented for this architecture.
+///
+/// # Errors
+/// Always returns [`Unsupported`].
+pub fn launch_virtual_machine() -> Result<Infallible, Unsupported> {
+    Err(Unsupported)
+}
+
+/// Does nothing, as virtualization is not implemented for this architecture.
+pub fn teardown_processor() -> ProcessorFrames {
+    ProcessorFrames
+}
+
+/// The frames released by [`teardown_processor`], of which there are none on this architecture.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ProcessorFrames;
+
+/// The error returned by every fallible operation, as virtualization is not implemented for this
+/// architecture.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct Unsupported;
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "virtualization is not supported on this architecture")
+    }
+}
@@ -1,4 +1,11 @@
 //! Definitions of architecture dependent mechanisms.
 
+#[cfg(target_arch = "x86_64")]
 mod x86_64;
+#[cfg(target_arch = "x86_64")]
 pub use x86_64::*;
+
+#[cfg(not(target_arch = "x86_64"))]
+mod dummy;
+#[cfg(not(target_arch = "x86_64"))]
+pub use dummy::*;
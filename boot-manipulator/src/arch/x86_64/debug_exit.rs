@@ -0,0 +1,31 @@
+//! Signaling of test results to QEMU through its `isa-debug-exit` device.
+
+/// The I/O port at which the `isa-debug-exit` device is mapped.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// The status reported to QEMU.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum QemuExitCode {
+    /// The run succeeded.
+    Success = 0x10,
+    /// The run failed.
+    Failure = 0x11,
+}
+
+/// Requests that QEMU exit with a status derived from `code`.
+///
+/// If the `isa-debug-exit` device is not present, the write is ignored and this function returns.
+pub fn exit_qemu(code: QemuExitCode) {
+    // SAFETY:
+    // Writing to the `isa-debug-exit` port has no effect on memory and is ignored if the device
+    // is not present.
+    unsafe {
+        core::arch::asm!(
+            "out dx, eax",
+            in("dx") DEBUG_EXIT_PORT,
+            in("eax") code as u32,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! The debug console of QEMU and Bochs, a write-only port at 0xE9.
+
+use crate::console::{Console, ReadError, WriteError};
+
+/// The I/O port of the debug console.
+const DEBUGCON_PORT: u16 = 0xE9;
+
+/// Console writing to the debug console.
+pub struct DebugconConsole {
+    /// Whether [`DebugconConsole::probe`] found the debug console.
+    present: bool,
+}
+
+impl DebugconConsole {
+    /// Creates a new [`DebugconConsole`], which is unavailable until the debug console is found.
+    pub const fn new() -> Self {
+        Self { present: false }
+    }
+
+    /// Returns `true` if the debug console is present, making the console available if so.
+    ///
+    /// Reading the debug console returns 0xE9, while an unclaimed port reads as 0xFF.
+    pub fn probe(&mut self) -> bool {
+        let byte: u8;
+        // SAFETY:
+        // Reading port 0xE9 has no side effects on either the debug console or unclaimed ports.
+        unsafe {
+            core::arch::asm!(
+                "in al, dx",
+                in("dx") DEBUGCON_PORT,
+                out("al") byte,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+
+        self.present = byte == 0xE9;
+        self.present
+    }
+}
+
+impl Console for DebugconConsole {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn is_available(&self) -> bool {
+        self.present
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        Ok(0)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        if !self.present {
+            return Err((WriteError::Unavailable, 0));
+        }
+
+        for &byte in data {
+            // SAFETY:
+            // The debug console was found by `probe`, and writing to it only emits `byte`.
+            unsafe {
+                core::arch::asm!(
+                    "out dx, al",
+                    in("dx") DEBUGCON_PORT,
+                    in("al") byte,
+                    options(nomem, nostack, preserves_flags)
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
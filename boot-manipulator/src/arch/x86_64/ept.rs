@@ -0,0 +1,500 @@
+//! Construction of the Extended Page Tables translating guest-physical to host-physical addresses.
+
+use core::{fmt, ops::BitOr, ptr::NonNull};
+
+use crate::{
+    arch::x86_64::{
+        mtrr::{MemoryType, MtrrMap},
+        paging::{
+            self, allocate_tables, EntryFormat, PageSize, PagingError, ENTRIES_PER_TABLE,
+            ENTRY_ADDRESS_MASK, ENTRY_LARGE_PAGE, PAGE_SIZE,
+        },
+        registers::msr::{read_msr, VMX_EPT_VPID_CAP},
+        virtualization::{invept, InveptType, InvvpidType, VmxInstructionError},
+    },
+    spinlock::Spinlock,
+};
+
+/// The capability bit reporting support for a page-walk length of 4.
+const CAP_PAGE_WALK_4: u64 = 1 << 6;
+/// The capability bit reporting support for the write-back memory type in the EPTP.
+const CAP_WRITE_BACK: u64 = 1 << 14;
+/// The capability bit reporting support for 2 MiB pages.
+const CAP_2MIB_PAGES: u64 = 1 << 16;
+/// The capability bit reporting support for 1 GiB pages.
+const CAP_1GIB_PAGES: u64 = 1 << 17;
+/// The capability bit reporting support for accessed and dirty flags.
+const CAP_ACCESSED_DIRTY: u64 = 1 << 21;
+/// The capability bit reporting support for `invept`.
+const CAP_INVEPT: u64 = 1 << 20;
+/// The capability bit reporting support for single-context `invept`.
+const CAP_INVEPT_SINGLE_CONTEXT: u64 = 1 << 25;
+/// The capability bit reporting support for all-context `invept`.
+const CAP_INVEPT_ALL_CONTEXT: u64 = 1 << 26;
+/// The capability bit reporting support for `invvpid`.
+const CAP_INVVPID: u64 = 1 << 32;
+/// The capability bit reporting support for individual-address `invvpid`.
+const CAP_INVVPID_INDIVIDUAL_ADDRESS: u64 = 1 << 40;
+/// The capability bit reporting support for single-context `invvpid`.
+const CAP_INVVPID_SINGLE_CONTEXT: u64 = 1 << 41;
+/// The capability bit reporting support for all-context `invvpid`.
+const CAP_INVVPID_ALL_CONTEXT: u64 = 1 << 42;
+/// The capability bit reporting support for single-context-retaining-globals `invvpid`.
+const CAP_INVVPID_SINGLE_CONTEXT_RETAINING_GLOBALS: u64 = 1 << 43;
+
+/// The bits of the EPTP encoding a page-walk length of 4.
+const EPTP_PAGE_WALK_4: u64 = 3 << 3;
+/// The bit of the EPTP enabling accessed and dirty flags.
+const EPTP_ACCESSED_DIRTY: u64 = 1 << 6;
+
+/// The EPT hierarchy of the guest, once it has been built.
+pub static GUEST_MEMORY: Spinlock<Option<GuestMemory>> = Spinlock::new(None);
+
+/// The EPT hierarchy of the guest along with the information needed to extend it after boot
+/// services have been exited.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "lazy-ept"), allow(dead_code))]
+pub struct GuestMemory {
+    /// The EPT hierarchy of the guest.
+    pub ept: EptHierarchy,
+    /// The MTRRs the hierarchy was built from.
+    pub mtrrs: MtrrMap,
+    /// The end of the highest range of RAM in the firmware memory map, above which lies MMIO.
+    pub top_of_memory: u64,
+}
+
+/// The access permissions granted to the guest by an EPT entry.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct EptPermissions(u64);
+
+// The individual permissions are part of the mapping interface even though the identity map only
+// grants all of them.
+#[allow(dead_code)]
+impl EptPermissions {
+    /// The guest may read the memory.
+    pub const READ: Self = Self(1 << 0);
+    /// The guest may write the memory.
+    pub const WRITE: Self = Self(1 << 1);
+    /// The guest may execute the memory.
+    pub const EXECUTE: Self = Self(1 << 2);
+    /// The guest may read, write, and execute the memory.
+    pub const ALL: Self = Self(0b111);
+
+    /// Returns the bits of an EPT entry granting these permissions.
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl BitOr for EptPermissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The encoding of EPT entries, in which an entry is present if it grants any permission.
+pub struct EptFormat;
+
+impl EntryFormat for EptFormat {
+    fn table_entry(table: u64) -> u64 {
+        (table & ENTRY_ADDRESS_MASK) | EptPermissions::ALL.bits()
+    }
+
+    fn is_present(entry: u64) -> bool {
+        entry & EptPermissions::ALL.bits() != 0
+    }
+}
+
+/// Returns an EPT entry mapping the page of `size` at `address` with `permissions` and
+/// `memory_type`.
+pub fn page_entry(
+    address: u64,
+    size: PageSize,
+    permissions: EptPermissions,
+    memory_type: MemoryType,
+) -> u64 {
+    let large = match size {
+        PageSize::Size4KiB => 0,
+        PageSize::Size2MiB | PageSize::Size1GiB => ENTRY_LARGE_PAGE,
+    };
+
+    (address & ENTRY_ADDRESS_MASK) | permissions.bits() | ((memory_type as u64) << 3) | large
+}
+
+/// The EPT and VPID capabilities of the processor, as reported by `IA32_VMX_EPT_VPID_CAP`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct EptVpidCapabilities(u64);
+
+impl EptVpidCapabilities {
+    /// Reads the EPT and VPID capabilities of the running processor.
+    ///
+    /// # Safety
+    /// The processor must support the secondary processor-based controls.
+    pub unsafe fn read() -> Self {
+        // SAFETY:
+        // `IA32_VMX_EPT_VPID_CAP` exists when the secondary processor-based controls do.
+        Self(unsafe { read_msr(VMX_EPT_VPID_CAP) })
+    }
+
+    /// Returns `true` if EPT paging structures with 4 levels and the write-back memory type can be
+    /// used.
+    pub fn usable(self) -> bool {
+        let required = CAP_PAGE_WALK_4 | CAP_WRITE_BACK;
+        self.0 & required == required
+    }
+
+    /// Returns `true` if EPT entries can map pages of `size`.
+    pub fn supports(self, size: PageSize) -> bool {
+        match size {
+            PageSize::Size4KiB => true,
+            PageSize::Size2MiB => self.0 & CAP_2MIB_PAGES == CAP_2MIB_PAGES,
+            PageSize::Size1GiB => self.0 & CAP_1GIB_PAGES == CAP_1GIB_PAGES,
+        }
+    }
+
+    /// Returns the largest supported page size that maps from `gpa` to `hpa` without exceeding
+    /// `remaining` bytes.
+    fn largest_page(self, gpa: u64, hpa: u64, remaining: u64) -> PageSize {
+        [PageSize::Size1GiB, PageSize::Size2MiB]
+            .into_iter()
+            .find(|&size| {
+                let bytes = size.bytes();
+                self.supports(size) && (gpa | hpa).is_multiple_of(bytes) && remaining >= bytes
+            })
+            .unwrap_or(PageSize::Size4KiB)
+    }
+
+    /// Returns `true` if the processor can maintain accessed and dirty flags in EPT entries.
+    pub fn accessed_dirty(self) -> bool {
+        self.0 & CAP_ACCESSED_DIRTY == CAP_ACCESSED_DIRTY
+    }
+
+    /// Returns `true` if `invept` supports invalidations of type `kind`.
+    pub fn supports_invept(self, kind: InveptType) -> bool {
+        let required = CAP_INVEPT
+            | match kind {
+                InveptType::SingleContext => CAP_INVEPT_SINGLE_CONTEXT,
+                InveptType::AllContext => CAP_INVEPT_ALL_CONTEXT,
+            };
+
+        self.0 & required == required
+    }
+
+    /// Returns `true` if `invvpid` supports invalidations of type `kind`.
+    pub fn supports_invvpid(self, kind: InvvpidType) -> bool {
+        let required = CAP_INVVPID
+            | match kind {
+                InvvpidType::IndividualAddress => CAP_INVVPID_INDIVIDUAL_ADDRESS,
+                InvvpidType::SingleContext => CAP_INVVPID_SINGLE_CONTEXT,
+                InvvpidType::AllContext => CAP_INVVPID_ALL_CONTEXT,
+                InvvpidType::SingleContextRetainingGlobals => {
+                    CAP_INVVPID_SINGLE_CONTEXT_RETAINING_GLOBALS
+                }
+            };
+
+        self.0 & required == required
+    }
+}
+
+/// A 4-level hierarchy of EPT paging structures.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EptHierarchy {
+    /// The PML4 at the root of the hierarchy.
+    pml4: NonNull<u64>,
+    /// The capabilities of the processor the hierarchy is built for.
+    capabilities: EptVpidCapabilities,
+    /// The next of the zeroed paging structures reserved for use once boot services have been
+    /// exited, along with the number remaining.
+    ///
+    /// Once tables have been reserved, no paging structures are allocated from the firmware.
+    spare_tables: Option<(NonNull<u64>, usize)>,
+    /// Whether the hierarchy is referenced by a VMCS, so that the processor may cache its
+    /// translations.
+    active: bool,
+}
+
+// SAFETY:
+// The paging structures are owned by the hierarchy and only accessed through it.
+unsafe impl Send for EptHierarchy {}
+
+impl EptHierarchy {
+    /// Allocates an empty [`EptHierarchy`] suitable for a processor with `capabilities`.
+    ///
+    /// # Errors
+    /// Returns [`PagingError::OutOfMemory`] if the PML4 cannot be allocated.
+    pub fn new(capabilities: EptVpidCapabilities) -> Result<Self, EptError> {
+        Ok(Self {
+            pml4: allocate_tables(1).ok_or(EptError::Paging(PagingError::OutOfMemory))?,
+            capabilities,
+            spare_tables: None,
+            active: false,
+        })
+    }
+
+    /// Records that the hierarchy is referenced by a VMCS, after which modifications invalidate
+    /// the translations cached by the processor.
+    pub fn activate(&mut self) {
+        self.active = true;
+    }
+
+    /// Reserves `count` zeroed paging structures, from which all further paging structures are
+    /// taken so that the hierarchy can be extended after boot services have been exited.
+    ///
+    /// # Errors
+    /// Returns [`PagingError::OutOfMemory`] if the paging structures cannot be allocated.
+    #[cfg_attr(not(feature = "lazy-ept"), allow(dead_code))]
+    pub fn reserve_tables(&mut self, count: usize) -> Result<(), EptError> {
+        let tables = allocate_tables(count).ok_or(EptError::Paging(PagingError::OutOfMemory))?;
+        self.spare_tables = Some((tables, count));
+        Ok(())
+    }
+
+    /// Maps the `size` bytes at guest-physical address `gpa` to host-physical address `hpa` with
+    /// `permissions` and `memory_type`, using the largest pages the processor supports.
+    ///
+    /// # Errors
+    /// - Returns [`EptError::Paging`] if `gpa`, `hpa`, or `size` is not a multiple of 4 KiB, if
+    ///   part of the range is already mapped by a larger page, or if a paging structure cannot be
+    ///   allocated.
+    /// - Returns [`EptError::Invalidation`] if invalidating the cached translations fails.
+    #[cfg_attr(not(feature = "lazy-ept"), allow(dead_code))]
+    pub fn map(
+        &mut self,
+        gpa: u64,
+        hpa: u64,
+        size: u64,
+        permissions: EptPermissions,
+        memory_type: MemoryType,
+    ) -> Result<(), EptError> {
+        if !(gpa | hpa | size).is_multiple_of(PAGE_SIZE) {
+            return Err(EptError::Paging(PagingError::Misaligned));
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let page_size =
+                self.capabilities
+                    .largest_page(gpa + offset, hpa + offset, size - offset);
+            paging::map_page::<EptFormat>(
+                self.pml4,
+                gpa + offset,
+                page_size,
+                page_entry(hpa + offset, page_size, permissions, memory_type),
+                || take_table(&mut self.spare_tables),
+            )?;
+            offset += page_size.bytes();
+        }
+
+        if self.active {
+            self.invalidate().map_err(EptError::Invalidation)?;
+        }
+
+        Ok(())
+    }
+
+    /// Invalidates the translations derived from this hierarchy, using a single-context
+    /// invalidation when supported and an all-context invalidation otherwise.
+    ///
+    /// # Errors
+    /// Returns a [`VmxInstructionError`] if `invept` fails.
+    pub fn invalidate(&self) -> Result<(), VmxInstructionError> {
+        let kind = if self.capabilities.supports_invept(InveptType::SingleContext) {
+            InveptType::SingleContext
+        } else {
+            InveptType::AllContext
+        };
+
+        invept(kind, self.eptp())
+    }
+
+    /// Identity maps the `size` bytes of physical memory starting at `base` with full
+    /// permissions, using the memory types assigned by `mtrrs`.
+    ///
+    /// Ranges whose memory type is not uniform are mapped with smaller pages until it is.
+    ///
+    /// # Errors
+    /// Returns an [`EptError`] if mapping any page fails.
+    pub fn identity_map(&mut self, base: u64, size: u64, mtrrs: &MtrrMap) -> Result<(), EptError> {
+        let capabilities = self.capabilities;
+        let next_page = |address, remaining| {
+            let mut page_size = capabilities.largest_page(address, address, remaining);
+            let memory_type = loop {
+                match mtrrs.memory_type(address, page_size.bytes()) {
+                    Some(memory_type) => break memory_type,
+                    None if page_size == PageSize::Size1GiB => page_size = PageSize::Size2MiB,
+                    None if page_size == PageSize::Size2MiB => page_size = PageSize::Size4KiB,
+                    None => break MemoryType::Uncacheable,
+                }
+            };
+
+            let entry = page_entry(address, page_size, EptPermissions::ALL, memory_type);
+            (page_size, entry)
+        };
+
+        paging::identity_map::<EptFormat>(self.pml4, base, size, next_page, || {
+            take_table(&mut self.spare_tables)
+        })?;
+
+        Ok(())
+    }
+
+    /// Returns the EPT pointer referencing this hierarchy, using the write-back memory type for
+    /// the paging structures and enabling accessed and dirty flags when supported.
+    pub fn eptp(&self) -> u64 {
+        let mut eptp = self.pml4.as_ptr() as u64 | EPTP_PAGE_WALK_4 | MemoryType::WriteBack as u64;
+        if self.capabilities.accessed_dirty() {
+            eptp |= EPTP_ACCESSED_DIRTY;
+        }
+
+        eptp
+    }
+}
+
+/// Returns a zeroed paging structure, taken from `spare_tables` if tables have been reserved and
+/// allocated from the firmware otherwise.
+fn take_table(spare_tables: &mut Option<(NonNull<u64>, usize)>) -> Option<NonNull<u64>> {
+    match spare_tables {
+        None => allocate_tables(1),
+        Some((_, 0)) => None,
+        Some((next, remaining)) => {
+            let table = *next;
+            // SAFETY:
+            // `remaining` tables starting at `next` were reserved, so the following table is
+            // within the reservation or directly past its end.
+            *next = unsafe { table.add(ENTRIES_PER_TABLE) };
+            *remaining -= 1;
+            Some(table)
+        }
+    }
+}
+
+/// Various errors that can occur while building an [`EptHierarchy`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum EptError {
+    /// Mapping a page into the hierarchy failed.
+    Paging(PagingError),
+    /// Invalidating the translations cached from the hierarchy failed.
+    Invalidation(VmxInstructionError),
+}
+
+impl fmt::Display for EptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Paging(error) => write!(f, "failed to map guest-physical memory: {error}"),
+            Self::Invalidation(error) => write!(f, "INVEPT failed: {error}"),
+        }
+    }
+}
+
+impl From<PagingError> for EptError {
+    fn from(error: PagingError) -> Self {
+        Self::Paging(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_entries_encode_permissions_memory_type_and_size() {
+        assert_eq!(
+            page_entry(
+                0x1234_5000,
+                PageSize::Size4KiB,
+                EptPermissions::ALL,
+                MemoryType::WriteBack
+            ),
+            0x1234_5000 | 0b111 | (6 << 3)
+        );
+        assert_eq!(
+            page_entry(
+                0x4020_0000,
+                PageSize::Size2MiB,
+                EptPermissions::READ | EptPermissions::EXECUTE,
+                MemoryType::Uncacheable
+            ),
+            0x4020_0000 | 0b101 | ENTRY_LARGE_PAGE
+        );
+        assert_eq!(
+            page_entry(
+                0x8000_0000,
+                PageSize::Size1GiB,
+                EptPermissions::READ | EptPermissions::WRITE,
+                MemoryType::WriteCombining
+            ),
+            0x8000_0000 | 0b011 | (1 << 3) | ENTRY_LARGE_PAGE
+        );
+    }
+
+    #[test]
+    fn page_entries_discard_bits_outside_the_address() {
+        let entry = page_entry(
+            0xFFF0_0000_0000_1FFF,
+            PageSize::Size4KiB,
+            EptPermissions::READ,
+            MemoryType::WriteThrough,
+        );
+
+        assert_eq!(entry, 0x0000_0000_0000_1000 | 0b001 | (4 << 3));
+    }
+
+    #[test]
+    fn table_entries_grant_all_permissions() {
+        assert_eq!(EptFormat::table_entry(0x7654_3000), 0x7654_3000 | 0b111);
+        assert!(EptFormat::is_present(EptFormat::table_entry(0)));
+        assert!(EptFormat::is_present(EptPermissions::EXECUTE.bits()));
+        assert!(!EptFormat::is_present(0x7654_3000 | (6 << 3)));
+    }
+
+    #[test]
+    fn capabilities_report_page_sizes_and_usability() {
+        let capabilities = EptVpidCapabilities(CAP_PAGE_WALK_4 | CAP_WRITE_BACK | CAP_2MIB_PAGES);
+
+        assert!(capabilities.usable());
+        assert!(!EptVpidCapabilities(CAP_PAGE_WALK_4).usable());
+        assert!(capabilities.supports(PageSize::Size4KiB));
+        assert!(capabilities.supports(PageSize::Size2MiB));
+        assert!(!capabilities.supports(PageSize::Size1GiB));
+        assert!(!capabilities.accessed_dirty());
+    }
+
+    #[test]
+    fn largest_page_respects_alignment_and_length() {
+        let capabilities = EptVpidCapabilities(CAP_2MIB_PAGES | CAP_1GIB_PAGES);
+        let gib = PageSize::Size1GiB.bytes();
+        let mib2 = PageSize::Size2MiB.bytes();
+
+        assert_eq!(capabilities.largest_page(0, 0, gib), PageSize::Size1GiB);
+        assert_eq!(capabilities.largest_page(0, 0, gib - 1), PageSize::Size2MiB);
+        assert_eq!(
+            capabilities.largest_page(mib2, mib2, gib),
+            PageSize::Size2MiB
+        );
+        assert_eq!(
+            capabilities.largest_page(0, PAGE_SIZE, gib),
+            PageSize::Size4KiB
+        );
+        assert_eq!(
+            EptVpidCapabilities(CAP_2MIB_PAGES).largest_page(0, 0, gib),
+            PageSize::Size2MiB
+        );
+    }
+
+    #[test]
+    fn invalidation_support_requires_the_instruction() {
+        let capabilities = EptVpidCapabilities(CAP_INVEPT_SINGLE_CONTEXT | CAP_INVVPID_ALL_CONTEXT);
+        assert!(!capabilities.supports_invept(InveptType::SingleContext));
+        assert!(!capabilities.supports_invvpid(InvvpidType::AllContext));
+
+        let capabilities = EptVpidCapabilities(
+            CAP_INVEPT | CAP_INVEPT_SINGLE_CONTEXT | CAP_INVVPID | CAP_INVVPID_ALL_CONTEXT,
+        );
+        assert!(capabilities.supports_invept(InveptType::SingleContext));
+        assert!(!capabilities.supports_invept(InveptType::AllContext));
+        assert!(capabilities.supports_invvpid(InvvpidType::AllContext));
+        assert!(!capabilities.supports_invvpid(InvvpidType::IndividualAddress));
+    }
+}
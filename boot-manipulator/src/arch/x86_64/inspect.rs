@@ -0,0 +1,362 @@
+//! Read-only inspection of the processor and physical memory for the debug shell.
+//!
+//! Reads that are likely to fault or to have side effects are refused unless they are forced.
+
+use core::{fmt, ops::Range};
+
+use crate::{
+    arch::x86_64::{
+        machine_state,
+        mapping::{self, CachePolicy},
+        paging::{PagingError, PAGE_SIZE},
+        registers::{
+            msr::{
+                self, APIC_BASE, EFER, FEATURE_CONTROL, FS_BASE, GS_BASE, KERNEL_GS_BASE, LSTAR,
+                MTRR_CAP, MTRR_DEF_TYPE, PAT, SFMASK, STAR, SYSENTER_CS, SYSENTER_EIP,
+                SYSENTER_ESP, VMX_CR0_FIXED0, VMX_CR0_FIXED1, VMX_CR4_FIXED0, VMX_CR4_FIXED1,
+                VMX_ENTRY_CTLS, VMX_EXIT_CTLS, VMX_PINBASED_CTLS, VMX_PROCBASED_CTLS, VMX_REVISION,
+                VM_CR, VM_HSAVE_PA,
+            },
+            segment::{self, SegmentDescriptor},
+            Gdtr,
+        },
+        virtualization::{self, Technology, VmxInstructionError},
+    },
+    exit_boot_services,
+};
+
+/// The maximum number of bytes of physical memory read at once unless the read is forced.
+const MAX_READ_LENGTH: u64 = 4096;
+
+/// The maximum number of bytes passed to the callback of [`read_physical`] at once.
+pub const CHUNK_LENGTH: usize = 16;
+
+/// Physical ranges that hold memory-mapped I/O or firmware, where reads may have side effects.
+const PROTECTED_RANGES: [Range<u64>; 3] = [
+    // The first page, which is never legitimately referenced.
+    0..PAGE_SIZE,
+    // The legacy video memory and option ROMs.
+    0xA_0000..0x10_0000,
+    // The I/O APIC, the local APIC, and the firmware flash.
+    0xFEC0_0000..0x1_0000_0000,
+];
+
+/// Writes the state of the virtualization support of the current processor to `f`.
+///
+/// # Errors
+/// Returns an error if writing to `f` fails.
+pub fn write_state(f: &mut dyn fmt::Write) -> fmt::Result {
+    match virtualization::supported_technology() {
+        Some(Technology::Vmx) => writeln!(f, "technology: VMX")?,
+        Some(Technology::Svm) => writeln!(f, "technology: SVM")?,
+        None => writeln!(f, "technology: none")?,
+    }
+    match crate::arch::nested::detect() {
+        Some(parent) => writeln!(f, "parent hypervisor: {parent}")?,
+        None => writeln!(f, "parent hypervisor: none")?,
+    }
+    writeln!(f, "VMX operation: {}", virtualization::in_vmx_operation())?;
+    if exit_boot_services::has_exited() {
+        writeln!(f, "state at ExitBootServices(): {:#x?}", machine_state())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the segment selectors of the current processor, the descriptors they reference, and the
+/// FS and GS bases to `f`.
+///
+/// # Errors
+/// Returns an error if writing to `f` fails.
+pub fn write_registers(f: &mut dyn fmt::Write) -> fmt::Result {
+    let gdtr = Gdtr::get();
+    writeln!(
+        f,
+        "GDT: base {:#x}, limit {:#x}",
+        gdtr.address(),
+        gdtr.limit()
+    )?;
+
+    let selectors = [
+        ("CS", segment::read_cs()),
+        ("SS", segment::read_ss()),
+        ("DS", segment::read_ds()),
+        ("ES", segment::read_es()),
+        ("FS", segment::read_fs()),
+        ("GS", segment::read_gs()),
+        ("LDTR", segment::read_ldtr()),
+        ("TR", segment::read_tr()),
+    ];
+    for (name, selector) in selectors {
+        write!(f, "{name:<4} {selector}: ")?;
+        // SAFETY:
+        // `gdtr` was read from the processor, and the GDT is identity mapped.
+        match unsafe { SegmentDescriptor::from_gdt(&gdtr, selector) } {
+            Ok(descriptor) => writeln!(f, "{descriptor}")?,
+            Err(error) => writeln!(f, "{error}")?,
+        }
+    }
+
+    writeln!(f, "FS base: {:#x}", segment::read_fs_base())?;
+    writeln!(f, "GS base: {:#x}", segment::read_gs_base())
+}
+
+/// Reads the field with `encoding` from the current VMCS.
+///
+/// # Errors
+/// - Returns [`InspectError::NotInVmxOperation`] if the processor has not executed `vmxon`.
+/// - Returns [`InspectError::Vmx`] if `vmread` fails, such as for an unknown encoding.
+pub fn read_vmcs_field(encoding: u32) -> Result<u64, InspectError> {
+    if !virtualization::in_vmx_operation() {
+        return Err(InspectError::NotInVmxOperation);
+    }
+
+    virtualization::vm_read_encoding(encoding).map_err(InspectError::Vmx)
+}
+
+/// MSRs implemented by every x86_64 processor, which can be read without side effects.
+const COMMON_MSRS: &[u32] = &[
+    APIC_BASE,
+    MTRR_CAP,
+    SYSENTER_CS,
+    SYSENTER_ESP,
+    SYSENTER_EIP,
+    PAT,
+    MTRR_DEF_TYPE,
+    EFER,
+    STAR,
+    LSTAR,
+    SFMASK,
+    FS_BASE,
+    GS_BASE,
+    KERNEL_GS_BASE,
+];
+
+/// MSRs implemented by every processor supporting VMX, which can be read without side effects.
+const VMX_MSRS: &[u32] = &[
+    FEATURE_CONTROL,
+    VMX_REVISION,
+    VMX_PINBASED_CTLS,
+    VMX_PROCBASED_CTLS,
+    VMX_EXIT_CTLS,
+    VMX_ENTRY_CTLS,
+    VMX_CR0_FIXED0,
+    VMX_CR0_FIXED1,
+    VMX_CR4_FIXED0,
+    VMX_CR4_FIXED1,
+];
+
+/// MSRs implemented by every processor supporting SVM, which can be read without side effects.
+const SVM_MSRS: &[u32] = &[VM_CR, VM_HSAVE_PA];
+
+/// Reads the model-specific register `address`.
+///
+/// Unless `force` is set, only MSRs that every processor supporting the detected virtualization
+/// technology implements are read. Forcing the read of an MSR the processor does not implement
+/// raises a general-protection fault.
+///
+/// # Errors
+/// Returns [`InspectError::UnlistedMsr`] if `address` is not known to be implemented and `force`
+/// is not set.
+pub fn read_msr(address: u32, force: bool) -> Result<u64, InspectError> {
+    if !force && !is_known_msr(address, virtualization::supported_technology()) {
+        return Err(InspectError::UnlistedMsr(address));
+    }
+
+    // SAFETY:
+    // Either `address` is implemented by every processor supporting `technology` and reading it
+    // has no side effects, or the user explicitly accepted that reading it may fault.
+    Ok(unsafe { msr::read_msr(address) })
+}
+
+/// Returns `true` if `address` is implemented by every processor supporting `technology`, and can
+/// be read without side effects.
+fn is_known_msr(address: u32, technology: Option<Technology>) -> bool {
+    let specific = match technology {
+        Some(Technology::Vmx) => VMX_MSRS,
+        Some(Technology::Svm) => SVM_MSRS,
+        None => &[],
+    };
+
+    COMMON_MSRS.contains(&address) || specific.contains(&address)
+}
+
+/// Reads the `length` bytes of physical memory at `physical`, calling `f` with the address and
+/// contents of each consecutive chunk of up to [`CHUNK_LENGTH`] bytes.
+///
+/// The range is mapped first if boot services are still active, and must already be identity
+/// mapped otherwise.
+///
+/// # Errors
+/// - Returns [`InspectError::TooLong`] if `length` exceeds 4 KiB and `force` is not set.
+/// - Returns [`InspectError::ProtectedRange`] if the range overlaps memory-mapped I/O or firmware
+///   and `force` is not set.
+/// - Returns [`InspectError::OutOfRange`] if the range wraps around the address space.
+/// - Returns [`InspectError::Paging`] if the range cannot be mapped.
+/// - Returns [`InspectError::NotMapped`] if boot services have exited and the range is not
+///   identity mapped.
+pub fn read_physical(
+    physical: u64,
+    length: u64,
+    force: bool,
+    f: &mut dyn FnMut(u64, &[u8]),
+) -> Result<(), InspectError> {
+    let end = physical
+        .checked_add(length)
+        .filter(|end| end.checked_next_multiple_of(PAGE_SIZE).is_some())
+        .ok_or(InspectError::OutOfRange(physical))?;
+    if !force {
+        if length > MAX_READ_LENGTH {
+            return Err(InspectError::TooLong(length));
+        }
+        if let Some(range) = PROTECTED_RANGES
+            .iter()
+            .find(|range| physical < range.end && range.start < end)
+        {
+            return Err(InspectError::ProtectedRange(physical.max(range.start)));
+        }
+    }
+    if length == 0 {
+        return Ok(());
+    }
+
+    let start = physical & !(PAGE_SIZE - 1);
+    let size = end.next_multiple_of(PAGE_SIZE) - start;
+    let boot_services_active = !exit_boot_services::has_exited();
+    if boot_services_active {
+        // SAFETY:
+        // Boot services are active, and the firmware's page tables are writable.
+        unsafe { mapping::map_frames(start, size, CachePolicy::Uncacheable) }
+            .map_err(InspectError::Paging)?;
+    } else if let Some(address) = mapping::unmapped_page(start, size) {
+        return Err(InspectError::NotMapped(address));
+    }
+
+    let mut chunk = [0; CHUNK_LENGTH];
+    let mut address = physical;
+    while address < end {
+        let count = (end - address).min(CHUNK_LENGTH as u64) as usize;
+        for (offset, byte) in chunk[..count].iter_mut().enumerate() {
+            let pointer = (address + offset as u64) as *const u8;
+            // SAFETY:
+            // The range was identity mapped above, and is only read.
+            *byte = unsafe { pointer.read_volatile() };
+        }
+
+        f(address, &chunk[..count]);
+        address += count as u64;
+    }
+
+    if boot_services_active {
+        // SAFETY:
+        // Boot services are active, and the range is no longer accessed.
+        unsafe { mapping::unmap_frames(start, size) }
+    }
+
+    Ok(())
+}
+
+/// Various errors that can occur while inspecting the processor or physical memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InspectError {
+    /// The processor is not in VMX operation, so there is no current VMCS.
+    NotInVmxOperation,
+    /// `vmread` failed.
+    Vmx(VmxInstructionError),
+    /// The MSR is not known to be implemented by the processor.
+    UnlistedMsr(u32),
+    /// The read is longer than allowed without forcing it.
+    TooLong(u64),
+    /// The range overlaps memory-mapped I/O or firmware at the given address.
+    ProtectedRange(u64),
+    /// The range starting at the given address wraps around the address space.
+    OutOfRange(u64),
+    /// The range could not be mapped.
+    Paging(PagingError),
+    /// The page at the given address is not identity mapped.
+    NotMapped(u64),
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInVmxOperation => write!(f, "the processor is not in VMX operation"),
+            Self::Vmx(error) => write!(f, "vmread failed: {error}"),
+            Self::UnlistedMsr(address) => write!(
+                f,
+                "MSR {address:#x} is not known to be implemented (add `force` to read it)"
+            ),
+            Self::TooLong(length) => write!(
+                f,
+                "{length} bytes is longer than {MAX_READ_LENGTH} (add `force` to read it)"
+            ),
+            Self::ProtectedRange(address) => write!(
+                f,
+                "{address:#x} holds memory-mapped I/O or firmware (add `force` to read it)"
+            ),
+            Self::OutOfRange(address) => {
+                write!(
+                    f,
+                    "the range at {address:#x} wraps around the address space"
+                )
+            }
+            Self::Paging(error) => write!(f, "failed to map the range: {error}"),
+            Self::NotMapped(address) => write!(f, "{address:#x} is not identity mapped"),
+        }
+    }
+}
+
+impl core::error::Error for InspectError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::x86_64::registers::msr::{CSTAR, VMX_VMFUNC};
+
+    #[test]
+    fn common_msrs_are_always_known() {
+        for technology in [None, Some(Technology::Vmx), Some(Technology::Svm)] {
+            for address in [APIC_BASE, PAT, EFER, LSTAR, FS_BASE, KERNEL_GS_BASE] {
+                assert!(is_known_msr(address, technology), "{address:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn technology_specific_msrs_require_the_technology() {
+        for address in [FEATURE_CONTROL, VMX_REVISION, VMX_CR4_FIXED1] {
+            assert!(is_known_msr(address, Some(Technology::Vmx)), "{address:#x}");
+            assert!(
+                !is_known_msr(address, Some(Technology::Svm)),
+                "{address:#x}"
+            );
+            assert!(!is_known_msr(address, None), "{address:#x}");
+        }
+        for address in [VM_CR, VM_HSAVE_PA] {
+            assert!(is_known_msr(address, Some(Technology::Svm)), "{address:#x}");
+            assert!(
+                !is_known_msr(address, Some(Technology::Vmx)),
+                "{address:#x}"
+            );
+            assert!(!is_known_msr(address, None), "{address:#x}");
+        }
+    }
+
+    #[test]
+    fn unimplemented_msrs_are_unknown() {
+        // MSRs within the ranges covered by an MSR bitmap are not necessarily implemented.
+        for address in [
+            0x0,
+            0x10,
+            0x1FFF,
+            CSTAR,
+            VMX_VMFUNC,
+            0xC000_1FFF,
+            0x4000_0000,
+        ] {
+            for technology in [None, Some(Technology::Vmx), Some(Technology::Svm)] {
+                assert!(!is_known_msr(address, technology), "{address:#x}");
+            }
+        }
+    }
+}
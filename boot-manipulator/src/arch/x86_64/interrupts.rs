@@ -0,0 +1,41 @@
+//! Masking of maskable interrupts on the current processor.
+
+/// The interrupt flag in RFLAGS.
+#[cfg(not(test))]
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Disables maskable interrupts on the current processor, returning whether they were enabled.
+#[cfg(not(test))]
+pub fn disable_save() -> bool {
+    let rflags: u64;
+    // SAFETY:
+    // Reading RFLAGS and clearing the interrupt flag only delays interrupts until `restore`.
+    unsafe {
+        core::arch::asm!(
+            "pushfq",
+            "pop {}",
+            "cli",
+            out(reg) rflags,
+            options(nomem, preserves_flags)
+        );
+    }
+
+    rflags & RFLAGS_IF != 0
+}
+
+/// Reports maskable interrupts as disabled, since host tests run in user mode, where `cli` faults
+/// and no interrupts reach the process.
+#[cfg(test)]
+pub fn disable_save() -> bool {
+    false
+}
+
+/// Enables maskable interrupts on the current processor if `enabled`, the value returned by the
+/// matching [`disable_save`].
+pub fn restore(enabled: bool) {
+    if enabled {
+        // SAFETY:
+        // Interrupts were enabled before the matching `disable_save`.
+        unsafe { core::arch::asm!("sti", options(nomem, nostack)) }
+    }
+}
@@ -1,56 +1,79 @@
 //! Architecture specific logging mechanisms.
 
-use core::fmt::Write;
+use core::fmt;
 
 use crate::{
-    arch::x86_64::serial::{
-        DmaMode, DmaTriggerLevel, FifoControl, InterruptEnable, LineControl, SerialPort,
-    },
+    arch::x86_64::serial::{SerialError, SerialPort, COM1},
+    console::{Console, ReadError, WriteError},
     spinlock::Spinlock,
 };
 
-pub fn init_transition_logger(logger: &mut TransitionLogger) {
-    let mut serial_port = logger.serial_port.lock();
-
-    serial_port.set_interrupt_enable(InterruptEnable::new());
-    serial_port.set_line_control(LineControl::new().set_dlab(true));
-    serial_port.set_divisor(1);
-    serial_port.set_line_control(LineControl::new());
-    serial_port.set_fifo_control(
-        FifoControl::new()
-            .enable_fifo(true)
-            .reset_receive_fifo(true)
-            .reset_transmit_fifo(true)
-            .dma_mode(DmaMode::MultiByte)
-            .trigger_level(DmaTriggerLevel::Bytes14),
-    );
+/// Console writing to a 16550-compatible serial port, which is unavailable until one was found.
+pub struct SerialConsole {
+    /// The serial port, or [`None`] before [`SerialConsole::initialize`] succeeded.
+    serial_port: Option<SerialPort>,
 }
 
-pub struct TransitionLogger {
-    serial_port: Spinlock<SerialPort>,
+impl SerialConsole {
+    /// Creates a new [`SerialConsole`] without a serial port.
+    pub const fn new() -> Self {
+        Self { serial_port: None }
+    }
+
+    /// Probes for a serial port at `io_port` and initializes it for `baud_rate`, making the
+    /// console available.
+    ///
+    /// # Errors
+    /// Returns a [`SerialError`] if the baud rate is not supported or no serial port is present,
+    /// in which case the console is left unchanged.
+    pub fn initialize(&mut self, io_port: u16, baud_rate: u32) -> Result<(), SerialError> {
+        // SAFETY:
+        // The I/O port was configured by the user as the serial port to log to.
+        self.serial_port = Some(unsafe { SerialPort::probe(io_port, baud_rate)? });
+
+        Ok(())
+    }
 }
 
-impl TransitionLogger {
-    pub const fn new() -> Self {
-        Self {
-            serial_port: unsafe { Spinlock::new(SerialPort::new(0x3f8)) },
+impl Console for SerialConsole {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn is_available(&self) -> bool {
+        self.serial_port.is_some()
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        match &mut self.serial_port {
+            Some(serial_port) => serial_port.read(data),
+            None => Err((ReadError::Unavailable, 0)),
         }
     }
-}
 
-impl log::Log for TransitionLogger {
-    fn enabled(&self, _: &log::Metadata) -> bool {
-        true
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        match &mut self.serial_port {
+            Some(serial_port) => serial_port.write(data),
+            None => Err((WriteError::Unavailable, 0)),
+        }
     }
+}
 
-    fn log(&self, record: &log::Record) {
-        let _ = writeln!(
-            self.serial_port.lock(),
-            "[{}]: {}",
-            record.level(),
-            record.args()
-        );
+/// Calls `f` with the serial port of `console`, without waiting for it to be unlocked.
+///
+/// If the console is locked, such as when a panic interrupted a write, or has no serial port yet,
+/// `f` is called with a fresh writer for the standard COM1 I/O port instead.
+pub fn force_write(console: &Spinlock<SerialConsole>, f: impl FnOnce(&mut dyn fmt::Write)) {
+    if let Ok(mut console) = console.try_lock() {
+        if let Some(serial_port) = &mut console.serial_port {
+            f(serial_port);
+            return;
+        }
     }
 
-    fn flush(&self) {}
+    // SAFETY:
+    // COM1 is the conventional serial port, and whatever held the lock is never resumed while
+    // panicking.
+    let mut serial_port = unsafe { SerialPort::new(COM1) };
+    f(&mut serial_port);
 }
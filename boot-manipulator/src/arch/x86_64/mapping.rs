@@ -0,0 +1,527 @@
+//! Mapping of physical memory into the address space of the current CR3.
+//!
+//! UEFI identity maps the memory it knows about, but not necessarily all of the physical address
+//! space. Ranges outside of that window are mapped at their physical address with 4 KiB pages, so
+//! that pointers to physical memory remain valid regardless of how they were obtained.
+
+use core::ptr::NonNull;
+
+use crate::{
+    arch::x86_64::{
+        paging::{
+            self, allocate_tables, EntryFormat, PageSize, PagingError, ENTRY_ADDRESS_MASK,
+            PAGE_SIZE,
+        },
+        registers::control::Cr3,
+    },
+    frames::deallocate_frames,
+    spinlock::Spinlock,
+};
+
+/// The bit in a long-mode entry marking it as present.
+const ENTRY_PRESENT: u64 = 1 << 0;
+/// The bit in a long-mode entry allowing writes.
+const ENTRY_WRITABLE: u64 = 1 << 1;
+/// The bit in a long-mode page entry selecting write-through caching.
+const ENTRY_WRITE_THROUGH: u64 = 1 << 3;
+/// The bit in a long-mode page entry disabling caching.
+const ENTRY_CACHE_DISABLE: u64 = 1 << 4;
+
+/// The maximum number of paging structures created by [`map_frames`] that can be live at once.
+const MAX_CREATED_TABLES: usize = 64;
+/// The maximum number of pages mapped by [`map_frames`] that can be live at once.
+const MAX_MAPPED_PAGES: usize = 256;
+
+/// The paging structures created by [`map_frames`], which [`unmap_frames`] frees once empty.
+static CREATED_TABLES: Spinlock<[Option<u64>; MAX_CREATED_TABLES]> =
+    Spinlock::new([None; MAX_CREATED_TABLES]);
+/// The pages whose entries were written by [`map_frames`], which [`unmap_frames`] clears.
+static MAPPED_PAGES: Spinlock<[Option<u64>; MAX_MAPPED_PAGES]> =
+    Spinlock::new([None; MAX_MAPPED_PAGES]);
+
+/// The encoding of the entries of the host's page tables.
+struct HostFormat;
+
+impl EntryFormat for HostFormat {
+    fn table_entry(table: u64) -> u64 {
+        (table & ENTRY_ADDRESS_MASK) | ENTRY_PRESENT | ENTRY_WRITABLE
+    }
+
+    fn is_present(entry: u64) -> bool {
+        entry & ENTRY_PRESENT == ENTRY_PRESENT
+    }
+}
+
+/// The cacheability of a mapping created by [`map_frames`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum CachePolicy {
+    /// Normal memory, cached according to the MTRRs.
+    WriteBack,
+    /// Memory-mapped I/O, which must not be cached.
+    Uncacheable,
+}
+
+impl CachePolicy {
+    /// Returns the bits of a 4 KiB page entry selecting this policy with the default PAT.
+    fn entry_bits(self) -> u64 {
+        match self {
+            Self::WriteBack => 0,
+            Self::Uncacheable => ENTRY_WRITE_THROUGH | ENTRY_CACHE_DISABLE,
+        }
+    }
+}
+
+/// Returns the entry mapping the writable 4 KiB page at `address` with `cache`.
+fn page_entry(address: u64, cache: CachePolicy) -> u64 {
+    (address & ENTRY_ADDRESS_MASK) | ENTRY_PRESENT | ENTRY_WRITABLE | cache.entry_bits()
+}
+
+/// Returns a pointer to the `size` bytes of physical memory at `physical`, mapping them at the
+/// same address in the current CR3 if they are not already identity mapped.
+///
+/// Pages that are already identity mapped keep their existing mapping, so `cache` only applies to
+/// pages outside of the identity-mapped window. On failure, the pages mapped so far are unmapped.
+///
+/// # Errors
+/// - Returns [`PagingError::Misaligned`] if `physical` or `size` is not a multiple of 4 KiB.
+/// - Returns [`PagingError::ConflictingMapping`] if part of the range is mapped at a different
+///   physical address.
+/// - Returns [`PagingError::OutOfMemory`] if a paging structure cannot be allocated.
+/// - Returns [`PagingError::TooManyMappings`] if the new pages cannot be recorded for
+///   [`unmap_frames`].
+///
+/// # Safety
+/// Boot services must be active, and the current page tables must be writable.
+pub unsafe fn map_frames(
+    physical: u64,
+    size: u64,
+    cache: CachePolicy,
+) -> Result<NonNull<u8>, PagingError> {
+    if !(physical | size).is_multiple_of(PAGE_SIZE) {
+        return Err(PagingError::Misaligned);
+    }
+    // The page at address 0 cannot be referenced through a pointer.
+    let pointer = NonNull::new(physical as *mut u8).ok_or(PagingError::ConflictingMapping(0))?;
+
+    let pml4 = current_pml4();
+    let mut mapped = MAPPED_PAGES.lock();
+    let result = map_range(
+        pml4,
+        physical,
+        size,
+        cache,
+        &mut *mapped,
+        allocate_recorded_table,
+        invalidate,
+    );
+    if let Err((error, address)) = result {
+        unmap_range(
+            pml4,
+            physical,
+            address - physical,
+            &mut *mapped,
+            owns_table,
+            release_recorded_table,
+            invalidate,
+        );
+        return Err(error);
+    }
+
+    Ok(pointer)
+}
+
+/// Removes the mappings [`map_frames`] created for the `size` bytes at `physical`, freeing the
+/// paging structures it created once they are empty.
+///
+/// Pages that were identity mapped before [`map_frames`] was called are left untouched.
+///
+/// # Safety
+/// Boot services must be active, and the range must no longer be accessed.
+pub unsafe fn unmap_frames(physical: u64, size: u64) {
+    unmap_range(
+        current_pml4(),
+        physical & !(PAGE_SIZE - 1),
+        size,
+        &mut *MAPPED_PAGES.lock(),
+        owns_table,
+        release_recorded_table,
+        invalidate,
+    );
+}
+
+/// Identity maps each page of the `size` bytes at `physical` that is unmapped in the hierarchy
+/// rooted at `pml4`, recording the page in `mapped` and passing it to `invalidate`.
+///
+/// Missing intermediate paging structures are taken from `allocate_table`.
+///
+/// # Errors
+/// Returns the error along with the address of the page that could not be mapped, so that the
+/// pages before it can be unmapped.
+fn map_range(
+    pml4: NonNull<u64>,
+    physical: u64,
+    size: u64,
+    cache: CachePolicy,
+    mapped: &mut [Option<u64>],
+    mut allocate_table: impl FnMut() -> Option<NonNull<u64>>,
+    mut invalidate: impl FnMut(u64),
+) -> Result<(), (PagingError, u64)> {
+    let mut address = physical;
+    while address < physical + size {
+        match paging::lookup::<HostFormat>(pml4, address) {
+            Some((entry, page_size)) => {
+                let offset = address & (page_size.bytes() - 1);
+                if (entry & ENTRY_ADDRESS_MASK & !(page_size.bytes() - 1)) + offset != address {
+                    return Err((PagingError::ConflictingMapping(address), address));
+                }
+            }
+            None => {
+                let slot = mapped
+                    .iter_mut()
+                    .find(|slot| slot.is_none())
+                    .ok_or((PagingError::TooManyMappings, address))?;
+                paging::map_page::<HostFormat>(
+                    pml4,
+                    address,
+                    PageSize::Size4KiB,
+                    page_entry(address, cache),
+                    &mut allocate_table,
+                )
+                .map_err(|error| (error, address))?;
+                *slot = Some(address);
+                invalidate(address);
+            }
+        }
+
+        address += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+/// Unmaps each page of the `size` bytes at `physical` that is recorded in `mapped` from the
+/// hierarchy rooted at `pml4`, regardless of which paging structure holds its entry.
+///
+/// Each unmapped page is passed to `invalidate`. Paging structures left empty are unlinked if
+/// `owns_table` returns `true` for them, and then passed to `release_table`.
+fn unmap_range(
+    pml4: NonNull<u64>,
+    physical: u64,
+    size: u64,
+    mapped: &mut [Option<u64>],
+    mut owns_table: impl FnMut(NonNull<u64>) -> bool,
+    mut release_table: impl FnMut(NonNull<u64>),
+    mut invalidate: impl FnMut(u64),
+) {
+    let mut address = physical;
+    while address < physical + size {
+        if let Some(slot) = mapped.iter_mut().find(|slot| **slot == Some(address)) {
+            *slot = None;
+
+            let unlinked = paging::unmap_page::<HostFormat>(pml4, address, &mut owns_table);
+            invalidate(address);
+
+            for table in unlinked.into_iter().flatten().flatten() {
+                release_table(table);
+            }
+        }
+
+        address += PAGE_SIZE;
+    }
+}
+
+/// Returns the first page of the `size` bytes at `physical` that is not identity mapped in the
+/// current CR3, or [`None`] if the whole range is.
+///
+/// Unlike [`map_frames`], this only reads the page tables, so it is usable at any time.
+pub fn unmapped_page(physical: u64, size: u64) -> Option<u64> {
+    let pml4 = current_pml4();
+    let mut address = physical & !(PAGE_SIZE - 1);
+    while address < physical + size {
+        let identity = paging::lookup::<HostFormat>(pml4, address).is_some_and(|(entry, size)| {
+            let offset = address & (size.bytes() - 1);
+            (entry & ENTRY_ADDRESS_MASK & !(size.bytes() - 1)) + offset == address
+        });
+        if !identity {
+            return Some(address);
+        }
+
+        address += PAGE_SIZE;
+    }
+
+    None
+}
+
+/// Returns the PML4 of the current address space.
+fn current_pml4() -> NonNull<u64> {
+    // UEFI identity maps all memory, so the physical address of the PML4 is usable as a pointer.
+    NonNull::new(Cr3::get().table_address() as *mut u64)
+        .expect("paging is enabled, so CR3 references a PML4")
+}
+
+/// Allocates a paging structure, recording it as created by [`map_frames`].
+fn allocate_recorded_table() -> Option<NonNull<u64>> {
+    let mut created = CREATED_TABLES.lock();
+    let slot = created.iter_mut().find(|slot| slot.is_none())?;
+    let table = allocate_tables(1)?;
+    *slot = Some(table.as_ptr() as u64);
+
+    Some(table)
+}
+
+/// Returns `true` if `table` was created by [`map_frames`].
+fn owns_table(table: NonNull<u64>) -> bool {
+    CREATED_TABLES.lock().contains(&Some(table.as_ptr() as u64))
+}
+
+/// Frees a paging structure created by [`map_frames`] after it was unlinked.
+fn release_recorded_table(table: NonNull<u64>) {
+    if let Some(slot) = CREATED_TABLES
+        .lock()
+        .iter_mut()
+        .find(|slot| **slot == Some(table.as_ptr() as u64))
+    {
+        *slot = None;
+    }
+
+    // SAFETY:
+    // `table` was allocated by `allocate_recorded_table` as a single frame, and is no longer
+    // referenced by the hierarchy.
+    unsafe { deallocate_frames(table.cast(), 1) }
+}
+
+/// Invalidates the TLB entries and paging-structure caches used to translate `address`.
+fn invalidate(address: u64) {
+    // SAFETY:
+    // Invalidating TLB entries has no effect other than a later page walk.
+    unsafe { core::arch::asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::arch::x86_64::paging::ENTRIES_PER_TABLE;
+
+    /// A paging structure allocated on the host.
+    #[repr(C, align(4096))]
+    struct Table([u64; ENTRIES_PER_TABLE]);
+
+    /// The paging structures handed out by a fake table allocator.
+    #[derive(Default)]
+    struct Tables {
+        /// The live structures.
+        live: RefCell<Vec<NonNull<u64>>>,
+        /// The structures that have been released.
+        released: RefCell<Vec<NonNull<u64>>>,
+    }
+
+    impl Tables {
+        /// Allocates a zeroed paging structure.
+        fn allocate(&self) -> Option<NonNull<u64>> {
+            let table = NonNull::from(Box::leak(Box::new(Table([0; ENTRIES_PER_TABLE])))).cast();
+            self.live.borrow_mut().push(table);
+            Some(table)
+        }
+
+        /// Returns `true` if `table` was allocated by this allocator and is live.
+        fn owns(&self, table: NonNull<u64>) -> bool {
+            self.live.borrow().contains(&table)
+        }
+
+        /// Releases `table`.
+        fn release(&self, table: NonNull<u64>) {
+            self.live.borrow_mut().retain(|&live| live != table);
+            self.released.borrow_mut().push(table);
+        }
+    }
+
+    /// Maps the range as [`map_frames`] does, using `tables` for new paging structures and
+    /// returning the invalidated pages.
+    fn map(
+        pml4: NonNull<u64>,
+        physical: u64,
+        size: u64,
+        mapped: &mut [Option<u64>],
+        tables: &Tables,
+    ) -> Result<Vec<u64>, (PagingError, u64)> {
+        let mut invalidated = Vec::new();
+        map_range(
+            pml4,
+            physical,
+            size,
+            CachePolicy::WriteBack,
+            mapped,
+            || tables.allocate(),
+            |address| invalidated.push(address),
+        )?;
+        Ok(invalidated)
+    }
+
+    /// Unmaps the range as [`unmap_frames`] does, returning the invalidated pages.
+    fn unmap(
+        pml4: NonNull<u64>,
+        physical: u64,
+        size: u64,
+        mapped: &mut [Option<u64>],
+        tables: &Tables,
+    ) -> Vec<u64> {
+        let mut invalidated = Vec::new();
+        unmap_range(
+            pml4,
+            physical,
+            size,
+            mapped,
+            |table| tables.owns(table),
+            |table| tables.release(table),
+            |address| invalidated.push(address),
+        );
+        invalidated
+    }
+
+    /// Returns the entry mapping `address` in the hierarchy rooted at `pml4`.
+    fn entry(pml4: NonNull<u64>, address: u64) -> Option<u64> {
+        paging::lookup::<HostFormat>(pml4, address).map(|(entry, _)| entry)
+    }
+
+    #[test]
+    fn page_entries_encode_flags_and_address() {
+        assert_eq!(
+            page_entry(0x1234_5000, CachePolicy::WriteBack),
+            0x1234_5000 | ENTRY_PRESENT | ENTRY_WRITABLE
+        );
+        assert_eq!(
+            page_entry(0xFEE0_0000, CachePolicy::Uncacheable),
+            0xFEE0_0000 | 0b1_1011
+        );
+        // Bits outside the address field never leak into the entry.
+        assert_eq!(
+            page_entry(0xFFF0_0000_0000_1FFF, CachePolicy::WriteBack),
+            0x1000 | 0b11
+        );
+    }
+
+    #[test]
+    fn table_entries_are_present_and_writable() {
+        assert_eq!(HostFormat::table_entry(0x7654_3000), 0x7654_3003);
+        assert_eq!(HostFormat::table_entry(0x7654_3FFF), 0x7654_3003);
+        assert!(HostFormat::is_present(page_entry(
+            0,
+            CachePolicy::WriteBack
+        )));
+        assert!(!HostFormat::is_present(0x7654_3000 | ENTRY_WRITABLE));
+    }
+
+    #[test]
+    fn map_unmap_round_trip_frees_created_tables() {
+        let tables = Tables::default();
+        let pml4 = tables.allocate().unwrap();
+        let firmware = tables.live.borrow().clone();
+        let mut mapped = [None; 4];
+
+        let invalidated = map(pml4, 0x4000_0000, 3 * PAGE_SIZE, &mut mapped, &tables).unwrap();
+
+        assert_eq!(invalidated, [0x4000_0000, 0x4000_1000, 0x4000_2000]);
+        for &address in &invalidated {
+            assert_eq!(
+                entry(pml4, address),
+                Some(page_entry(address, CachePolicy::WriteBack))
+            );
+        }
+        // A page-directory-pointer table, a page directory and a page table.
+        assert_eq!(tables.live.borrow().len(), firmware.len() + 3);
+        assert_eq!(mapped.iter().flatten().count(), 3);
+
+        let invalidated = unmap(pml4, 0x4000_0000, 3 * PAGE_SIZE, &mut mapped, &tables);
+
+        assert_eq!(invalidated, [0x4000_0000, 0x4000_1000, 0x4000_2000]);
+        assert!(invalidated
+            .iter()
+            .all(|&address| entry(pml4, address).is_none()));
+        assert_eq!(tables.released.borrow().len(), 3);
+        assert_eq!(mapped, [None; 4]);
+        // SAFETY:
+        // `pml4` holds `ENTRIES_PER_TABLE` entries.
+        let pml4_entries = unsafe { core::slice::from_raw_parts(pml4.as_ptr(), ENTRIES_PER_TABLE) };
+        assert!(pml4_entries.iter().all(|&entry| entry == 0));
+    }
+
+    #[test]
+    fn unmaps_pages_inserted_into_existing_tables() {
+        let tables = Tables::default();
+        let pml4 = tables.allocate().unwrap();
+        // The firmware identity maps the first page.
+        paging::map_page::<HostFormat>(
+            pml4,
+            0x4000_0000,
+            PageSize::Size4KiB,
+            page_entry(0x4000_0000, CachePolicy::WriteBack),
+            || tables.allocate(),
+        )
+        .unwrap();
+        let firmware = tables.live.borrow().clone();
+        let mut mapped = [None; 4];
+
+        let invalidated = map(pml4, 0x4000_0000, 3 * PAGE_SIZE, &mut mapped, &tables).unwrap();
+
+        assert_eq!(invalidated, [0x4000_1000, 0x4000_2000]);
+        assert_eq!(*tables.live.borrow(), firmware);
+
+        let invalidated = unmap(pml4, 0x4000_0000, 3 * PAGE_SIZE, &mut mapped, &tables);
+
+        assert_eq!(invalidated, [0x4000_1000, 0x4000_2000]);
+        assert!(entry(pml4, 0x4000_0000).is_some());
+        assert!(entry(pml4, 0x4000_1000).is_none());
+        assert!(entry(pml4, 0x4000_2000).is_none());
+        // Only the pages were removed; the firmware's tables stay linked.
+        assert!(tables.released.borrow().is_empty());
+    }
+
+    #[test]
+    fn unmap_ignores_unrecorded_pages() {
+        let tables = Tables::default();
+        let pml4 = tables.allocate().unwrap();
+        let mut mapped = [None; 4];
+        map(pml4, 0x4000_0000, PAGE_SIZE, &mut mapped, &tables).unwrap();
+
+        // A second caller maps nothing new, since the page is already identity mapped.
+        let mut other = [None; 4];
+        assert_eq!(
+            map(pml4, 0x4000_0000, PAGE_SIZE, &mut other, &tables),
+            Ok(Vec::new())
+        );
+        assert!(unmap(pml4, 0x4000_0000, PAGE_SIZE, &mut other, &tables).is_empty());
+        assert!(entry(pml4, 0x4000_0000).is_some());
+    }
+
+    #[test]
+    fn conflicting_and_untracked_mappings_report_the_failing_page() {
+        let tables = Tables::default();
+        let pml4 = tables.allocate().unwrap();
+        paging::map_page::<HostFormat>(
+            pml4,
+            0x4000_2000,
+            PageSize::Size4KiB,
+            page_entry(0x9000, CachePolicy::WriteBack),
+            || tables.allocate(),
+        )
+        .unwrap();
+
+        let mut mapped = [None; 4];
+        assert_eq!(
+            map(pml4, 0x4000_0000, 3 * PAGE_SIZE, &mut mapped, &tables),
+            Err((PagingError::ConflictingMapping(0x4000_2000), 0x4000_2000))
+        );
+        assert_eq!(mapped.iter().flatten().count(), 2);
+
+        let mut mapped = [None; 1];
+        assert_eq!(
+            map(pml4, 0x8000_0000, 2 * PAGE_SIZE, &mut mapped, &tables),
+            Err((PagingError::TooManyMappings, 0x8000_1000))
+        );
+        assert_eq!(mapped, [Some(0x8000_0000)]);
+        assert!(entry(pml4, 0x8000_1000).is_none());
+    }
+}
@@ -2,10 +2,69 @@
 
 use core::mem::MaybeUninit;
 
+use crate::sync::Lazy;
+
+#[cfg(feature = "test-exit")]
+pub mod debug_exit;
+#[cfg(feature = "debugcon")]
+pub mod debugcon;
+mod ept;
+pub mod inspect;
+pub mod interrupts;
+#[cfg(feature = "serial-logging")]
 pub mod logging;
+mod mapping;
+mod msr_bitmap;
+mod mtrr;
+pub mod nested;
+mod npt;
+mod paging;
 mod registers;
+#[cfg(feature = "serial-logging")]
 mod serial;
+mod svm;
+mod svm_exit;
+mod tables;
+pub mod time;
 pub mod virtualization;
+mod vm_exit;
+mod vmcb;
+pub mod vmcs_fields;
+mod xsave;
+
+/// The `cpuid` leaf enumerating the extended topology, which reports the x2APIC ID.
+const CPUID_EXTENDED_TOPOLOGY: u32 = 0xB;
+
+/// Returns the APIC ID of the current processor.
+///
+/// This only executes `cpuid`, so it is safe to call from any processor at any time.
+pub fn processor_id() -> Option<u32> {
+    if core::arch::x86_64::__cpuid(0).eax >= CPUID_EXTENDED_TOPOLOGY {
+        let topology = core::arch::x86_64::__cpuid_count(CPUID_EXTENDED_TOPOLOGY, 0);
+        // A leaf reporting no logical processors at its level is not implemented.
+        if topology.ebx & 0xFFFF != 0 {
+            return Some(topology.edx);
+        }
+    }
+
+    Some(core::arch::x86_64::__cpuid(1).ebx >> 24)
+}
+
+/// Resets the processor by triple faulting, which requires neither boot nor runtime services.
+pub fn reset() -> ! {
+    let null_idt = [0u64; 2];
+    // SAFETY:
+    // With an empty interrupt descriptor table, `int3` escalates to a triple fault, which resets
+    // the processor.
+    unsafe {
+        core::arch::asm!(
+            "lidt [{}]",
+            "int3",
+            in(reg) &null_idt,
+            options(noreturn, nostack),
+        )
+    }
+}
 
 extern "efiapi" {
     #[link_name = "exit_boot_services_handler"]
@@ -15,15 +74,22 @@ extern "efiapi" {
     ) -> uefi::Status;
 }
 
+extern "efiapi" {
+    /// The instruction at which the firmware resumes from `ExitBootServices()`.
+    fn exit_boot_services_return();
+}
+
 core::arch::global_asm!(
     ".global exit_boot_services_handler",
     "exit_boot_services_handler:",
     "sub rsp, 40",
-    "call qword ptr [rip + {intercepted_func}]",
+    "call {intercepted_func}",
     "mov qword ptr [rsp + 32], rax",
     "cmp rax, 0",
     "je 5f",
     "4:",
+    ".global exit_boot_services_return",
+    "exit_boot_services_return:", // The guest resumes here once launched.
     "mov rax, qword ptr [rsp + 32]", // exit failed or in virtual machine.
     "add rsp, 40",
     "ret",
@@ -54,30 +120,38 @@ core::arch::global_asm!(
     "mov [{uefi_registers} + 152], rax",
     "mov rax, cr4",
     "mov [{uefi_registers} + 160], rax",
-    "mov ax, cs",
-    "mov [{uefi_registers} + 168], ax",
-    "mov ax, ss",
-    "mov [{uefi_registers} + 170], ax",
-    "mov ax, ds",
-    "mov [{uefi_registers} + 172], ax",
-    "mov ax, es",
-    "mov [{uefi_registers} + 174], ax",
-    "mov ax, fs",
-    "mov [{uefi_registers} + 176], ax",
-    "mov ax, gs",
-    "mov [{uefi_registers} + 178], ax",
-    "sldt [{uefi_registers} + 180]",
-    "str [{uefi_registers} + 182]",
     "pushfq",
     "pop rax",
-    "mov [{uefi_registers} + 184], rax",
+    "mov [{uefi_registers} + 168], rax",
     "call {setup_virtualization}",
-    intercepted_func = sym crate::EXIT_BOOT_SERVICES_PTR,
+    intercepted_func = sym crate::exit_boot_services::forward,
     setup_virtualization = sym crate::setup_virtualization,
     uefi_registers = sym REGISTERS
 );
 
-pub static mut REGISTERS: MaybeUninit<UefiRegisters> = MaybeUninit::zeroed();
+/// The registers saved by `exit_boot_services_handler`, which are only accessed through
+/// [`machine_state`] once written.
+static mut REGISTERS: MaybeUninit<UefiRegisters> = MaybeUninit::zeroed();
+
+/// A copy of [`REGISTERS`], taken the first time it is accessed.
+static MACHINE_STATE: Lazy<UefiRegisters> = Lazy::new(|| {
+    let registers = &raw const REGISTERS;
+    // SAFETY:
+    // `REGISTERS` is only written by `exit_boot_services_handler` before virtualization is set up.
+    let registers = unsafe { &*registers };
+    // SAFETY:
+    // `REGISTERS` is zero-initialized, and every bit pattern is a valid `UefiRegisters`.
+    unsafe { registers.assume_init_read() }
+});
+
+/// Returns the state of the processor saved by `exit_boot_services_handler` when
+/// `ExitBootServices()` succeeded.
+///
+/// The state is captured the first time this is called, which must be after
+/// `ExitBootServices()` succeeded.
+pub fn machine_state() -> &'static UefiRegisters {
+    &MACHINE_STATE
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Hash, Default, PartialEq, Eq)]
@@ -103,13 +177,5 @@ pub struct UefiRegisters {
     cr2: u64,
     cr3: u64,
     cr4: u64,
-    cs: u16,
-    ss: u16,
-    ds: u16,
-    es: u16,
-    fs: u16,
-    gs: u16,
-    ldtr: u16,
-    tr: u16,
     rflags: u64,
 }
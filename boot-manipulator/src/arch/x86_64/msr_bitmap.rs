@@ -0,0 +1,204 @@
+//! Management of the VMX MSR bitmap, which selects the MSR accesses that cause VM exits.
+
+use core::{fmt, ptr::NonNull};
+
+use crate::frames::{allocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE};
+
+/// The size of an MSR bitmap in bytes.
+const BITMAP_SIZE: usize = 4096;
+
+// The bitmap is allocated as a single frame.
+const _: () = assert!(BITMAP_SIZE <= FRAME_SIZE);
+
+/// The last MSR of the low range covered by the bitmap.
+const LOW_MSR_LAST: u32 = 0x1FFF;
+/// The first MSR of the high range covered by the bitmap.
+const HIGH_MSR_BASE: u32 = 0xC000_0000;
+/// The last MSR of the high range covered by the bitmap.
+const HIGH_MSR_LAST: u32 = 0xC000_1FFF;
+
+/// The offset of the bitmap covering reads of the low MSR range.
+const READ_LOW_OFFSET: usize = 0;
+/// The offset of the bitmap covering reads of the high MSR range.
+const READ_HIGH_OFFSET: usize = 1024;
+/// The offset of the bitmap covering writes to the low MSR range.
+const WRITE_LOW_OFFSET: usize = 2048;
+/// The offset of the bitmap covering writes to the high MSR range.
+const WRITE_HIGH_OFFSET: usize = 3072;
+
+/// A 4 KiB MSR bitmap, in which every set bit causes the corresponding MSR access to exit.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MsrBitmap {
+    /// The page holding the bitmap.
+    frame: NonNull<u8>,
+}
+
+impl MsrBitmap {
+    /// Allocates a zeroed [`MsrBitmap`], which intercepts no MSR accesses.
+    ///
+    /// # Errors
+    /// Returns an [`OutOfMemoryError`] if the page holding the bitmap cannot be allocated.
+    pub fn allocate() -> Result<Self, OutOfMemoryError> {
+        Ok(Self {
+            frame: allocate_frames(1, MemoryKind::Persistent)?,
+        })
+    }
+
+    /// Causes reads of `msr` to exit.
+    ///
+    /// # Errors
+    /// Returns [`MsrOutOfRange`] if `msr` is not covered by the bitmap.
+    pub fn intercept_read(&mut self, msr: u32) -> Result<(), MsrOutOfRange> {
+        self.set(msr, false)
+    }
+
+    /// Causes writes to `msr` to exit.
+    ///
+    /// # Errors
+    /// Returns [`MsrOutOfRange`] if `msr` is not covered by the bitmap.
+    pub fn intercept_write(&mut self, msr: u32) -> Result<(), MsrOutOfRange> {
+        self.set(msr, true)
+    }
+
+    /// Consumes the [`MsrBitmap`], returning the page holding it, which remains allocated.
+    ///
+    /// As UEFI identity maps all memory, the address of the page is also its physical address.
+    pub fn into_frame(self) -> *mut u8 {
+        self.frame.as_ptr()
+    }
+
+    /// Sets the bit intercepting writes to `msr` if `write` is set, or reads of it otherwise.
+    fn set(&mut self, msr: u32, write: bool) -> Result<(), MsrOutOfRange> {
+        let (byte, bit) = bit_position(msr, write).ok_or(MsrOutOfRange(msr))?;
+
+        // SAFETY:
+        // `bit_position` only returns offsets within the `BITMAP_SIZE` bytes of the page.
+        let entry = unsafe { self.frame.as_ptr().add(byte) };
+        // SAFETY:
+        // `entry` lies within the page owned by this bitmap.
+        unsafe { *entry |= 1 << bit }
+
+        Ok(())
+    }
+}
+
+/// Returns `true` if `msr` lies within the ranges covered by an [`MsrBitmap`].
+///
+/// Accesses to MSRs outside of these ranges always exit.
+pub fn covers(msr: u32) -> bool {
+    bit_position(msr, false).is_some()
+}
+
+/// Returns the byte offset and bit index within an MSR bitmap of the bit intercepting writes to
+/// `msr` if `write` is set, or reads of it otherwise.
+///
+/// Returns [`None`] if `msr` lies outside of the ranges covered by the bitmap.
+fn bit_position(msr: u32, write: bool) -> Option<(usize, u8)> {
+    let (index, base) = match (msr, write) {
+        (0..=LOW_MSR_LAST, false) => (msr, READ_LOW_OFFSET),
+        (HIGH_MSR_BASE..=HIGH_MSR_LAST, false) => (msr - HIGH_MSR_BASE, READ_HIGH_OFFSET),
+        (0..=LOW_MSR_LAST, true) => (msr, WRITE_LOW_OFFSET),
+        (HIGH_MSR_BASE..=HIGH_MSR_LAST, true) => (msr - HIGH_MSR_BASE, WRITE_HIGH_OFFSET),
+        _ => return None,
+    };
+
+    Some((base + (index / 8) as usize, (index % 8) as u8))
+}
+
+/// An MSR outside of the ranges covered by an [`MsrBitmap`], whose accesses always exit.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct MsrOutOfRange(pub u32);
+
+impl fmt::Display for MsrOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MSR {:#x} is not covered by the MSR bitmap", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_msrs_map_to_the_first_quadrants() {
+        assert_eq!(bit_position(0, false), Some((READ_LOW_OFFSET, 0)));
+        assert_eq!(bit_position(0x3A, false), Some((READ_LOW_OFFSET + 7, 2)));
+        assert_eq!(
+            bit_position(LOW_MSR_LAST, false),
+            Some((READ_LOW_OFFSET + 1023, 7))
+        );
+        assert_eq!(bit_position(0x3A, true), Some((WRITE_LOW_OFFSET + 7, 2)));
+        assert_eq!(
+            bit_position(LOW_MSR_LAST, true),
+            Some((WRITE_LOW_OFFSET + 1023, 7))
+        );
+    }
+
+    #[test]
+    fn high_msrs_map_to_the_second_quadrants() {
+        assert_eq!(
+            bit_position(HIGH_MSR_BASE, false),
+            Some((READ_HIGH_OFFSET, 0))
+        );
+        // IA32_EFER.
+        assert_eq!(
+            bit_position(0xC000_0080, false),
+            Some((READ_HIGH_OFFSET + 16, 0))
+        );
+        assert_eq!(
+            bit_position(0xC000_0080, true),
+            Some((WRITE_HIGH_OFFSET + 16, 0))
+        );
+        assert_eq!(
+            bit_position(HIGH_MSR_LAST, true),
+            Some((BITMAP_SIZE - 1, 7))
+        );
+    }
+
+    #[test]
+    fn msrs_outside_the_ranges_are_rejected() {
+        for msr in [
+            0x2000,
+            0x4000_0000,
+            HIGH_MSR_BASE - 1,
+            0xC000_2000,
+            u32::MAX,
+        ] {
+            assert_eq!(bit_position(msr, false), None, "{msr:#x}");
+            assert_eq!(bit_position(msr, true), None, "{msr:#x}");
+            assert!(!covers(msr));
+        }
+
+        assert!(covers(0x3A));
+        assert!(covers(0xC000_0080));
+    }
+
+    #[test]
+    fn intercepts_set_a_single_bit() {
+        let mut page = vec![0u8; BITMAP_SIZE];
+        let mut bitmap = MsrBitmap {
+            frame: NonNull::new(page.as_mut_ptr()).unwrap(),
+        };
+
+        bitmap.intercept_read(0x3A).unwrap();
+        bitmap.intercept_write(0xC000_0081).unwrap();
+        assert_eq!(
+            bitmap.intercept_read(0x4000_0000),
+            Err(MsrOutOfRange(0x4000_0000))
+        );
+
+        let set = page
+            .iter()
+            .enumerate()
+            .filter(|&(_, &byte)| byte != 0)
+            .map(|(offset, &byte)| (offset, byte))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            set,
+            [
+                (READ_LOW_OFFSET + 7, 1 << 2),
+                (WRITE_HIGH_OFFSET + 16, 1 << 1)
+            ]
+        );
+    }
+}
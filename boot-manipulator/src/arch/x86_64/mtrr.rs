@@ -0,0 +1,208 @@
+//! Decoding of the memory types assigned to physical memory by the Memory Type Range Registers.
+
+use crate::arch::x86_64::registers::msr::{
+    read_msr, MTRR_CAP, MTRR_DEF_TYPE, MTRR_FIX_16K_80000, MTRR_FIX_4K_C0000, MTRR_FIX_64K_00000,
+    MTRR_PHYS_BASE_0,
+};
+
+/// The bit in `IA32_MTRR_DEF_TYPE` enabling the MTRRs.
+const DEF_TYPE_ENABLE: u64 = 1 << 11;
+/// The bit in `IA32_MTRR_DEF_TYPE` enabling the fixed-range MTRRs.
+const DEF_TYPE_FIXED_ENABLE: u64 = 1 << 10;
+/// The bit in `IA32_MTRRCAP` reporting support for the fixed-range MTRRs.
+const CAP_FIXED: u64 = 1 << 8;
+/// The bit in an `IA32_MTRR_PHYSMASKn` register enabling the variable range.
+const PHYS_MASK_VALID: u64 = 1 << 11;
+/// The bits of `IA32_MTRR_PHYSBASEn` and `IA32_MTRR_PHYSMASKn` holding an address.
+const PHYS_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The end of the memory covered by the fixed-range MTRRs.
+const FIXED_RANGE_END: u64 = 0x10_0000;
+
+/// The maximum number of variable-range MTRRs that are taken into account.
+const MAX_VARIABLE_RANGES: usize = 32;
+
+/// The memory types that can be assigned to physical memory.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    /// Uncacheable.
+    Uncacheable = 0,
+    /// Write combining.
+    WriteCombining = 1,
+    /// Write-through.
+    WriteThrough = 4,
+    /// Write-protected.
+    WriteProtected = 5,
+    /// Write-back.
+    WriteBack = 6,
+}
+
+impl MemoryType {
+    /// Returns the [`MemoryType`] with the architectural encoding `value`, treating reserved
+    /// encodings as [`MemoryType::Uncacheable`].
+    fn from_encoding(value: u8) -> Self {
+        match value {
+            1 => Self::WriteCombining,
+            4 => Self::WriteThrough,
+            5 => Self::WriteProtected,
+            6 => Self::WriteBack,
+            _ => Self::Uncacheable,
+        }
+    }
+
+    /// Returns the memory type of memory covered by both `self` and `other`.
+    fn combine(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Self::WriteThrough, Self::WriteBack) | (Self::WriteBack, Self::WriteThrough) => {
+                Self::WriteThrough
+            }
+            _ => Self::Uncacheable,
+        }
+    }
+}
+
+/// A single enabled variable-range MTRR.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct VariableRange {
+    /// The base address of the range.
+    base: u64,
+    /// The mask selecting the address bits compared against `base`.
+    mask: u64,
+    /// The memory type of the range.
+    memory_type: MemoryType,
+}
+
+/// A snapshot of the MTRRs of the running processor.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct MtrrMap {
+    /// Whether the MTRRs are enabled at all.
+    enabled: bool,
+    /// The memory type of memory not covered by any MTRR.
+    default_type: MemoryType,
+    /// The memory types of the first 1 MiB in 4 KiB units, if the fixed-range MTRRs are enabled.
+    fixed: Option<[MemoryType; 256]>,
+    /// The enabled variable ranges.
+    variable: [VariableRange; MAX_VARIABLE_RANGES],
+    /// The number of valid entries in `variable`.
+    variable_count: usize,
+}
+
+impl MtrrMap {
+    /// Reads the MTRRs of the running processor.
+    pub fn read() -> Self {
+        // SAFETY:
+        // The MTRR MSRs exist on every processor supporting VMX.
+        let capabilities = unsafe { read_msr(MTRR_CAP) };
+        // SAFETY:
+        // The MTRR MSRs exist on every processor supporting VMX.
+        let default = unsafe { read_msr(MTRR_DEF_TYPE) };
+
+        let fixed_enabled = capabilities & CAP_FIXED == CAP_FIXED
+            && default & DEF_TYPE_FIXED_ENABLE == DEF_TYPE_FIXED_ENABLE;
+        let fixed = fixed_enabled.then(read_fixed_ranges);
+
+        let unused = VariableRange {
+            base: 0,
+            mask: 0,
+            memory_type: MemoryType::Uncacheable,
+        };
+        let mut variable = [unused; MAX_VARIABLE_RANGES];
+        let mut variable_count = 0;
+        for index in 0..((capabilities & 0xFF) as u32).min(MAX_VARIABLE_RANGES as u32) {
+            // SAFETY:
+            // `IA32_MTRRCAP` reports that this variable range exists.
+            let base = unsafe { read_msr(MTRR_PHYS_BASE_0 + index * 2) };
+            // SAFETY:
+            // `IA32_MTRRCAP` reports that this variable range exists.
+            let mask = unsafe { read_msr(MTRR_PHYS_BASE_0 + index * 2 + 1) };
+            if mask & PHYS_MASK_VALID == 0 {
+                continue;
+            }
+
+            variable[variable_count] = VariableRange {
+                base: base & PHYS_ADDRESS_MASK,
+                mask: mask & PHYS_ADDRESS_MASK,
+                memory_type: MemoryType::from_encoding(base as u8),
+            };
+            variable_count += 1;
+        }
+
+        Self {
+            enabled: default & DEF_TYPE_ENABLE == DEF_TYPE_ENABLE,
+            default_type: MemoryType::from_encoding(default as u8),
+            fixed,
+            variable,
+            variable_count,
+        }
+    }
+
+    /// Returns the memory type of the `size` bytes starting at `base`, or [`None`] if the range
+    /// does not have a single memory type.
+    ///
+    /// `size` must be a power of two no smaller than 4 KiB and `base` must be aligned to it.
+    pub fn memory_type(&self, base: u64, size: u64) -> Option<MemoryType> {
+        if !self.enabled {
+            return Some(MemoryType::Uncacheable);
+        }
+
+        if let Some(fixed) = &self.fixed {
+            if base < FIXED_RANGE_END {
+                let first = fixed[(base / 4096) as usize];
+                let end = (base + size).min(FIXED_RANGE_END);
+                let uniform = (base / 4096..end / 4096).all(|page| fixed[page as usize] == first);
+
+                return (uniform && base + size <= FIXED_RANGE_END).then_some(first);
+            }
+        }
+
+        let mut memory_type = None::<MemoryType>;
+        for range in &self.variable[..self.variable_count] {
+            // Bits of the mask within the range would make the MTRR cover only part of it.
+            let outer_mask = range.mask & !(size - 1);
+            if base & outer_mask != range.base & outer_mask {
+                continue;
+            }
+            if range.mask & (size - 1) != 0 {
+                return None;
+            }
+
+            memory_type = Some(match memory_type {
+                Some(current) => current.combine(range.memory_type),
+                None => range.memory_type,
+            });
+        }
+
+        Some(memory_type.unwrap_or(self.default_type))
+    }
+}
+
+/// Reads the fixed-range MTRRs, returning the memory type of each 4 KiB page of the first 1 MiB.
+fn read_fixed_ranges() -> [MemoryType; 256] {
+    // Each fixed-range MTRR holds eight memory types, each covering `unit` bytes.
+    let registers = [
+        (MTRR_FIX_64K_00000, 1, 0x1_0000),
+        (MTRR_FIX_16K_80000, 2, 0x4000),
+        (MTRR_FIX_4K_C0000, 8, 0x1000),
+    ];
+
+    let mut types = [MemoryType::Uncacheable; 256];
+    let mut page = 0;
+    for (first, count, unit) in registers {
+        for msr in first..first + count {
+            // SAFETY:
+            // The fixed-range MTRRs exist when `IA32_MTRRCAP` reports support for them.
+            let value = unsafe { read_msr(msr) };
+            for byte in value.to_le_bytes() {
+                let memory_type = MemoryType::from_encoding(byte);
+                for _ in 0..unit / 4096 {
+                    types[page] = memory_type;
+                    page += 1;
+                }
+            }
+        }
+    }
+
+    types
+}
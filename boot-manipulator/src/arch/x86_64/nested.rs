@@ -0,0 +1,187 @@
+//! Detection of a parent hypervisor when `boot-manipulator` itself runs virtualized.
+
+use core::{
+    arch::x86_64::{__cpuid, CpuidResult},
+    fmt,
+};
+
+/// Bit in CPUID leaf 1 ECX indicating that a hypervisor is present.
+const CPUID_HYPERVISOR_PRESENT: u32 = 1 << 31;
+
+/// The CPUID leaf containing the hypervisor vendor signature.
+const CPUID_HYPERVISOR_VENDOR_LEAF: u32 = 0x4000_0000;
+
+/// A hypervisor underneath `boot-manipulator`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ParentHypervisor {
+    /// Linux KVM.
+    Kvm,
+    /// Microsoft Hyper-V.
+    HyperV,
+    /// VMware.
+    VMware,
+    /// Xen.
+    Xen,
+    /// QEMU's software emulation (TCG).
+    Tcg,
+    /// Oracle VirtualBox.
+    VirtualBox,
+    /// A hypervisor that does not implement the vendor leaf.
+    Unidentified,
+    /// An unrecognized hypervisor with the given vendor signature.
+    Unknown([u8; 12]),
+}
+
+impl ParentHypervisor {
+    /// Identifies the parent hypervisor from the registers returned by CPUID leaf `0x4000_0000`.
+    pub fn from_signature(ebx: u32, ecx: u32, edx: u32) -> Self {
+        let mut signature = [0; 12];
+        signature[..4].copy_from_slice(&ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&ecx.to_le_bytes());
+        signature[8..].copy_from_slice(&edx.to_le_bytes());
+
+        match &signature {
+            b"KVMKVMKVM\0\0\0" => Self::Kvm,
+            b"Microsoft Hv" => Self::HyperV,
+            b"VMwareVMware" => Self::VMware,
+            b"XenVMMXenVMM" => Self::Xen,
+            b"TCGTCGTCGTCG" => Self::Tcg,
+            b"VBoxVBoxVBox" => Self::VirtualBox,
+            _ => Self::Unknown(signature),
+        }
+    }
+}
+
+impl fmt::Display for ParentHypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvm => write!(f, "KVM"),
+            Self::HyperV => write!(f, "Hyper-V"),
+            Self::VMware => write!(f, "VMware"),
+            Self::Xen => write!(f, "Xen"),
+            Self::Tcg => write!(f, "QEMU TCG"),
+            Self::VirtualBox => write!(f, "VirtualBox"),
+            Self::Unidentified => write!(f, "unidentified hypervisor"),
+            Self::Unknown(signature) => {
+                write!(f, "unknown hypervisor \"")?;
+                for &byte in signature {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        write!(f, "{}", byte as char)?;
+                    } else {
+                        write!(f, "\\x{byte:02x}")?;
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+/// Returns the [`ParentHypervisor`] if the processor reports that it is virtualized.
+pub fn detect() -> Option<ParentHypervisor> {
+    identify(__cpuid(1), __cpuid(CPUID_HYPERVISOR_VENDOR_LEAF))
+}
+
+/// Returns the [`ParentHypervisor`] described by CPUID leaf 1, `features`, and the hypervisor
+/// vendor leaf, `vendor`, or [`None`] if no hypervisor is present.
+fn identify(features: CpuidResult, vendor: CpuidResult) -> Option<ParentHypervisor> {
+    if features.ecx & CPUID_HYPERVISOR_PRESENT == 0 {
+        return None;
+    }
+
+    // The vendor leaf reports the highest hypervisor leaf in EAX. Below the vendor leaf itself,
+    // the leaf is not implemented and the processor returned the data of another leaf instead.
+    if vendor.eax < CPUID_HYPERVISOR_VENDOR_LEAF {
+        return Some(ParentHypervisor::Unidentified);
+    }
+
+    Some(ParentHypervisor::from_signature(
+        vendor.ebx, vendor.ecx, vendor.edx,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPUID leaf 1 with the hypervisor-present bit set.
+    const VIRTUALIZED: CpuidResult = CpuidResult {
+        eax: 0x000A_0671,
+        ebx: 0x0000_0800,
+        ecx: 0xFFFA_3203,
+        edx: 0x0F8B_FBFF,
+    };
+
+    /// Returns the vendor leaf reporting `signature` and `max_leaf`.
+    fn vendor(max_leaf: u32, signature: &[u8; 12]) -> CpuidResult {
+        let register = |index: usize| {
+            u32::from_le_bytes(signature[index * 4..index * 4 + 4].try_into().unwrap())
+        };
+
+        CpuidResult {
+            eax: max_leaf,
+            ebx: register(0),
+            ecx: register(1),
+            edx: register(2),
+        }
+    }
+
+    #[test]
+    fn identifies_known_signatures() {
+        let cases = [
+            (0x4000_0001, b"KVMKVMKVM\0\0\0", ParentHypervisor::Kvm),
+            (0x4000_000B, b"Microsoft Hv", ParentHypervisor::HyperV),
+            (0x4000_0010, b"VMwareVMware", ParentHypervisor::VMware),
+            (0x4000_0005, b"XenVMMXenVMM", ParentHypervisor::Xen),
+            (0x4000_0001, b"TCGTCGTCGTCG", ParentHypervisor::Tcg),
+            (0x4000_0006, b"VBoxVBoxVBox", ParentHypervisor::VirtualBox),
+        ];
+
+        for (max_leaf, signature, expected) in cases {
+            assert_eq!(
+                identify(VIRTUALIZED, vendor(max_leaf, signature)),
+                Some(expected),
+                "{signature:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_unknown_signatures() {
+        let signature = *b"bhyve bhyve ";
+
+        assert_eq!(
+            identify(VIRTUALIZED, vendor(0x4000_0000, &signature)),
+            Some(ParentHypervisor::Unknown(signature))
+        );
+        assert_eq!(
+            ParentHypervisor::Unknown(*b"Odd\0\x7Fnames\x01 ").to_string(),
+            "unknown hypervisor \"Odd\\x00\\x7fnames\\x01 \""
+        );
+    }
+
+    #[test]
+    fn ignores_a_vendor_leaf_below_the_hypervisor_range() {
+        // Intel processors return the highest basic leaf for unimplemented leaves, so the
+        // registers hold unrelated data.
+        let basic = vendor(0x0000_0016, b"KVMKVMKVM\0\0\0");
+
+        assert_eq!(
+            identify(VIRTUALIZED, basic),
+            Some(ParentHypervisor::Unidentified)
+        );
+    }
+
+    #[test]
+    fn reports_bare_metal_without_the_hypervisor_bit() {
+        let bare_metal = CpuidResult {
+            ecx: VIRTUALIZED.ecx & !CPUID_HYPERVISOR_PRESENT,
+            ..VIRTUALIZED
+        };
+
+        assert_eq!(
+            identify(bare_metal, vendor(0x4000_0001, b"KVMKVMKVM\0\0\0")),
+            None
+        );
+    }
+}
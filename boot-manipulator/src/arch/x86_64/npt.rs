@@ -0,0 +1,89 @@
+//! Construction of the nested page tables translating guest-physical to host-physical addresses
+//! under SVM.
+
+use core::arch::x86_64::__cpuid;
+
+use crate::arch::x86_64::paging::{
+    self, allocate_tables, EntryFormat, PageSize, PagingError, ENTRY_ADDRESS_MASK, ENTRY_LARGE_PAGE,
+};
+
+/// The CPUID leaf reporting SVM features.
+const CPUID_SVM_FEATURES: u32 = 0x8000_000A;
+/// The bit in EDX of [`CPUID_SVM_FEATURES`] reporting support for nested paging.
+const CPUID_SVM_FEATURES_EDX_NESTED_PAGING: u32 = 1;
+
+/// The bit in a long-mode entry marking it as present.
+const ENTRY_PRESENT: u64 = 1 << 0;
+/// The bit in a long-mode entry allowing writes.
+const ENTRY_WRITABLE: u64 = 1 << 1;
+/// The bit in a long-mode entry allowing user-mode accesses, which nested page walks always are.
+const ENTRY_USER: u64 = 1 << 2;
+
+/// The bit in the error code of a nested page fault set if the page was present.
+pub const FAULT_PRESENT: u64 = 1 << 0;
+/// The bit in the error code of a nested page fault set if the access was a write.
+pub const FAULT_WRITE: u64 = 1 << 1;
+/// The bit in the error code of a nested page fault set if a reserved bit was set in an entry.
+pub const FAULT_RESERVED: u64 = 1 << 3;
+/// The bit in the error code of a nested page fault set if the access was an instruction fetch.
+pub const FAULT_EXECUTE: u64 = 1 << 4;
+/// The bit in the error code of a nested page fault set if the fault occurred while translating
+/// the final guest-physical address.
+pub const FAULT_FINAL_TRANSLATION: u64 = 1 << 32;
+/// The bit in the error code of a nested page fault set if the fault occurred while translating a
+/// guest paging structure.
+pub const FAULT_PAGE_TABLE_WALK: u64 = 1 << 33;
+
+/// The encoding of nested page table entries, which is that of long-mode paging.
+pub struct LongModeFormat;
+
+impl EntryFormat for LongModeFormat {
+    fn table_entry(table: u64) -> u64 {
+        (table & ENTRY_ADDRESS_MASK) | ENTRY_PRESENT | ENTRY_WRITABLE | ENTRY_USER
+    }
+
+    fn is_present(entry: u64) -> bool {
+        entry & ENTRY_PRESENT == ENTRY_PRESENT
+    }
+}
+
+/// Returns a nested page table entry mapping the writable page of `size` at `address`.
+///
+/// The entry selects PAT entry 0, so the effective memory type follows the MTRRs and the guest's
+/// own page tables.
+pub fn page_entry(address: u64, size: PageSize) -> u64 {
+    let large = match size {
+        PageSize::Size4KiB => 0,
+        PageSize::Size2MiB | PageSize::Size1GiB => ENTRY_LARGE_PAGE,
+    };
+
+    (address & ENTRY_ADDRESS_MASK) | ENTRY_PRESENT | ENTRY_WRITABLE | ENTRY_USER | large
+}
+
+/// Returns `true` if the processor supports nested paging.
+pub fn is_supported() -> bool {
+    __cpuid(CPUID_SVM_FEATURES).edx & CPUID_SVM_FEATURES_EDX_NESTED_PAGING != 0
+}
+
+/// Builds nested page tables identity mapping the first `size` bytes of physical memory with
+/// 2 MiB pages, returning the physical address of their PML4 for use as the nested CR3.
+///
+/// # Errors
+/// Returns a [`PagingError`] if `size` is not a multiple of 4 KiB or a paging structure cannot be
+/// allocated.
+pub fn build_identity_map(size: u64) -> Result<u64, PagingError> {
+    let pml4 = allocate_tables(1).ok_or(PagingError::OutOfMemory)?;
+
+    let next_page = |address, remaining| {
+        let page_size = if remaining >= PageSize::Size2MiB.bytes() {
+            PageSize::Size2MiB
+        } else {
+            PageSize::Size4KiB
+        };
+
+        (page_size, page_entry(address, page_size))
+    };
+    paging::identity_map::<LongModeFormat>(pml4, 0, size, next_page, || allocate_tables(1))?;
+
+    Ok(pml4.as_ptr() as u64)
+}
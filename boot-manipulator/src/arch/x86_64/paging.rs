@@ -0,0 +1,286 @@
+//! Construction of 4-level paging structures shared by EPT and nested paging, which differ only in
+//! the encoding of their entries.
+
+use core::{fmt, ptr::NonNull};
+
+use crate::frames::{allocate_frames, MemoryKind};
+
+/// The size of a page and of each paging structure.
+pub const PAGE_SIZE: u64 = 4096;
+/// The number of entries in each paging structure.
+pub const ENTRIES_PER_TABLE: usize = 512;
+
+/// The bit in an entry mapping a page rather than referencing another paging structure, which is
+/// shared by EPT and long-mode entries.
+pub const ENTRY_LARGE_PAGE: u64 = 1 << 7;
+/// The bits of an entry holding a physical address.
+pub const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The CPUID leaf reporting the physical and linear address widths.
+const CPUID_ADDRESS_SIZES: u32 = 0x8000_0008;
+
+/// Returns the number of bits in a physical address on the current processor, MAXPHYADDR.
+pub fn physical_address_width() -> u32 {
+    // CPUID leaf 0x80000008 exists on every processor supporting 64-bit mode.
+    core::arch::x86_64::__cpuid(CPUID_ADDRESS_SIZES).eax & 0xFF
+}
+
+/// The encoding of the entries of a 4-level paging hierarchy.
+pub trait EntryFormat {
+    /// Returns an entry referencing the paging structure at `table`.
+    fn table_entry(table: u64) -> u64;
+
+    /// Returns `true` if `entry` maps a page or references a paging structure.
+    fn is_present(entry: u64) -> bool;
+}
+
+/// The sizes of the pages an entry can map.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum PageSize {
+    /// A 4 KiB page mapped by a page-table entry.
+    Size4KiB,
+    /// A 2 MiB page mapped by a page-directory entry.
+    Size2MiB,
+    /// A 1 GiB page mapped by a page-directory-pointer-table entry.
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Returns the number of bytes in a page of this size.
+    pub fn bytes(self) -> u64 {
+        match self {
+            Self::Size4KiB => 1 << 12,
+            Self::Size2MiB => 1 << 21,
+            Self::Size1GiB => 1 << 30,
+        }
+    }
+
+    /// Returns the level of the paging structure holding entries that map pages of this size,
+    /// where the page table is level 0 and the PML4 is level 3.
+    fn level(self) -> u32 {
+        match self {
+            Self::Size4KiB => 0,
+            Self::Size2MiB => 1,
+            Self::Size1GiB => 2,
+        }
+    }
+}
+
+/// Identity maps the `size` bytes of physical memory starting at `base` in the hierarchy rooted at
+/// `pml4`.
+///
+/// `next_page` is given the address of each page and the number of bytes left to map, and returns
+/// the size of the page to map there along with the entry mapping it. Intermediate paging
+/// structures are taken from `allocate_table`.
+///
+/// # Errors
+/// - Returns [`PagingError::Misaligned`] if `base` or `size` is not a multiple of 4 KiB.
+/// - Returns [`PagingError::ConflictingMapping`] if part of the range is already mapped by a
+///   larger page.
+/// - Returns [`PagingError::OutOfMemory`] if a paging structure cannot be allocated.
+pub fn identity_map<F: EntryFormat>(
+    pml4: NonNull<u64>,
+    base: u64,
+    size: u64,
+    mut next_page: impl FnMut(u64, u64) -> (PageSize, u64),
+    mut allocate_table: impl FnMut() -> Option<NonNull<u64>>,
+) -> Result<(), PagingError> {
+    if !(base | size).is_multiple_of(PAGE_SIZE) {
+        return Err(PagingError::Misaligned);
+    }
+
+    let end = base + size;
+    let mut address = base;
+    while address < end {
+        let (page_size, entry) = next_page(address, end - address);
+        map_page::<F>(pml4, address, page_size, entry, &mut allocate_table)?;
+        address += page_size.bytes();
+    }
+
+    Ok(())
+}
+
+/// Writes `entry`, mapping the page of `size` at `address`, into the hierarchy rooted at `pml4`,
+/// taking any missing intermediate paging structures from `allocate_table`.
+///
+/// # Errors
+/// - Returns [`PagingError::ConflictingMapping`] if `address` is already mapped by a larger page.
+/// - Returns [`PagingError::OutOfMemory`] if a paging structure cannot be allocated.
+pub fn map_page<F: EntryFormat>(
+    pml4: NonNull<u64>,
+    address: u64,
+    size: PageSize,
+    entry: u64,
+    mut allocate_table: impl FnMut() -> Option<NonNull<u64>>,
+) -> Result<(), PagingError> {
+    let mut table = pml4;
+    for level in (size.level() + 1..=3).rev() {
+        // SAFETY:
+        // `table` is a paging structure of the hierarchy, which holds `ENTRIES_PER_TABLE` entries.
+        let slot = unsafe { table.add(table_index(address, level)) };
+        // SAFETY:
+        // `slot` lies within `table`.
+        let value = unsafe { slot.read() };
+
+        table = if !F::is_present(value) {
+            let next = allocate_table().ok_or(PagingError::OutOfMemory)?;
+            // SAFETY:
+            // `slot` lies within `table`.
+            unsafe { slot.write(F::table_entry(next.as_ptr() as u64)) }
+            next
+        } else if value & ENTRY_LARGE_PAGE == ENTRY_LARGE_PAGE {
+            return Err(PagingError::ConflictingMapping(address));
+        } else {
+            // UEFI identity maps all memory, so the physical address in the entry is usable as a
+            // pointer.
+            NonNull::new((value & ENTRY_ADDRESS_MASK) as *mut u64)
+                .ok_or(PagingError::ConflictingMapping(address))?
+        };
+    }
+
+    // SAFETY:
+    // `table` is a paging structure of the hierarchy, which holds `ENTRIES_PER_TABLE` entries.
+    let slot = unsafe { table.add(table_index(address, size.level())) };
+    // SAFETY:
+    // `slot` lies within `table`.
+    unsafe { slot.write(entry) }
+
+    Ok(())
+}
+
+/// Returns the entry mapping `address` in the hierarchy rooted at `pml4` along with the size of the
+/// page it maps, or [`None`] if `address` is not mapped.
+pub fn lookup<F: EntryFormat>(pml4: NonNull<u64>, address: u64) -> Option<(u64, PageSize)> {
+    let mut table = pml4;
+    for level in (0..=3).rev() {
+        // SAFETY:
+        // `table` is a paging structure of the hierarchy, which holds `ENTRIES_PER_TABLE` entries.
+        let slot = unsafe { table.add(table_index(address, level)) };
+        // SAFETY:
+        // `slot` lies within `table`.
+        let entry = unsafe { slot.read() };
+        if !F::is_present(entry) {
+            return None;
+        }
+
+        let large = entry & ENTRY_LARGE_PAGE == ENTRY_LARGE_PAGE;
+        match level {
+            0 => return Some((entry, PageSize::Size4KiB)),
+            1 if large => return Some((entry, PageSize::Size2MiB)),
+            2 if large => return Some((entry, PageSize::Size1GiB)),
+            _ => {}
+        }
+
+        // UEFI identity maps all memory, so the physical address in the entry is usable as a
+        // pointer.
+        table = NonNull::new((entry & ENTRY_ADDRESS_MASK) as *mut u64)?;
+    }
+
+    None
+}
+
+/// Removes the 4 KiB page mapped at `address` from the hierarchy rooted at `pml4`.
+///
+/// Each paging structure left without entries is offered to `owns_table`, from the page table
+/// upwards. If it returns `true`, the structure is unlinked from its parent and the next one is
+/// offered; otherwise no further structures are offered. The unlinked structures are returned, and
+/// may be freed once the TLB entries for `address` have been invalidated.
+///
+/// Returns [`None`] if `address` is not mapped by a 4 KiB page.
+pub fn unmap_page<F: EntryFormat>(
+    pml4: NonNull<u64>,
+    address: u64,
+    mut owns_table: impl FnMut(NonNull<u64>) -> bool,
+) -> Option<[Option<NonNull<u64>>; 3]> {
+    // The slot referencing the paging structure of each level below the PML4, from the page table
+    // upwards, and the structure itself.
+    let mut path: [Option<(NonNull<u64>, NonNull<u64>)>; 3] = [None; 3];
+
+    let mut table = pml4;
+    for level in (1..=3).rev() {
+        // SAFETY:
+        // `table` is a paging structure of the hierarchy, which holds `ENTRIES_PER_TABLE` entries.
+        let slot = unsafe { table.add(table_index(address, level)) };
+        // SAFETY:
+        // `slot` lies within `table`.
+        let entry = unsafe { slot.read() };
+        if !F::is_present(entry) || entry & ENTRY_LARGE_PAGE == ENTRY_LARGE_PAGE {
+            return None;
+        }
+
+        // UEFI identity maps all memory, so the physical address in the entry is usable as a
+        // pointer.
+        let next = NonNull::new((entry & ENTRY_ADDRESS_MASK) as *mut u64)?;
+        path[level as usize - 1] = Some((slot, next));
+        table = next;
+    }
+
+    // SAFETY:
+    // `table` is a page table of the hierarchy, which holds `ENTRIES_PER_TABLE` entries.
+    let slot = unsafe { table.add(table_index(address, 0)) };
+    // SAFETY:
+    // `slot` lies within `table`.
+    if !F::is_present(unsafe { slot.read() }) {
+        return None;
+    }
+    // SAFETY:
+    // `slot` lies within `table`.
+    unsafe { slot.write(0) }
+
+    let mut unlinked = [None; 3];
+    for ((slot, table), unlinked) in path.into_iter().flatten().zip(&mut unlinked) {
+        // SAFETY:
+        // `table` is a paging structure of the hierarchy, which holds `ENTRIES_PER_TABLE` entries.
+        let entries = unsafe { core::slice::from_raw_parts(table.as_ptr(), ENTRIES_PER_TABLE) };
+        if entries.iter().any(|&entry| F::is_present(entry)) || !owns_table(table) {
+            break;
+        }
+
+        // SAFETY:
+        // `slot` lies within the parent of `table`.
+        unsafe { slot.write(0) }
+        *unlinked = Some(table);
+    }
+
+    Some(unlinked)
+}
+
+/// Returns the index into the paging structure at `level` used to translate `address`.
+fn table_index(address: u64, level: u32) -> usize {
+    ((address >> (12 + 9 * level)) as usize) % ENTRIES_PER_TABLE
+}
+
+/// Allocates `count` contiguous zeroed paging structures from the firmware.
+///
+/// Returns [`None`] if the memory cannot be allocated.
+pub fn allocate_tables(count: usize) -> Option<NonNull<u64>> {
+    allocate_frames(count, MemoryKind::Persistent)
+        .ok()
+        .map(NonNull::cast)
+}
+
+/// Various errors that can occur while building a paging hierarchy.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum PagingError {
+    /// An address or size was not a multiple of 4 KiB.
+    Misaligned,
+    /// The address is already mapped by a larger page.
+    ConflictingMapping(u64),
+    /// A paging structure could not be allocated.
+    OutOfMemory,
+    /// A new mapping could not be recorded, because too many are live.
+    TooManyMappings,
+}
+
+impl fmt::Display for PagingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Misaligned => write!(f, "address or size is not 4 KiB aligned"),
+            Self::ConflictingMapping(address) => {
+                write!(f, "address {address:#x} is already mapped by a larger page")
+            }
+            Self::OutOfMemory => write!(f, "failed to allocate a paging structure"),
+            Self::TooManyMappings => write!(f, "too many pages are mapped at once"),
+        }
+    }
+}
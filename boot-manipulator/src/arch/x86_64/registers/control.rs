@@ -2,6 +2,11 @@
 
 use core::fmt;
 
+use crate::arch::x86_64::paging::{physical_address_width, PAGE_SIZE};
+
+/// The bits of CR3 holding the PCID when CR4.PCIDE is set.
+const CR3_PCID: u64 = 0xFFF;
+
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct Cr0(u64);
 
@@ -17,6 +22,39 @@ impl Cr0 {
         Self(cr0)
     }
 
+    /// Returns the raw value of the register.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Writes this value to CR0.
+    ///
+    /// # Safety
+    /// The value must be valid for the current state of the processor, and changing how memory is
+    /// accessed, such as through PG, CD or WP, must not invalidate any references in use.
+    pub unsafe fn write(self) {
+        // SAFETY:
+        // The caller guarantees that the value is valid and safe to load.
+        unsafe {
+            core::arch::asm!(
+                "mov cr0, {}",
+                in(reg) self.0,
+                options(nostack, preserves_flags)
+            )
+        }
+    }
+
+    /// Returns this value with the bits set in `fixed0` set and the bits clear in `fixed1`
+    /// cleared, as reported by the `IA32_VMX_CR0_FIXED0` and `IA32_VMX_CR0_FIXED1` MSRs.
+    pub fn apply_fixed(self, fixed0: u64, fixed1: u64) -> Self {
+        Self((self.0 | fixed0) & fixed1)
+    }
+
+    /// Returns this value with bit `bit` set to `value`.
+    fn with_bit(self, bit: u32, value: bool) -> Self {
+        Self((self.0 & !(1 << bit)) | (u64::from(value) << bit))
+    }
+
     pub fn pe(&self) -> bool {
         self.0 & 1 == 1
     }
@@ -62,6 +100,65 @@ impl Cr0 {
     }
 }
 
+/// Setters of the bits of CR0, each returning the updated value.
+#[allow(dead_code)]
+impl Cr0 {
+    /// Returns this value with CR0.PE set to `pe`.
+    pub fn set_pe(self, pe: bool) -> Self {
+        self.with_bit(0, pe)
+    }
+
+    /// Returns this value with CR0.MP set to `mp`.
+    pub fn set_mp(self, mp: bool) -> Self {
+        self.with_bit(1, mp)
+    }
+
+    /// Returns this value with CR0.EM set to `em`.
+    pub fn set_em(self, em: bool) -> Self {
+        self.with_bit(2, em)
+    }
+
+    /// Returns this value with CR0.TS set to `ts`.
+    pub fn set_ts(self, ts: bool) -> Self {
+        self.with_bit(3, ts)
+    }
+
+    /// Returns this value with CR0.ET set to `et`.
+    pub fn set_et(self, et: bool) -> Self {
+        self.with_bit(4, et)
+    }
+
+    /// Returns this value with CR0.NE set to `ne`.
+    pub fn set_ne(self, ne: bool) -> Self {
+        self.with_bit(5, ne)
+    }
+
+    /// Returns this value with CR0.WP set to `wp`.
+    pub fn set_wp(self, wp: bool) -> Self {
+        self.with_bit(16, wp)
+    }
+
+    /// Returns this value with CR0.AM set to `am`.
+    pub fn set_am(self, am: bool) -> Self {
+        self.with_bit(18, am)
+    }
+
+    /// Returns this value with CR0.NW set to `nw`.
+    pub fn set_nw(self, nw: bool) -> Self {
+        self.with_bit(29, nw)
+    }
+
+    /// Returns this value with CR0.CD set to `cd`.
+    pub fn set_cd(self, cd: bool) -> Self {
+        self.with_bit(30, cd)
+    }
+
+    /// Returns this value with CR0.PG set to `pg`.
+    pub fn set_pg(self, pg: bool) -> Self {
+        self.with_bit(31, pg)
+    }
+}
+
 impl fmt::Display for Cr0 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         Cr0Display(self.0).fmt(f)
@@ -135,6 +232,95 @@ impl Cr3 {
         }
         Self(cr3)
     }
+
+    /// Creates a [`Cr3`] from `bits`.
+    #[allow(dead_code)]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw value of the register.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Writes this value to CR3, switching to its address space and flushing the non-global
+    /// translations of the PCID it selects.
+    ///
+    /// # Safety
+    /// The paging structures at [`Cr3::table_address`] must map the code, stack, and every
+    /// reference in use, and must remain valid while CR3 holds them.
+    #[allow(dead_code)]
+    pub unsafe fn write(self) {
+        // SAFETY:
+        // The caller guarantees that the address space is valid and safe to switch to.
+        unsafe {
+            core::arch::asm!(
+                "mov cr3, {}",
+                in(reg) self.0,
+                options(nostack, preserves_flags)
+            )
+        }
+    }
+
+    /// Returns the physical address of the top-level paging structure.
+    ///
+    /// Only the bits below the MAXPHYADDR reported by CPUID are part of the address.
+    pub fn table_address(&self) -> u64 {
+        self.table_address_with(physical_address_width())
+    }
+
+    /// Returns the physical address of the top-level paging structure on a processor with a
+    /// MAXPHYADDR of `width`.
+    fn table_address_with(&self, width: u32) -> u64 {
+        let width_mask = 1u64.checked_shl(width).map_or(u64::MAX, |bit| bit - 1);
+        self.0 & width_mask & !(PAGE_SIZE - 1)
+    }
+
+    /// Returns the process-context identifier, or [`None`] if CR4.PCIDE is clear on the current
+    /// processor.
+    #[allow(dead_code)]
+    pub fn pcid(&self) -> Option<u16> {
+        self.pcid_with(Cr4::get().pcide())
+    }
+
+    /// Returns the process-context identifier, or [`None`] if `pcide` is clear.
+    fn pcid_with(&self, pcide: bool) -> Option<u16> {
+        pcide.then_some((self.0 & CR3_PCID) as u16)
+    }
+
+    /// Returns `true` if the top-level paging structure is accessed with write-through caching,
+    /// or [`None`] if CR4.PCIDE is set on the current processor and the bit is part of the PCID.
+    #[allow(dead_code)]
+    pub fn pwt(&self) -> Option<bool> {
+        self.pwt_with(Cr4::get().pcide())
+    }
+
+    /// Returns CR3.PWT, or [`None`] if `pcide` is set and the bit is part of the PCID.
+    fn pwt_with(&self, pcide: bool) -> Option<bool> {
+        (!pcide).then_some(self.0 & (1 << 3) == (1 << 3))
+    }
+
+    /// Returns `true` if the top-level paging structure is not cached, or [`None`] if CR4.PCIDE
+    /// is set on the current processor and the bit is part of the PCID.
+    #[allow(dead_code)]
+    pub fn pcd(&self) -> Option<bool> {
+        self.pcd_with(Cr4::get().pcide())
+    }
+
+    /// Returns CR3.PCD, or [`None`] if `pcide` is set and the bit is part of the PCID.
+    fn pcd_with(&self, pcide: bool) -> Option<bool> {
+        (!pcide).then_some(self.0 & (1 << 4) == (1 << 4))
+    }
+}
+
+/// Flushes the non-global translations of the current address space by rewriting CR3 with its
+/// current value.
+#[allow(dead_code)]
+pub fn flush_tlb_all() {
+    // SAFETY:
+    // Reloading CR3 keeps the current address space, and only invalidates cached translations.
+    unsafe { Cr3::get().write() }
 }
 
 #[derive(Clone, Copy, Default, Hash, PartialEq, Eq)]
@@ -152,6 +338,39 @@ impl Cr4 {
         Self(cr4)
     }
 
+    /// Returns the raw value of the register.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Writes this value to CR4.
+    ///
+    /// # Safety
+    /// The value must be valid for the current state of the processor, and changing how memory is
+    /// accessed, such as through PAE, PGE or PCIDE, must not invalidate any references in use.
+    pub unsafe fn write(self) {
+        // SAFETY:
+        // The caller guarantees that the value is valid and safe to load.
+        unsafe {
+            core::arch::asm!(
+                "mov cr4, {}",
+                in(reg) self.0,
+                options(nostack, preserves_flags)
+            )
+        }
+    }
+
+    /// Returns this value with the bits set in `fixed0` set and the bits clear in `fixed1`
+    /// cleared, as reported by the `IA32_VMX_CR4_FIXED0` and `IA32_VMX_CR4_FIXED1` MSRs.
+    pub fn apply_fixed(self, fixed0: u64, fixed1: u64) -> Self {
+        Self((self.0 | fixed0) & fixed1)
+    }
+
+    /// Returns this value with bit `bit` set to `value`.
+    fn with_bit(self, bit: u32, value: bool) -> Self {
+        Self((self.0 & !(1 << bit)) | (u64::from(value) << bit))
+    }
+
     pub fn vme(&self) -> bool {
         self.0 & 1 == 1
     }
@@ -253,6 +472,135 @@ impl Cr4 {
     }
 }
 
+/// Setters of the bits of CR4, each returning the updated value.
+#[allow(dead_code)]
+impl Cr4 {
+    /// Returns this value with CR4.VME set to `vme`.
+    pub fn set_vme(self, vme: bool) -> Self {
+        self.with_bit(0, vme)
+    }
+
+    /// Returns this value with CR4.PVI set to `pvi`.
+    pub fn set_pvi(self, pvi: bool) -> Self {
+        self.with_bit(1, pvi)
+    }
+
+    /// Returns this value with CR4.TSD set to `tsd`.
+    pub fn set_tsd(self, tsd: bool) -> Self {
+        self.with_bit(2, tsd)
+    }
+
+    /// Returns this value with CR4.DE set to `de`.
+    pub fn set_de(self, de: bool) -> Self {
+        self.with_bit(3, de)
+    }
+
+    /// Returns this value with CR4.PSE set to `pse`.
+    pub fn set_pse(self, pse: bool) -> Self {
+        self.with_bit(4, pse)
+    }
+
+    /// Returns this value with CR4.PAE set to `pae`.
+    pub fn set_pae(self, pae: bool) -> Self {
+        self.with_bit(5, pae)
+    }
+
+    /// Returns this value with CR4.MCE set to `mce`.
+    pub fn set_mce(self, mce: bool) -> Self {
+        self.with_bit(6, mce)
+    }
+
+    /// Returns this value with CR4.PGE set to `pge`.
+    pub fn set_pge(self, pge: bool) -> Self {
+        self.with_bit(7, pge)
+    }
+
+    /// Returns this value with CR4.PCE set to `pce`.
+    pub fn set_pce(self, pce: bool) -> Self {
+        self.with_bit(8, pce)
+    }
+
+    /// Returns this value with CR4.OSFXSR set to `osfxsr`.
+    pub fn set_osfxsr(self, osfxsr: bool) -> Self {
+        self.with_bit(9, osfxsr)
+    }
+
+    /// Returns this value with CR4.OSXMMEXCPT set to `osxmmexcpt`.
+    pub fn set_osxmmexcpt(self, osxmmexcpt: bool) -> Self {
+        self.with_bit(10, osxmmexcpt)
+    }
+
+    /// Returns this value with CR4.UMIP set to `umip`.
+    pub fn set_umip(self, umip: bool) -> Self {
+        self.with_bit(11, umip)
+    }
+
+    /// Returns this value with CR4.LA57 set to `la57`.
+    pub fn set_la57(self, la57: bool) -> Self {
+        self.with_bit(12, la57)
+    }
+
+    /// Returns this value with CR4.VMXE set to `vmxe`.
+    pub fn set_vmxe(self, vmxe: bool) -> Self {
+        self.with_bit(13, vmxe)
+    }
+
+    /// Returns this value with CR4.SMXE set to `smxe`.
+    pub fn set_smxe(self, smxe: bool) -> Self {
+        self.with_bit(14, smxe)
+    }
+
+    /// Returns this value with CR4.FSGSBASE set to `fsgsbase`.
+    pub fn set_fsgsbase(self, fsgsbase: bool) -> Self {
+        self.with_bit(16, fsgsbase)
+    }
+
+    /// Returns this value with CR4.PCIDE set to `pcide`.
+    pub fn set_pcide(self, pcide: bool) -> Self {
+        self.with_bit(17, pcide)
+    }
+
+    /// Returns this value with CR4.OSXSAVE set to `osxsave`.
+    pub fn set_osxsave(self, osxsave: bool) -> Self {
+        self.with_bit(18, osxsave)
+    }
+
+    /// Returns this value with CR4.KL set to `kl`.
+    pub fn set_kl(self, kl: bool) -> Self {
+        self.with_bit(19, kl)
+    }
+
+    /// Returns this value with CR4.SMEP set to `smep`.
+    pub fn set_smep(self, smep: bool) -> Self {
+        self.with_bit(20, smep)
+    }
+
+    /// Returns this value with CR4.SMAP set to `smap`.
+    pub fn set_smap(self, smap: bool) -> Self {
+        self.with_bit(21, smap)
+    }
+
+    /// Returns this value with CR4.PKE set to `pke`.
+    pub fn set_pke(self, pke: bool) -> Self {
+        self.with_bit(22, pke)
+    }
+
+    /// Returns this value with CR4.CET set to `cet`.
+    pub fn set_cet(self, cet: bool) -> Self {
+        self.with_bit(23, cet)
+    }
+
+    /// Returns this value with CR4.PKS set to `pks`.
+    pub fn set_pks(self, pks: bool) -> Self {
+        self.with_bit(24, pks)
+    }
+
+    /// Returns this value with CR4.UINTR set to `uintr`.
+    pub fn set_uintr(self, uintr: bool) -> Self {
+        self.with_bit(25, uintr)
+    }
+}
+
 impl fmt::Display for Cr4 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         Cr4Display(self.0).fmt(f)
@@ -309,3 +657,144 @@ impl fmt::Display for Cr4Display {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The setter, getter, and position of a bit of a control register.
+    type Bit<T> = (fn(T, bool) -> T, fn(&T) -> bool, u32);
+
+    #[test]
+    fn cr0_apply_fixed_forces_bits() {
+        // A typical IA32_VMX_CR0_FIXED0 and IA32_VMX_CR0_FIXED1.
+        let (fixed0, fixed1) = (0x8000_0021, 0xFFFF_FFFF);
+
+        let cr0 = Cr0(0x6000_0010).apply_fixed(fixed0, fixed1);
+        assert_eq!(cr0.bits(), 0xE000_0031);
+        assert!(cr0.pe() && cr0.ne() && cr0.pg());
+
+        // Bits clear in FIXED1 are cleared, even if they are set in the value.
+        let cr0 = Cr0(0xFFFF_FFFF_0000_0000).apply_fixed(fixed0, fixed1);
+        assert_eq!(cr0.bits(), fixed0);
+    }
+
+    #[test]
+    fn cr0_setters_only_change_their_bit() {
+        let setters: [Bit<Cr0>; 11] = [
+            (Cr0::set_pe, Cr0::pe, 0),
+            (Cr0::set_mp, Cr0::mp, 1),
+            (Cr0::set_em, Cr0::em, 2),
+            (Cr0::set_ts, Cr0::ts, 3),
+            (Cr0::set_et, Cr0::et, 4),
+            (Cr0::set_ne, Cr0::ne, 5),
+            (Cr0::set_wp, Cr0::wp, 16),
+            (Cr0::set_am, Cr0::am, 18),
+            (Cr0::set_nw, Cr0::nw, 29),
+            (Cr0::set_cd, Cr0::cd, 30),
+            (Cr0::set_pg, Cr0::pg, 31),
+        ];
+
+        for (set, get, bit) in setters {
+            let set_value = set(Cr0(0), true);
+            assert_eq!(set_value.bits(), 1 << bit);
+            assert!(get(&set_value));
+
+            let cleared = set(Cr0(u64::MAX), false);
+            assert_eq!(cleared.bits(), !(1 << bit));
+            assert!(!get(&cleared));
+
+            assert_eq!(set(Cr0(1 << bit), true).bits(), 1 << bit);
+        }
+    }
+
+    #[test]
+    fn cr4_apply_fixed_forces_bits() {
+        // A typical IA32_VMX_CR4_FIXED0 and IA32_VMX_CR4_FIXED1.
+        let (fixed0, fixed1) = (0x2000, 0x003F_6FFF);
+
+        let cr4 = Cr4(0x0000_0620).apply_fixed(fixed0, fixed1);
+        assert!(cr4.vmxe());
+        assert_eq!(cr4.bits(), 0x2620);
+
+        // LA57 and bits above those the processor supports are cleared.
+        let cr4 = Cr4(0x0400_1000).apply_fixed(fixed0, fixed1);
+        assert_eq!(cr4.bits(), 0x2000);
+    }
+
+    #[test]
+    fn cr4_setters_only_change_their_bit() {
+        let setters: [Bit<Cr4>; 25] = [
+            (Cr4::set_vme, Cr4::vme, 0),
+            (Cr4::set_pvi, Cr4::pvi, 1),
+            (Cr4::set_tsd, Cr4::tsd, 2),
+            (Cr4::set_de, Cr4::de, 3),
+            (Cr4::set_pse, Cr4::pse, 4),
+            (Cr4::set_pae, Cr4::pae, 5),
+            (Cr4::set_mce, Cr4::mce, 6),
+            (Cr4::set_pge, Cr4::pge, 7),
+            (Cr4::set_pce, Cr4::pce, 8),
+            (Cr4::set_osfxsr, Cr4::osfxsr, 9),
+            (Cr4::set_osxmmexcpt, Cr4::osxmmexcpt, 10),
+            (Cr4::set_umip, Cr4::umip, 11),
+            (Cr4::set_la57, Cr4::la57, 12),
+            (Cr4::set_vmxe, Cr4::vmxe, 13),
+            (Cr4::set_smxe, Cr4::smxe, 14),
+            (Cr4::set_fsgsbase, Cr4::fsgsbase, 16),
+            (Cr4::set_pcide, Cr4::pcide, 17),
+            (Cr4::set_osxsave, Cr4::osxsave, 18),
+            (Cr4::set_kl, Cr4::kl, 19),
+            (Cr4::set_smep, Cr4::smep, 20),
+            (Cr4::set_smap, Cr4::smap, 21),
+            (Cr4::set_pke, Cr4::pke, 22),
+            (Cr4::set_cet, Cr4::cet, 23),
+            (Cr4::set_pks, Cr4::pks, 24),
+            (Cr4::set_uintr, Cr4::uintr, 25),
+        ];
+
+        for (set, get, bit) in setters {
+            let set_value = set(Cr4(0), true);
+            assert_eq!(set_value.bits(), 1 << bit);
+            assert!(get(&set_value));
+
+            let cleared = set(Cr4(u64::MAX), false);
+            assert_eq!(cleared.bits(), !(1 << bit));
+            assert!(!get(&cleared));
+        }
+    }
+
+    #[test]
+    fn display_lists_set_flags() {
+        assert_eq!(Cr0(0x8001_0033).to_string(), "PE | MP | ET | NE | WP | PG");
+        assert_eq!(Cr0(0).to_string(), "");
+        assert_eq!(Cr4Display(0x2_2020).to_string(), "PAE | VMXE | PCIDE");
+    }
+
+    #[test]
+    fn cr3_table_address_masks_flags_and_high_bits() {
+        let cr3 = Cr3::from_bits(0xFFF0_0012_3456_7018);
+
+        assert_eq!(cr3.table_address_with(36), 0x0000_0002_3456_7000);
+        assert_eq!(cr3.table_address_with(48), 0x0000_0012_3456_7000);
+        assert_eq!(cr3.table_address_with(52), 0x0000_0012_3456_7000);
+        assert_eq!(cr3.table_address_with(64), 0xFFF0_0012_3456_7000);
+    }
+
+    #[test]
+    fn cr3_pcid_replaces_the_cache_bits() {
+        let cr3 = Cr3::from_bits(0x1000 | 0x18);
+        assert_eq!(cr3.pcid_with(false), None);
+        assert_eq!(cr3.pwt_with(false), Some(true));
+        assert_eq!(cr3.pcd_with(false), Some(true));
+
+        assert_eq!(cr3.pcid_with(true), Some(0x18));
+        assert_eq!(cr3.pwt_with(true), None);
+        assert_eq!(cr3.pcd_with(true), None);
+
+        let cr3 = Cr3::from_bits(0x1000 | 0xFE7);
+        assert_eq!(cr3.pcid_with(true), Some(0xFE7));
+        assert_eq!(cr3.pwt_with(false), Some(false));
+        assert_eq!(cr3.pcd_with(false), Some(false));
+        assert_eq!(cr3.table_address_with(52), 0x1000);
+    }
+}
@@ -0,0 +1,133 @@
+//! Definitions of `x86_64` debug registers.
+
+use core::fmt;
+
+/// The debug control register, DR7.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct Dr7(u64);
+
+impl Dr7 {
+    /// Reads DR7 of the current processor.
+    pub fn get() -> Self {
+        let dr7: u64;
+        // SAFETY:
+        // Reading DR7 has no side effects, and the driver runs at privilege level 0.
+        unsafe {
+            core::arch::asm!(
+                "mov {}, dr7",
+                out(reg) dr7,
+                options(nomem, nostack, preserves_flags)
+            )
+        }
+
+        Self(dr7)
+    }
+
+    /// Returns the raw value of the register.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if breakpoint `index` is enabled for the current task.
+    ///
+    /// # Panics
+    /// Panics if `index` is not in `0..4`.
+    pub fn local_enable(&self, index: u8) -> bool {
+        assert!(index < 4);
+        self.0 & (1 << (index * 2)) != 0
+    }
+
+    /// Returns `true` if breakpoint `index` is enabled for every task.
+    ///
+    /// # Panics
+    /// Panics if `index` is not in `0..4`.
+    pub fn global_enable(&self, index: u8) -> bool {
+        assert!(index < 4);
+        self.0 & (1 << (index * 2 + 1)) != 0
+    }
+
+    /// Returns `true` if exact local breakpoints are enabled.
+    pub fn le(&self) -> bool {
+        self.0 & (1 << 8) == (1 << 8)
+    }
+
+    /// Returns `true` if exact global breakpoints are enabled.
+    pub fn ge(&self) -> bool {
+        self.0 & (1 << 9) == (1 << 9)
+    }
+
+    /// Returns `true` if accesses to the debug registers raise a debug exception.
+    pub fn gd(&self) -> bool {
+        self.0 & (1 << 13) == (1 << 13)
+    }
+}
+
+impl fmt::Display for Dr7 {
+    #[allow(unused_assignments)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev = false;
+
+        macro_rules! flag {
+            ($flag_enabled:expr, $name:expr) => {
+                if $flag_enabled {
+                    if prev {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, $name)?;
+                    prev = true;
+                }
+            };
+        }
+
+        flag!(self.local_enable(0), "L0");
+        flag!(self.global_enable(0), "G0");
+        flag!(self.local_enable(1), "L1");
+        flag!(self.global_enable(1), "G1");
+        flag!(self.local_enable(2), "L2");
+        flag!(self.global_enable(2), "G2");
+        flag!(self.local_enable(3), "L3");
+        flag!(self.global_enable(3), "G3");
+        flag!(self.le(), "LE");
+        flag!(self.ge(), "GE");
+        flag!(self.gd(), "GD");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breakpoint_enables_alternate() {
+        let dr7 = Dr7(0b1001_0110);
+
+        assert!(!dr7.local_enable(0));
+        assert!(dr7.global_enable(0));
+        assert!(dr7.local_enable(1));
+        assert!(!dr7.global_enable(1));
+        assert!(dr7.local_enable(2));
+        assert!(!dr7.global_enable(2));
+        assert!(!dr7.local_enable(3));
+        assert!(dr7.global_enable(3));
+    }
+
+    #[test]
+    fn control_bits_decode() {
+        // The architectural reset value, with only the reserved bit 10 set.
+        let reset = Dr7(0x400);
+        assert!(!reset.le() && !reset.ge() && !reset.gd());
+        assert_eq!(reset.to_string(), "");
+
+        let dr7 = Dr7(0x2701);
+        assert!(dr7.le() && dr7.ge() && dr7.gd());
+        assert_eq!(dr7.to_string(), "L0 | LE | GE | GD");
+    }
+
+    #[test]
+    #[should_panic]
+    fn breakpoint_index_is_checked() {
+        Dr7(0).local_enable(4);
+    }
+}
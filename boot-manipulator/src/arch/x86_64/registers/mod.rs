@@ -3,7 +3,11 @@
 use core::mem::MaybeUninit;
 
 pub mod control;
+pub mod debug;
 pub mod msr;
+pub mod rflags;
+pub mod segment;
+pub mod xcr0;
 
 #[repr(C)]
 pub struct Idtr {
@@ -16,7 +20,10 @@ impl Idtr {
     pub fn get() -> Self {
         let mut reg = Self::new(0, 0);
 
-        unsafe { core::arch::asm!("sidt [{}]", in(reg) &mut reg) }
+        // SAFETY:
+        // `sidt` stores the 2-byte limit followed by the 8-byte base, which matches the layout
+        // of the fields following the padding.
+        unsafe { core::arch::asm!("sidt [{}]", in(reg) core::ptr::addr_of_mut!(reg.limit)) }
 
         reg
     }
@@ -36,6 +43,24 @@ impl Idtr {
     pub fn address(&self) -> u64 {
         self.address
     }
+
+    /// Loads this value into the current processor with `lidt`.
+    ///
+    /// # Safety
+    /// The table must be valid and must remain valid while it is loaded.
+    #[allow(dead_code)]
+    pub unsafe fn load(&self) {
+        // SAFETY:
+        // The limit is followed by the base, which matches the operand of `lidt`, and the caller
+        // guarantees that the table is valid.
+        unsafe {
+            core::arch::asm!(
+                "lidt [{}]",
+                in(reg) core::ptr::addr_of!(self.limit),
+                options(readonly, nostack, preserves_flags)
+            )
+        }
+    }
 }
 
 #[repr(C)]
@@ -49,7 +74,10 @@ impl Gdtr {
     pub fn get() -> Self {
         let mut reg = Self::new(0, 0);
 
-        unsafe { core::arch::asm!("sgdt [{}]", in(reg) &mut reg) }
+        // SAFETY:
+        // `sgdt` stores the 2-byte limit followed by the 8-byte base, which matches the layout
+        // of the fields following the padding.
+        unsafe { core::arch::asm!("sgdt [{}]", in(reg) core::ptr::addr_of_mut!(reg.limit)) }
 
         reg
     }
@@ -69,4 +97,22 @@ impl Gdtr {
     pub fn address(&self) -> u64 {
         self.address
     }
+
+    /// Loads this value into the current processor with `lgdt`.
+    ///
+    /// # Safety
+    /// The table must be valid and must remain valid while it is loaded.
+    #[allow(dead_code)]
+    pub unsafe fn load(&self) {
+        // SAFETY:
+        // The limit is followed by the base, which matches the operand of `lgdt`, and the caller
+        // guarantees that the table is valid.
+        unsafe {
+            core::arch::asm!(
+                "lgdt [{}]",
+                in(reg) core::ptr::addr_of!(self.limit),
+                options(readonly, nostack, preserves_flags)
+            )
+        }
+    }
 }
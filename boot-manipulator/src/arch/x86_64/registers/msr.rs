@@ -1,6 +1,6 @@
 //! Definitions of interfaces for Model Specific Registers.
 
-use core::arch::asm;
+use core::{arch::asm, fmt};
 
 /// Reads a [`u64`] from the msr at location `msr`.
 ///
@@ -26,12 +26,569 @@ pub unsafe fn write_msr(msr: u32, value: u64) {
     }
 }
 
+pub const APIC_BASE: u32 = 0x1B;
 pub const FEATURE_CONTROL: u32 = 0x3a;
 
 pub const VMX_REVISION: u32 = 0x480;
+pub const VMX_PINBASED_CTLS: u32 = 0x481;
+pub const VMX_PROCBASED_CTLS: u32 = 0x482;
+pub const VMX_EXIT_CTLS: u32 = 0x483;
+pub const VMX_ENTRY_CTLS: u32 = 0x484;
 
 pub const VMX_CR0_FIXED0: u32 = 0x486;
 pub const VMX_CR0_FIXED1: u32 = 0x487;
 
 pub const VMX_CR4_FIXED0: u32 = 0x488;
 pub const VMX_CR4_FIXED1: u32 = 0x489;
+
+pub const VMX_PROCBASED_CTLS2: u32 = 0x48B;
+pub const VMX_EPT_VPID_CAP: u32 = 0x48C;
+pub const VMX_TRUE_PINBASED_CTLS: u32 = 0x48D;
+pub const VMX_TRUE_PROCBASED_CTLS: u32 = 0x48E;
+pub const VMX_TRUE_EXIT_CTLS: u32 = 0x48F;
+pub const VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+pub const VMX_VMFUNC: u32 = 0x491;
+
+pub const MTRR_CAP: u32 = 0xFE;
+pub const MTRR_PHYS_BASE_0: u32 = 0x200;
+pub const MTRR_FIX_64K_00000: u32 = 0x250;
+pub const MTRR_FIX_16K_80000: u32 = 0x258;
+pub const MTRR_FIX_4K_C0000: u32 = 0x268;
+pub const MTRR_DEF_TYPE: u32 = 0x2FF;
+
+pub const SYSENTER_CS: u32 = 0x174;
+pub const SYSENTER_ESP: u32 = 0x175;
+pub const SYSENTER_EIP: u32 = 0x176;
+
+pub const PAT: u32 = 0x277;
+
+pub const EFER: u32 = 0xC000_0080;
+pub const STAR: u32 = 0xC000_0081;
+pub const LSTAR: u32 = 0xC000_0082;
+pub const CSTAR: u32 = 0xC000_0083;
+pub const SFMASK: u32 = 0xC000_0084;
+pub const FS_BASE: u32 = 0xC000_0100;
+pub const GS_BASE: u32 = 0xC000_0101;
+pub const KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+pub const VM_CR: u32 = 0xC001_0114;
+pub const VM_HSAVE_PA: u32 = 0xC001_0117;
+
+/// The bit in `IA32_VMX_BASIC` indicating that the `TRUE` control capability MSRs exist.
+const VMX_BASIC_TRUE_CONTROLS: u64 = 1 << 55;
+/// The bit in `IA32_VMX_BASIC` limiting the addresses of VMX structures to 32 bits.
+const VMX_BASIC_32BIT_ADDRESSES: u64 = 1 << 48;
+/// The bit in `IA32_VMX_BASIC` reporting support for the dual-monitor treatment of SMIs.
+const VMX_BASIC_DUAL_MONITOR: u64 = 1 << 49;
+
+/// A model-specific register whose contents are decoded into [`Msr::Value`].
+pub trait Msr {
+    /// The address of the register.
+    const ADDRESS: u32;
+
+    /// The decoded contents of the register.
+    type Value: From<u64>;
+
+    /// Reads the register of the current processor.
+    ///
+    /// # Safety
+    /// - The register must exist on the current processor.
+    unsafe fn read() -> Self::Value {
+        // SAFETY:
+        // The caller guarantees that the register exists.
+        Self::Value::from(unsafe { read_msr(Self::ADDRESS) })
+    }
+}
+
+/// A [`Msr`] that can be written.
+pub trait WritableMsr: Msr<Value: Into<u64>> {
+    /// Writes `value` to the register of the current processor.
+    ///
+    /// # Safety
+    /// - The register must exist on the current processor.
+    /// - `value` must be a valid value for the register and must not break assumptions.
+    unsafe fn write(value: Self::Value) {
+        // SAFETY:
+        // The caller guarantees that the register exists and that `value` is valid.
+        unsafe { write_msr(Self::ADDRESS, value.into()) }
+    }
+}
+
+/// Defines a zero-sized type implementing [`Msr`], and [`WritableMsr`] if `writable` is given.
+macro_rules! msr {
+    ($(#[$attr:meta])* $name:ident($address:expr) -> $value:ty) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+        pub struct $name;
+
+        impl Msr for $name {
+            const ADDRESS: u32 = $address;
+            type Value = $value;
+        }
+    };
+    ($(#[$attr:meta])* $name:ident($address:expr) -> $value:ty, writable) => {
+        msr!($(#[$attr])* $name($address) -> $value);
+
+        impl WritableMsr for $name {}
+    };
+}
+
+msr!(
+    /// `IA32_APIC_BASE`, which locates and enables the local APIC.
+    #[allow(dead_code)]
+    ApicBase(APIC_BASE) -> ApicBaseValue, writable
+);
+msr!(
+    /// `IA32_FEATURE_CONTROL`, which controls whether VMX may be enabled.
+    FeatureControl(FEATURE_CONTROL) -> FeatureControlValue, writable
+);
+msr!(
+    /// `IA32_VMX_BASIC`, which reports the basic VMX capabilities.
+    VmxBasic(VMX_REVISION) -> VmxBasicValue
+);
+msr!(
+    /// `IA32_VMX_CR0_FIXED0`, which reports the bits of CR0 that must be set in VMX operation.
+    VmxCr0Fixed0(VMX_CR0_FIXED0) -> u64
+);
+msr!(
+    /// `IA32_VMX_CR0_FIXED1`, which reports the bits of CR0 that may be set in VMX operation.
+    VmxCr0Fixed1(VMX_CR0_FIXED1) -> u64
+);
+msr!(
+    /// `IA32_VMX_CR4_FIXED0`, which reports the bits of CR4 that must be set in VMX operation.
+    VmxCr4Fixed0(VMX_CR4_FIXED0) -> u64
+);
+msr!(
+    /// `IA32_VMX_CR4_FIXED1`, which reports the bits of CR4 that may be set in VMX operation.
+    VmxCr4Fixed1(VMX_CR4_FIXED1) -> u64
+);
+msr!(
+    /// `IA32_SYSENTER_CS`, the code segment selector loaded by `sysenter`.
+    SysenterCs(SYSENTER_CS) -> u64, writable
+);
+msr!(
+    /// `IA32_SYSENTER_ESP`, the stack pointer loaded by `sysenter`.
+    SysenterEsp(SYSENTER_ESP) -> u64, writable
+);
+msr!(
+    /// `IA32_SYSENTER_EIP`, the instruction pointer loaded by `sysenter`.
+    SysenterEip(SYSENTER_EIP) -> u64, writable
+);
+msr!(
+    /// `IA32_EFER`, the extended feature enable register.
+    Efer(EFER) -> EferValue, writable
+);
+msr!(
+    /// `IA32_FS_BASE`, the base of FS in 64-bit mode.
+    FsBase(FS_BASE) -> u64, writable
+);
+msr!(
+    /// `IA32_GS_BASE`, the base of GS in 64-bit mode.
+    GsBase(GS_BASE) -> u64, writable
+);
+msr!(
+    /// `VM_CR`, which controls whether SVM may be enabled.
+    VmCr(VM_CR) -> VmCrValue
+);
+msr!(
+    /// `VM_HSAVE_PA`, the physical address of the host save area used by `vmrun`.
+    VmHsavePa(VM_HSAVE_PA) -> u64, writable
+);
+
+/// Implements the conversions between a decoded MSR value wrapping a [`u64`] and its raw value.
+macro_rules! raw_value {
+    ($name:ident) => {
+        impl From<u64> for $name {
+            fn from(value: u64) -> Self {
+                Self(value)
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl $name {
+            /// Returns the raw value of the register.
+            pub fn bits(&self) -> u64 {
+                self.0
+            }
+
+            /// Returns this value with bit `bit` set to `value`.
+            #[allow(dead_code)]
+            fn with_bit(self, bit: u32, value: bool) -> Self {
+                Self((self.0 & !(1 << bit)) | (u64::from(value) << bit))
+            }
+        }
+    };
+}
+
+/// The contents of `IA32_APIC_BASE`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct ApicBaseValue(u64);
+
+raw_value!(ApicBaseValue);
+
+#[allow(dead_code)]
+impl ApicBaseValue {
+    /// Returns `true` if the current processor is the bootstrap processor.
+    pub fn bsp(&self) -> bool {
+        self.0 & (1 << 8) == (1 << 8)
+    }
+
+    /// Returns `true` if the local APIC is in x2APIC mode.
+    pub fn x2apic_enable(&self) -> bool {
+        self.0 & (1 << 10) == (1 << 10)
+    }
+
+    /// Returns `true` if the local APIC is enabled.
+    pub fn global_enable(&self) -> bool {
+        self.0 & (1 << 11) == (1 << 11)
+    }
+
+    /// Returns the physical address of the local APIC registers.
+    pub fn base(&self) -> u64 {
+        self.0 & 0x000F_FFFF_FFFF_F000
+    }
+
+    /// Returns this value with x2APIC mode set to `x2apic_enable`.
+    pub fn set_x2apic_enable(self, x2apic_enable: bool) -> Self {
+        self.with_bit(10, x2apic_enable)
+    }
+
+    /// Returns this value with the local APIC enable set to `global_enable`.
+    pub fn set_global_enable(self, global_enable: bool) -> Self {
+        self.with_bit(11, global_enable)
+    }
+}
+
+/// The contents of `IA32_FEATURE_CONTROL`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct FeatureControlValue(u64);
+
+raw_value!(FeatureControlValue);
+
+impl FeatureControlValue {
+    /// Returns `true` if the register is locked against writes until reset.
+    pub fn locked(&self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    /// Returns `true` if VMX may be enabled inside SMX operation.
+    pub fn vmx_inside_smx(&self) -> bool {
+        self.0 & (1 << 1) == (1 << 1)
+    }
+
+    /// Returns `true` if VMX may be enabled outside SMX operation.
+    pub fn vmx_outside_smx(&self) -> bool {
+        self.0 & (1 << 2) == (1 << 2)
+    }
+
+    /// Returns this value with the lock set to `locked`.
+    pub fn set_locked(self, locked: bool) -> Self {
+        self.with_bit(0, locked)
+    }
+
+    /// Returns this value with VMX inside SMX operation set to `vmx_inside_smx`.
+    pub fn set_vmx_inside_smx(self, vmx_inside_smx: bool) -> Self {
+        self.with_bit(1, vmx_inside_smx)
+    }
+
+    /// Returns this value with VMX outside SMX operation set to `vmx_outside_smx`.
+    pub fn set_vmx_outside_smx(self, vmx_outside_smx: bool) -> Self {
+        self.with_bit(2, vmx_outside_smx)
+    }
+}
+
+impl fmt::Display for FeatureControlValue {
+    #[allow(unused_assignments)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev = false;
+
+        macro_rules! flag {
+            ($flag_enabled:expr, $name:expr) => {
+                if $flag_enabled {
+                    if prev {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, $name)?;
+                    prev = true;
+                }
+            };
+        }
+
+        flag!(self.locked(), "LOCK");
+        flag!(self.vmx_inside_smx(), "VMX_INSIDE_SMX");
+        flag!(self.vmx_outside_smx(), "VMX_OUTSIDE_SMX");
+
+        Ok(())
+    }
+}
+
+/// The basic VMX capabilities of the processor, as reported by `IA32_VMX_BASIC`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct VmxBasicValue {
+    /// The VMCS revision identifier, which begins the VMXON region and every VMCS.
+    pub revision: u32,
+    /// The number of bytes required for the VMXON region and each VMCS.
+    pub region_size: u16,
+    /// Whether the addresses of the VMXON region, VMCS, and structures they reference are
+    /// limited to 32 bits.
+    pub addresses_limited_to_32_bits: bool,
+    /// Whether the dual-monitor treatment of SMIs and SMM is supported.
+    pub dual_monitor: bool,
+    /// The memory type required for the VMCS and the structures it references.
+    pub memory_type: u8,
+    /// Whether the `TRUE` control capability MSRs exist.
+    pub true_controls: bool,
+}
+
+impl From<u64> for VmxBasicValue {
+    fn from(value: u64) -> Self {
+        Self {
+            revision: (value as u32) & 0x7FFF_FFFF,
+            region_size: ((value >> 32) & 0x1FFF) as u16,
+            addresses_limited_to_32_bits: value & VMX_BASIC_32BIT_ADDRESSES
+                == VMX_BASIC_32BIT_ADDRESSES,
+            dual_monitor: value & VMX_BASIC_DUAL_MONITOR == VMX_BASIC_DUAL_MONITOR,
+            memory_type: ((value >> 50) & 0xF) as u8,
+            true_controls: value & VMX_BASIC_TRUE_CONTROLS == VMX_BASIC_TRUE_CONTROLS,
+        }
+    }
+}
+
+/// The contents of `IA32_EFER`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct EferValue(u64);
+
+raw_value!(EferValue);
+
+impl EferValue {
+    /// Returns `true` if `syscall` and `sysret` are enabled.
+    pub fn sce(&self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    /// Returns `true` if long mode is enabled.
+    pub fn lme(&self) -> bool {
+        self.0 & (1 << 8) == (1 << 8)
+    }
+
+    /// Returns `true` if long mode is active.
+    pub fn lma(&self) -> bool {
+        self.0 & (1 << 10) == (1 << 10)
+    }
+
+    /// Returns `true` if the execute-disable bit of page table entries is enabled.
+    pub fn nxe(&self) -> bool {
+        self.0 & (1 << 11) == (1 << 11)
+    }
+
+    /// Returns `true` if SVM is enabled.
+    pub fn svme(&self) -> bool {
+        self.0 & (1 << 12) == (1 << 12)
+    }
+
+    /// Returns this value with SVM enabled if `svme` is set.
+    pub fn set_svme(self, svme: bool) -> Self {
+        self.with_bit(12, svme)
+    }
+}
+
+impl fmt::Display for EferValue {
+    #[allow(unused_assignments)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev = false;
+
+        macro_rules! flag {
+            ($flag_enabled:expr, $name:expr) => {
+                if $flag_enabled {
+                    if prev {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, $name)?;
+                    prev = true;
+                }
+            };
+        }
+
+        flag!(self.sce(), "SCE");
+        flag!(self.lme(), "LME");
+        flag!(self.lma(), "LMA");
+        flag!(self.nxe(), "NXE");
+        flag!(self.svme(), "SVME");
+
+        Ok(())
+    }
+}
+
+/// The contents of `VM_CR`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct VmCrValue(u64);
+
+raw_value!(VmCrValue);
+
+impl VmCrValue {
+    /// Returns `true` if SVMDIS is locked.
+    pub fn lock(&self) -> bool {
+        self.0 & (1 << 3) == (1 << 3)
+    }
+
+    /// Returns `true` if SVM has been disabled by the firmware.
+    pub fn svm_disable(&self) -> bool {
+        self.0 & (1 << 4) == (1 << 4)
+    }
+
+    /// Returns this value with SVMDIS locked if `lock` is set.
+    pub fn set_lock(self, lock: bool) -> Self {
+        self.with_bit(3, lock)
+    }
+
+    /// Returns this value with SVM disabled if `svm_disable` is set.
+    pub fn set_svm_disable(self, svm_disable: bool) -> Self {
+        self.with_bit(4, svm_disable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmx_basic_decodes_every_field() {
+        let value = VmxBasicValue::from(
+            (1 << 55) | (6 << 50) | (1 << 49) | (1 << 48) | (0x0400 << 32) | 0x0000_0012,
+        );
+
+        assert_eq!(
+            value,
+            VmxBasicValue {
+                revision: 0x12,
+                region_size: 0x400,
+                addresses_limited_to_32_bits: true,
+                dual_monitor: true,
+                memory_type: 6,
+                true_controls: true,
+            }
+        );
+    }
+
+    #[test]
+    fn vmx_basic_ignores_bits_outside_its_fields() {
+        // Bit 31 of the revision identifier is always zero and bits 47:45 are reserved.
+        let value = VmxBasicValue::from((0x7 << 45) | 0x8000_0001);
+
+        assert_eq!(
+            value,
+            VmxBasicValue {
+                revision: 1,
+                region_size: 0,
+                addresses_limited_to_32_bits: false,
+                dual_monitor: false,
+                memory_type: 0,
+                true_controls: false,
+            }
+        );
+    }
+
+    #[test]
+    fn efer_flags_decode_their_bits() {
+        let efer = EferValue::from(0xD01);
+        assert!(efer.sce() && efer.lme() && efer.lma() && efer.nxe());
+        assert!(!efer.svme());
+        assert_eq!(efer.to_string(), "SCE | LME | LMA | NXE");
+
+        assert!(EferValue::from(1 << 12).svme());
+        assert_eq!(EferValue::default().to_string(), "");
+    }
+
+    #[test]
+    fn efer_set_svme_only_changes_its_bit() {
+        let efer = EferValue::from(0xD01).set_svme(true);
+        assert_eq!(efer.bits(), 0x1D01);
+        assert_eq!(efer.set_svme(false).bits(), 0xD01);
+        assert_eq!(EferValue::from(u64::MAX).set_svme(false).bits(), !(1 << 12));
+    }
+
+    #[test]
+    fn raw_values_round_trip() {
+        for raw in [0, 1, 0xFEE0_0900, 0x8000_0000_0000_0005, u64::MAX] {
+            assert_eq!(u64::from(FeatureControlValue::from(raw)), raw);
+            assert_eq!(u64::from(EferValue::from(raw)), raw);
+            assert_eq!(u64::from(VmCrValue::from(raw)), raw);
+            assert_eq!(u64::from(ApicBaseValue::from(raw)), raw);
+        }
+    }
+
+    #[test]
+    fn feature_control_setters_round_trip() {
+        let value = FeatureControlValue::default()
+            .set_vmx_outside_smx(true)
+            .set_locked(true);
+        assert_eq!(value.bits(), 0b101);
+        assert!(value.locked() && value.vmx_outside_smx() && !value.vmx_inside_smx());
+        assert_eq!(value.to_string(), "LOCK | VMX_OUTSIDE_SMX");
+
+        let value = FeatureControlValue::from(u64::MAX)
+            .set_locked(false)
+            .set_vmx_inside_smx(false);
+        assert_eq!(value.bits(), !0b011);
+        assert!(!value.locked() && !value.vmx_inside_smx() && value.vmx_outside_smx());
+    }
+
+    #[test]
+    fn vm_cr_setters_round_trip() {
+        let value = VmCrValue::default().set_svm_disable(true).set_lock(true);
+        assert_eq!(value.bits(), 0x18);
+        assert!(value.lock() && value.svm_disable());
+
+        let value = value.set_lock(false);
+        assert_eq!(value.bits(), 0x10);
+        assert!(!value.lock() && value.svm_disable());
+    }
+
+    #[test]
+    fn apic_base_fields_round_trip() {
+        // The bootstrap processor's APIC at its default address, globally enabled.
+        let value = ApicBaseValue::from(0xFEE0_0900);
+        assert!(value.bsp() && value.global_enable() && !value.x2apic_enable());
+        assert_eq!(value.base(), 0xFEE0_0000);
+
+        let value = value.set_x2apic_enable(true);
+        assert_eq!(value.bits(), 0xFEE0_0D00);
+        assert_eq!(value.set_global_enable(false).bits(), 0xFEE0_0500);
+
+        // Bits 63:52 and the flags are not part of the base.
+        assert_eq!(ApicBaseValue::from(u64::MAX).base(), 0x000F_FFFF_FFFF_F000);
+    }
+
+    #[test]
+    fn vmx_basic_fields_round_trip() {
+        for (revision, region_size, memory_type, flags) in [
+            (1, 0x1000, 6, false),
+            (0x7FFF_FFFF, 0x1FFF, 0, true),
+            (0x12, 0x400, 6, true),
+        ] {
+            let raw = u64::from(revision)
+                | (u64::from(region_size) << 32)
+                | (u64::from(flags) << 48)
+                | (u64::from(flags) << 49)
+                | (u64::from(memory_type) << 50)
+                | (u64::from(flags) << 55);
+
+            assert_eq!(
+                VmxBasicValue::from(raw),
+                VmxBasicValue {
+                    revision,
+                    region_size,
+                    addresses_limited_to_32_bits: flags,
+                    dual_monitor: flags,
+                    memory_type,
+                    true_controls: flags,
+                }
+            );
+        }
+    }
+}
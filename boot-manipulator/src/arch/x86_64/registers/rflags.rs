@@ -0,0 +1,225 @@
+//! Definitions of the `x86_64` flags register.
+
+use core::fmt;
+
+/// The bit of RFLAGS that is reserved and always set.
+const RESERVED_ONE: u64 = 1 << 1;
+
+/// The flags register, RFLAGS, whose reserved bit 1 is always set.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct Rflags(u64);
+
+impl Rflags {
+    /// Reads RFLAGS of the current processor.
+    #[allow(dead_code)]
+    pub fn get() -> Self {
+        let rflags: u64;
+        // SAFETY:
+        // Pushing RFLAGS and popping it into a register leaves the stack unchanged.
+        unsafe {
+            core::arch::asm!(
+                "pushfq",
+                "pop {}",
+                out(reg) rflags,
+                options(nomem, preserves_flags)
+            )
+        }
+
+        Self::from_bits(rflags)
+    }
+
+    /// Creates an [`Rflags`] from `bits`, setting the reserved bit 1 that RFLAGS always holds.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits | RESERVED_ONE)
+    }
+
+    /// Returns the raw value of the register.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if the carry flag is set.
+    pub fn cf(&self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    /// Returns `true` if the parity flag is set.
+    pub fn pf(&self) -> bool {
+        self.0 & (1 << 2) == (1 << 2)
+    }
+
+    /// Returns `true` if the auxiliary carry flag is set.
+    pub fn af(&self) -> bool {
+        self.0 & (1 << 4) == (1 << 4)
+    }
+
+    /// Returns `true` if the zero flag is set.
+    pub fn zf(&self) -> bool {
+        self.0 & (1 << 6) == (1 << 6)
+    }
+
+    /// Returns `true` if the sign flag is set.
+    pub fn sf(&self) -> bool {
+        self.0 & (1 << 7) == (1 << 7)
+    }
+
+    /// Returns `true` if the trap flag is set.
+    pub fn tf(&self) -> bool {
+        self.0 & (1 << 8) == (1 << 8)
+    }
+
+    /// Returns `true` if maskable interrupts are enabled.
+    pub fn interrupt_enable(&self) -> bool {
+        self.0 & (1 << 9) == (1 << 9)
+    }
+
+    /// Returns `true` if the direction flag is set.
+    pub fn df(&self) -> bool {
+        self.0 & (1 << 10) == (1 << 10)
+    }
+
+    /// Returns `true` if the overflow flag is set.
+    pub fn of(&self) -> bool {
+        self.0 & (1 << 11) == (1 << 11)
+    }
+
+    /// Returns the I/O privilege level.
+    pub fn iopl(&self) -> u8 {
+        ((self.0 >> 12) & 0b11) as u8
+    }
+
+    /// Returns `true` if the nested task flag is set.
+    pub fn nt(&self) -> bool {
+        self.0 & (1 << 14) == (1 << 14)
+    }
+
+    /// Returns `true` if the resume flag is set.
+    pub fn rf(&self) -> bool {
+        self.0 & (1 << 16) == (1 << 16)
+    }
+
+    /// Returns `true` if virtual-8086 mode is enabled.
+    pub fn vm(&self) -> bool {
+        self.0 & (1 << 17) == (1 << 17)
+    }
+
+    /// Returns `true` if alignment checking is enabled.
+    pub fn ac(&self) -> bool {
+        self.0 & (1 << 18) == (1 << 18)
+    }
+
+    /// Returns `true` if the virtual interrupt flag is set.
+    pub fn vif(&self) -> bool {
+        self.0 & (1 << 19) == (1 << 19)
+    }
+
+    /// Returns `true` if the virtual interrupt pending flag is set.
+    pub fn vip(&self) -> bool {
+        self.0 & (1 << 20) == (1 << 20)
+    }
+
+    /// Returns `true` if the `cpuid` instruction is reported as supported.
+    pub fn id(&self) -> bool {
+        self.0 & (1 << 21) == (1 << 21)
+    }
+}
+
+impl fmt::Display for Rflags {
+    #[allow(unused_assignments)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev = false;
+
+        macro_rules! flag {
+            ($flag_enabled:expr, $name:expr) => {
+                if $flag_enabled {
+                    if prev {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, $name)?;
+                    prev = true;
+                }
+            };
+        }
+
+        flag!(self.cf(), "CF");
+        flag!(self.pf(), "PF");
+        flag!(self.af(), "AF");
+        flag!(self.zf(), "ZF");
+        flag!(self.sf(), "SF");
+        flag!(self.tf(), "TF");
+        flag!(self.interrupt_enable(), "IF");
+        flag!(self.df(), "DF");
+        flag!(self.of(), "OF");
+        flag!(self.iopl() != 0, "IOPL");
+        flag!(self.nt(), "NT");
+        flag!(self.rf(), "RF");
+        flag!(self.vm(), "VM");
+        flag!(self.ac(), "AC");
+        flag!(self.vif(), "VIF");
+        flag!(self.vip(), "VIP");
+        flag!(self.id(), "ID");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The getter and position of a flag.
+    type Flag = (fn(&Rflags) -> bool, u32);
+
+    #[test]
+    fn from_bits_sets_the_reserved_bit() {
+        assert_eq!(Rflags::from_bits(0).bits(), 0x2);
+        assert_eq!(Rflags::from_bits(0x2).bits(), 0x2);
+        assert_eq!(Rflags::from_bits(0x246).bits(), 0x246);
+        assert_eq!(Rflags::from_bits(0x200), Rflags::from_bits(0x202));
+    }
+
+    #[test]
+    fn flags_decode_their_bits() {
+        let flags: [Flag; 16] = [
+            (Rflags::cf, 0),
+            (Rflags::pf, 2),
+            (Rflags::af, 4),
+            (Rflags::zf, 6),
+            (Rflags::sf, 7),
+            (Rflags::tf, 8),
+            (Rflags::interrupt_enable, 9),
+            (Rflags::df, 10),
+            (Rflags::of, 11),
+            (Rflags::nt, 14),
+            (Rflags::rf, 16),
+            (Rflags::vm, 17),
+            (Rflags::ac, 18),
+            (Rflags::vif, 19),
+            (Rflags::vip, 20),
+            (Rflags::id, 21),
+        ];
+
+        for (flag, bit) in flags {
+            assert!(flag(&Rflags::from_bits(1 << bit)), "bit {bit}");
+            assert!(!flag(&Rflags::from_bits(!(1 << bit))), "bit {bit}");
+        }
+    }
+
+    #[test]
+    fn iopl_is_two_bits() {
+        assert_eq!(Rflags::from_bits(0).iopl(), 0);
+        assert_eq!(Rflags::from_bits(1 << 12).iopl(), 1);
+        assert_eq!(Rflags::from_bits(3 << 12).iopl(), 3);
+        assert_eq!(Rflags::from_bits(!(3 << 12)).iopl(), 0);
+    }
+
+    #[test]
+    fn display_lists_set_flags() {
+        assert_eq!(Rflags::from_bits(0).to_string(), "");
+        assert_eq!(Rflags::from_bits(0x246).to_string(), "PF | ZF | IF");
+        assert_eq!(
+            Rflags::from_bits(0x3_3001).to_string(),
+            "CF | IOPL | RF | VM"
+        );
+    }
+}
@@ -0,0 +1,479 @@
+//! Segment selectors of the current processor and decoding of segment descriptors from the
+//! Global Descriptor Table.
+
+use core::{arch::asm, fmt};
+
+use crate::arch::x86_64::registers::{
+    control::Cr4,
+    msr::{FsBase, GsBase, Msr},
+    Gdtr,
+};
+
+/// The bit in the VMCS access-rights format marking a segment as unusable.
+pub const ACCESS_RIGHTS_UNUSABLE: u32 = 1 << 16;
+/// The VMCS access rights of a present, busy 64-bit TSS.
+const ACCESS_RIGHTS_BUSY_TSS: u32 = 0x8B;
+
+/// The bit in a selector indicating that it refers to the Local Descriptor Table.
+const SELECTOR_TABLE_INDICATOR: u16 = 1 << 2;
+/// The bits of a selector holding the requested privilege level.
+const SELECTOR_RPL: u16 = 0b11;
+
+/// Reads the selector in the segment register `$register` with `$instruction`.
+macro_rules! read_selector {
+    ($instruction:literal, $register:literal) => {{
+        let selector: u16;
+        // SAFETY:
+        // Reading a segment selector has no side effects.
+        unsafe {
+            asm!(
+                concat!($instruction, " {:x}", $register),
+                out(reg) selector,
+                options(nomem, nostack, preserves_flags)
+            )
+        }
+
+        SegmentSelector(selector)
+    }};
+}
+
+/// Returns the selector in CS.
+pub fn read_cs() -> SegmentSelector {
+    read_selector!("mov", ", cs")
+}
+
+/// Returns the selector in SS.
+pub fn read_ss() -> SegmentSelector {
+    read_selector!("mov", ", ss")
+}
+
+/// Returns the selector in DS.
+pub fn read_ds() -> SegmentSelector {
+    read_selector!("mov", ", ds")
+}
+
+/// Returns the selector in ES.
+pub fn read_es() -> SegmentSelector {
+    read_selector!("mov", ", es")
+}
+
+/// Returns the selector in FS.
+pub fn read_fs() -> SegmentSelector {
+    read_selector!("mov", ", fs")
+}
+
+/// Returns the selector in GS.
+pub fn read_gs() -> SegmentSelector {
+    read_selector!("mov", ", gs")
+}
+
+/// Returns the selector of the task register, read with `str`.
+pub fn read_tr() -> SegmentSelector {
+    read_selector!("str", "")
+}
+
+/// Returns the selector of the LDT register, read with `sldt`.
+pub fn read_ldtr() -> SegmentSelector {
+    read_selector!("sldt", "")
+}
+
+/// Returns the base of FS, read with `rdfsbase` if CR4.FSGSBASE is set and from `IA32_FS_BASE`
+/// otherwise.
+pub fn read_fs_base() -> u64 {
+    if !Cr4::get().fsgsbase() {
+        // SAFETY:
+        // `IA32_FS_BASE` exists on every processor supporting 64-bit mode.
+        return unsafe { FsBase::read() };
+    }
+
+    let base: u64;
+    // SAFETY:
+    // CR4.FSGSBASE is set, so `rdfsbase` is enabled.
+    unsafe { asm!("rdfsbase {}", out(reg) base, options(nomem, nostack, preserves_flags)) }
+    base
+}
+
+/// Returns the base of GS, read with `rdgsbase` if CR4.FSGSBASE is set and from `IA32_GS_BASE`
+/// otherwise.
+pub fn read_gs_base() -> u64 {
+    if !Cr4::get().fsgsbase() {
+        // SAFETY:
+        // `IA32_GS_BASE` exists on every processor supporting 64-bit mode.
+        return unsafe { GsBase::read() };
+    }
+
+    let base: u64;
+    // SAFETY:
+    // CR4.FSGSBASE is set, so `rdgsbase` is enabled.
+    unsafe { asm!("rdgsbase {}", out(reg) base, options(nomem, nostack, preserves_flags)) }
+    base
+}
+
+/// A segment selector, referencing a descriptor in the GDT or the LDT.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct SegmentSelector(u16);
+
+impl SegmentSelector {
+    /// Creates a [`SegmentSelector`] from `bits`.
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw value of the selector.
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
+    /// Returns the index of the referenced descriptor in its table.
+    pub fn index(&self) -> u16 {
+        self.0 >> 3
+    }
+
+    /// Returns the requested privilege level.
+    pub fn rpl(&self) -> u8 {
+        (self.0 & SELECTOR_RPL) as u8
+    }
+
+    /// Returns the table holding the referenced descriptor.
+    pub fn table(&self) -> DescriptorTable {
+        if self.0 & SELECTOR_TABLE_INDICATOR == SELECTOR_TABLE_INDICATOR {
+            DescriptorTable::Ldt
+        } else {
+            DescriptorTable::Gdt
+        }
+    }
+
+    /// Returns `true` if the selector references no descriptor.
+    pub fn is_null(&self) -> bool {
+        self.table() == DescriptorTable::Gdt && self.index() == 0
+    }
+}
+
+impl fmt::Display for SegmentSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x} ({} index {}, RPL {})",
+            self.0,
+            self.table(),
+            self.index(),
+            self.rpl()
+        )
+    }
+}
+
+/// The tables a [`SegmentSelector`] can reference.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum DescriptorTable {
+    /// The Global Descriptor Table.
+    Gdt,
+    /// The Local Descriptor Table.
+    Ldt,
+}
+
+impl fmt::Display for DescriptorTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gdt => write!(f, "GDT"),
+            Self::Ldt => write!(f, "LDT"),
+        }
+    }
+}
+
+/// The bit in the access byte of a descriptor that is set for code and data segments.
+const DESCRIPTOR_S: u64 = 1 << 44;
+/// The bit in the flags of a descriptor selecting a limit measured in 4 KiB units.
+const DESCRIPTOR_G: u64 = 1 << 55;
+
+/// The decoded contents of a segment descriptor.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct SegmentDescriptor {
+    /// The base address of the segment.
+    base: u64,
+    /// The limit of the segment in bytes, with the granularity already applied.
+    limit: u32,
+    /// The attributes of the segment in the VMCS access-rights format.
+    access_rights: u32,
+}
+
+impl SegmentDescriptor {
+    /// A descriptor for a null selector, which the VMCS marks as unusable.
+    pub const UNUSABLE: Self = Self {
+        base: 0,
+        limit: 0,
+        access_rights: ACCESS_RIGHTS_UNUSABLE,
+    };
+
+    /// Returns the descriptor of a present, busy 64-bit TSS at `base` with `limit`, which is how
+    /// the processor describes a TSS loaded into TR.
+    pub fn busy_tss(base: u64, limit: u32) -> Self {
+        Self {
+            base,
+            limit,
+            access_rights: ACCESS_RIGHTS_BUSY_TSS,
+        }
+    }
+
+    /// Decodes a descriptor from its `low` 8 bytes and, for system descriptors, its `high` 8 bytes.
+    ///
+    /// In 64-bit mode, system descriptors such as the TSS and LDT are 16 bytes long and carry bits
+    /// 63:32 of the base in the low half of `high`.
+    pub fn decode(low: u64, high: u64) -> Self {
+        let mut base = ((low >> 16) & 0xFF_FFFF) | (((low >> 56) & 0xFF) << 24);
+        if low & DESCRIPTOR_S == 0 {
+            base |= (high & 0xFFFF_FFFF) << 32;
+        }
+
+        let mut limit = ((low & 0xFFFF) | (((low >> 48) & 0xF) << 16)) as u32;
+        if low & DESCRIPTOR_G == DESCRIPTOR_G {
+            limit = (limit << 12) | 0xFFF;
+        }
+
+        // The type, S, DPL, and P bits occupy bits 7:0 and the AVL, L, D/B, and G bits occupy
+        // bits 15:12 of the access-rights format.
+        let access_rights = (((low >> 40) & 0xFF) | (((low >> 52) & 0xF) << 12)) as u32;
+
+        Self {
+            base,
+            limit,
+            access_rights,
+        }
+    }
+
+    /// Reads the descriptor referenced by `selector` from the GDT described by `gdtr`.
+    ///
+    /// Null selectors produce [`SegmentDescriptor::UNUSABLE`].
+    ///
+    /// # Errors
+    /// Returns a [`SegmentDescriptorError`] if `selector` refers to the LDT or lies outside of the
+    /// GDT.
+    ///
+    /// # Safety
+    /// `gdtr` must describe a readable, identity-mapped GDT.
+    pub unsafe fn from_gdt(
+        gdtr: &Gdtr,
+        selector: SegmentSelector,
+    ) -> Result<Self, SegmentDescriptorError> {
+        if selector.table() == DescriptorTable::Ldt {
+            return Err(SegmentDescriptorError::LocalDescriptorTable(
+                selector.bits(),
+            ));
+        }
+        if selector.is_null() {
+            return Ok(Self::UNUSABLE);
+        }
+
+        let offset = u64::from(selector.index()) * 8;
+
+        let entry = |offset: u64| {
+            if offset + 7 > u64::from(gdtr.limit()) {
+                return Err(SegmentDescriptorError::OutsideTable(selector.bits()));
+            }
+
+            let address = (gdtr.address() + offset) as *const u64;
+            // SAFETY:
+            // The entry lies within the GDT, which the caller guarantees is readable.
+            Ok(unsafe { address.read_unaligned() })
+        };
+
+        let low = entry(offset)?;
+        let high = if low & DESCRIPTOR_S == 0 {
+            entry(offset + 8)?
+        } else {
+            0
+        };
+
+        Ok(Self::decode(low, high))
+    }
+
+    /// Returns the base address of the segment.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Returns the limit of the segment in bytes.
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// Returns the attributes of the segment in the VMCS access-rights format.
+    pub fn access_rights(&self) -> u32 {
+        self.access_rights
+    }
+
+    /// Returns the attributes of the segment in the VMCB format, which packs bits 7:0 and 15:12
+    /// of the access-rights format into bits 11:0.
+    pub fn vmcb_attributes(&self) -> u16 {
+        if self.access_rights & ACCESS_RIGHTS_UNUSABLE == ACCESS_RIGHTS_UNUSABLE {
+            return 0;
+        }
+
+        ((self.access_rights & 0xFF) | ((self.access_rights >> 4) & 0xF00)) as u16
+    }
+}
+
+impl fmt::Display for SegmentDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.access_rights & ACCESS_RIGHTS_UNUSABLE == ACCESS_RIGHTS_UNUSABLE {
+            return write!(f, "unusable");
+        }
+
+        write!(
+            f,
+            "base {:#x}, limit {:#x}, access rights {:#06x}",
+            self.base, self.limit, self.access_rights
+        )
+    }
+}
+
+/// Various errors that can occur while reading a segment descriptor.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SegmentDescriptorError {
+    /// The selector refers to the Local Descriptor Table, which is not supported.
+    LocalDescriptorTable(u16),
+    /// The selector refers to an entry beyond the limit of the GDT.
+    OutsideTable(u16),
+}
+
+impl fmt::Display for SegmentDescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LocalDescriptorTable(selector) => {
+                write!(f, "selector {selector:#06x} refers to the LDT")
+            }
+            Self::OutsideTable(selector) => {
+                write!(f, "selector {selector:#06x} lies outside of the GDT")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes the 8-byte descriptor with `base`, the 20-bit `limit`, the `access` byte, and the
+    /// AVL, L, D/B, and G `flags`.
+    fn encode(base: u32, limit: u32, access: u8, flags: u8) -> u64 {
+        u64::from(limit & 0xFFFF)
+            | (u64::from(base & 0xFF_FFFF) << 16)
+            | (u64::from(access) << 40)
+            | (u64::from((limit >> 16) & 0xF) << 48)
+            | (u64::from(flags & 0xF) << 52)
+            | (u64::from(base >> 24) << 56)
+    }
+
+    #[test]
+    fn decodes_64_bit_code_segment() {
+        let descriptor = SegmentDescriptor::decode(0x00AF_9A00_0000_FFFF, 0);
+
+        assert_eq!(descriptor.base(), 0);
+        assert_eq!(descriptor.limit(), 0xFFFF_FFFF);
+        // Present, ring 0, execute/read code with L and G set.
+        assert_eq!(descriptor.access_rights(), 0xA09A);
+        assert_eq!(descriptor.vmcb_attributes(), 0xA9A);
+    }
+
+    #[test]
+    fn decodes_data_segment() {
+        let descriptor = SegmentDescriptor::decode(encode(0x1234_5678, 0xABCDE, 0xF3, 0b0100), 0);
+
+        assert_eq!(descriptor.base(), 0x1234_5678);
+        assert_eq!(descriptor.limit(), 0xABCDE);
+        // Present, ring 3, accessed read/write data with D/B set.
+        assert_eq!(descriptor.access_rights(), 0x40F3);
+    }
+
+    #[test]
+    fn granularity_scales_the_limit() {
+        let bytes = SegmentDescriptor::decode(encode(0, 0x12345, 0x93, 0b0100), 0);
+        let pages = SegmentDescriptor::decode(encode(0, 0x12345, 0x93, 0b1100), 0);
+
+        assert_eq!(bytes.limit(), 0x12345);
+        assert_eq!(pages.limit(), 0x1234_5FFF);
+        assert_eq!(pages.access_rights(), 0xC093);
+    }
+
+    #[test]
+    fn decodes_16_byte_system_tss() {
+        let low = encode(0x1234_5678, 0x67, 0x8B, 0);
+        let high = 0xDEAD_BEEF_FFFF_8000;
+
+        let descriptor = SegmentDescriptor::decode(low, high);
+
+        // Only the low half of `high` carries base bits 63:32.
+        assert_eq!(descriptor.base(), 0xFFFF_8000_1234_5678);
+        assert_eq!(descriptor.limit(), 0x67);
+        assert_eq!(descriptor.access_rights(), 0x8B);
+        assert_eq!(
+            descriptor,
+            SegmentDescriptor::busy_tss(0xFFFF_8000_1234_5678, 0x67)
+        );
+    }
+
+    #[test]
+    fn code_and_data_ignore_high_half() {
+        let descriptor = SegmentDescriptor::decode(0x00CF_9200_0000_FFFF, u64::MAX);
+
+        assert_eq!(descriptor.base(), 0);
+    }
+
+    #[test]
+    fn from_gdt_reads_synthetic_table() {
+        let tss_low = encode(0x1000_0000, 0x67, 0x8B, 0);
+        let gdt: [u64; 5] = [
+            0,
+            0x00AF_9A00_0000_FFFF,
+            0x00CF_9200_0000_FFFF,
+            tss_low,
+            0x0000_0000_0000_0001,
+        ];
+        let gdtr = Gdtr::new(gdt.as_ptr() as u64, (gdt.len() * 8 - 1) as u16);
+
+        let read = |selector: u16| {
+            // SAFETY:
+            // `gdtr` describes `gdt`, which is readable for the duration of the call.
+            unsafe { SegmentDescriptor::from_gdt(&gdtr, SegmentSelector::from_bits(selector)) }
+        };
+
+        assert_eq!(read(0x00), Ok(SegmentDescriptor::UNUSABLE));
+        // The RPL of a null selector does not make it usable.
+        assert_eq!(read(0x03), Ok(SegmentDescriptor::UNUSABLE));
+        assert_eq!(read(0x08), Ok(SegmentDescriptor::decode(gdt[1], 0)));
+        assert_eq!(
+            read(0x10).map(|descriptor| descriptor.access_rights()),
+            Ok(0xC092)
+        );
+        assert_eq!(
+            read(0x18),
+            Ok(SegmentDescriptor::busy_tss(0x1_1000_0000, 0x67))
+        );
+        assert_eq!(read(0x28), Err(SegmentDescriptorError::OutsideTable(0x28)));
+        assert_eq!(
+            read(0x0C),
+            Err(SegmentDescriptorError::LocalDescriptorTable(0x0C))
+        );
+    }
+
+    #[test]
+    fn system_descriptor_must_fit_in_table() {
+        let gdt: [u64; 2] = [0, encode(0, 0x67, 0x89, 0)];
+        let gdtr = Gdtr::new(gdt.as_ptr() as u64, (gdt.len() * 8 - 1) as u16);
+
+        // SAFETY:
+        // `gdtr` describes `gdt`, which is readable for the duration of the call.
+        let result =
+            unsafe { SegmentDescriptor::from_gdt(&gdtr, SegmentSelector::from_bits(0x08)) };
+
+        assert_eq!(result, Err(SegmentDescriptorError::OutsideTable(0x08)));
+    }
+
+    #[test]
+    fn unusable_descriptor() {
+        assert_eq!(
+            SegmentDescriptor::UNUSABLE.access_rights(),
+            ACCESS_RIGHTS_UNUSABLE
+        );
+        assert_eq!(SegmentDescriptor::UNUSABLE.vmcb_attributes(), 0);
+    }
+}
@@ -0,0 +1,245 @@
+//! Definitions of the `x86_64` extended control register XCR0.
+
+use core::fmt;
+
+use crate::arch::x86_64::registers::control::Cr4;
+
+/// The bit of XCR0 enabling the x87 state, which must always be set.
+const XCR0_X87: u64 = 1;
+/// The bits of XCR0 enabling the AVX-512 state, which must be set together.
+const XCR0_AVX512: u64 = 0b111 << 5;
+/// The bits of XCR0 enabling the MPX state, which must be set together.
+const XCR0_MPX: u64 = 0b11 << 3;
+/// The bits of XCR0 enabling the AMX state, which must be set together.
+const XCR0_AMX: u64 = 0b11 << 17;
+
+/// The extended control register XCR0, which selects the state components managed by `xsave`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Xcr0(u64);
+
+impl Xcr0 {
+    /// Reads XCR0 of the current processor, or returns [`None`] if CR4.OSXSAVE is clear and
+    /// `xgetbv` is unavailable.
+    pub fn get() -> Option<Self> {
+        if !Cr4::get().osxsave() {
+            return None;
+        }
+
+        let low: u32;
+        let high: u32;
+        // SAFETY:
+        // CR4.OSXSAVE is set, so `xgetbv` is enabled, and XCR0 always exists.
+        unsafe {
+            core::arch::asm!(
+                "xgetbv",
+                in("ecx") 0,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags)
+            )
+        }
+
+        Some(Self(u64::from(low) | (u64::from(high) << 32)))
+    }
+
+    /// Creates an [`Xcr0`] from `bits`.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Writes this value to XCR0.
+    ///
+    /// # Safety
+    /// CR4.OSXSAVE must be set, the value must be valid for the processor as checked by
+    /// [`Xcr0::is_valid`], and disabling a state component must not discard state in use.
+    pub unsafe fn write(self) {
+        // SAFETY:
+        // The caller guarantees that `xsetbv` is enabled and that the value is valid.
+        unsafe {
+            core::arch::asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") self.0 as u32,
+                in("edx") (self.0 >> 32) as u32,
+                options(nomem, nostack, preserves_flags)
+            )
+        }
+    }
+
+    /// Returns the raw value of the register.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if `xsetbv` accepts this value on a processor supporting the state
+    /// components in `supported`.
+    pub fn is_valid(&self, supported: Xcr0) -> bool {
+        let avx512 = self.0 & XCR0_AVX512;
+        let mpx = self.0 & XCR0_MPX;
+        let amx = self.0 & XCR0_AMX;
+
+        self.x87()
+            && self.0 & !supported.0 == 0
+            && (!self.avx() || self.sse())
+            && (avx512 == 0 || (avx512 == XCR0_AVX512 && self.avx()))
+            && (mpx == 0 || mpx == XCR0_MPX)
+            && (amx == 0 || amx == XCR0_AMX)
+    }
+
+    /// Returns `true` if the x87 state is enabled.
+    pub fn x87(&self) -> bool {
+        self.0 & XCR0_X87 == XCR0_X87
+    }
+
+    /// Returns `true` if the SSE state is enabled.
+    pub fn sse(&self) -> bool {
+        self.0 & (1 << 1) == (1 << 1)
+    }
+
+    /// Returns `true` if the AVX state is enabled.
+    pub fn avx(&self) -> bool {
+        self.0 & (1 << 2) == (1 << 2)
+    }
+
+    /// Returns `true` if the MPX bound register state is enabled.
+    pub fn bndregs(&self) -> bool {
+        self.0 & (1 << 3) == (1 << 3)
+    }
+
+    /// Returns `true` if the MPX bound configuration state is enabled.
+    pub fn bndcsr(&self) -> bool {
+        self.0 & (1 << 4) == (1 << 4)
+    }
+
+    /// Returns `true` if the AVX-512 opmask state is enabled.
+    pub fn opmask(&self) -> bool {
+        self.0 & (1 << 5) == (1 << 5)
+    }
+
+    /// Returns `true` if the upper halves of ZMM0-ZMM15 are enabled.
+    pub fn zmm_hi256(&self) -> bool {
+        self.0 & (1 << 6) == (1 << 6)
+    }
+
+    /// Returns `true` if ZMM16-ZMM31 are enabled.
+    pub fn hi16_zmm(&self) -> bool {
+        self.0 & (1 << 7) == (1 << 7)
+    }
+
+    /// Returns `true` if the protection key state is enabled.
+    pub fn pkru(&self) -> bool {
+        self.0 & (1 << 9) == (1 << 9)
+    }
+
+    /// Returns `true` if the AMX tile configuration state is enabled.
+    pub fn tilecfg(&self) -> bool {
+        self.0 & (1 << 17) == (1 << 17)
+    }
+
+    /// Returns `true` if the AMX tile data state is enabled.
+    pub fn tiledata(&self) -> bool {
+        self.0 & (1 << 18) == (1 << 18)
+    }
+}
+
+impl fmt::Display for Xcr0 {
+    #[allow(unused_assignments)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev = false;
+
+        macro_rules! flag {
+            ($flag_enabled:expr, $name:expr) => {
+                if $flag_enabled {
+                    if prev {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, $name)?;
+                    prev = true;
+                }
+            };
+        }
+
+        flag!(self.x87(), "X87");
+        flag!(self.sse(), "SSE");
+        flag!(self.avx(), "AVX");
+        flag!(self.bndregs(), "BNDREGS");
+        flag!(self.bndcsr(), "BNDCSR");
+        flag!(self.opmask(), "OPMASK");
+        flag!(self.zmm_hi256(), "ZMM_HI256");
+        flag!(self.hi16_zmm(), "HI16_ZMM");
+        flag!(self.pkru(), "PKRU");
+        flag!(self.tilecfg(), "TILECFG");
+        flag!(self.tiledata(), "TILEDATA");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// x87, SSE, and AVX.
+    const AVX: u64 = 0b111;
+    /// Every state component known to the driver.
+    const ALL: Xcr0 = Xcr0::from_bits(AVX | XCR0_MPX | XCR0_AVX512 | (1 << 9) | XCR0_AMX);
+
+    #[test]
+    fn valid_values_are_accepted() {
+        let values = [
+            XCR0_X87,
+            0b11,
+            AVX,
+            AVX | XCR0_AVX512,
+            AVX | XCR0_MPX,
+            0b11 | (1 << 9),
+            AVX | XCR0_AMX,
+            ALL.bits(),
+        ];
+        for value in values {
+            assert!(Xcr0::from_bits(value).is_valid(ALL), "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let values = [
+            // x87 must always be enabled.
+            0,
+            0b110,
+            ALL.bits() & !XCR0_X87,
+            // AVX requires SSE.
+            0b101,
+            // The AVX-512 components must be enabled together, and require AVX.
+            AVX | (1 << 5),
+            AVX | (0b11 << 6),
+            0b11 | XCR0_AVX512,
+            // The MPX and AMX components must be enabled together.
+            0b11 | (1 << 3),
+            0b11 | (1 << 4),
+            AVX | (1 << 17),
+            AVX | (1 << 18),
+        ];
+        for value in values {
+            assert!(!Xcr0::from_bits(value).is_valid(ALL), "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn unsupported_components_are_rejected() {
+        let supported = Xcr0::from_bits(AVX);
+        assert!(Xcr0::from_bits(AVX).is_valid(supported));
+        for value in [
+            AVX | XCR0_AVX512,
+            0b11 | (1 << 9),
+            AVX | (1 << 8),
+            AVX | (1 << 63),
+        ] {
+            assert!(!Xcr0::from_bits(value).is_valid(supported), "{value:#x}");
+        }
+
+        // Bits outside of the components known to the driver are rejected unless supported.
+        assert!(!Xcr0::from_bits(AVX | (1 << 19)).is_valid(ALL));
+        assert!(Xcr0::from_bits(AVX | (1 << 19)).is_valid(Xcr0::from_bits(ALL.bits() | (1 << 19))));
+    }
+}
@@ -2,6 +2,21 @@
 
 use core::fmt;
 
+use crate::console::{Console, ReadError, WriteError};
+
+/// The I/O port of the first standard serial port.
+pub const COM1: u16 = 0x3F8;
+
+/// The rate of the UART's clock divided by 16, which is the baud rate at a divisor of 1.
+const BASE_BAUD_RATE: u32 = 115_200;
+
+/// The number of times the line status is polled before a transmission or the loopback test
+/// gives up.
+const MAX_POLLS: u32 = 100_000;
+
+/// The byte sent through the UART in loopback mode by [`SerialPort::probe`].
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+
 pub struct SerialPort {
     io_port: u16,
 }
@@ -11,6 +26,79 @@ impl SerialPort {
         Self { io_port }
     }
 
+    /// Programs the UART at `io_port` for `baud_rate` with 8 data bits, no parity and one stop
+    /// bit, with interrupts disabled and its FIFOs enabled.
+    ///
+    /// # Errors
+    /// Returns [`SerialError::InvalidBaudRate`] if `baud_rate` is not [`BASE_BAUD_RATE`] divided
+    /// by an integer.
+    ///
+    /// # Safety
+    /// `io_port` must be the base of a 16550-compatible UART, or of unused I/O ports, which
+    /// nothing else accesses.
+    pub unsafe fn initialize(io_port: u16, baud_rate: u32) -> Result<Self, SerialError> {
+        let divisor = divisor(baud_rate).ok_or(SerialError::InvalidBaudRate(baud_rate))?;
+        // SAFETY:
+        // The caller guarantees that `io_port` may be accessed as a UART.
+        let mut serial_port = unsafe { Self::new(io_port) };
+
+        serial_port.set_interrupt_enable(InterruptEnable::new());
+        serial_port.set_line_control(LineControl::new().set_dlab(true));
+        serial_port.set_divisor(divisor);
+        serial_port.set_line_control(LineControl::new());
+        serial_port.set_fifo_control(
+            FifoControl::new()
+                .enable_fifo(true)
+                .reset_receive_fifo(true)
+                .reset_transmit_fifo(true)
+                .dma_mode(DmaMode::MultiByte)
+                .trigger_level(DmaTriggerLevel::Bytes14),
+        );
+        serial_port.set_modem_control(ModemControl::ACTIVE);
+
+        Ok(serial_port)
+    }
+
+    /// Initializes the UART at `io_port` like [`SerialPort::initialize`] after checking that one
+    /// is present.
+    ///
+    /// A UART is considered present if its scratch register holds what was written to it and a
+    /// byte sent in loopback mode is received unchanged.
+    ///
+    /// # Errors
+    /// Returns [`SerialError::InvalidBaudRate`] if `baud_rate` is not supported, and
+    /// [`SerialError::Absent`] if no working UART is found at `io_port`.
+    ///
+    /// # Safety
+    /// `io_port` must be the base of a 16550-compatible UART, or of unused I/O ports, which
+    /// nothing else accesses.
+    pub unsafe fn probe(io_port: u16, baud_rate: u32) -> Result<Self, SerialError> {
+        // SAFETY:
+        // The caller guarantees that `io_port` may be accessed as a UART.
+        let mut serial_port = unsafe { Self::new(io_port) };
+        for pattern in [0x55, 0xAA] {
+            outb(serial_port.scratch_pad_port(), pattern);
+            if inb(serial_port.scratch_pad_port()) != pattern {
+                return Err(SerialError::Absent(io_port));
+            }
+        }
+
+        // SAFETY:
+        // The caller guarantees that `io_port` may be accessed as a UART.
+        serial_port = unsafe { Self::initialize(io_port, baud_rate)? };
+        serial_port.set_modem_control(ModemControl::ACTIVE.set_loopback(true));
+        outb(serial_port.transmit_port(), LOOPBACK_TEST_BYTE);
+        let received = (0..MAX_POLLS)
+            .find(|_| serial_port.get_line_status().data_ready())
+            .map(|_| inb(serial_port.recieve_port()));
+        serial_port.set_modem_control(ModemControl::ACTIVE);
+
+        if received != Some(LOOPBACK_TEST_BYTE) {
+            return Err(SerialError::Absent(io_port));
+        }
+        Ok(serial_port)
+    }
+
     pub fn set_interrupt_enable(&mut self, interrupt_enable: InterruptEnable) {
         outb(self.interrupt_enable_port(), interrupt_enable.0)
     }
@@ -40,6 +128,11 @@ impl SerialPort {
         outb(self.divisor_high_port(), (divisor >> 8) as u8);
     }
 
+    /// Sets the modem control register.
+    pub fn set_modem_control(&mut self, modem_control: ModemControl) {
+        outb(self.modem_control_port(), modem_control.0)
+    }
+
     pub fn get_line_status(&self) -> LineStatus {
         LineStatus(inb(self.line_status_port()))
     }
@@ -134,6 +227,44 @@ impl SerialPort {
     }
 }
 
+impl Console for SerialPort {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        let mut count = 0;
+        while count < data.len() {
+            let line_status = self.get_line_status();
+            if !line_status.data_ready() {
+                break;
+            }
+
+            // Reading the byte removes it from the FIFO, and reading the line status cleared the
+            // error, so a corrupted byte is dropped.
+            let byte = inb(self.recieve_port());
+            if line_status.error_set() {
+                return Err((ReadError::Corrupted, count));
+            }
+
+            data[count] = byte;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        for (written, &byte) in data.iter().enumerate() {
+            (0..MAX_POLLS)
+                .find(|_| self.try_write_byte(byte).is_ok())
+                .ok_or((WriteError::TimedOut, written))?;
+        }
+
+        Ok(())
+    }
+}
+
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for byte in s.bytes() {
@@ -239,15 +370,15 @@ impl FifoControl {
     }
 
     pub const fn reset_transmit_fifo(self, reset: bool) -> Self {
-        Self((self.0 & 0b100) | ((reset as u8) << 2))
+        Self((self.0 & !0b100) | ((reset as u8) << 2))
     }
 
     pub const fn dma_mode(self, dma_mode: DmaMode) -> Self {
-        Self((self.0 & 0b1000) | ((dma_mode as u8) << 3))
+        Self((self.0 & !0b1000) | ((dma_mode as u8) << 3))
     }
 
     pub const fn trigger_level(self, dma_trigger_level: DmaTriggerLevel) -> Self {
-        Self((self.0 & 0b11000000) | ((dma_trigger_level as u8) << 6))
+        Self((self.0 & !0b11000000) | ((dma_trigger_level as u8) << 6))
     }
 }
 
@@ -371,6 +502,44 @@ pub enum Parity {
     Forced0 = 7,
 }
 
+/// The modem control register, whose outputs are wired back to its inputs in loopback mode.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ModemControl(u8);
+
+impl ModemControl {
+    /// Data terminal ready and request to send asserted, and the interrupt line enabled.
+    pub const ACTIVE: Self = Self(0)
+        .set_data_terminal_ready(true)
+        .set_request_to_send(true)
+        .set_out1(true)
+        .set_out2(true);
+
+    /// Sets the data terminal ready output.
+    pub const fn set_data_terminal_ready(self, enable: bool) -> Self {
+        Self((self.0 & !0b1) | (enable as u8))
+    }
+
+    /// Sets the request to send output.
+    pub const fn set_request_to_send(self, enable: bool) -> Self {
+        Self((self.0 & !0b10) | ((enable as u8) << 1))
+    }
+
+    /// Sets the auxiliary output 1.
+    pub const fn set_out1(self, enable: bool) -> Self {
+        Self((self.0 & !0b100) | ((enable as u8) << 2))
+    }
+
+    /// Sets the auxiliary output 2, which gates the UART's interrupt line on PCs.
+    pub const fn set_out2(self, enable: bool) -> Self {
+        Self((self.0 & !0b1000) | ((enable as u8) << 3))
+    }
+
+    /// Sets whether the UART's outputs are wired back to its inputs.
+    pub const fn set_loopback(self, enable: bool) -> Self {
+        Self((self.0 & !0b10000) | ((enable as u8) << 4))
+    }
+}
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
 pub struct LineStatus(u8);
 
@@ -412,6 +581,38 @@ impl LineStatus {
     }
 }
 
+/// Returns the divisor programming the UART for `baud_rate`, or [`None`] if it cannot be reached
+/// exactly.
+fn divisor(baud_rate: u32) -> Option<u16> {
+    if baud_rate == 0 || !BASE_BAUD_RATE.is_multiple_of(baud_rate) {
+        return None;
+    }
+
+    u16::try_from(BASE_BAUD_RATE / baud_rate).ok()
+}
+
+/// Various errors that can occur while setting up a [`SerialPort`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SerialError {
+    /// The baud rate is not the base rate of 115200 divided by an integer.
+    InvalidBaudRate(u32),
+    /// No working UART responded at the I/O port.
+    Absent(u16),
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidBaudRate(baud_rate) => {
+                write!(f, "unsupported serial baud rate {baud_rate}")
+            }
+            Self::Absent(io_port) => write!(f, "no serial port at I/O port {io_port:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for SerialError {}
+
 fn outb(port: u16, byte: u8) {
     unsafe {
         core::arch::asm!(
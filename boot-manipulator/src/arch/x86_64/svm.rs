@@ -0,0 +1,450 @@
+//! Definitions of AMD Secure Virtual Machine (SVM) mechanisms.
+
+use core::{
+    arch::x86_64::__cpuid,
+    convert::Infallible,
+    fmt, ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::x86_64::{
+        exit_boot_services_return, npt,
+        registers::{
+            control::{Cr0, Cr3, Cr4},
+            msr::{
+                read_msr, Efer, Msr, SysenterCs, SysenterEip, SysenterEsp, VmCr, VmHsavePa,
+                WritableMsr, CSTAR, EFER, KERNEL_GS_BASE, LSTAR, PAT, SFMASK, STAR, VM_CR,
+                VM_HSAVE_PA,
+            },
+            segment::{self, SegmentDescriptor, SegmentDescriptorError, SegmentSelector},
+            Gdtr, Idtr,
+        },
+        svm_exit::svm_run,
+        virtualization::IDENTITY_MAP_SIZE,
+        vm_exit::GuestRegisters,
+        vmcb::{
+            Segment, Vmcb, INTERCEPT_CPUID, INTERCEPT_MSR_PROT, INTERCEPT_SHUTDOWN,
+            INTERCEPT_VMRUN, NESTED_PAGING_ENABLE,
+        },
+    },
+    frames::{allocate_frames, deallocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE},
+};
+
+/// The CPUID leaf reporting extended processor features.
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+/// The bit in ECX of [`CPUID_EXTENDED_FEATURES`] reporting support for SVM.
+const CPUID_EXTENDED_FEATURES_ECX_SVM: u32 = 1 << 2;
+
+/// The value of DR6 after reset.
+const DR6_RESET: u64 = 0xFFFF_0FF0;
+/// The value of DR7 after reset, with all breakpoints disabled.
+const DR7_RESET: u64 = 0x400;
+
+/// The address space identifier of the guest; ASID 0 is reserved for the host.
+const GUEST_ASID: u32 = 1;
+
+/// The number of pages in the MSR permissions map.
+const MSRPM_PAGES: usize = 2;
+/// The MSRs whose reads and writes exit: `EFER`, whose SVME bit is hidden from the guest, and the
+/// SVM control MSRs.
+const INTERCEPTED_MSRS: [u32; 3] = [EFER, VM_CR, VM_HSAVE_PA];
+
+/// The number of pages in the stack used by the host while the guest runs.
+const HOST_STACK_PAGES: usize = 4;
+
+// The VMCBs are allocated as individual pages, which must satisfy their alignment.
+const _: () = assert!(align_of::<Vmcb>() <= FRAME_SIZE);
+
+/// The host save area, in which `vmrun` stores the host state.
+static HOST_SAVE_AREA: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// The VMCB describing the guest.
+static GUEST_VMCB: AtomicPtr<Vmcb> = AtomicPtr::new(ptr::null_mut());
+/// The VMCB holding the host state saved and restored by `vmsave` and `vmload`.
+static HOST_VMCB: AtomicPtr<Vmcb> = AtomicPtr::new(ptr::null_mut());
+/// The MSR permissions map of the guest.
+static MSR_PERMISSIONS: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// The base of the stack used by the host while the guest runs.
+static HOST_STACK: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// The root of the nested page tables of the guest, or zero if nested paging is not in use.
+static NESTED_CR3: AtomicU64 = AtomicU64::new(0);
+
+/// Returns `true` if the processor supports SVM and the firmware has not disabled it.
+pub fn is_supported() -> bool {
+    if __cpuid(CPUID_EXTENDED_FEATURES).ecx & CPUID_EXTENDED_FEATURES_ECX_SVM == 0 {
+        return false;
+    }
+
+    // SAFETY:
+    // `VM_CR` exists on every processor supporting SVM.
+    !unsafe { VmCr::read() }.svm_disable()
+}
+
+/// Allocates the host save area, the VMCBs, the MSR permissions map, the host stack, and the
+/// nested page tables.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if any of the memory other than the nested page tables cannot
+/// be allocated, after releasing whatever was already allocated.
+pub fn allocate_basic_memory() -> Result<(), OutOfMemoryError> {
+    let result = allocate_svm_memory();
+    if result.is_err() {
+        // SAFETY:
+        // SVM has not been enabled, so none of the memory is in use.
+        unsafe { release_svm_memory() }
+    }
+
+    result
+}
+
+/// Allocates the memory described by [`allocate_basic_memory`].
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if any of the memory other than the nested page tables cannot
+/// be allocated.
+fn allocate_svm_memory() -> Result<(), OutOfMemoryError> {
+    HOST_SAVE_AREA.store(
+        allocate_frames(1, MemoryKind::Persistent)?.as_ptr(),
+        Ordering::Relaxed,
+    );
+    GUEST_VMCB.store(
+        allocate_frames(1, MemoryKind::Persistent)?
+            .cast::<Vmcb>()
+            .as_ptr(),
+        Ordering::Relaxed,
+    );
+    HOST_VMCB.store(
+        allocate_frames(1, MemoryKind::Persistent)?
+            .cast::<Vmcb>()
+            .as_ptr(),
+        Ordering::Relaxed,
+    );
+    HOST_STACK.store(
+        allocate_frames(HOST_STACK_PAGES, MemoryKind::Persistent)?.as_ptr(),
+        Ordering::Relaxed,
+    );
+
+    let msr_permissions = allocate_frames(MSRPM_PAGES, MemoryKind::Persistent)?.as_ptr();
+    for msr in INTERCEPTED_MSRS {
+        let (byte, bit) = msr_permission_bits(msr).unwrap();
+        // SAFETY:
+        // `msr_permission_bits` only returns offsets within the map.
+        let entry = unsafe { msr_permissions.add(byte) };
+        // SAFETY:
+        // `entry` lies within the map, which is owned by this module.
+        unsafe { *entry |= 0b11 << bit }
+    }
+    MSR_PERMISSIONS.store(msr_permissions, Ordering::Relaxed);
+
+    if !npt::is_supported() {
+        log::warn!("nested paging is not supported; the guest will not be isolated");
+        return Ok(());
+    }
+
+    match npt::build_identity_map(IDENTITY_MAP_SIZE) {
+        Ok(nested_cr3) => NESTED_CR3.store(nested_cr3, Ordering::Relaxed),
+        Err(error) => log::warn!("failed to build the nested page table identity map: {error}"),
+    }
+
+    Ok(())
+}
+
+/// Frees the memory allocated by [`allocate_svm_memory`], skipping anything that was not
+/// allocated.
+///
+/// # Safety
+/// None of the memory may be in use by the processor.
+unsafe fn release_svm_memory() {
+    let regions = [
+        (HOST_SAVE_AREA.swap(ptr::null_mut(), Ordering::Relaxed), 1),
+        (
+            GUEST_VMCB.swap(ptr::null_mut(), Ordering::Relaxed).cast(),
+            1,
+        ),
+        (HOST_VMCB.swap(ptr::null_mut(), Ordering::Relaxed).cast(), 1),
+        (
+            HOST_STACK.swap(ptr::null_mut(), Ordering::Relaxed),
+            HOST_STACK_PAGES,
+        ),
+        (
+            MSR_PERMISSIONS.swap(ptr::null_mut(), Ordering::Relaxed),
+            MSRPM_PAGES,
+        ),
+    ];
+    for (frames, count) in regions {
+        let Some(frames) = ptr::NonNull::new(frames) else {
+            continue;
+        };
+
+        // SAFETY:
+        // Each region was allocated with `count` frames, and the caller guarantees it is unused.
+        unsafe { deallocate_frames(frames, count) }
+    }
+}
+
+/// Returns the byte offset within the MSR permissions map of the bits intercepting reads and
+/// writes of `msr`, along with the index of the read bit, which the write bit follows.
+///
+/// Returns [`None`] if `msr` is not covered by the map, in which case its accesses always exit.
+pub fn msr_permission_bits(msr: u32) -> Option<(usize, u8)> {
+    let (index, base) = match msr {
+        0..=0x1FFF => (msr, 0),
+        0xC000_0000..=0xC000_1FFF => (msr - 0xC000_0000, 0x800),
+        0xC001_0000..=0xC001_1FFF => (msr - 0xC001_0000, 0x1000),
+        _ => return None,
+    };
+
+    Some((base + (index / 4) as usize, ((index % 4) * 2) as u8))
+}
+
+/// Enables SVM on the running processor and registers the host save area.
+///
+/// # Errors
+/// - Returns [`SvmError::Disabled`] if the firmware has disabled SVM.
+/// - Returns [`SvmError::MissingHostSaveArea`] if [`allocate_basic_memory`] has not been called.
+pub fn enable_support() -> Result<(), SvmError> {
+    if !is_supported() {
+        return Err(SvmError::Disabled);
+    }
+
+    let host_save_area = HOST_SAVE_AREA.load(Ordering::Relaxed);
+    if host_save_area.is_null() {
+        return Err(SvmError::MissingHostSaveArea);
+    }
+
+    // SAFETY:
+    // `EFER` exists on every processor supporting 64-bit mode.
+    let efer = unsafe { Efer::read() };
+    // SAFETY:
+    // SVM is supported and has not been disabled, so `EFER.SVME` may be set.
+    unsafe { Efer::write(efer.set_svme(true)) }
+    log::trace!("Enabled EFER SVM bit");
+
+    // SAFETY:
+    // The host save area is a page-aligned page, and UEFI identity maps all memory, so its
+    // address is also its physical address.
+    unsafe { VmHsavePa::write(host_save_area as u64) }
+
+    Ok(())
+}
+
+/// Programs the guest VMCB with the state of the firmware at the return of `ExitBootServices()`,
+/// the way [`super::virtualization::setup_virtual_machine_state`] programs the VMCS.
+///
+/// # Errors
+/// Returns [`SvmError::InvalidSegment`] if a segment selector does not refer to a usable GDT
+/// entry.
+pub fn setup_virtual_machine_state() -> Result<(), SvmError> {
+    let vmcb = GUEST_VMCB.load(Ordering::Relaxed);
+    assert!(!vmcb.is_null());
+    // SAFETY:
+    // The guest VMCB is a page owned by this module, and no guest is running.
+    let vmcb = unsafe { &mut *vmcb };
+
+    let machine_state = crate::arch::machine_state();
+
+    let control = &mut vmcb.control;
+    control.intercept_misc1 = INTERCEPT_CPUID | INTERCEPT_MSR_PROT | INTERCEPT_SHUTDOWN;
+    control.intercept_misc2 = INTERCEPT_VMRUN;
+    control.msrpm_base = MSR_PERMISSIONS.load(Ordering::Relaxed) as u64;
+    control.guest_asid = GUEST_ASID;
+    let nested_cr3 = NESTED_CR3.load(Ordering::Relaxed);
+    if nested_cr3 != 0 {
+        control.nested_paging = NESTED_PAGING_ENABLE;
+        control.nested_cr3 = nested_cr3;
+    }
+
+    let gdtr = Gdtr::get();
+    let idtr = Idtr::get();
+    let segment = |selector: SegmentSelector| {
+        // SAFETY:
+        // `gdtr` was read from the processor, and UEFI identity maps all memory.
+        let descriptor = unsafe { SegmentDescriptor::from_gdt(&gdtr, selector) }
+            .map_err(SvmError::InvalidSegment)?;
+
+        Ok(Segment {
+            selector: selector.bits(),
+            attributes: descriptor.vmcb_attributes(),
+            limit: descriptor.limit(),
+            base: descriptor.base(),
+        })
+    };
+
+    let save = &mut vmcb.save;
+    save.es = segment(segment::read_es())?;
+    save.cs = segment(segment::read_cs())?;
+    save.ss = segment(segment::read_ss())?;
+    save.ds = segment(segment::read_ds())?;
+    save.fs = segment(segment::read_fs())?;
+    save.gs = segment(segment::read_gs())?;
+    save.ldtr = segment(segment::read_ldtr())?;
+    save.tr = segment(segment::read_tr())?;
+    save.gdtr = Segment {
+        limit: u32::from(gdtr.limit()),
+        base: gdtr.address(),
+        ..Segment::default()
+    };
+    save.idtr = Segment {
+        limit: u32::from(idtr.limit()),
+        base: idtr.address(),
+        ..Segment::default()
+    };
+    save.cpl = segment::read_cs().rpl();
+
+    /// Reads `M`, which exists on every processor supporting SVM.
+    fn read<M: Msr>() -> M::Value {
+        // SAFETY:
+        // The MSRs read below exist on every processor supporting SVM.
+        unsafe { M::read() }
+    }
+    let read_raw = |msr| {
+        // SAFETY:
+        // The MSRs read below exist on every processor supporting SVM.
+        unsafe { read_msr(msr) }
+    };
+
+    // In 64-bit mode, the FS and GS bases come from their MSRs rather than their descriptors.
+    save.fs.base = segment::read_fs_base();
+    save.gs.base = segment::read_gs_base();
+    save.efer = read::<Efer>().bits();
+    save.star = read_raw(STAR);
+    save.lstar = read_raw(LSTAR);
+    save.cstar = read_raw(CSTAR);
+    save.sfmask = read_raw(SFMASK);
+    save.kernel_gs_base = read_raw(KERNEL_GS_BASE);
+    save.sysenter_cs = read::<SysenterCs>();
+    save.sysenter_esp = read::<SysenterEsp>();
+    save.sysenter_eip = read::<SysenterEip>();
+    save.g_pat = read_raw(PAT);
+
+    // The guest resumes where `ExitBootServices()` returns to the firmware.
+    save.cr0 = Cr0::get().bits();
+    save.cr3 = Cr3::get().bits();
+    save.cr4 = Cr4::get().bits();
+    save.dr6 = DR6_RESET;
+    save.dr7 = DR7_RESET;
+    save.rflags = machine_state.rflags;
+    save.rip = exit_boot_services_return as *const () as u64;
+    save.rsp = machine_state.rsp;
+    save.rax = machine_state.rax;
+
+    Ok(())
+}
+
+/// Runs the guest described by the guest VMCB until a `#VMEXIT` cannot be handled.
+///
+/// # Errors
+/// Returns [`SvmError::UnhandledExit`] describing the `#VMEXIT` that stopped the guest, which
+/// includes `vmrun` rejecting the guest state.
+pub fn launch_virtual_machine() -> Result<Infallible, SvmError> {
+    let machine_state = crate::arch::machine_state();
+
+    // RAX and RSP are held in the VMCB.
+    let mut guest_registers = GuestRegisters {
+        rax: machine_state.rax,
+        rcx: machine_state.rcx,
+        rdx: machine_state.rdx,
+        rbx: machine_state.rbx,
+        rbp: machine_state.rbp,
+        rsi: machine_state.rsi,
+        rdi: machine_state.rdi,
+        r8: machine_state.r8,
+        r9: machine_state.r9,
+        r10: machine_state.r10,
+        r11: machine_state.r11,
+        r12: machine_state.r12,
+        r13: machine_state.r13,
+        r14: machine_state.r14,
+        r15: machine_state.r15,
+    };
+
+    let guest_vmcb = GUEST_VMCB.load(Ordering::Relaxed);
+    let host_vmcb = HOST_VMCB.load(Ordering::Relaxed);
+    let host_stack = HOST_STACK.load(Ordering::Relaxed);
+    assert!(!guest_vmcb.is_null() && !host_vmcb.is_null() && !host_stack.is_null());
+    let host_stack_top = host_stack as u64 + (HOST_STACK_PAGES * FRAME_SIZE) as u64;
+
+    // SAFETY:
+    // The VMCBs are page-aligned pages, UEFI identity maps all memory so their addresses are also
+    // their physical addresses, the guest VMCB has been programmed, and the host stack is unused.
+    unsafe {
+        svm_run(
+            guest_vmcb as u64,
+            host_vmcb as u64,
+            &mut guest_registers,
+            host_stack_top,
+        );
+    }
+
+    // SAFETY:
+    // The guest is no longer running, so the guest VMCB is not being modified.
+    let control = unsafe { &(*guest_vmcb).control };
+    Err(SvmError::UnhandledExit {
+        code: control.exit_code,
+        info_1: control.exit_info_1,
+        info_2: control.exit_info_2,
+    })
+}
+
+/// Returns the guest VMCB, which is only accessed while the guest is not running.
+pub fn guest_vmcb() -> *mut Vmcb {
+    GUEST_VMCB.load(Ordering::Relaxed)
+}
+
+/// Undoes [`enable_support`], returning the host save area, which is no longer in use.
+///
+/// The returned pointer is null if the host save area was never allocated.
+pub fn teardown_processor() -> *mut u8 {
+    let host_save_area = HOST_SAVE_AREA.swap(ptr::null_mut(), Ordering::Relaxed);
+
+    // SAFETY:
+    // `teardown_processor` is only called on processors supporting SVM, on which `VM_HSAVE_PA`
+    // exists; no guest is running, so the host save area is not in use.
+    unsafe { VmHsavePa::write(0) }
+    // SAFETY:
+    // `EFER` exists on every processor supporting 64-bit mode.
+    let efer = unsafe { Efer::read() };
+    // SAFETY:
+    // No guest is running, so SVM may be disabled.
+    unsafe { Efer::write(efer.set_svme(false)) }
+
+    host_save_area
+}
+
+/// Various errors that can occur while placing the processor under SVM control.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SvmError {
+    /// The firmware has disabled SVM through `VM_CR`.
+    Disabled,
+    /// The host save area has not been allocated.
+    MissingHostSaveArea,
+    /// A guest segment selector does not refer to a usable GDT entry.
+    InvalidSegment(SegmentDescriptorError),
+    /// The guest stopped on a `#VMEXIT` that could not be handled.
+    UnhandledExit {
+        /// The exit code of the `#VMEXIT`.
+        code: u64,
+        /// The first piece of information about the `#VMEXIT`.
+        info_1: u64,
+        /// The second piece of information about the `#VMEXIT`.
+        info_2: u64,
+    },
+}
+
+impl fmt::Display for SvmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "SVM has been disabled by the firmware"),
+            Self::MissingHostSaveArea => write!(f, "the SVM host save area was not allocated"),
+            Self::InvalidSegment(error) => write!(f, "invalid guest segment: {error}"),
+            Self::UnhandledExit {
+                code,
+                info_1,
+                info_2,
+            } => write!(
+                f,
+                "unhandled #VMEXIT {code:#x} (EXITINFO1 {info_1:#x}, EXITINFO2 {info_2:#x})"
+            ),
+        }
+    }
+}
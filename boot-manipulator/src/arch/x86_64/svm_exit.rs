@@ -0,0 +1,242 @@
+//! Running SVM guests and handling their `#VMEXIT`s.
+
+use crate::arch::x86_64::{
+    npt::{
+        FAULT_EXECUTE, FAULT_FINAL_TRANSLATION, FAULT_PAGE_TABLE_WALK, FAULT_PRESENT,
+        FAULT_RESERVED, FAULT_WRITE,
+    },
+    registers::msr::{EferValue, Msr, VmCr, EFER, VM_CR, VM_HSAVE_PA},
+    svm,
+    vm_exit::{emulate_cpuid, GuestRegisters},
+    vmcb::{Vmcb, EVENT_DELIVER_ERROR_CODE, EVENT_TYPE_EXCEPTION, EVENT_VALID},
+};
+
+/// The exit code of an intercepted `cpuid`.
+const EXIT_CPUID: u64 = 0x72;
+/// The exit code of an intercepted `rdmsr` or `wrmsr`.
+const EXIT_MSR: u64 = 0x7C;
+/// The exit code of an intercepted `vmrun`.
+const EXIT_VMRUN: u64 = 0x80;
+/// The exit code of a nested page fault.
+const EXIT_NESTED_PAGE_FAULT: u64 = 0x400;
+
+/// The length of `cpuid`, `rdmsr`, and `wrmsr`, which the guest is moved past once emulated.
+const INSTRUCTION_LENGTH: u64 = 2;
+
+/// The vector of the invalid opcode exception.
+const VECTOR_INVALID_OPCODE: u64 = 6;
+/// The vector of the general protection exception.
+const VECTOR_GENERAL_PROTECTION: u64 = 13;
+
+core::arch::global_asm!(
+    ".global svm_run",
+    "svm_run:",
+    // Preserve the callee-saved registers of the caller, then switch to the host stack, keeping
+    // the caller's stack pointer, the VMCBs, and the guest registers at fixed offsets.
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rax, rsp",
+    "mov rsp, rcx",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "2:",
+    // Save the host state not handled by `vmrun`, then load the guest's general purpose
+    // registers other than RAX and RSP, which are held in the VMCB.
+    "mov rax, [rsp + 8]",
+    "vmsave rax",
+    "mov rax, [rsp]",
+    "mov rcx, [rax + 8]",
+    "mov rdx, [rax + 16]",
+    "mov rbx, [rax + 24]",
+    "mov rbp, [rax + 32]",
+    "mov rsi, [rax + 40]",
+    "mov rdi, [rax + 48]",
+    "mov r8, [rax + 56]",
+    "mov r9, [rax + 64]",
+    "mov r10, [rax + 72]",
+    "mov r11, [rax + 80]",
+    "mov r12, [rax + 88]",
+    "mov r13, [rax + 96]",
+    "mov r14, [rax + 104]",
+    "mov r15, [rax + 112]",
+    "mov rax, [rsp + 16]",
+    "vmload rax",
+    "vmrun rax",
+    "vmsave rax",
+    // `#VMEXIT` restores RAX and RSP from the host save area, so store the guest's registers
+    // through RAX before restoring the rest of the host state.
+    "mov rax, [rsp]",
+    "mov [rax + 8], rcx",
+    "mov [rax + 16], rdx",
+    "mov [rax + 24], rbx",
+    "mov [rax + 32], rbp",
+    "mov [rax + 40], rsi",
+    "mov [rax + 48], rdi",
+    "mov [rax + 56], r8",
+    "mov [rax + 64], r9",
+    "mov [rax + 72], r10",
+    "mov [rax + 80], r11",
+    "mov [rax + 88], r12",
+    "mov [rax + 96], r13",
+    "mov [rax + 104], r14",
+    "mov [rax + 112], r15",
+    "mov rax, [rsp + 8]",
+    "vmload rax",
+    "mov rdi, [rsp]",
+    "call {dispatch_svm_exit}",
+    "test al, al",
+    "jnz 2b",
+    // The `#VMEXIT` could not be handled, so return to the caller.
+    "mov rsp, [rsp + 24]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    dispatch_svm_exit = sym dispatch_svm_exit,
+);
+
+extern "sysv64" {
+    /// Runs the guest described by the VMCB at `guest_vmcb` on the stack ending at `host_stack`,
+    /// saving and restoring the host state through the VMCB at `host_vmcb` around each `vmrun`,
+    /// until a `#VMEXIT` cannot be handled.
+    ///
+    /// # Safety
+    /// Both VMCBs must be identity-mapped pages, the guest VMCB must describe a valid guest, SVM
+    /// must be enabled, and `host_stack` must be the 16-byte aligned top of an unused stack.
+    pub fn svm_run(
+        guest_vmcb: u64,
+        host_vmcb: u64,
+        registers: *mut GuestRegisters,
+        host_stack: u64,
+    );
+}
+
+/// Handles the `#VMEXIT` described by the guest VMCB, returning `false` if the guest cannot be
+/// resumed.
+extern "sysv64" fn dispatch_svm_exit(registers: &mut GuestRegisters) -> bool {
+    // SAFETY:
+    // The guest is not running while its `#VMEXIT` is handled, so nothing else accesses its VMCB.
+    let vmcb = unsafe { &mut *svm::guest_vmcb() };
+    registers.rax = vmcb.save.rax;
+
+    let resume = match vmcb.control.exit_code {
+        EXIT_CPUID => {
+            handle_cpuid(vmcb, registers);
+            true
+        }
+        EXIT_MSR if vmcb.control.exit_info_1 == 0 => {
+            handle_rdmsr(vmcb, registers);
+            true
+        }
+        EXIT_MSR => {
+            handle_wrmsr(vmcb, registers);
+            true
+        }
+        EXIT_VMRUN => {
+            // Nested virtualization is not supported, so behave as a processor without SVM.
+            vmcb.control.event_injection =
+                EVENT_VALID | EVENT_TYPE_EXCEPTION | VECTOR_INVALID_OPCODE;
+            true
+        }
+        EXIT_NESTED_PAGE_FAULT => {
+            log_nested_page_fault(vmcb);
+            false
+        }
+        _ => false,
+    };
+
+    vmcb.save.rax = registers.rax;
+    resume
+}
+
+/// Logs the guest-physical address and decoded error code of a nested page fault.
+fn log_nested_page_fault(vmcb: &Vmcb) {
+    let gpa = vmcb.control.exit_info_2;
+    let error_code = vmcb.control.exit_info_1;
+    let bit = |mask: u64| error_code & mask == mask;
+    let stage = if bit(FAULT_PAGE_TABLE_WALK) {
+        "guest page table walk"
+    } else if bit(FAULT_FINAL_TRANSLATION) {
+        "final translation"
+    } else {
+        "unknown stage"
+    };
+
+    log::error!(
+        "nested page fault at guest physical address {gpa:#x} during {stage} \
+         (error code {error_code:#x}: present={}, write={}, reserved={}, execute={}, RIP {:#x})",
+        bit(FAULT_PRESENT),
+        bit(FAULT_WRITE),
+        bit(FAULT_RESERVED),
+        bit(FAULT_EXECUTE),
+        vmcb.save.rip,
+    );
+}
+
+/// Emulates `cpuid` with the guest's EAX and ECX.
+fn handle_cpuid(vmcb: &mut Vmcb, registers: &mut GuestRegisters) {
+    let result = emulate_cpuid(registers.rax as u32, registers.rcx as u32);
+
+    registers.rax = u64::from(result.eax);
+    registers.rbx = u64::from(result.ebx);
+    registers.rcx = u64::from(result.ecx);
+    registers.rdx = u64::from(result.edx);
+    vmcb.save.rip += INSTRUCTION_LENGTH;
+}
+
+/// Emulates `rdmsr` of an intercepted MSR, hiding SVM from the guest.
+fn handle_rdmsr(vmcb: &mut Vmcb, registers: &mut GuestRegisters) {
+    let msr = registers.rcx as u32;
+
+    let value = match msr {
+        EFER => EferValue::from(vmcb.save.efer).set_svme(false).bits(),
+        // Report SVM as disabled and locked, so the guest cannot attempt to enable it.
+        VM_CR => {
+            // SAFETY:
+            // `VM_CR` exists on every processor supporting SVM.
+            let value = unsafe { VmCr::read() };
+            value.set_lock(true).set_svm_disable(true).bits()
+        }
+        VM_HSAVE_PA => 0,
+        // MSRs outside of the permissions map always exit; they are not forwarded.
+        msr => return inject_general_protection(vmcb, msr),
+    };
+
+    registers.rax = value & 0xFFFF_FFFF;
+    registers.rdx = value >> 32;
+    vmcb.save.rip += INSTRUCTION_LENGTH;
+}
+
+/// Emulates `wrmsr` of an intercepted MSR, keeping SVM enabled underneath the guest.
+fn handle_wrmsr(vmcb: &mut Vmcb, registers: &mut GuestRegisters) {
+    let msr = registers.rcx as u32;
+    let value = ((registers.rdx & 0xFFFF_FFFF) << 32) | (registers.rax & 0xFFFF_FFFF);
+
+    match msr {
+        // `vmrun` requires the guest's EFER.SVME to remain set.
+        EFER => vmcb.save.efer = EferValue::from(value).set_svme(true).bits(),
+        // `VM_CR` is reported as locked, `VM_HSAVE_PA` belongs to the host, and MSRs outside of
+        // the permissions map always exit; none of them are forwarded.
+        msr => return inject_general_protection(vmcb, msr),
+    }
+
+    vmcb.save.rip += INSTRUCTION_LENGTH;
+}
+
+/// Injects #GP(0) into the guest on the next `vmrun` in response to an access to `msr`, leaving
+/// the guest RIP on the faulting instruction.
+fn inject_general_protection(vmcb: &mut Vmcb, msr: u32) {
+    log::debug!("injecting #GP(0) for guest access to MSR {msr:#x}");
+
+    vmcb.control.event_injection =
+        EVENT_VALID | EVENT_DELIVER_ERROR_CODE | EVENT_TYPE_EXCEPTION | VECTOR_GENERAL_PROTECTION;
+}
@@ -0,0 +1,455 @@
+//! The GDT, TSS, and IDT the host runs with after VM exits.
+//!
+//! The tables are owned by the hypervisor and live in persistent memory, so that they remain
+//! valid once the operating system has reclaimed or replaced the firmware's tables. VM exits load
+//! them from the host-state fields of the VMCS, with the limits of the GDT and IDT set to
+//! `0xFFFF`; the host runs with interrupts disabled, so only exceptions reach the IDT.
+
+use core::{
+    mem::size_of,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::{
+    arch::x86_64::{registers::segment::SegmentSelector, vm_exit::halt},
+    frames::{allocate_frames, deallocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE},
+};
+
+/// The selector of the 64-bit code segment.
+pub const CODE_SELECTOR: SegmentSelector = SegmentSelector::from_bits(0x08);
+/// The selector of the data segment, used for every data segment register.
+pub const DATA_SELECTOR: SegmentSelector = SegmentSelector::from_bits(0x10);
+/// The selector of the TSS, whose descriptor occupies two entries.
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::from_bits(0x18);
+
+/// The limit of the TSS.
+pub const TSS_LIMIT: u32 = size_of::<TaskStateSegment>() as u32 - 1;
+
+/// The number of entries in the GDT: the null descriptor, the code and data segments, and the
+/// two halves of the TSS descriptor.
+const GDT_ENTRIES: usize = 5;
+/// The number of entries in the IDT, which covers the exception vectors.
+const IDT_ENTRIES: usize = 32;
+
+/// The number of pages in each interrupt stack.
+const IST_STACK_PAGES: usize = 2;
+/// The interrupt stack used by NMIs.
+const IST_NMI: u8 = 1;
+/// The interrupt stack used by double faults.
+const IST_DOUBLE_FAULT: u8 = 2;
+/// The interrupt stack used by machine checks.
+const IST_MACHINE_CHECK: u8 = 3;
+/// The number of interrupt stacks allocated.
+const IST_STACKS: usize = 3;
+
+/// The vector of NMIs.
+const VECTOR_NMI: usize = 2;
+/// The vector of double faults.
+const VECTOR_DOUBLE_FAULT: usize = 8;
+/// The vector of machine checks.
+const VECTOR_MACHINE_CHECK: usize = 18;
+
+/// The descriptor of a present, ring 0, 64-bit code segment.
+const CODE_DESCRIPTOR: u64 = 0x00AF_9A00_0000_FFFF;
+/// The descriptor of a present, ring 0, writable data segment.
+const DATA_DESCRIPTOR: u64 = 0x00CF_9200_0000_FFFF;
+/// The type of an available 64-bit TSS.
+const TSS_TYPE_AVAILABLE: u64 = 0x9;
+/// The bit of a descriptor marking it as present.
+const DESCRIPTOR_PRESENT: u64 = 1 << 47;
+/// The attributes of a present, ring 0, 64-bit interrupt gate.
+const INTERRUPT_GATE: u8 = 0x8E;
+
+/// The number of bytes between consecutive exception stubs.
+const STUB_SIZE: u64 = 16;
+
+// The tables are allocated as a single frame.
+const _: () = assert!(size_of::<HostTables>() <= FRAME_SIZE);
+
+/// The tables, which are null until [`allocate`] succeeds.
+static TABLES: AtomicPtr<HostTables> = AtomicPtr::new(ptr::null_mut());
+/// The interrupt stacks referenced by the TSS.
+static IST_STACK_FRAMES: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+core::arch::global_asm!(
+    // One 16-byte stub per exception vector, which pushes a zero in place of the error code if
+    // the processor does not push one, followed by the vector.
+    ".balign 16",
+    "host_exception_stubs:",
+    ".irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31",
+    ".balign 16",
+    ".if !(\\vector == 8 || (\\vector >= 10 && \\vector <= 14) || \\vector == 17 || \\vector == 21 || \\vector == 29 || \\vector == 30)",
+    "push 0",
+    ".endif",
+    "push \\vector",
+    "jmp host_exception_common",
+    ".endr",
+    "host_exception_common:",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {host_exception}",
+    "ud2",
+    host_exception = sym host_exception,
+);
+
+extern "sysv64" {
+    /// The first of the [`IDT_ENTRIES`] exception stubs, each [`STUB_SIZE`] bytes apart.
+    fn host_exception_stubs();
+}
+
+/// The stack built by an exception stub, followed by the frame pushed by the processor.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct ExceptionFrame {
+    /// The vector of the exception.
+    vector: u64,
+    /// The error code pushed by the processor, or zero.
+    error_code: u64,
+    /// The RIP at which the exception occurred.
+    rip: u64,
+    /// The CS at which the exception occurred.
+    cs: u64,
+    /// The RFLAGS at the time of the exception.
+    rflags: u64,
+    /// The RSP at the time of the exception.
+    rsp: u64,
+    /// The SS at the time of the exception.
+    ss: u64,
+}
+
+/// Reports an exception taken by the host and halts, as the host has no way to recover.
+extern "sysv64" fn host_exception(frame: &ExceptionFrame) -> ! {
+    log::error!(
+        "host exception {} (error code {:#x}) at {:#x}, RSP {:#x}, RFLAGS {:#x}",
+        frame.vector,
+        frame.error_code,
+        frame.rip,
+        frame.rsp,
+        frame.rflags
+    );
+    halt()
+}
+
+/// A 64-bit task-state segment, which only supplies the interrupt stacks.
+#[repr(C, packed(4))]
+#[derive(Clone, Copy, Debug, Default)]
+struct TaskStateSegment {
+    /// Reserved.
+    _reserved0: u32,
+    /// The stack pointers loaded on privilege-level changes, which the host never performs.
+    rsp: [u64; 3],
+    /// Reserved.
+    _reserved1: u64,
+    /// The interrupt stack table, whose entry `n - 1` holds IST `n`.
+    ist: [u64; 7],
+    /// Reserved.
+    _reserved2: u64,
+    /// Reserved.
+    _reserved3: u16,
+    /// The offset of the I/O permission bitmap, which lies past the limit so none is used.
+    iomap_base: u16,
+}
+
+/// An entry of the IDT.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+struct GateDescriptor {
+    /// Bits 0-15 of the handler.
+    offset_low: u16,
+    /// The code segment of the handler.
+    selector: u16,
+    /// The interrupt stack used by the handler, or zero for the current stack.
+    ist: u8,
+    /// The type, privilege level, and present bit of the gate.
+    attributes: u8,
+    /// Bits 16-31 of the handler.
+    offset_middle: u16,
+    /// Bits 32-63 of the handler.
+    offset_high: u32,
+    /// Reserved.
+    _reserved: u32,
+}
+
+impl GateDescriptor {
+    /// Returns an interrupt gate to `handler` in the code segment `selector`, switching to IST
+    /// `ist` unless it is zero.
+    const fn interrupt(handler: u64, selector: SegmentSelector, ist: u8) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector: selector.bits(),
+            ist,
+            attributes: INTERRUPT_GATE,
+            offset_middle: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+}
+
+/// Returns the two GDT entries describing an available 64-bit TSS at `base` with `limit`.
+const fn tss_descriptor(base: u64, limit: u32) -> [u64; 2] {
+    let low = (limit as u64 & 0xFFFF)
+        | ((base & 0xFF_FFFF) << 16)
+        | (TSS_TYPE_AVAILABLE << 40)
+        | DESCRIPTOR_PRESENT
+        | (((limit as u64 >> 16) & 0xF) << 48)
+        | (((base >> 24) & 0xFF) << 56);
+
+    [low, base >> 32]
+}
+
+/// The GDT, TSS, and IDT of the host, which are allocated together.
+#[repr(C, align(16))]
+struct HostTables {
+    /// The GDT.
+    gdt: [u64; GDT_ENTRIES],
+    /// The TSS referenced by [`TSS_SELECTOR`].
+    tss: TaskStateSegment,
+    /// The IDT.
+    idt: [GateDescriptor; IDT_ENTRIES],
+}
+
+/// Allocates the interrupt stacks and builds the host's tables in persistent memory.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if the tables or the stacks cannot be allocated.
+pub fn allocate() -> Result<(), OutOfMemoryError> {
+    let stacks = allocate_frames(IST_STACKS * IST_STACK_PAGES, MemoryKind::Persistent)?;
+    IST_STACK_FRAMES.store(stacks.as_ptr(), Ordering::Relaxed);
+    let tables = allocate_frames(1, MemoryKind::Persistent)?.cast::<HostTables>();
+    TABLES.store(tables.as_ptr(), Ordering::Relaxed);
+
+    // Stacks grow down, so each IST entry holds the end of its stack.
+    let stack_size = (IST_STACK_PAGES * FRAME_SIZE) as u64;
+    let ist = core::array::from_fn(|index| {
+        if index < IST_STACKS {
+            stacks.as_ptr() as u64 + (index as u64 + 1) * stack_size
+        } else {
+            0
+        }
+    });
+    let tss = TaskStateSegment {
+        ist,
+        iomap_base: size_of::<TaskStateSegment>() as u16,
+        ..TaskStateSegment::default()
+    };
+
+    let stubs = host_exception_stubs as *const () as u64;
+    let idt = core::array::from_fn(|vector| {
+        let ist = match vector {
+            VECTOR_NMI => IST_NMI,
+            VECTOR_DOUBLE_FAULT => IST_DOUBLE_FAULT,
+            VECTOR_MACHINE_CHECK => IST_MACHINE_CHECK,
+            _ => 0,
+        };
+
+        GateDescriptor::interrupt(stubs + vector as u64 * STUB_SIZE, CODE_SELECTOR, ist)
+    });
+
+    let tss_address = tables.as_ptr() as u64 + core::mem::offset_of!(HostTables, tss) as u64;
+    let [tss_low, tss_high] = tss_descriptor(tss_address, TSS_LIMIT);
+
+    // SAFETY:
+    // `tables` points to a freshly allocated frame large enough for the tables, which UEFI
+    // identity maps.
+    unsafe {
+        tables.write(HostTables {
+            gdt: [0, CODE_DESCRIPTOR, DATA_DESCRIPTOR, tss_low, tss_high],
+            tss,
+            idt,
+        })
+    }
+
+    Ok(())
+}
+
+/// Frees the memory allocated by [`allocate`], skipping anything that was not allocated.
+///
+/// # Safety
+/// The tables must not be in use by the processor, and boot services must not have been exited.
+pub unsafe fn release() {
+    let regions = [
+        (
+            TABLES.swap(ptr::null_mut(), Ordering::Relaxed).cast::<u8>(),
+            1,
+        ),
+        (
+            IST_STACK_FRAMES.swap(ptr::null_mut(), Ordering::Relaxed),
+            IST_STACKS * IST_STACK_PAGES,
+        ),
+    ];
+    for (frames, count) in regions {
+        let Some(frames) = NonNull::new(frames) else {
+            continue;
+        };
+
+        // SAFETY:
+        // The region was allocated with `count` frames, and the caller guarantees it is unused.
+        unsafe { deallocate_frames(frames, count) }
+    }
+}
+
+/// Returns the address of the GDT.
+///
+/// # Panics
+/// Panics if the tables have not been allocated.
+pub fn gdt_address() -> u64 {
+    tables().as_ptr() as u64
+}
+
+/// Returns the address of the TSS.
+///
+/// # Panics
+/// Panics if the tables have not been allocated.
+pub fn tss_address() -> u64 {
+    tables().as_ptr() as u64 + core::mem::offset_of!(HostTables, tss) as u64
+}
+
+/// Returns the address of the IDT.
+///
+/// # Panics
+/// Panics if the tables have not been allocated.
+pub fn idt_address() -> u64 {
+    tables().as_ptr() as u64 + core::mem::offset_of!(HostTables, idt) as u64
+}
+
+/// Loads the host's tables into the current processor with `lgdt`, `ltr`, and `lidt`, and
+/// reloads the segment registers with the host's selectors.
+///
+/// VM exits load the tables from the VMCS, so this is only needed to run host code outside of
+/// a VM exit.
+///
+/// # Safety
+/// The tables must remain allocated while loaded, and the state of the processor captured for
+/// the guest must not be read after the tables are loaded.
+///
+/// # Panics
+/// Panics if the tables have not been allocated.
+#[allow(dead_code)]
+pub unsafe fn load() {
+    let gdtr = crate::arch::x86_64::registers::Gdtr::new(
+        gdt_address(),
+        (GDT_ENTRIES * size_of::<u64>() - 1) as u16,
+    );
+    let idtr = crate::arch::x86_64::registers::Idtr::new(
+        idt_address(),
+        (IDT_ENTRIES * size_of::<GateDescriptor>() - 1) as u16,
+    );
+
+    // SAFETY:
+    // The GDT describes a 64-bit code segment, a data segment and the TSS, and the caller
+    // guarantees that it remains allocated.
+    unsafe { gdtr.load() }
+    // SAFETY:
+    // The selectors reference the code and data descriptors of the GDT loaded above; CS is
+    // reloaded with a far return to the following instruction.
+    unsafe {
+        core::arch::asm!(
+            "push {code}",
+            "lea {scratch}, [rip + 2f]",
+            "push {scratch}",
+            "retfq",
+            "2:",
+            "mov ss, {data:x}",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            code = in(reg) u64::from(CODE_SELECTOR.bits()),
+            data = in(reg) DATA_SELECTOR.bits(),
+            scratch = out(reg) _,
+        )
+    }
+    // SAFETY:
+    // The selector references the available TSS descriptor of the GDT loaded above.
+    unsafe { core::arch::asm!("ltr {:x}", in(reg) TSS_SELECTOR.bits(), options(nostack)) }
+    // SAFETY:
+    // The IDT references the exception stubs, and the caller guarantees that it remains
+    // allocated.
+    unsafe { idtr.load() }
+}
+
+/// Returns the host's tables.
+///
+/// # Panics
+/// Panics if the tables have not been allocated.
+fn tables() -> NonNull<HostTables> {
+    NonNull::new(TABLES.load(Ordering::Relaxed)).expect("the host tables are allocated")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::x86_64::registers::segment::SegmentDescriptor;
+
+    #[test]
+    fn code_descriptor_is_a_flat_64_bit_segment() {
+        let code = SegmentDescriptor::decode(CODE_DESCRIPTOR, 0);
+
+        assert_eq!(code.base(), 0);
+        assert_eq!(code.limit(), 0xFFFF_FFFF);
+        // Execute/read, accessed clear, present, ring 0, with the L and G flags.
+        assert_eq!(code.access_rights(), 0xA09A);
+    }
+
+    #[test]
+    fn data_descriptor_is_a_flat_writable_segment() {
+        let data = SegmentDescriptor::decode(DATA_DESCRIPTOR, 0);
+
+        assert_eq!(data.base(), 0);
+        assert_eq!(data.limit(), 0xFFFF_FFFF);
+        // Read/write, present, ring 0, with the D/B and G flags.
+        assert_eq!(data.access_rights(), 0xC092);
+    }
+
+    #[test]
+    fn tss_descriptor_round_trips() {
+        let base = 0xFFFF_8000_1234_5678;
+        let [low, high] = tss_descriptor(base, TSS_LIMIT);
+        let tss = SegmentDescriptor::decode(low, high);
+
+        assert_eq!(tss.base(), base);
+        assert_eq!(tss.limit(), TSS_LIMIT);
+        assert_eq!(tss.access_rights(), 0x89);
+        assert_eq!(high >> 32, 0);
+
+        // Limits wider than 16 bits are split across the descriptor.
+        let [low, high] = tss_descriptor(0x1000, 0xA_BCDE);
+        assert_eq!(SegmentDescriptor::decode(low, high).limit(), 0xA_BCDE);
+    }
+
+    #[test]
+    fn tss_limit_covers_the_architectural_tss() {
+        assert_eq!(size_of::<TaskStateSegment>(), 0x68);
+        assert_eq!(TSS_LIMIT, 0x67);
+    }
+
+    #[test]
+    fn interrupt_gates_split_the_handler() {
+        let gate = GateDescriptor::interrupt(0xFFFF_8000_1234_5678, CODE_SELECTOR, IST_NMI);
+
+        assert_eq!(size_of::<GateDescriptor>(), 16);
+        assert_eq!(
+            gate,
+            GateDescriptor {
+                offset_low: 0x5678,
+                selector: 0x08,
+                ist: 1,
+                attributes: 0x8E,
+                offset_middle: 0x1234,
+                offset_high: 0xFFFF_8000,
+                _reserved: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn selectors_index_the_gdt() {
+        assert_eq!(usize::from(CODE_SELECTOR.index()), 1);
+        assert_eq!(usize::from(DATA_SELECTOR.index()), 2);
+        // The TSS descriptor occupies the last two entries.
+        assert_eq!(usize::from(TSS_SELECTOR.index()) + 2, GDT_ENTRIES);
+        assert!([CODE_SELECTOR, DATA_SELECTOR, TSS_SELECTOR]
+            .iter()
+            .all(|selector| selector.rpl() == 0));
+    }
+}
@@ -0,0 +1,21 @@
+//! The time-stamp counter, used as the monotonic clock.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+/// The CPUID leaf reporting advanced power management features.
+const CPUID_ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+/// The bit in EDX of [`CPUID_ADVANCED_POWER_MANAGEMENT`] reporting an invariant TSC.
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Returns the current value of the time-stamp counter.
+pub fn read_counter() -> u64 {
+    // SAFETY:
+    // The time-stamp counter is available on every `x86_64` processor.
+    unsafe { _rdtsc() }
+}
+
+/// Returns `true` if the counter runs at a constant rate regardless of power state.
+pub fn is_invariant() -> bool {
+    __cpuid(0x8000_0000).eax >= CPUID_ADVANCED_POWER_MANAGEMENT
+        && __cpuid(CPUID_ADVANCED_POWER_MANAGEMENT).edx & CPUID_INVARIANT_TSC != 0
+}
@@ -2,181 +2,1634 @@
 
 use core::{
     arch::asm,
-    ptr,
-    sync::atomic::{AtomicPtr, Ordering},
+    convert::Infallible,
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU64, Ordering},
 };
 
-use uefi::boot;
+use uefi::{boot, mem::memory_map::MemoryMap};
 
-use crate::arch::x86_64::registers::{
-    control::{Cr0, Cr0Display, Cr4, Cr4Display},
-    msr::{
-        read_msr, write_msr, FEATURE_CONTROL, VMX_CR0_FIXED0, VMX_CR0_FIXED1, VMX_CR4_FIXED0,
-        VMX_CR4_FIXED1, VMX_REVISION,
+use crate::{
+    arch::x86_64::{
+        ept::{EptError, EptHierarchy, EptVpidCapabilities, GuestMemory, GUEST_MEMORY},
+        exit_boot_services_return,
+        msr_bitmap::MsrBitmap,
+        mtrr::MtrrMap,
+        paging::{self, PAGE_SIZE},
+        registers::{
+            control::{Cr0, Cr0Display, Cr3, Cr4, Cr4Display},
+            debug::Dr7,
+            msr::{
+                read_msr, Efer, FeatureControl, Msr, SysenterCs, SysenterEip, SysenterEsp,
+                VmxBasic, VmxBasicValue, VmxCr0Fixed0, VmxCr0Fixed1, VmxCr4Fixed0, VmxCr4Fixed1,
+                WritableMsr, FEATURE_CONTROL, VMX_ENTRY_CTLS, VMX_EXIT_CTLS, VMX_PINBASED_CTLS,
+                VMX_PROCBASED_CTLS, VMX_PROCBASED_CTLS2, VMX_REVISION, VMX_TRUE_ENTRY_CTLS,
+                VMX_TRUE_EXIT_CTLS, VMX_TRUE_PINBASED_CTLS, VMX_TRUE_PROCBASED_CTLS, VMX_VMFUNC,
+            },
+            rflags::Rflags,
+            segment::{self, SegmentDescriptor, SegmentDescriptorError},
+            Gdtr, Idtr,
+        },
+        svm::{self, SvmError},
+        tables,
+        vm_exit::vmexit_entry,
+        vmcs_fields::VmcsField,
+        xsave, UefiRegisters,
     },
-    Gdtr, Idtr,
+    frames::{allocate_frames, deallocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE},
 };
 
 const CR4_VMXE_BIT: u8 = 5;
 const CR4_VMXE: u64 = 1 << CR4_VMXE_BIT;
 
-const FEATURE_CONTROL_MSR_LOCKED: u64 = 1;
-const FEATURE_CONTROL_MSR_VMX_OUTSIDE_SMX: u64 = 1 << 2;
-
 static VMXON_REGION: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
 static VMCS_REGION: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
 
-pub fn is_supported() -> bool {
-    let ecx = unsafe { core::arch::x86_64::__cpuid(1).ecx };
-    (ecx as u64 & CR4_VMXE) == CR4_VMXE
+/// Whether the processor has successfully executed `vmxon`.
+static IN_VMX_OPERATION: AtomicBool = AtomicBool::new(false);
+
+/// The memory type encoding for write-back memory in `IA32_VMX_BASIC`.
+const VMX_BASIC_MEMORY_TYPE_WRITE_BACK: u8 = 6;
+
+/// The number of bytes allocated for the VMXON region and the VMCS, which is a single page.
+const REGION_SIZE: usize = FRAME_SIZE;
+
+// Memory is allocated in frames, which must also serve as paging structures and as the 4 KiB
+// aligned regions VMX requires.
+const _: () = assert!(FRAME_SIZE.is_power_of_two() && FRAME_SIZE as u64 == PAGE_SIZE);
+
+/// The primary processor-based control causing `hlt` to exit.
+const PROCBASED_HLT_EXITING: u32 = 1 << 7;
+/// The primary processor-based control restricting MSR exits to those selected by the MSR bitmap.
+const PROCBASED_USE_MSR_BITMAPS: u32 = 1 << 28;
+/// The primary processor-based control enabling the secondary processor-based controls.
+const PROCBASED_ACTIVATE_SECONDARY_CONTROLS: u32 = 1 << 31;
+
+/// The secondary processor-based control enabling EPT.
+const PROCBASED2_ENABLE_EPT: u32 = 1 << 1;
+/// The secondary processor-based control enabling VPIDs.
+const PROCBASED2_ENABLE_VPID: u32 = 1 << 5;
+/// The secondary processor-based control enabling `rdtscp` in the guest.
+const PROCBASED2_ENABLE_RDTSCP: u32 = 1 << 3;
+/// The secondary processor-based control enabling `invpcid` in the guest.
+const PROCBASED2_ENABLE_INVPCID: u32 = 1 << 12;
+/// The secondary processor-based control enabling `xsaves` and `xrstors` in the guest.
+const PROCBASED2_ENABLE_XSAVES: u32 = 1 << 20;
+
+/// The VM-exit control returning to a 64-bit host.
+const EXIT_HOST_ADDRESS_SPACE_SIZE: u32 = 1 << 9;
+/// The VM-exit control saving the guest's `IA32_EFER`.
+const EXIT_SAVE_IA32_EFER: u32 = 1 << 20;
+/// The VM-exit control loading the host's `IA32_EFER`.
+const EXIT_LOAD_IA32_EFER: u32 = 1 << 21;
+
+/// The VM-entry control entering a guest in IA-32e mode.
+const ENTRY_IA32E_MODE_GUEST: u32 = 1 << 9;
+/// The VM-entry control loading the guest's `IA32_EFER`.
+const ENTRY_LOAD_IA32_EFER: u32 = 1 << 15;
+
+/// The secondary processor-based controls requested for the guest, which otherwise faults on
+/// instructions the firmware and operating system expect to be available.
+const DESIRED_SECONDARY_CONTROLS: u32 =
+    PROCBASED2_ENABLE_RDTSCP | PROCBASED2_ENABLE_INVPCID | PROCBASED2_ENABLE_XSAVES;
+
+/// The bit in CR4 enabling VMX operation, which is hidden from the guest.
+pub const CR4_VMX_ENABLE: u64 = 1 << 13;
+
+/// The MSRs whose reads and writes exit by default: the feature control MSR, which would reveal
+/// that VMX is enabled and locked, and the VMX capability MSRs.
+const INTERCEPTED_MSRS: [core::ops::RangeInclusive<u32>; 2] =
+    [FEATURE_CONTROL..=FEATURE_CONTROL, VMX_REVISION..=VMX_VMFUNC];
+
+/// The number of bytes of physical memory identity mapped for the guest by EPT or nested paging.
+pub const IDENTITY_MAP_SIZE: u64 = 512 << 30;
+
+/// The number of EPT paging structures reserved for mapping memory on demand once boot services
+/// have been exited.
+#[cfg(feature = "lazy-ept")]
+const LAZY_EPT_SPARE_TABLES: usize = 64;
+
+/// The next VPID to assign to a logical processor; VPID 0 is reserved for VMX root operation.
+static NEXT_VPID: AtomicU16 = AtomicU16::new(1);
+
+/// The EPT pointer of the guest, or zero if EPT is not in use.
+static EPT_POINTER: AtomicU64 = AtomicU64::new(0);
+
+/// The page holding the MSR bitmap of the guest.
+static MSR_BITMAP: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+/// The number of pages in the stack used by the host after a VM exit.
+const HOST_STACK_PAGES: usize = 4;
+
+/// The base of the stack used by the host after a VM exit.
+static HOST_STACK: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+core::arch::global_asm!(
+    ".global vmx_launch",
+    "vmx_launch:",
+    // Preserve the callee-saved registers of the host in case `vmlaunch` fails.
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // Load the guest's general purpose registers from the `UefiRegisters` pointed to by `rdi`,
+    // loading `rdi` itself last.
+    "mov rax, [rdi]",
+    "mov rbx, [rdi + 8]",
+    "mov rcx, [rdi + 16]",
+    "mov rdx, [rdi + 24]",
+    "mov rsi, [rdi + 40]",
+    "mov rbp, [rdi + 56]",
+    "mov r8, [rdi + 64]",
+    "mov r9, [rdi + 72]",
+    "mov r10, [rdi + 80]",
+    "mov r11, [rdi + 88]",
+    "mov r12, [rdi + 96]",
+    "mov r13, [rdi + 104]",
+    "mov r14, [rdi + 112]",
+    "mov r15, [rdi + 120]",
+    "mov rdi, [rdi + 32]",
+    "vmlaunch",
+    // `vmlaunch` only falls through on failure, so return the RFLAGS it left behind.
+    "pushfq",
+    "pop rax",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+extern "sysv64" {
+    /// Launches the guest described by the current VMCS with its general purpose registers loaded
+    /// from `registers`, returning the RFLAGS left by `vmlaunch` if it fails.
+    fn vmx_launch(registers: *const UefiRegisters) -> u64;
 }
 
-pub fn allocate_basic_memory() {
-    let vmxon_ptr = boot::allocate_pages(
-        boot::AllocateType::AnyPages,
-        boot::MemoryType::LOADER_DATA,
-        1,
-    )
-    .unwrap();
+/// The hardware virtualization technologies that can place the processor under the driver's
+/// control.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Technology {
+    /// Intel Virtual Machine Extensions.
+    Vmx,
+    /// AMD Secure Virtual Machine.
+    Svm,
+}
 
-    VMXON_REGION.store(vmxon_ptr.as_ptr(), Ordering::Relaxed);
+/// Returns the virtualization [`Technology`] supported by the running processor, selected by its
+/// vendor.
+pub fn supported_technology() -> Option<Technology> {
+    let vendor = core::arch::x86_64::__cpuid(0);
+    let mut signature = [0; 12];
+    signature[..4].copy_from_slice(&vendor.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&vendor.edx.to_le_bytes());
+    signature[8..].copy_from_slice(&vendor.ecx.to_le_bytes());
 
-    let vmcs_ptr = boot::allocate_pages(
-        boot::AllocateType::AnyPages,
-        boot::MemoryType::LOADER_DATA,
-        1,
-    )
-    .unwrap();
+    match &signature {
+        b"GenuineIntel" => vmx_supported().then_some(Technology::Vmx),
+        b"AuthenticAMD" => svm::is_supported().then_some(Technology::Svm),
+        _ => None,
+    }
+}
 
-    VMCS_REGION.store(vmcs_ptr.as_ptr(), Ordering::Relaxed);
+/// Returns `true` if the processor can be virtualized.
+pub fn is_supported() -> bool {
+    supported_technology().is_some()
 }
 
-pub fn enable_support() {
-    assert!(is_supported());
+/// Returns `true` if the processor supports VMX.
+fn vmx_supported() -> bool {
+    let ecx = core::arch::x86_64::__cpuid(1).ecx;
+    (ecx as u64 & CR4_VMXE) == CR4_VMXE
+}
 
-    let feature_control = unsafe { read_msr(FEATURE_CONTROL) };
-    let required_bits = FEATURE_CONTROL_MSR_LOCKED | FEATURE_CONTROL_MSR_VMX_OUTSIDE_SMX;
-    log::trace!("VMX Feature Control: {feature_control:016X}");
-    log::trace!("VMX Feature Control Required: {required_bits:016X}");
+/// Allocates the memory needed to virtualize the processor with the supported technology.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if any of the memory cannot be allocated, after releasing
+/// whatever was already allocated.
+pub fn allocate_basic_memory() -> Result<(), OutOfMemoryError> {
+    if supported_technology() == Some(Technology::Svm) {
+        return svm::allocate_basic_memory();
+    }
 
-    assert!(
-        (feature_control & FEATURE_CONTROL_MSR_LOCKED) != FEATURE_CONTROL_MSR_LOCKED
-            || ((feature_control & required_bits) == required_bits)
+    let result = allocate_vmx_memory();
+    if result.is_err() {
+        // SAFETY:
+        // Virtualization has not been enabled, so none of the memory is in use.
+        unsafe { release_vmx_memory() }
+    }
+
+    result
+}
+
+/// Allocates the VMXON region, the VMCS, the host stack and tables, the MSR bitmap, the guest's
+/// XSAVE area, and the EPT identity map.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if any of the memory other than the EPT identity map cannot be
+/// allocated.
+fn allocate_vmx_memory() -> Result<(), OutOfMemoryError> {
+    VMXON_REGION.store(
+        allocate_frames(1, MemoryKind::Persistent)?.as_ptr(),
+        Ordering::Relaxed,
+    );
+    VMCS_REGION.store(
+        allocate_frames(1, MemoryKind::Persistent)?.as_ptr(),
+        Ordering::Relaxed,
+    );
+    HOST_STACK.store(
+        allocate_frames(HOST_STACK_PAGES, MemoryKind::Persistent)?.as_ptr(),
+        Ordering::Relaxed,
     );
 
-    if (feature_control & required_bits) != required_bits {
-        unsafe { write_msr(FEATURE_CONTROL, feature_control | required_bits) }
-        log::trace!("Enabled feature control bits");
+    let mut msr_bitmap = MsrBitmap::allocate()?;
+    for msr in INTERCEPTED_MSRS.into_iter().flatten() {
+        msr_bitmap.intercept_read(msr).unwrap();
+        msr_bitmap.intercept_write(msr).unwrap();
     }
 
-    unsafe {
-        asm!(
-            "mov {0}, cr4",
-            "or {0}, 0x2000",
-            "mov cr4, {0}",
-            out(reg) _,
-            options(nomem, nostack)
-        );
+    MSR_BITMAP.store(msr_bitmap.into_frame(), Ordering::Relaxed);
+
+    xsave::allocate_guest_area()?;
+    tables::allocate()?;
+
+    match build_identity_map() {
+        Ok(Some(eptp)) => EPT_POINTER.store(eptp, Ordering::Relaxed),
+        Ok(None) => log::warn!("EPT is not supported; the guest will not be isolated"),
+        Err(error) => log::warn!("failed to build the EPT identity map: {error}"),
     }
-    log::trace!("Enabled CR4 VMX bit");
 
-    log::trace!("CR0 VMX Fixed 0: {}", unsafe {
-        Cr0Display(read_msr(VMX_CR0_FIXED0))
-    });
-    log::trace!("CR0 VMX Fixed 1: {}", unsafe {
-        Cr0Display(!read_msr(VMX_CR0_FIXED1))
+    Ok(())
+}
+
+/// Frees the memory allocated by [`allocate_vmx_memory`], skipping anything that was not
+/// allocated.
+///
+/// # Safety
+/// None of the memory may be in use by the processor.
+unsafe fn release_vmx_memory() {
+    let regions = [
+        (&VMXON_REGION, 1),
+        (&VMCS_REGION, 1),
+        (&HOST_STACK, HOST_STACK_PAGES),
+        (&MSR_BITMAP, 1),
+    ];
+    for (region, count) in regions {
+        let Some(frames) = ptr::NonNull::new(region.swap(ptr::null_mut(), Ordering::Relaxed))
+        else {
+            continue;
+        };
+
+        // SAFETY:
+        // The region was allocated with `count` frames, and the caller guarantees it is unused.
+        unsafe { deallocate_frames(frames, count) }
+    }
+
+    // SAFETY:
+    // The caller guarantees that none of the memory is in use.
+    unsafe { xsave::release_guest_area() }
+    // SAFETY:
+    // The caller guarantees that none of the memory is in use.
+    unsafe { tables::release() }
+}
+
+/// Builds an EPT hierarchy identity mapping the first [`IDENTITY_MAP_SIZE`] bytes of physical
+/// memory with the memory types assigned by the MTRRs, returning its EPT pointer.
+///
+/// Returns [`None`] if the processor does not support a usable form of EPT.
+///
+/// # Errors
+/// Returns an [`EptError`] if building the hierarchy fails.
+fn build_identity_map() -> Result<Option<u64>, EptError> {
+    let Some(capabilities) = ept_vpid_capabilities() else {
+        return Ok(None);
+    };
+    if !secondary_control_allowed(PROCBASED2_ENABLE_EPT) || !capabilities.usable() {
+        return Ok(None);
+    }
+
+    let mtrrs = MtrrMap::read();
+    let mut ept = EptHierarchy::new(capabilities)?;
+    ept.identity_map(0, IDENTITY_MAP_SIZE, &mtrrs)?;
+    #[cfg(feature = "lazy-ept")]
+    ept.reserve_tables(LAZY_EPT_SPARE_TABLES)?;
+
+    let eptp = ept.eptp();
+    *GUEST_MEMORY.lock() = Some(GuestMemory {
+        ept,
+        mtrrs,
+        top_of_memory: top_of_memory(),
     });
+
+    Ok(Some(eptp))
+}
+
+/// Returns the EPT and VPID capabilities of the processor, or [`None`] if it does not support the
+/// secondary processor-based controls that enable EPT and VPIDs.
+fn ept_vpid_capabilities() -> Option<EptVpidCapabilities> {
+    // SAFETY:
+    // The VMX capability MSRs exist on every processor supporting VMX.
+    let primary = unsafe { read_msr(VMX_PROCBASED_CTLS) };
+    if fold_controls(PROCBASED_ACTIVATE_SECONDARY_CONTROLS, primary)
+        & PROCBASED_ACTIVATE_SECONDARY_CONTROLS
+        == 0
+    {
+        return None;
+    }
+
+    // SAFETY:
+    // The secondary processor-based controls are supported.
+    Some(unsafe { EptVpidCapabilities::read() })
+}
+
+/// Returns `true` if the secondary processor-based `control` may be set.
+///
+/// The processor must support the secondary processor-based controls.
+fn secondary_control_allowed(control: u32) -> bool {
+    // SAFETY:
+    // The caller guarantees that the secondary processor-based controls are supported.
+    let capability = unsafe { read_msr(VMX_PROCBASED_CTLS2) };
+    fold_controls(control, capability) & control == control
+}
+
+/// Returns the end of the highest range of RAM in the firmware memory map, or zero if the memory
+/// map cannot be retrieved.
+fn top_of_memory() -> u64 {
+    let Ok(memory_map) = boot::memory_map(boot::MemoryType::LOADER_DATA) else {
+        return 0;
+    };
+
+    memory_map
+        .entries()
+        .filter(|descriptor| {
+            !matches!(
+                descriptor.ty,
+                boot::MemoryType::RESERVED
+                    | boot::MemoryType::MMIO
+                    | boot::MemoryType::MMIO_PORT_SPACE
+            )
+        })
+        .map(|descriptor| descriptor.phys_start + descriptor.page_count * boot::PAGE_SIZE as u64)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Enables VMX or SVM on the running processor and enters VMX operation if VMX is used.
+///
+/// # Errors
+/// - Returns [`InitializeProcessorError::VmxUnsupported`] if the processor supports neither VMX
+///   nor SVM.
+/// - Returns [`InitializeProcessorError::VmxDisabled`] if the firmware locked
+///   `IA32_FEATURE_CONTROL` with VMX outside SMX operation disabled.
+/// - Returns an [`InitializeProcessorError`] if the VMX regions are unsuitable, `vmxon` fails, or
+///   SVM cannot be enabled.
+pub fn enable_support() -> Result<(), InitializeProcessorError> {
+    if supported_technology() == Some(Technology::Svm) {
+        return svm::enable_support().map_err(InitializeProcessorError::Svm);
+    }
+
+    if !vmx_supported() {
+        return Err(InitializeProcessorError::VmxUnsupported);
+    }
+
+    // SAFETY:
+    // `IA32_FEATURE_CONTROL` exists on every processor supporting VMX.
+    let feature_control = unsafe { FeatureControl::read() };
+    log::trace!("VMX Feature Control: {feature_control}");
+
+    if feature_control.locked() && !feature_control.vmx_outside_smx() {
+        return Err(InitializeProcessorError::VmxDisabled);
+    }
+
+    if !feature_control.locked() {
+        let feature_control = feature_control.set_locked(true).set_vmx_outside_smx(true);
+        // SAFETY:
+        // `IA32_FEATURE_CONTROL` is unlocked, and enabling VMX outside SMX operation only allows
+        // `vmxon` to be executed.
+        unsafe { FeatureControl::write(feature_control) }
+        log::trace!("Enabled feature control bits");
+    }
+
+    let cr0_fixed = FixedBits::cr0();
+    log::trace!("CR0 VMX Fixed 0: {}", Cr0Display(cr0_fixed.fixed_one));
+    log::trace!("CR0 VMX Fixed 1: {}", Cr0Display(!cr0_fixed.allowed_one));
+    let cr0 = Cr0::get().apply_fixed(cr0_fixed.fixed_one, cr0_fixed.allowed_one);
+    // SAFETY:
+    // While UEFI runs, PE and PG are already set, so VMX at most requires NE, which only changes
+    // how x87 errors are reported.
+    unsafe { cr0.write() }
     log::trace!("CR0: {}", Cr0::get());
 
-    log::trace!("CR4 VMX Fixed 0: {}", unsafe {
-        Cr4Display(read_msr(VMX_CR4_FIXED0))
-    });
-    log::trace!("CR4 VMX Fixed 1: {}", unsafe {
-        Cr4Display(!read_msr(VMX_CR4_FIXED1))
-    });
+    let cr4_fixed = FixedBits::cr4();
+    log::trace!("CR4 VMX Fixed 0: {}", Cr4Display(cr4_fixed.fixed_one));
+    log::trace!("CR4 VMX Fixed 1: {}", Cr4Display(!cr4_fixed.allowed_one));
+    let cr4 = Cr4::get()
+        .set_vmxe(true)
+        .apply_fixed(cr4_fixed.fixed_one, cr4_fixed.allowed_one);
+    // SAFETY:
+    // VMX only requires VMXE to be set in CR4, which permits `vmxon` without changing how memory
+    // is accessed.
+    unsafe { cr4.write() }
     log::trace!("CR4: {}", Cr4::get());
 
-    let vmx_basic = unsafe { read_msr(VMX_REVISION) };
-    let vmx_revision = vmx_basic as u32;
-    log::trace!("VMX basic: {:016X}", vmx_basic);
+    // SAFETY:
+    // `IA32_VMX_BASIC` exists on every processor supporting VMX.
+    let vmx_basic = unsafe { VmxBasic::read() };
+    log::trace!("VMX basic: {vmx_basic:?}");
+    vmx_basic.validate()?;
 
     let vmxon_ptr = VMXON_REGION.load(Ordering::Relaxed);
     assert!(!vmxon_ptr.is_null());
     log::trace!("VMXON ptr: {vmxon_ptr:p}");
-    unsafe { core::ptr::write_bytes::<u8>(vmxon_ptr, 0, 4096) }
-    unsafe { vmxon_ptr.cast::<u32>().write(vmx_revision) }
+    // SAFETY:
+    // `vmxon_ptr` is non-null and points to the frame of `REGION_SIZE` bytes allocated for the
+    // VMXON region, which nothing else references.
+    unsafe { core::ptr::write_bytes::<u8>(vmxon_ptr, 0, REGION_SIZE) }
+    // SAFETY:
+    // The VMXON region is page-aligned and large enough to hold the revision identifier.
+    unsafe { vmxon_ptr.cast::<u32>().write(vmx_basic.revision) }
 
-    let success: u8;
+    // SAFETY:
+    // `vmxon_ptr` points to a zeroed, page-aligned region carrying the VMCS revision identifier,
+    // and CR4.VMXE has been set.
+    unsafe { vmxon(vmxon_ptr as u64) }.map_err(InitializeProcessorError::Vmxon)?;
+    IN_VMX_OPERATION.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Returns `true` if the processor has executed `vmxon` and not yet left VMX operation.
+pub fn in_vmx_operation() -> bool {
+    IN_VMX_OPERATION.load(Ordering::Relaxed)
+}
+
+/// Undoes [`enable_support`] and [`setup_virtual_machine_state`]: clears the VMCS, leaves VMX
+/// operation, and clears CR4.VMXE, returning the frames that are no longer in use. Under SVM,
+/// clears EFER.SVME and releases the host save area instead.
+///
+/// Failures of `vmclear` and `vmxoff` are logged, as the teardown must run to completion.
+pub fn teardown_processor() -> ProcessorFrames {
+    if supported_technology() == Some(Technology::Svm) {
+        return ProcessorFrames {
+            vmxon: ptr::null_mut(),
+            vmcs: ptr::null_mut(),
+            host_save_area: svm::teardown_processor(),
+        };
+    }
+
+    let vmxon = VMXON_REGION.swap(ptr::null_mut(), Ordering::Relaxed);
+    let vmcs = VMCS_REGION.swap(ptr::null_mut(), Ordering::Relaxed);
+
+    if IN_VMX_OPERATION.swap(false, Ordering::Relaxed) {
+        if !vmcs.is_null() {
+            // SAFETY:
+            // The processor is in VMX operation and `vmcs` is the region prepared by
+            // `setup_virtual_machine_state`, if it was reached.
+            if let Err(error) = unsafe { vmclear(vmcs as u64) } {
+                log::warn!("failed to clear the VMCS: {error}");
+            }
+        }
+
+        // SAFETY:
+        // The processor is in VMX operation and the VMCS has been cleared.
+        if let Err(error) = unsafe { vmxoff() } {
+            log::warn!("failed to leave VMX operation: {error}");
+        }
+    }
+
+    // SAFETY:
+    // The processor has left VMX operation, so CR4.VMXE may be cleared.
+    unsafe { Cr4::get().set_vmxe(false).write() }
+
+    ProcessorFrames {
+        vmxon,
+        vmcs,
+        host_save_area: ptr::null_mut(),
+    }
+}
+
+/// The frames released by [`teardown_processor`], which the caller is responsible for freeing.
+///
+/// A pointer is null if the frame was never allocated or is not used by the active [`Technology`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ProcessorFrames {
+    /// The frame that held the VMXON region.
+    pub vmxon: *mut u8,
+    /// The frame that held the VMCS.
+    pub vmcs: *mut u8,
+    /// The frame that held the SVM host save area.
+    pub host_save_area: *mut u8,
+}
+
+pub fn setup_virtual_machine_state() -> Result<(), InitializeProcessorError> {
+    if supported_technology() == Some(Technology::Svm) {
+        return svm::setup_virtual_machine_state().map_err(InitializeProcessorError::Svm);
+    }
+
+    let vmcs_ptr = VMCS_REGION.load(Ordering::Relaxed);
+
+    // SAFETY:
+    // `IA32_VMX_BASIC` exists on every processor supporting VMX.
+    let vmx_basic = unsafe { VmxBasic::read() };
+    vmx_basic.validate()?;
+
+    assert!(!vmcs_ptr.is_null());
+    // SAFETY:
+    // `vmcs_ptr` is non-null and points to the frame of `REGION_SIZE` bytes allocated for the
+    // VMCS, which is not yet current on any processor.
+    unsafe { core::ptr::write_bytes::<u8>(vmcs_ptr, 0, REGION_SIZE) }
+    // SAFETY:
+    // The VMCS region is page-aligned and large enough to hold the revision identifier.
+    unsafe { vmcs_ptr.cast::<u32>().write(vmx_basic.revision) }
+    log::trace!("VMCS ptr: {vmcs_ptr:p}");
+
+    // SAFETY:
+    // `vmcs_ptr` points to a zeroed, page-aligned region carrying the VMCS revision identifier,
+    // and the processor is in VMX operation.
+    unsafe { vmclear(vmcs_ptr as u64) }.map_err(InitializeProcessorError::Vmclear)?;
+    // SAFETY:
+    // `vmcs_ptr` points to a cleared, page-aligned region carrying the VMCS revision identifier,
+    // and the processor is in VMX operation.
+    unsafe { vmptrld(vmcs_ptr as u64) }.map_err(InitializeProcessorError::Vmptrld)?;
+
+    let mut secondary = DESIRED_SECONDARY_CONTROLS;
+    let eptp = EPT_POINTER.load(Ordering::Relaxed);
+    if eptp != 0 {
+        write_field(VmcsField::EptPointer, eptp)?;
+        secondary |= PROCBASED2_ENABLE_EPT;
+        if let Some(memory) = GUEST_MEMORY.lock().as_mut() {
+            memory.ept.activate();
+        }
+    }
+
+    // Emulated CR3 loads must flush the guest's translations, which requires `invvpid`.
+    let vpid_usable = ept_vpid_capabilities().is_some_and(|capabilities| {
+        capabilities.supports_invvpid(InvvpidType::SingleContext)
+            && secondary_control_allowed(PROCBASED2_ENABLE_VPID)
+    });
+    if vpid_usable {
+        let vpid = NEXT_VPID.fetch_add(1, Ordering::Relaxed);
+        write_field(VmcsField::VirtualProcessorId, u64::from(vpid))?;
+        secondary |= PROCBASED2_ENABLE_VPID;
+    }
+
+    setup_execution_controls(Some(secondary))?;
+    setup_guest_state()?;
+    setup_host_state()
+}
+
+/// Launches the guest described by the current VMCS, which resumes the firmware at the return of
+/// `ExitBootServices()` under VMX control. Under SVM, runs the guest described by the guest VMCB
+/// instead.
+///
+/// # Errors
+/// - Returns [`InitializeProcessorError::Vmlaunch`] if `vmlaunch` fails.
+/// - Returns [`InitializeProcessorError::Svm`] if the SVM guest stops.
+pub fn launch_virtual_machine() -> Result<Infallible, InitializeProcessorError> {
+    if supported_technology() == Some(Technology::Svm) {
+        return svm::launch_virtual_machine().map_err(InitializeProcessorError::Svm);
+    }
+
+    let registers = crate::arch::machine_state();
+    // SAFETY:
+    // The guest- and host-state areas of the current VMCS have been programmed.
+    let rflags = unsafe { vmx_launch(registers) };
+
+    let error = vmx_result_from_rflags(rflags)
+        .err()
+        .unwrap_or(VmxInstructionError::FailInvalid);
+
+    Err(InitializeProcessorError::Vmlaunch(error))
+}
+
+/// Writes the pin-based, primary processor-based, VM-exit, and VM-entry controls, adjusted to the
+/// capabilities of the processor.
+///
+/// If `secondary` is provided, the secondary processor-based controls are activated and written as
+/// well.
+fn setup_execution_controls(secondary: Option<u32>) -> Result<(), InitializeProcessorError> {
+    // SAFETY:
+    // `IA32_VMX_BASIC` exists on every processor supporting VMX.
+    let vmx_basic = unsafe { VmxBasic::read() };
+
+    let msr_bitmap = MSR_BITMAP.load(Ordering::Relaxed);
+    assert!(!msr_bitmap.is_null());
+    write_field(VmcsField::MsrBitmaps, msr_bitmap as u64)?;
+
+    let mut primary = PROCBASED_USE_MSR_BITMAPS;
+    if secondary.is_some() {
+        primary |= PROCBASED_ACTIVATE_SECONDARY_CONTROLS;
+    }
+    // The debug shell is summoned while the guest idles.
+    if crate::config::current().shell {
+        primary |= PROCBASED_HLT_EXITING;
+    }
+
+    let controls = [
+        (VmcsField::PinBasedControls, 0, ControlKind::PinBased),
+        (
+            VmcsField::PrimaryProcessorBasedControls,
+            primary,
+            ControlKind::ProcessorBased,
+        ),
+        (
+            VmcsField::VmExitControls,
+            EXIT_HOST_ADDRESS_SPACE_SIZE | EXIT_SAVE_IA32_EFER | EXIT_LOAD_IA32_EFER,
+            ControlKind::Exit,
+        ),
+        (
+            VmcsField::VmEntryControls,
+            ENTRY_IA32E_MODE_GUEST | ENTRY_LOAD_IA32_EFER,
+            ControlKind::Entry,
+        ),
+    ];
+    // CR3-load and CR3-store exiting are left clear unless the processor requires them, in which
+    // case an empty CR3-target list makes every load exit.
+    for (field, desired, kind) in controls {
+        let value = adjust_controls(desired, kind.capability_msr(vmx_basic));
+        write_field(field, u64::from(value))?;
+    }
+    write_field(VmcsField::Cr3TargetCount, 0)?;
+
+    if let Some(secondary) = secondary {
+        let value = adjust_controls(secondary, VMX_PROCBASED_CTLS2);
+        write_field(VmcsField::SecondaryProcessorBasedControls, u64::from(value))?;
+    }
+
+    Ok(())
+}
+
+/// Returns `desired` with the bits required by the capability MSR `msr` set and the bits it does
+/// not allow cleared.
+fn adjust_controls(desired: u32, msr: u32) -> u32 {
+    // SAFETY:
+    // The VMX capability MSRs exist on every processor supporting VMX.
+    fold_controls(desired, unsafe { read_msr(msr) })
+}
+
+/// Returns `desired` adjusted to the VMX control `capability`, whose low half holds the bits that
+/// must be set and whose high half holds the bits that may be set.
+fn fold_controls(desired: u32, capability: u64) -> u32 {
+    let required = capability as u32;
+    let allowed = (capability >> 32) as u32;
+
+    (desired | required) & allowed
+}
+
+/// The VMX control fields whose allowed settings are reported by capability MSRs.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum ControlKind {
+    /// The pin-based VM-execution controls.
+    PinBased,
+    /// The primary processor-based VM-execution controls.
+    ProcessorBased,
+    /// The primary VM-exit controls.
+    Exit,
+    /// The VM-entry controls.
+    Entry,
+}
+
+impl ControlKind {
+    /// Returns the capability MSR reporting the allowed settings of the controls, given the
+    /// contents of `IA32_VMX_BASIC`.
+    ///
+    /// The `TRUE` capability MSRs are used when `vmx_basic` reports their existence, since they
+    /// permit clearing default-1 controls that the original MSRs report as required.
+    fn capability_msr(self, vmx_basic: VmxBasicValue) -> u32 {
+        match (self, vmx_basic.true_controls) {
+            (Self::PinBased, false) => VMX_PINBASED_CTLS,
+            (Self::PinBased, true) => VMX_TRUE_PINBASED_CTLS,
+            (Self::ProcessorBased, false) => VMX_PROCBASED_CTLS,
+            (Self::ProcessorBased, true) => VMX_TRUE_PROCBASED_CTLS,
+            (Self::Exit, false) => VMX_EXIT_CTLS,
+            (Self::Exit, true) => VMX_TRUE_EXIT_CTLS,
+            (Self::Entry, false) => VMX_ENTRY_CTLS,
+            (Self::Entry, true) => VMX_TRUE_ENTRY_CTLS,
+        }
+    }
+}
+
+impl VmxBasicValue {
+    /// Checks that the regions allocated for the VMXON region and the VMCS satisfy the processor.
+    ///
+    /// # Errors
+    /// - Returns [`InitializeProcessorError::RegionTooSmall`] if the processor requires regions
+    ///   larger than those allocated.
+    /// - Returns [`InitializeProcessorError::UnsupportedMemoryType`] if the processor requires a
+    ///   memory type other than write-back.
+    pub fn validate(self) -> Result<(), InitializeProcessorError> {
+        if usize::from(self.region_size) > REGION_SIZE {
+            return Err(InitializeProcessorError::RegionTooSmall {
+                required: self.region_size,
+            });
+        }
+
+        if self.memory_type != VMX_BASIC_MEMORY_TYPE_WRITE_BACK {
+            return Err(InitializeProcessorError::UnsupportedMemoryType(
+                self.memory_type,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn setup_guest_state() -> Result<(), InitializeProcessorError> {
+    let machine_state = crate::arch::machine_state();
+    let idtr = Idtr::get();
+    let gdtr = Gdtr::get();
+
+    let segments = [
+        (
+            segment::read_es(),
+            [
+                VmcsField::GuestEsSelector,
+                VmcsField::GuestEsBase,
+                VmcsField::GuestEsLimit,
+                VmcsField::GuestEsAccessRights,
+            ],
+        ),
+        (
+            segment::read_cs(),
+            [
+                VmcsField::GuestCsSelector,
+                VmcsField::GuestCsBase,
+                VmcsField::GuestCsLimit,
+                VmcsField::GuestCsAccessRights,
+            ],
+        ),
+        (
+            segment::read_ss(),
+            [
+                VmcsField::GuestSsSelector,
+                VmcsField::GuestSsBase,
+                VmcsField::GuestSsLimit,
+                VmcsField::GuestSsAccessRights,
+            ],
+        ),
+        (
+            segment::read_ds(),
+            [
+                VmcsField::GuestDsSelector,
+                VmcsField::GuestDsBase,
+                VmcsField::GuestDsLimit,
+                VmcsField::GuestDsAccessRights,
+            ],
+        ),
+        (
+            segment::read_fs(),
+            [
+                VmcsField::GuestFsSelector,
+                VmcsField::GuestFsBase,
+                VmcsField::GuestFsLimit,
+                VmcsField::GuestFsAccessRights,
+            ],
+        ),
+        (
+            segment::read_gs(),
+            [
+                VmcsField::GuestGsSelector,
+                VmcsField::GuestGsBase,
+                VmcsField::GuestGsLimit,
+                VmcsField::GuestGsAccessRights,
+            ],
+        ),
+        (
+            segment::read_ldtr(),
+            [
+                VmcsField::GuestLdtrSelector,
+                VmcsField::GuestLdtrBase,
+                VmcsField::GuestLdtrLimit,
+                VmcsField::GuestLdtrAccessRights,
+            ],
+        ),
+        (
+            segment::read_tr(),
+            [
+                VmcsField::GuestTrSelector,
+                VmcsField::GuestTrBase,
+                VmcsField::GuestTrLimit,
+                VmcsField::GuestTrAccessRights,
+            ],
+        ),
+    ];
+
+    for (selector, [selector_field, base_field, limit_field, access_rights_field]) in segments {
+        let descriptor = if selector_field == VmcsField::GuestTrSelector && selector.is_null() {
+            // VM entry requires TR to be a usable, busy 64-bit TSS, but firmware commonly runs
+            // without one. The firmware never switches stacks through TR, so the guest can borrow
+            // the host's TSS until it loads its own.
+            SegmentDescriptor::busy_tss(tables::tss_address(), tables::TSS_LIMIT)
+        } else {
+            // SAFETY:
+            // `gdtr` was read from the processor, and UEFI identity maps all memory.
+            unsafe { SegmentDescriptor::from_gdt(&gdtr, selector) }
+                .map_err(InitializeProcessorError::InvalidSegment)?
+        };
+
+        write_field(selector_field, u64::from(selector.bits()))?;
+        write_field(base_field, descriptor.base())?;
+        write_field(limit_field, u64::from(descriptor.limit()))?;
+        write_field(access_rights_field, u64::from(descriptor.access_rights()))?;
+    }
+
+    // In 64-bit mode, the FS and GS bases come from their MSRs rather than their descriptors.
+    write_field(VmcsField::GuestFsBase, segment::read_fs_base())?;
+    write_field(VmcsField::GuestGsBase, segment::read_gs_base())?;
+    // SAFETY:
+    // `IA32_EFER` exists on every processor supporting 64-bit mode.
+    let efer = unsafe { Efer::read() };
+    log::trace!("Guest EFER: {efer}");
+    write_field(VmcsField::GuestIa32Efer, efer.bits())?;
+
+    write_field(VmcsField::GuestGdtrLimit, u64::from(gdtr.limit()))?;
+    write_field(VmcsField::GuestGdtrBase, gdtr.address())?;
+    write_field(VmcsField::GuestIdtrLimit, u64::from(idtr.limit()))?;
+    write_field(VmcsField::GuestIdtrBase, idtr.address())?;
+
+    // The guest resumes where `ExitBootServices()` returns to the firmware.
+    // Writes to the bits fixed by VMX operation exit, and reads of them return the read shadow.
+    let cr0 = Cr0::get().bits();
+    let cr4 = Cr4::get().bits();
+    write_field(VmcsField::GuestCr0, FixedBits::cr0().apply(cr0))?;
+    write_field(VmcsField::Cr0GuestHostMask, FixedBits::cr0().mask())?;
+    write_field(VmcsField::Cr0ReadShadow, cr0)?;
+    write_field(VmcsField::GuestCr3, Cr3::get().bits())?;
+    write_field(VmcsField::GuestCr4, FixedBits::cr4().apply(cr4))?;
+    write_field(
+        VmcsField::Cr4GuestHostMask,
+        FixedBits::cr4().mask() | CR4_VMX_ENABLE,
+    )?;
+    write_field(VmcsField::Cr4ReadShadow, cr4 & !CR4_VMX_ENABLE)?;
+    // The firmware keeps any breakpoints it had set.
+    let dr7 = Dr7::get();
+    log::trace!("Guest DR7: {dr7}");
+    write_field(VmcsField::GuestDr7, dr7.bits())?;
+    write_field(VmcsField::GuestRsp, machine_state.rsp)?;
+    write_field(
+        VmcsField::GuestRip,
+        exit_boot_services_return as *const () as u64,
+    )?;
+    let rflags = Rflags::from_bits(machine_state.rflags);
+    log::trace!("Guest RFLAGS: {rflags}");
+    write_field(VmcsField::GuestRflags, rflags.bits())?;
+
+    write_field(VmcsField::VmcsLinkPointer, u64::MAX)?;
+    write_field(VmcsField::GuestIa32Debugctl, 0)?;
+    write_field(VmcsField::GuestActivityState, 0)?;
+    write_field(VmcsField::GuestInterruptibilityState, 0)?;
+    write_field(VmcsField::GuestPendingDebugExceptions, 0)?;
+    // SAFETY:
+    // The SYSENTER MSRs exist on every processor supporting VMX.
+    write_field(VmcsField::GuestIa32SysenterCs, unsafe {
+        SysenterCs::read()
+    })?;
+    // SAFETY:
+    // The SYSENTER MSRs exist on every processor supporting VMX.
+    write_field(VmcsField::GuestIa32SysenterEsp, unsafe {
+        SysenterEsp::read()
+    })?;
+    // SAFETY:
+    // The SYSENTER MSRs exist on every processor supporting VMX.
+    write_field(VmcsField::GuestIa32SysenterEip, unsafe {
+        SysenterEip::read()
+    })?;
+
+    Ok(())
+}
+
+/// Programs the host-state area of the current VMCS with the state of the running processor and
+/// the hypervisor's own tables, so that VM exits resume at [`vmexit_entry`] on the dedicated host
+/// stack.
+fn setup_host_state() -> Result<(), InitializeProcessorError> {
+    let host_stack = HOST_STACK.load(Ordering::Relaxed);
+    assert!(!host_stack.is_null());
+    let host_stack_top = host_stack as u64 + (HOST_STACK_PAGES * FRAME_SIZE) as u64;
+
+    write_field(VmcsField::HostCr0, Cr0::get().bits())?;
+    write_field(VmcsField::HostCr3, Cr3::get().bits())?;
+    // The host saves the guest's extended state on every VM exit, which requires CR4.OSXSAVE
+    // even if the firmware never set it.
+    let host_cr4 = Cr4::get().set_osxsave(Cr4::get().osxsave() || xsave::supported());
+    write_field(VmcsField::HostCr4, host_cr4.bits())?;
+    write_field(VmcsField::HostRsp, host_stack_top)?;
+    write_field(VmcsField::HostRip, vmexit_entry as *const () as u64)?;
+
+    // The host's selectors reference its own GDT, which outlives the firmware's.
+    let selectors = [
+        (VmcsField::HostEsSelector, tables::DATA_SELECTOR),
+        (VmcsField::HostCsSelector, tables::CODE_SELECTOR),
+        (VmcsField::HostSsSelector, tables::DATA_SELECTOR),
+        (VmcsField::HostDsSelector, tables::DATA_SELECTOR),
+        (VmcsField::HostFsSelector, tables::DATA_SELECTOR),
+        (VmcsField::HostGsSelector, tables::DATA_SELECTOR),
+        (VmcsField::HostTrSelector, tables::TSS_SELECTOR),
+    ];
+    for (field, selector) in selectors {
+        write_field(field, u64::from(selector.bits()))?;
+    }
+
+    write_field(VmcsField::HostTrBase, tables::tss_address())?;
+    write_field(VmcsField::HostFsBase, segment::read_fs_base())?;
+    write_field(VmcsField::HostGsBase, segment::read_gs_base())?;
+    write_field(VmcsField::HostGdtrBase, tables::gdt_address())?;
+    write_field(VmcsField::HostIdtrBase, tables::idt_address())?;
+
+    // SAFETY:
+    // `IA32_EFER` exists on every processor supporting 64-bit mode.
+    write_field(VmcsField::HostIa32Efer, unsafe { Efer::read() }.bits())?;
+    // SAFETY:
+    // The SYSENTER MSRs exist on every processor supporting VMX.
+    write_field(VmcsField::HostIa32SysenterCs, unsafe { SysenterCs::read() })?;
+    // SAFETY:
+    // The SYSENTER MSRs exist on every processor supporting VMX.
+    write_field(VmcsField::HostIa32SysenterEsp, unsafe {
+        SysenterEsp::read()
+    })?;
+    // SAFETY:
+    // The SYSENTER MSRs exist on every processor supporting VMX.
+    write_field(VmcsField::HostIa32SysenterEip, unsafe {
+        SysenterEip::read()
+    })?;
+
+    Ok(())
+}
+
+/// Writes `value` to `field` of the current VMCS.
+///
+/// In debug builds, the field is read back and any mismatch is logged.
+///
+/// # Errors
+/// Returns [`InitializeProcessorError::Vmwrite`] if `vmwrite` fails.
+fn write_field(field: VmcsField, value: u64) -> Result<(), InitializeProcessorError> {
+    vm_write(field, value).map_err(|error| InitializeProcessorError::Vmwrite { field, error })?;
+
+    if cfg!(debug_assertions) {
+        let expected = value & field.width().mask();
+        match vm_read(field) {
+            Ok(value) if value == expected => {}
+            Ok(value) => {
+                log::warn!("VMCS field {field:?} holds {value:#x}, but {expected:#x} was written")
+            }
+            Err(error) => log::warn!("failed to read back VMCS field {field:?}: {error}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Enters VMX operation using the VMXON region at `physical_address`.
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmxon` fails.
+///
+/// # Safety
+/// The VMXON region must be a zeroed, page-aligned region beginning with the VMCS revision
+/// identifier, and CR4.VMXE must be set.
+unsafe fn vmxon(physical_address: u64) -> Result<(), VmxInstructionError> {
+    debug_check_region(physical_address);
+
+    // The instruction takes an 8-byte memory operand holding the physical address of the region.
+    let carry: u8;
+    let zero: u8;
+
+    // SAFETY:
+    // The invariants of the VMXON region are upheld by the caller.
     unsafe {
         asm!(
             "vmxon [{}]",
-            "seta {}",
-            in(reg) &vmxon_ptr,
-            lateout(reg_byte) success,
+            "setc {}",
+            "setz {}",
+            in(reg) &raw const physical_address,
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
         )
     }
-    assert_eq!(success, 1);
+
+    vmx_result(carry, zero)
 }
 
-pub fn setup_virtual_machine_state() {
-    let vmcs_ptr = VMCS_REGION.load(Ordering::Relaxed);
+/// Leaves VMX operation.
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmxoff` fails.
+///
+/// # Safety
+/// The processor must be in VMX operation.
+unsafe fn vmxoff() -> Result<(), VmxInstructionError> {
+    let carry: u8;
+    let zero: u8;
 
-    unsafe { core::ptr::write_bytes::<u8>(vmcs_ptr, 0, 4096) }
-    unsafe { vmcs_ptr.cast::<u32>().write(read_msr(VMX_REVISION) as u32) }
-    log::trace!("VMCS ptr: {vmcs_ptr:p}");
+    // SAFETY:
+    // The processor is in VMX operation, as guaranteed by the caller.
+    unsafe {
+        asm!(
+            "vmxoff",
+            "setc {}",
+            "setz {}",
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
+        )
+    }
+
+    vmx_result(carry, zero)
+}
+
+/// Initializes the VMCS at `physical_address`, clearing its launch state.
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmclear` fails.
+///
+/// # Safety
+/// The processor must be in VMX operation and the VMCS must be a page-aligned region beginning
+/// with the VMCS revision identifier.
+unsafe fn vmclear(physical_address: u64) -> Result<(), VmxInstructionError> {
+    debug_check_region(physical_address);
+
+    // The instruction takes an 8-byte memory operand holding the physical address of the region.
+    let carry: u8;
+    let zero: u8;
 
-    let valid_vmcs_ptr: u8;
-    let other_error: u8;
+    // SAFETY:
+    // The invariants of the VMCS region are upheld by the caller.
+    unsafe {
+        asm!(
+            "vmclear [{}]",
+            "setc {}",
+            "setz {}",
+            in(reg) &raw const physical_address,
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
+        )
+    }
+
+    vmx_result(carry, zero)
+}
+
+/// Makes the VMCS at `physical_address` current.
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmptrld` fails.
+///
+/// # Safety
+/// The processor must be in VMX operation and the VMCS must be a zeroed, page-aligned region
+/// beginning with the VMCS revision identifier.
+unsafe fn vmptrld(physical_address: u64) -> Result<(), VmxInstructionError> {
+    debug_check_region(physical_address);
+
+    // The instruction takes an 8-byte memory operand holding the physical address of the region.
+    let carry: u8;
+    let zero: u8;
+
+    // SAFETY:
+    // The invariants of the VMCS region are upheld by the caller.
     unsafe {
         asm!(
             "vmptrld [{}]",
-            "setnc {}",
-            "setnz {}",
-            in(reg) &vmcs_ptr,
-            lateout(reg_byte) valid_vmcs_ptr,
-            lateout(reg_byte) other_error,
+            "setc {}",
+            "setz {}",
+            in(reg) &raw const physical_address,
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
         )
     }
 
-    assert!(valid_vmcs_ptr == 1);
-    assert!(other_error == 1);
+    vmx_result(carry, zero)
+}
+
+/// Asserts in debug builds that `physical_address` is suitable for a VMXON region or VMCS: it must
+/// be 4 KiB aligned and within the physical-address width usable by VMX.
+///
+/// The regions are allocated from UEFI, which identity maps all memory, so their addresses are
+/// also their physical addresses.
+fn debug_check_region(physical_address: u64) {
+    debug_assert!(physical_address.is_multiple_of(4096));
+
+    // SAFETY:
+    // `IA32_VMX_BASIC` exists on every processor supporting VMX.
+    let width = if unsafe { VmxBasic::read() }.addresses_limited_to_32_bits {
+        32
+    } else {
+        paging::physical_address_width()
+    };
+    debug_assert!(physical_address >> width == 0);
+}
+
+/// The bits of a control register that are fixed by VMX operation, as reported by the
+/// `IA32_VMX_CRn_FIXED0` and `IA32_VMX_CRn_FIXED1` MSRs.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct FixedBits {
+    /// The bits that must be set.
+    pub fixed_one: u64,
+    /// The bits that may be set; all other bits must be clear.
+    pub allowed_one: u64,
+}
+
+impl FixedBits {
+    /// Returns the bits of CR0 fixed by VMX operation.
+    pub fn cr0() -> Self {
+        // SAFETY:
+        // The VMX capability MSRs exist on every processor supporting VMX.
+        let fixed_one = unsafe { VmxCr0Fixed0::read() };
+        // SAFETY:
+        // The VMX capability MSRs exist on every processor supporting VMX.
+        let allowed_one = unsafe { VmxCr0Fixed1::read() };
+
+        Self {
+            fixed_one,
+            allowed_one,
+        }
+    }
+
+    /// Returns the bits of CR4 fixed by VMX operation.
+    pub fn cr4() -> Self {
+        // SAFETY:
+        // The VMX capability MSRs exist on every processor supporting VMX.
+        let fixed_one = unsafe { VmxCr4Fixed0::read() };
+        // SAFETY:
+        // The VMX capability MSRs exist on every processor supporting VMX.
+        let allowed_one = unsafe { VmxCr4Fixed1::read() };
+
+        Self {
+            fixed_one,
+            allowed_one,
+        }
+    }
 
-    setup_guest_state();
+    /// Returns the mask of the bits that cannot be changed by the guest.
+    pub fn mask(self) -> u64 {
+        self.fixed_one | !self.allowed_one
+    }
+
+    /// Returns `value` with the fixed bits forced to their required values.
+    pub fn apply(self, value: u64) -> u64 {
+        (value | self.fixed_one) & self.allowed_one
+    }
 }
 
-fn setup_guest_state() {
-    let machine_state = unsafe { crate::arch::REGISTERS.assume_init_ref() };
-    let idtr = Idtr::get();
-    let gdtr = Gdtr::get();
+/// The types of invalidation performed by `invept`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InveptType {
+    /// Invalidate the translations derived from a single EPT hierarchy.
+    SingleContext = 1,
+    /// Invalidate the translations derived from all EPT hierarchies.
+    AllContext = 2,
+}
+
+/// The types of invalidation performed by `invvpid`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InvvpidType {
+    /// Invalidate the translations of a single linear address tagged with a VPID.
+    IndividualAddress = 0,
+    /// Invalidate the translations tagged with a VPID.
+    SingleContext = 1,
+    /// Invalidate the translations tagged with any non-zero VPID.
+    AllContext = 2,
+    /// Invalidate the non-global translations tagged with a VPID.
+    SingleContextRetainingGlobals = 3,
+}
+
+/// Invalidates the cached translations derived from the EPT hierarchy referenced by `eptp`, as
+/// selected by `kind`.
+///
+/// # Errors
+/// - Returns [`VmxInstructionError::Unsupported`] if the processor does not support `kind`.
+/// - Returns a [`VmxInstructionError`] if `invept` fails.
+pub fn invept(kind: InveptType, eptp: u64) -> Result<(), VmxInstructionError> {
+    if !ept_vpid_capabilities().is_some_and(|capabilities| capabilities.supports_invept(kind)) {
+        return Err(VmxInstructionError::Unsupported);
+    }
 
-    assert!(vm_write(0x00000800, machine_state.es as u64));
-    assert!(vm_write(0x00000802, machine_state.cs as u64));
-    assert!(vm_write(0x00000804, machine_state.ss as u64));
-    assert!(vm_write(0x00000806, machine_state.ds as u64));
-    assert!(vm_write(0x00000808, machine_state.fs as u64));
-    assert!(vm_write(0x0000080A, machine_state.gs as u64));
+    let descriptor = [eptp, 0u64];
+    let carry: u8;
+    let zero: u8;
 
-    // GDT configuration
-    assert!(vm_write(0x00004810, gdtr.limit() as u64));
-    assert!(vm_write(0x00006816, gdtr.address()));
+    // SAFETY:
+    // Invalidating cached translations only forces them to be reloaded from memory.
+    unsafe {
+        asm!(
+            "invept {}, [{}]",
+            "setc {}",
+            "setz {}",
+            in(reg) kind as u64,
+            in(reg) &descriptor,
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
+        )
+    }
 
-    // IDT configuration
-    assert!(vm_write(0x00004812, idtr.limit() as u64));
-    assert!(vm_write(0x00006818, idtr.address()));
+    vmx_result(carry, zero)
 }
 
-pub fn vm_write(encoding: u32, value: u64) -> bool {
-    let other_error: u8;
+/// Invalidates the cached translations tagged with `vpid`, as selected by `kind`.
+///
+/// `linear_address` is only used by [`InvvpidType::IndividualAddress`] invalidations.
+///
+/// # Errors
+/// - Returns [`VmxInstructionError::Unsupported`] if the processor does not support `kind`.
+/// - Returns a [`VmxInstructionError`] if `invvpid` fails.
+pub fn invvpid(
+    kind: InvvpidType,
+    vpid: u16,
+    linear_address: u64,
+) -> Result<(), VmxInstructionError> {
+    if !ept_vpid_capabilities().is_some_and(|capabilities| capabilities.supports_invvpid(kind)) {
+        return Err(VmxInstructionError::Unsupported);
+    }
+
+    let descriptor = [u64::from(vpid), linear_address];
+    let carry: u8;
+    let zero: u8;
 
+    // SAFETY:
+    // Invalidating cached translations only forces them to be reloaded from memory.
+    unsafe {
+        asm!(
+            "invvpid {}, [{}]",
+            "setc {}",
+            "setz {}",
+            in(reg) kind as u64,
+            in(reg) &descriptor,
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
+        )
+    }
+
+    vmx_result(carry, zero)
+}
+
+/// Writes `value` to `field` of the current VMCS.
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmwrite` fails.
+pub fn vm_write(field: VmcsField, value: u64) -> Result<(), VmxInstructionError> {
+    let carry: u8;
+    let zero: u8;
+
+    // SAFETY:
+    // Writing a field of the current VMCS does not affect the running processor state.
     unsafe {
         asm!(
             "vmwrite {}, {}",
-            "setnz {}",
-            in(reg) encoding as u64,
+            "setc {}",
+            "setz {}",
+            in(reg) u64::from(field.encoding()),
             in(reg) value,
-            lateout(reg_byte) other_error
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
         )
     }
 
-    other_error == 1
+    vmx_result(carry, zero)
+}
+
+/// Converts the RFLAGS left by a VMX instruction into a [`Result`], as [`vmx_result`] does for
+/// the extracted carry and zero flags.
+///
+/// `vmlaunch` and `vmresume` only return on failure, so their outcome is captured as RFLAGS.
+pub fn vmx_result_from_rflags(rflags: u64) -> Result<(), VmxInstructionError> {
+    let rflags = Rflags::from_bits(rflags);
+
+    vmx_result(u8::from(rflags.cf()), u8::from(rflags.zf()))
+}
+
+/// Reads `field` of the current VMCS.
+///
+/// Only the bits defined for the width of the field are returned; the remaining bits are zero.
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmread` fails.
+pub fn vm_read(field: VmcsField) -> Result<u64, VmxInstructionError> {
+    let (value, carry, zero) = vm_read_raw(field.encoding());
+    vmx_result(carry, zero)?;
+
+    Ok(value & field.width().mask())
+}
+
+/// Reads the field with `encoding` from the current VMCS, for fields without a [`VmcsField`].
+///
+/// # Errors
+/// Returns a [`VmxInstructionError`] if `vmread` fails, such as for an unsupported encoding.
+pub fn vm_read_encoding(encoding: u32) -> Result<u64, VmxInstructionError> {
+    let (value, carry, zero) = vm_read_raw(encoding);
+    vmx_result(carry, zero)?;
+
+    Ok(value)
+}
+
+/// Reads the field with `encoding` from the current VMCS, returning the value along with the carry
+/// and zero flags left by `vmread`.
+fn vm_read_raw(encoding: u32) -> (u64, u8, u8) {
+    let value: u64;
+    let carry: u8;
+    let zero: u8;
+
+    // SAFETY:
+    // Reading a field of the current VMCS has no side effects.
+    unsafe {
+        asm!(
+            "vmread {}, {}",
+            "setc {}",
+            "setz {}",
+            lateout(reg) value,
+            in(reg) u64::from(encoding),
+            lateout(reg_byte) carry,
+            lateout(reg_byte) zero,
+        )
+    }
+
+    (value, carry, zero)
+}
+
+/// Converts the carry and zero flags left by a VMX instruction into a [`Result`], reading the
+/// VM-instruction error field if the instruction failed with a current VMCS.
+///
+/// Every VMX instruction reports its outcome through these flags:
+///
+/// | CF | ZF | Outcome                                                          |
+/// |----|----|------------------------------------------------------------------|
+/// | 0  | 0  | VMsucceed                                                        |
+/// | 1  | 0  | VMfailInvalid: there is no current VMCS to hold an error number  |
+/// | 0  | 1  | VMfailValid: the VM-instruction error field holds the reason     |
+///
+/// The instructions never set both flags; CF takes precedence if they are.
+pub fn vmx_result(carry: u8, zero: u8) -> Result<(), VmxInstructionError> {
+    if carry != 0 {
+        return Err(VmxInstructionError::FailInvalid);
+    }
+
+    if zero != 0 {
+        return match vm_read_raw(VmcsField::VmInstructionError.encoding()) {
+            (code, 0, 0) => Err(VmxInstructionError::FailValid(VmInstructionError(
+                code as u32,
+            ))),
+            _ => Err(VmxInstructionError::FailInvalid),
+        };
+    }
+
+    Ok(())
+}
+
+/// The ways in which a VMX instruction can fail.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum VmxInstructionError {
+    /// The instruction failed without a current VMCS (VMfailInvalid).
+    FailInvalid,
+    /// The instruction failed with a current VMCS, which holds the reason (VMfailValid).
+    FailValid(VmInstructionError),
+    /// The processor does not support the requested form of the instruction.
+    Unsupported,
+}
+
+impl fmt::Display for VmxInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FailInvalid => write!(f, "VMfailInvalid"),
+            Self::FailValid(error) => write!(f, "VMfailValid: {error}"),
+            Self::Unsupported => write!(f, "not supported by the processor"),
+        }
+    }
+}
+
+/// A value of the VM-instruction error field.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct VmInstructionError(pub u32);
+
+impl VmInstructionError {
+    /// Returns the architectural description of the error, if the error number is known.
+    pub fn description(&self) -> Option<&'static str> {
+        let description = match self.0 {
+            1 => "VMCALL executed in VMX root operation",
+            2 => "VMCLEAR with invalid physical address",
+            3 => "VMCLEAR with VMXON pointer",
+            4 => "VMLAUNCH with non-clear VMCS",
+            5 => "VMRESUME with non-launched VMCS",
+            6 => "VMRESUME after VMXOFF",
+            7 => "VM entry with invalid control field(s)",
+            8 => "VM entry with invalid host-state field(s)",
+            9 => "VMPTRLD with invalid physical address",
+            10 => "VMPTRLD with VMXON pointer",
+            11 => "VMPTRLD with incorrect VMCS revision identifier",
+            12 => "VMREAD/VMWRITE from/to unsupported VMCS component",
+            13 => "VMWRITE to read-only VMCS component",
+            15 => "VMXON executed in VMX root operation",
+            16 => "VM entry with invalid executive-VMCS pointer",
+            17 => "VM entry with non-launched executive VMCS",
+            18 => "VM entry with executive-VMCS pointer not VMXON pointer",
+            19 => "VMCALL with non-clear VMCS",
+            20 => "VMCALL with invalid VM-exit control fields",
+            22 => "VMCALL with incorrect MSEG revision identifier",
+            23 => "VMXOFF under dual-monitor treatment of SMIs and SMM",
+            24 => "VMCALL with invalid SMM-monitor features",
+            25 => "VM entry with invalid VM-execution control fields in executive VMCS",
+            26 => "VM entry with events blocked by MOV SS",
+            28 => "Invalid operand to INVEPT/INVVPID",
+            _ => return None,
+        };
+
+        Some(description)
+    }
+}
+
+impl fmt::Display for VmInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.description() {
+            Some(description) => write!(f, "{description} (error {})", self.0),
+            None => write!(f, "unknown VM-instruction error {}", self.0),
+        }
+    }
+}
+
+/// Various errors that can occur while placing the processor under VMX control.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InitializeProcessorError {
+    /// The processor does not support VMX.
+    VmxUnsupported,
+    /// The firmware locked `IA32_FEATURE_CONTROL` with VMX outside SMX operation disabled.
+    VmxDisabled,
+    /// `vmxon` failed.
+    Vmxon(VmxInstructionError),
+    /// `vmclear` failed.
+    Vmclear(VmxInstructionError),
+    /// `vmptrld` failed.
+    Vmptrld(VmxInstructionError),
+    /// `vmlaunch` failed.
+    Vmlaunch(VmxInstructionError),
+    /// A guest segment selector does not refer to a usable GDT entry.
+    InvalidSegment(SegmentDescriptorError),
+    /// `vmwrite` failed.
+    Vmwrite {
+        /// The field being written.
+        field: VmcsField,
+        /// The reason the write failed.
+        error: VmxInstructionError,
+    },
+    /// The processor requires a VMXON region and VMCS larger than the allocated regions.
+    RegionTooSmall {
+        /// The number of bytes required by the processor.
+        required: u16,
+    },
+    /// The processor requires a memory type other than write-back for VMX structures.
+    UnsupportedMemoryType(u8),
+    /// Placing the processor under SVM control failed.
+    Svm(SvmError),
+}
+
+impl fmt::Display for InitializeProcessorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VmxUnsupported => write!(f, "VMX is not supported"),
+            Self::VmxDisabled => write!(f, "VMX has been disabled by the firmware"),
+            Self::Vmxon(error) => write!(f, "VMXON failed: {error}"),
+            Self::Vmclear(error) => write!(f, "VMCLEAR failed: {error}"),
+            Self::Vmptrld(error) => write!(f, "VMPTRLD failed: {error}"),
+            Self::Vmlaunch(error) => write!(f, "VMLAUNCH failed: {error}"),
+            Self::InvalidSegment(error) => write!(f, "invalid guest segment: {error}"),
+            Self::Vmwrite { field, error } => write!(f, "VMWRITE to {field:?} failed: {error}"),
+            Self::RegionTooSmall { required } => write!(
+                f,
+                "VMX regions of {required} bytes are required, but only {REGION_SIZE} are allocated"
+            ),
+            Self::UnsupportedMemoryType(memory_type) => {
+                write!(f, "unsupported VMX structure memory type {memory_type}")
+            }
+            Self::Svm(error) => write!(f, "SVM failed: {error}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `IA32_VMX_BASIC` value with bit 55 set, reporting the `TRUE` capability MSRs.
+    const VMX_BASIC_WITH_TRUE_CONTROLS: u64 = (1 << 55) | (6 << 50) | (0x1000 << 32) | 0x4;
+    /// An `IA32_VMX_BASIC` value with bit 55 clear.
+    const VMX_BASIC_WITHOUT_TRUE_CONTROLS: u64 = (6 << 50) | (0x1000 << 32) | 0x4;
+
+    /// Every kind of control field.
+    const KINDS: [ControlKind; 4] = [
+        ControlKind::PinBased,
+        ControlKind::ProcessorBased,
+        ControlKind::Exit,
+        ControlKind::Entry,
+    ];
+
+    /// Returns the mocked capability MSR `msr`, in which the `TRUE` MSRs allow the default-1 bit 1
+    /// to be cleared while the original MSRs require it.
+    fn mocked_capability(msr: u32) -> u64 {
+        match msr {
+            VMX_PINBASED_CTLS | VMX_PROCBASED_CTLS | VMX_EXIT_CTLS | VMX_ENTRY_CTLS => {
+                0xFFFF_FFFF_0000_0002
+            }
+            VMX_TRUE_PINBASED_CTLS
+            | VMX_TRUE_PROCBASED_CTLS
+            | VMX_TRUE_EXIT_CTLS
+            | VMX_TRUE_ENTRY_CTLS => 0xFFFF_FFFF_0000_0000,
+            msr => panic!("unexpected capability MSR {msr:#x}"),
+        }
+    }
+
+    #[test]
+    fn true_msrs_are_used_when_bit_55_is_set() {
+        let vmx_basic = VmxBasicValue::from(VMX_BASIC_WITH_TRUE_CONTROLS);
+
+        let msrs = KINDS.map(|kind| kind.capability_msr(vmx_basic));
+
+        assert_eq!(
+            msrs,
+            [
+                VMX_TRUE_PINBASED_CTLS,
+                VMX_TRUE_PROCBASED_CTLS,
+                VMX_TRUE_EXIT_CTLS,
+                VMX_TRUE_ENTRY_CTLS
+            ]
+        );
+    }
+
+    #[test]
+    fn original_msrs_are_used_when_bit_55_is_clear() {
+        let vmx_basic = VmxBasicValue::from(VMX_BASIC_WITHOUT_TRUE_CONTROLS);
+
+        let msrs = KINDS.map(|kind| kind.capability_msr(vmx_basic));
+
+        assert_eq!(
+            msrs,
+            [
+                VMX_PINBASED_CTLS,
+                VMX_PROCBASED_CTLS,
+                VMX_EXIT_CTLS,
+                VMX_ENTRY_CTLS
+            ]
+        );
+    }
+
+    #[test]
+    fn default_one_bits_depend_on_the_selected_msr() {
+        for (vmx_basic, expected) in [
+            (VMX_BASIC_WITH_TRUE_CONTROLS, 0x0),
+            (VMX_BASIC_WITHOUT_TRUE_CONTROLS, 0x2),
+        ] {
+            let vmx_basic = VmxBasicValue::from(vmx_basic);
+
+            for kind in KINDS {
+                let capability = mocked_capability(kind.capability_msr(vmx_basic));
+                assert_eq!(fold_controls(0, capability), expected, "{kind:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn allowed_zero_bits_are_forced_on() {
+        let capability = 0xFFFF_FFFF_0000_0016;
+
+        assert_eq!(fold_controls(0, capability), 0x16);
+        assert_eq!(fold_controls(0x100, capability), 0x116);
+    }
+
+    #[test]
+    fn allowed_one_bits_mask_desired_controls() {
+        let capability = 0x0000_00FF_0000_0000;
+
+        assert_eq!(fold_controls(0xFFFF, capability), 0xFF);
+        assert_eq!(fold_controls(0x8000_0100, capability), 0);
+    }
+
+    #[test]
+    fn required_and_allowed_bits_combine() {
+        let capability = 0x0000_F0F3_0000_0003;
+
+        assert_eq!(fold_controls(0x8080, capability), 0x8083);
+        assert_eq!(fold_controls(0x0F00, capability), 0x0003);
+    }
+
+    #[test]
+    fn validate_accepts_write_back_regions_that_fit() {
+        let vmx_basic = VmxBasicValue::from(VMX_BASIC_WITHOUT_TRUE_CONTROLS);
+        assert_eq!(vmx_basic.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_regions_larger_than_a_frame() {
+        let vmx_basic = VmxBasicValue::from(VMX_BASIC_WITH_TRUE_CONTROLS | (0x1001 << 32));
+        assert_eq!(
+            vmx_basic.validate(),
+            Err(InitializeProcessorError::RegionTooSmall { required: 0x1001 })
+        );
+    }
+
+    #[test]
+    fn validate_rejects_other_memory_types() {
+        let vmx_basic = VmxBasicValue::from((0x1000 << 32) | 0x4);
+        assert_eq!(
+            vmx_basic.validate(),
+            Err(InitializeProcessorError::UnsupportedMemoryType(0))
+        );
+    }
 }
@@ -0,0 +1,1070 @@
+//! Handling of VM exits.
+
+use core::{
+    arch::{asm, x86_64::CpuidResult},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "lazy-ept")]
+use crate::arch::x86_64::{ept::EptPermissions, mtrr::MemoryType};
+use crate::arch::x86_64::{
+    ept::GUEST_MEMORY,
+    msr_bitmap,
+    registers::{
+        msr::{
+            read_msr, write_msr, FeatureControl, Msr, FEATURE_CONTROL, VMX_REVISION, VMX_VMFUNC,
+        },
+        xcr0::Xcr0,
+    },
+    virtualization::{
+        invvpid, vm_read, vm_write, vmx_result_from_rflags, FixedBits, InvvpidType, CR4_VMX_ENABLE,
+    },
+    vmcs_fields::VmcsField,
+    xsave,
+};
+
+/// The bit in the exit reason indicating that VM entry failed.
+const EXIT_REASON_ENTRY_FAILURE: u64 = 1 << 31;
+
+/// The CPUID leaf reporting processor features, including the hypervisor-present bit.
+const CPUID_FEATURES: u32 = 0x1;
+/// The bit in ECX of [`CPUID_FEATURES`] reporting support for VMX.
+const CPUID_FEATURES_ECX_VMX: u32 = 1 << 5;
+/// The bit in ECX of [`CPUID_FEATURES`] reporting that a hypervisor is present.
+const CPUID_FEATURES_ECX_HYPERVISOR: u32 = 1 << 31;
+/// The CPUID leaf reporting extended processor features.
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+/// The bit in ECX of [`CPUID_EXTENDED_FEATURES`] reporting support for SVM.
+const CPUID_EXTENDED_FEATURES_ECX_SVM: u32 = 1 << 2;
+/// The first CPUID leaf of the range reserved for hypervisors.
+const CPUID_HYPERVISOR_BASE: u32 = 0x4000_0000;
+/// The last CPUID leaf of the range reserved for hypervisors.
+const CPUID_HYPERVISOR_LAST: u32 = 0x4000_00FF;
+/// The vendor signature reported in EBX, ECX, and EDX of [`CPUID_HYPERVISOR_BASE`].
+///
+/// Those registers only hold 12 bytes, so the name is abbreviated to fill them exactly, like
+/// `"Microsoft Hv"` and `"VMwareVMware"`, rather than being truncated and padded.
+const HYPERVISOR_SIGNATURE: [u8; 12] = *b"BootManipltr";
+
+/// The bit in the VM-entry interruption-information field marking it as valid.
+const INTERRUPTION_VALID: u64 = 1 << 31;
+/// The bit in the VM-entry interruption-information field requesting an error code be pushed.
+const INTERRUPTION_DELIVER_ERROR_CODE: u64 = 1 << 11;
+/// The interruption type of a hardware exception.
+const INTERRUPTION_TYPE_HARDWARE_EXCEPTION: u64 = 3 << 8;
+/// The vector of the general-protection exception.
+const VECTOR_GENERAL_PROTECTION: u8 = 13;
+
+/// The bit in an EPT violation qualification indicating a data read.
+const EPT_VIOLATION_READ: u64 = 1 << 0;
+/// The bit in an EPT violation qualification indicating a data write.
+const EPT_VIOLATION_WRITE: u64 = 1 << 1;
+/// The bit in an EPT violation qualification indicating an instruction fetch.
+const EPT_VIOLATION_EXECUTE: u64 = 1 << 2;
+/// The bits in an EPT violation qualification reporting the permissions of the guest-physical
+/// address, which are all clear if it is unmapped.
+const EPT_VIOLATION_PERMISSIONS: u64 = 0b111 << 3;
+/// The bit in an EPT violation qualification indicating that the guest linear-address field is
+/// valid.
+const EPT_VIOLATION_LINEAR_ADDRESS_VALID: u64 = 1 << 7;
+/// The size of the regions mapped on demand when the `lazy-ept` feature is enabled.
+#[cfg(feature = "lazy-ept")]
+const LAZY_EPT_REGION_SIZE: u64 = 2 * 1024 * 1024;
+
+/// The number of intercepted CPUID leaves logged at trace level.
+const CPUID_TRACE_LIMIT: usize = 16;
+
+/// Whether CPUID reveals the hypervisor to the guest.
+///
+/// When set, the hypervisor-present bit is reported and [`CPUID_HYPERVISOR_BASE`] returns the
+/// boot-manipulator signature. When clear, the hypervisor-present bit is masked off and the
+/// hypervisor leaves read as zero, hiding any hypervisor from the guest.
+pub static EXPOSE_HYPERVISOR: AtomicBool = AtomicBool::new(false);
+
+/// The number of CPUID leaves intercepted so far.
+static CPUID_EXITS: AtomicUsize = AtomicUsize::new(0);
+
+core::arch::global_asm!(
+    ".global vmexit_entry",
+    "vmexit_entry:",
+    // Save the guest's general purpose registers in the layout of `GuestRegisters`. The guest's
+    // RSP is held in the VMCS, and its extended state is saved by `dispatch_vm_exit`.
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rdi",
+    "push rsi",
+    "push rbp",
+    "push rbx",
+    "push rdx",
+    "push rcx",
+    "push rax",
+    "mov rdi, rsp",
+    // The host stack starts 16-byte aligned, so 15 pushes leave it 8 bytes off.
+    "sub rsp, 8",
+    "call {dispatch_vm_exit}",
+    "add rsp, 8",
+    "pop rax",
+    "pop rcx",
+    "pop rdx",
+    "pop rbx",
+    "pop rbp",
+    "pop rsi",
+    "pop rdi",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "vmresume",
+    // `vmresume` only falls through on failure.
+    "pushfq",
+    "pop rdi",
+    "and rsp, -16",
+    "call {vmresume_failed}",
+    "ud2",
+    dispatch_vm_exit = sym dispatch_vm_exit,
+    vmresume_failed = sym vmresume_failed,
+);
+
+extern "sysv64" {
+    /// The instruction at which the host resumes after a VM exit.
+    pub fn vmexit_entry();
+}
+
+/// The general purpose registers of the guest at the time of a VM exit, other than RSP.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct GuestRegisters {
+    /// The value of `rax`.
+    pub rax: u64,
+    /// The value of `rcx`.
+    pub rcx: u64,
+    /// The value of `rdx`.
+    pub rdx: u64,
+    /// The value of `rbx`.
+    pub rbx: u64,
+    /// The value of `rbp`.
+    pub rbp: u64,
+    /// The value of `rsi`.
+    pub rsi: u64,
+    /// The value of `rdi`.
+    pub rdi: u64,
+    /// The value of `r8`.
+    pub r8: u64,
+    /// The value of `r9`.
+    pub r9: u64,
+    /// The value of `r10`.
+    pub r10: u64,
+    /// The value of `r11`.
+    pub r11: u64,
+    /// The value of `r12`.
+    pub r12: u64,
+    /// The value of `r13`.
+    pub r13: u64,
+    /// The value of `r14`.
+    pub r14: u64,
+    /// The value of `r15`.
+    pub r15: u64,
+}
+
+impl GuestRegisters {
+    /// Returns the value of the register with the architectural `index`, as used in exit
+    /// qualifications.
+    fn get(&self, index: u64) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => vm_read(VmcsField::GuestRsp).unwrap_or_else(|error| fatal(error)),
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            _ => self.r15,
+        }
+    }
+
+    /// Sets the register with the architectural `index`, as used in exit qualifications, to
+    /// `value`.
+    fn set(&mut self, index: u64, value: u64) {
+        let register = match index {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => {
+                vm_write(VmcsField::GuestRsp, value).unwrap_or_else(|error| fatal(error));
+                return;
+            }
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        };
+
+        *register = value;
+    }
+}
+
+/// The basic reasons for a VM exit.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ExitReason {
+    /// An exception or non-maskable interrupt occurred.
+    ExceptionOrNmi,
+    /// An external interrupt arrived.
+    ExternalInterrupt,
+    /// The guest triple faulted.
+    TripleFault,
+    /// The guest executed `cpuid`.
+    Cpuid,
+    /// The guest executed `hlt`.
+    Hlt,
+    /// The guest executed `vmcall`.
+    Vmcall,
+    /// The guest accessed a control register.
+    CrAccess,
+    /// The guest executed an I/O instruction.
+    IoInstruction,
+    /// The guest executed `rdmsr`.
+    Rdmsr,
+    /// The guest executed `wrmsr`.
+    Wrmsr,
+    /// VM entry failed because of invalid guest state.
+    InvalidGuestState,
+    /// VM entry failed while loading an MSR from the VM-entry MSR-load area.
+    MsrLoading,
+    /// VM entry failed because of a machine-check event.
+    MachineCheckDuringEntry,
+    /// The guest accessed memory in a way its EPT entries do not permit.
+    EptViolation,
+    /// The guest accessed memory through a misconfigured EPT entry.
+    EptMisconfiguration,
+    /// The guest executed `xsetbv`.
+    Xsetbv,
+    /// A reason without dedicated handling.
+    Unknown(u16),
+}
+
+impl ExitReason {
+    /// Returns the [`ExitReason`] corresponding to the basic exit reason `value`.
+    pub fn from_basic(value: u16) -> Self {
+        match value {
+            0 => Self::ExceptionOrNmi,
+            1 => Self::ExternalInterrupt,
+            2 => Self::TripleFault,
+            10 => Self::Cpuid,
+            12 => Self::Hlt,
+            18 => Self::Vmcall,
+            28 => Self::CrAccess,
+            30 => Self::IoInstruction,
+            31 => Self::Rdmsr,
+            32 => Self::Wrmsr,
+            33 => Self::InvalidGuestState,
+            34 => Self::MsrLoading,
+            41 => Self::MachineCheckDuringEntry,
+            48 => Self::EptViolation,
+            49 => Self::EptMisconfiguration,
+            55 => Self::Xsetbv,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ExceptionOrNmi => write!(f, "exception or NMI"),
+            Self::ExternalInterrupt => write!(f, "external interrupt"),
+            Self::TripleFault => write!(f, "triple fault"),
+            Self::Cpuid => write!(f, "CPUID"),
+            Self::Hlt => write!(f, "HLT"),
+            Self::Vmcall => write!(f, "VMCALL"),
+            Self::CrAccess => write!(f, "control register access"),
+            Self::IoInstruction => write!(f, "I/O instruction"),
+            Self::Rdmsr => write!(f, "RDMSR"),
+            Self::Wrmsr => write!(f, "WRMSR"),
+            Self::InvalidGuestState => write!(f, "VM entry failure due to invalid guest state"),
+            Self::MsrLoading => write!(f, "VM entry failure due to MSR loading"),
+            Self::MachineCheckDuringEntry => {
+                write!(f, "VM entry failure due to a machine-check event")
+            }
+            Self::EptViolation => write!(f, "EPT violation"),
+            Self::EptMisconfiguration => write!(f, "EPT misconfiguration"),
+            Self::Xsetbv => write!(f, "XSETBV"),
+            Self::Unknown(value) => write!(f, "unknown exit reason {value}"),
+        }
+    }
+}
+
+/// The cause of a failed VM entry, decoded from the exit reason and the exit qualification.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum EntryFailure {
+    /// The guest state failed the checks performed on VM entry.
+    InvalidGuestState(GuestStateFailure),
+    /// Loading the entry with the given index, starting at 1, of the VM-entry MSR-load area
+    /// failed.
+    MsrLoading(u64),
+    /// A machine-check event occurred during VM entry.
+    MachineCheck,
+    /// VM entry failed with a basic exit reason that does not describe an entry failure.
+    Other(ExitReason),
+}
+
+impl EntryFailure {
+    /// Decodes the raw `exit_reason` and `qualification` of a VM exit, returning [`None`] if the
+    /// exit was not caused by a failed VM entry.
+    pub fn decode(exit_reason: u64, qualification: u64) -> Option<Self> {
+        if exit_reason & EXIT_REASON_ENTRY_FAILURE != EXIT_REASON_ENTRY_FAILURE {
+            return None;
+        }
+
+        let failure = match ExitReason::from_basic(exit_reason as u16) {
+            ExitReason::InvalidGuestState => {
+                Self::InvalidGuestState(GuestStateFailure::from_qualification(qualification))
+            }
+            ExitReason::MsrLoading => Self::MsrLoading(qualification),
+            ExitReason::MachineCheckDuringEntry => Self::MachineCheck,
+            reason => Self::Other(reason),
+        };
+
+        Some(failure)
+    }
+}
+
+impl fmt::Display for EntryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidGuestState(failure) => write!(f, "invalid guest state: {failure}"),
+            Self::MsrLoading(index) => {
+                write!(
+                    f,
+                    "failed to load entry {index} of the VM-entry MSR-load area"
+                )
+            }
+            Self::MachineCheck => write!(f, "machine-check event during VM entry"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// The part of the guest state that caused VM entry to fail, as reported by the exit
+/// qualification.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum GuestStateFailure {
+    /// The processor did not narrow down the failing check.
+    Unspecified,
+    /// Loading the PDPTEs referenced by the guest's CR3 failed.
+    PdpteLoading,
+    /// The guest attempted to inject an NMI while blocked by NMI.
+    NmiInjection,
+    /// The VMCS link pointer is invalid.
+    VmcsLinkPointer,
+    /// A qualification without a defined meaning.
+    Unknown(u64),
+}
+
+impl GuestStateFailure {
+    /// Returns the [`GuestStateFailure`] described by the exit `qualification`.
+    pub fn from_qualification(qualification: u64) -> Self {
+        match qualification {
+            0 => Self::Unspecified,
+            2 => Self::PdpteLoading,
+            3 => Self::NmiInjection,
+            4 => Self::VmcsLinkPointer,
+            qualification => Self::Unknown(qualification),
+        }
+    }
+}
+
+impl fmt::Display for GuestStateFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unspecified => write!(f, "no specific check reported"),
+            Self::PdpteLoading => write!(f, "failed to load the PDPTEs referenced by CR3"),
+            Self::NmiInjection => write!(f, "NMI injection while blocked by NMI"),
+            Self::VmcsLinkPointer => write!(f, "invalid VMCS link pointer"),
+            Self::Unknown(qualification) => write!(f, "unknown qualification {qualification:#x}"),
+        }
+    }
+}
+
+/// Reports the failed VM entry described by `failure`, along with the VMCS fields relevant to it,
+/// and halts.
+fn report_entry_failure(failure: EntryFailure) -> ! {
+    log::error!("VM entry failed: {failure}");
+
+    let fields: &[(&str, VmcsField)] = match failure {
+        EntryFailure::InvalidGuestState(GuestStateFailure::PdpteLoading) => &[
+            ("guest CR0", VmcsField::GuestCr0),
+            ("guest CR3", VmcsField::GuestCr3),
+            ("guest CR4", VmcsField::GuestCr4),
+        ],
+        EntryFailure::InvalidGuestState(GuestStateFailure::NmiInjection) => &[
+            (
+                "guest interruptibility state",
+                VmcsField::GuestInterruptibilityState,
+            ),
+            (
+                "VM-entry interruption information",
+                VmcsField::VmEntryInterruptionInformation,
+            ),
+        ],
+        EntryFailure::InvalidGuestState(GuestStateFailure::VmcsLinkPointer) => {
+            &[("VMCS link pointer", VmcsField::VmcsLinkPointer)]
+        }
+        EntryFailure::InvalidGuestState(_) => &[
+            ("guest CR0", VmcsField::GuestCr0),
+            ("guest CR4", VmcsField::GuestCr4),
+            ("guest EFER", VmcsField::GuestIa32Efer),
+            ("guest RIP", VmcsField::GuestRip),
+            ("guest RFLAGS", VmcsField::GuestRflags),
+            ("guest CS access rights", VmcsField::GuestCsAccessRights),
+            ("guest SS access rights", VmcsField::GuestSsAccessRights),
+            ("guest TR access rights", VmcsField::GuestTrAccessRights),
+            ("guest activity state", VmcsField::GuestActivityState),
+            (
+                "guest interruptibility state",
+                VmcsField::GuestInterruptibilityState,
+            ),
+        ],
+        EntryFailure::MsrLoading(_) => &[
+            ("VM-entry MSR-load area", VmcsField::VmEntryMsrLoadAddress),
+            ("VM-entry MSR-load count", VmcsField::VmEntryMsrLoadCount),
+        ],
+        EntryFailure::MachineCheck | EntryFailure::Other(_) => &[],
+    };
+    for &(name, field) in fields {
+        match vm_read(field) {
+            Ok(value) => log::error!("  {name}: {value:#x}"),
+            Err(error) => log::error!("  {name}: {error}"),
+        }
+    }
+
+    halt()
+}
+
+/// Decodes the current VM exit and dispatches it to [`handle_vmexit`].
+extern "sysv64" fn dispatch_vm_exit(registers: &mut GuestRegisters) {
+    let exit_reason = vm_read(VmcsField::VmExitReason).unwrap_or_else(|error| fatal(error));
+    let reason = ExitReason::from_basic(exit_reason as u16);
+
+    if exit_reason & EXIT_REASON_ENTRY_FAILURE == EXIT_REASON_ENTRY_FAILURE {
+        let qualification = vm_read(VmcsField::ExitQualification).unwrap_or_default();
+        if let Some(failure) = EntryFailure::decode(exit_reason, qualification) {
+            report_entry_failure(failure);
+        }
+    }
+
+    xsave::save_guest_state();
+    handle_vmexit(registers, reason);
+    xsave::restore_guest_state();
+}
+
+/// Handles a VM exit caused by `reason`, updating the guest's `registers` as needed.
+///
+/// The guest is moved past the instruction that caused the exit unless emulating it raised a
+/// fault in the guest.
+pub fn handle_vmexit(registers: &mut GuestRegisters, reason: ExitReason) {
+    match reason {
+        ExitReason::Cpuid => handle_cpuid(registers),
+        ExitReason::Hlt => crate::shell::idle(),
+        ExitReason::Rdmsr => return handle_rdmsr(registers),
+        ExitReason::Wrmsr => return handle_wrmsr(registers),
+        ExitReason::Xsetbv => return handle_xsetbv(registers),
+        ExitReason::CrAccess => handle_cr_access(registers),
+        ExitReason::EptViolation => return handle_ept_violation(),
+        reason => {
+            let qualification = vm_read(VmcsField::ExitQualification).unwrap_or_default();
+            log::error!("unhandled VM exit: {reason} (qualification {qualification:#x})");
+            halt();
+        }
+    }
+
+    advance_rip();
+}
+
+/// Handles an access by the guest to a guest-physical address that its EPT hierarchy does not
+/// permit.
+///
+/// With the `lazy-ept` feature, unmapped addresses are identity mapped in 2 MiB regions and the
+/// access is retried. Otherwise, or if the address is mapped without the required permissions, the
+/// access is reported in detail and the hypervisor panics.
+fn handle_ept_violation() {
+    let qualification = vm_read(VmcsField::ExitQualification).unwrap_or_else(|error| fatal(error));
+    let address = vm_read(VmcsField::GuestPhysicalAddress).unwrap_or_else(|error| fatal(error));
+    let mapped = qualification & EPT_VIOLATION_PERMISSIONS != 0;
+
+    let access = match qualification & (EPT_VIOLATION_WRITE | EPT_VIOLATION_EXECUTE) {
+        EPT_VIOLATION_WRITE => "write",
+        EPT_VIOLATION_EXECUTE => "instruction fetch",
+        _ if qualification & EPT_VIOLATION_READ == EPT_VIOLATION_READ => "read",
+        _ => "access",
+    };
+
+    let region = match GUEST_MEMORY.lock().as_ref() {
+        Some(memory) if address >= memory.top_of_memory => "MMIO above top of memory",
+        Some(_) => "RAM",
+        None => "memory",
+    };
+    let state = if mapped { "mapped" } else { "unmapped" };
+    log::debug!("EPT violation: {access} of {state} {region} at {address:#x}");
+
+    #[cfg(feature = "lazy-ept")]
+    if !mapped && map_on_demand(address) {
+        return;
+    }
+
+    let linear_address = match qualification & EPT_VIOLATION_LINEAR_ADDRESS_VALID {
+        0 => None,
+        _ => vm_read(VmcsField::GuestLinearAddress).ok(),
+    };
+    let rip = vm_read(VmcsField::GuestRip).unwrap_or_default();
+    panic!(
+        "EPT violation: {access} of {state} {region} at guest-physical address {address:#x} \
+         (linear address {linear_address:#x?}, RIP {rip:#x}, qualification {qualification:#x})"
+    );
+}
+
+/// Identity maps the 2 MiB region containing `address`, or only its 4 KiB page if the memory type
+/// varies within the region, returning `true` if the mapping succeeded.
+#[cfg(feature = "lazy-ept")]
+fn map_on_demand(address: u64) -> bool {
+    let mut guest_memory = GUEST_MEMORY.lock();
+    let Some(memory) = guest_memory.as_mut() else {
+        return false;
+    };
+
+    let region = address & !(LAZY_EPT_REGION_SIZE - 1);
+    let (base, size) = if memory
+        .mtrrs
+        .memory_type(region, LAZY_EPT_REGION_SIZE)
+        .is_some()
+    {
+        (region, LAZY_EPT_REGION_SIZE)
+    } else {
+        (address & !0xFFF, 0x1000)
+    };
+    let memory_type = memory
+        .mtrrs
+        .memory_type(base, size)
+        .unwrap_or(MemoryType::Uncacheable);
+
+    match memory
+        .ept
+        .map(base, base, size, EptPermissions::ALL, memory_type)
+    {
+        Ok(()) => {
+            log::debug!("mapped {size:#x} bytes at {base:#x} on demand");
+            true
+        }
+        Err(error) => {
+            log::error!("failed to map {base:#x} on demand: {error}");
+            false
+        }
+    }
+}
+
+/// Emulates `rdmsr` using the MSR index in the guest's ECX, injecting #GP(0) if the MSR is denied
+/// to the guest.
+fn handle_rdmsr(registers: &mut GuestRegisters) {
+    let msr = registers.rcx as u32;
+
+    let value = match msr {
+        // Report VMX as disabled and locked, so the guest cannot attempt to enable it.
+        FEATURE_CONTROL => {
+            // SAFETY:
+            // `IA32_FEATURE_CONTROL` exists on every processor supporting VMX.
+            let value = unsafe { FeatureControl::read() };
+            value
+                .set_locked(true)
+                .set_vmx_inside_smx(false)
+                .set_vmx_outside_smx(false)
+                .bits()
+        }
+        // Processors without VMX fault on reads of the VMX capability MSRs.
+        VMX_REVISION..=VMX_VMFUNC => return inject_general_protection(msr),
+        msr if !msr_bitmap::covers(msr) => return inject_general_protection(msr),
+        msr => {
+            // SAFETY:
+            // The guest would have read the same MSR had it not been intercepted.
+            unsafe { read_msr(msr) }
+        }
+    };
+
+    registers.rax = value & 0xFFFF_FFFF;
+    registers.rdx = value >> 32;
+    advance_rip();
+}
+
+/// Emulates `wrmsr` using the MSR index in the guest's ECX and the value in EDX:EAX, injecting
+/// #GP(0) if the MSR is denied to the guest.
+fn handle_wrmsr(registers: &mut GuestRegisters) {
+    let msr = registers.rcx as u32;
+    let value = ((registers.rdx & 0xFFFF_FFFF) << 32) | (registers.rax & 0xFFFF_FFFF);
+
+    match msr {
+        // `IA32_FEATURE_CONTROL` is locked and the capability MSRs are read-only, so forwarding
+        // the write would fault in the host.
+        FEATURE_CONTROL | VMX_REVISION..=VMX_VMFUNC => return inject_general_protection(msr),
+        msr if !msr_bitmap::covers(msr) => return inject_general_protection(msr),
+        msr => {
+            // SAFETY:
+            // The guest would have written the same MSR had it not been intercepted.
+            unsafe { write_msr(msr, value) }
+        }
+    }
+
+    advance_rip();
+}
+
+/// Emulates `xsetbv` using the register index in the guest's ECX and the value in EDX:EAX,
+/// injecting #GP(0) if the value is not accepted by the processor.
+///
+/// Only XCR0 can be written, and only with the state components reported by CPUID in the
+/// combinations the architecture allows.
+fn handle_xsetbv(registers: &mut GuestRegisters) {
+    let index = registers.rcx as u32;
+    let value =
+        Xcr0::from_bits(((registers.rdx & 0xFFFF_FFFF) << 32) | (registers.rax & 0xFFFF_FFFF));
+
+    if index != 0 || !value.is_valid(xsave::supported_components()) {
+        log::debug!(
+            "injecting #GP(0) for guest write of {:#x} to XCR{index}",
+            value.bits()
+        );
+        return queue_general_protection();
+    }
+
+    let previous = Xcr0::get().unwrap_or_default();
+    log::trace!("Guest XCR0: {previous} -> {value}");
+    // SAFETY:
+    // The guest could only execute `xsetbv` with CR4.OSXSAVE set, so the host, which runs with
+    // CR4.OSXSAVE set whenever `xsave` is supported, may execute it, and the value was validated
+    // above. The state of newly disabled components belongs to the guest, which requested it.
+    unsafe { value.write() }
+    advance_rip();
+}
+
+/// Injects #GP(0) into the guest on the next VM entry in response to an access to `msr`, leaving
+/// the guest RIP on the faulting instruction.
+fn inject_general_protection(msr: u32) {
+    log::debug!("injecting #GP(0) for guest access to MSR {msr:#x}");
+    queue_general_protection();
+}
+
+/// Injects #GP(0) into the guest on the next VM entry, leaving the guest RIP on the faulting
+/// instruction.
+fn queue_general_protection() {
+    let information = INTERRUPTION_VALID
+        | INTERRUPTION_DELIVER_ERROR_CODE
+        | INTERRUPTION_TYPE_HARDWARE_EXCEPTION
+        | u64::from(VECTOR_GENERAL_PROTECTION);
+    vm_write(VmcsField::VmEntryInterruptionInformation, information)
+        .unwrap_or_else(|error| fatal(error));
+    vm_write(VmcsField::VmEntryExceptionErrorCode, 0).unwrap_or_else(|error| fatal(error));
+}
+
+/// Emulates `cpuid` with the guest's EAX and ECX.
+fn handle_cpuid(registers: &mut GuestRegisters) {
+    let result = emulate_cpuid(registers.rax as u32, registers.rcx as u32);
+
+    registers.rax = u64::from(result.eax);
+    registers.rbx = u64::from(result.ebx);
+    registers.rcx = u64::from(result.ecx);
+    registers.rdx = u64::from(result.edx);
+}
+
+/// Returns the result of `cpuid` as seen by the guest: the result of executing it on the host
+/// with VMX and SVM support hidden and the hypervisor leaves adjusted according to
+/// [`EXPOSE_HYPERVISOR`].
+pub fn emulate_cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    if CPUID_EXITS.fetch_add(1, Ordering::Relaxed) < CPUID_TRACE_LIMIT {
+        log::trace!("CPUID leaf {leaf:#x} subleaf {subleaf:#x}");
+    }
+
+    let expose = EXPOSE_HYPERVISOR.load(Ordering::Relaxed);
+    let mut result = core::arch::x86_64::__cpuid_count(leaf, subleaf);
+    match leaf {
+        CPUID_FEATURES if expose => {
+            result.ecx = (result.ecx | CPUID_FEATURES_ECX_HYPERVISOR) & !CPUID_FEATURES_ECX_VMX;
+        }
+        CPUID_FEATURES => {
+            result.ecx &= !(CPUID_FEATURES_ECX_HYPERVISOR | CPUID_FEATURES_ECX_VMX);
+        }
+        CPUID_EXTENDED_FEATURES => result.ecx &= !CPUID_EXTENDED_FEATURES_ECX_SVM,
+        CPUID_HYPERVISOR_BASE if expose => {
+            let word = |index: usize| {
+                let bytes = &HYPERVISOR_SIGNATURE[index * 4..index * 4 + 4];
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            };
+
+            result.eax = CPUID_HYPERVISOR_BASE;
+            result.ebx = word(0);
+            result.ecx = word(1);
+            result.edx = word(2);
+        }
+        CPUID_HYPERVISOR_BASE..=CPUID_HYPERVISOR_LAST => {
+            result.eax = 0;
+            result.ebx = 0;
+            result.ecx = 0;
+            result.edx = 0;
+        }
+        _ => {}
+    }
+
+    result
+}
+
+/// A control register access that caused a VM exit, as decoded from its exit qualification.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum ControlRegisterAccess {
+    /// A `mov` of the general-purpose `register` to `control_register`.
+    MoveTo {
+        /// The number of the control register written.
+        control_register: u8,
+        /// The architectural index of the source register.
+        register: u64,
+    },
+    /// A `mov` of `control_register` to the general-purpose `register`.
+    MoveFrom {
+        /// The number of the control register read.
+        control_register: u8,
+        /// The architectural index of the destination register.
+        register: u64,
+    },
+    /// A `clts` instruction.
+    Clts,
+    /// An `lmsw` instruction loading `source` into the low bits of CR0.
+    Lmsw {
+        /// The source operand of the instruction.
+        source: u16,
+    },
+}
+
+impl ControlRegisterAccess {
+    /// Decodes the exit qualification of a control register access VM exit.
+    fn decode(qualification: u64) -> Self {
+        let control_register = (qualification & 0xF) as u8;
+        let register = (qualification >> 8) & 0xF;
+
+        match (qualification >> 4) & 0b11 {
+            0 => Self::MoveTo {
+                control_register,
+                register,
+            },
+            1 => Self::MoveFrom {
+                control_register,
+                register,
+            },
+            2 => Self::Clts,
+            _ => Self::Lmsw {
+                source: (qualification >> 16) as u16,
+            },
+        }
+    }
+}
+
+/// The bit in CR0 enabling protected mode.
+const CR0_PROTECTION_ENABLE: u64 = 1;
+/// The bit in CR0 indicating that a task switch has occurred.
+const CR0_TASK_SWITCHED: u64 = 1 << 3;
+/// The bits of CR0 loaded by `lmsw`.
+const CR0_LMSW_BITS: u64 = 0xF;
+/// The bit in a value loaded into CR3 preserving the cached translations of its PCID when
+/// CR4.PCIDE is set, which is never stored in CR3 itself.
+const CR3_NO_INVALIDATE: u64 = 1 << 63;
+/// The bit in CR4 enabling process-context identifiers.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// Emulates the control register access that caused the current VM exit, keeping the bits fixed
+/// by VMX operation set in the real control registers while the read shadows hold the values the
+/// guest expects.
+fn handle_cr_access(registers: &mut GuestRegisters) {
+    let qualification = vm_read(VmcsField::ExitQualification).unwrap_or_else(|error| fatal(error));
+
+    match ControlRegisterAccess::decode(qualification) {
+        ControlRegisterAccess::MoveTo {
+            control_register,
+            register,
+        } => write_control_register(control_register, registers.get(register)),
+        ControlRegisterAccess::MoveFrom {
+            control_register: 3,
+            register,
+        } => {
+            let value = vm_read(VmcsField::GuestCr3).unwrap_or_else(|error| fatal(error));
+            registers.set(register, value);
+        }
+        ControlRegisterAccess::Clts => {
+            let shadow = vm_read(VmcsField::Cr0ReadShadow).unwrap_or_else(|error| fatal(error));
+            write_control_register(0, shadow & !CR0_TASK_SWITCHED);
+        }
+        ControlRegisterAccess::Lmsw { source } => {
+            let shadow = vm_read(VmcsField::Cr0ReadShadow).unwrap_or_else(|error| fatal(error));
+            write_control_register(0, lmsw(shadow, source));
+        }
+        access => {
+            log::error!("unhandled control register access: {access:?}");
+            halt();
+        }
+    }
+}
+
+/// Returns the value of CR0 after `lmsw` loads `source` into `cr0`.
+fn lmsw(cr0: u64, source: u16) -> u64 {
+    // `lmsw` can set, but never clear, CR0.PE.
+    let source = u64::from(source) & CR0_LMSW_BITS;
+    (cr0 & !(CR0_LMSW_BITS & !CR0_PROTECTION_ENABLE)) | source
+}
+
+/// Emulates a write of `value` to the guest's `control_register`.
+fn write_control_register(control_register: u8, value: u64) {
+    // The bits hidden from the guest are set without its knowledge.
+    let (field, shadow, fixed, hidden) = match control_register {
+        0 => (
+            VmcsField::GuestCr0,
+            VmcsField::Cr0ReadShadow,
+            FixedBits::cr0(),
+            0,
+        ),
+        3 => {
+            let cr4 = vm_read(VmcsField::GuestCr4).unwrap_or_else(|error| fatal(error));
+            let (cr3, invalidate) = load_cr3(value, cr4);
+            vm_write(VmcsField::GuestCr3, cr3).unwrap_or_else(|error| fatal(error));
+            if invalidate {
+                flush_guest_translations();
+            }
+            return;
+        }
+        4 => (
+            VmcsField::GuestCr4,
+            VmcsField::Cr4ReadShadow,
+            FixedBits::cr4(),
+            CR4_VMX_ENABLE,
+        ),
+        _ => {
+            log::error!("unhandled write of {value:#x} to CR{control_register}");
+            halt();
+        }
+    };
+
+    let actual = fixed.apply(value);
+    if (actual ^ value) & !hidden != 0 {
+        log::warn!(
+            "CR{control_register} write of {value:#x} conflicts with VMX, using {actual:#x}"
+        );
+    }
+
+    vm_write(field, actual).unwrap_or_else(|error| fatal(error));
+    vm_write(shadow, value & !hidden).unwrap_or_else(|error| fatal(error));
+    flush_guest_translations();
+}
+
+/// Returns the value CR3 holds after `mov cr3` loads `value` while CR4 holds `cr4`, and whether
+/// the cached translations are invalidated.
+///
+/// When CR4.PCIDE is set, bit 63 of `value` requests that the translations of the PCID are kept,
+/// and is not stored in CR3.
+fn load_cr3(value: u64, cr4: u64) -> (u64, bool) {
+    if cr4 & CR4_PCIDE == 0 {
+        return (value, true);
+    }
+
+    (value & !CR3_NO_INVALIDATE, value & CR3_NO_INVALIDATE == 0)
+}
+
+/// Invalidates the translations cached for the guest's VPID, as a load of CR3 by the guest would
+/// have done.
+fn flush_guest_translations() {
+    // Without VPIDs, every VM entry and exit already flushes the guest's translations.
+    let vpid = match vm_read(VmcsField::VirtualProcessorId) {
+        Ok(vpid) if vpid != 0 => vpid as u16,
+        _ => return,
+    };
+
+    invvpid(InvvpidType::SingleContext, vpid, 0).unwrap_or_else(|error| fatal(error));
+}
+
+/// Moves the guest past the instruction that caused the current VM exit.
+fn advance_rip() {
+    let rip = vm_read(VmcsField::GuestRip).unwrap_or_else(|error| fatal(error));
+    let length = vm_read(VmcsField::VmExitInstructionLength).unwrap_or_else(|error| fatal(error));
+    vm_write(VmcsField::GuestRip, rip + length).unwrap_or_else(|error| fatal(error));
+}
+
+/// Reports a failed `vmresume`, given the RFLAGS it left behind.
+extern "sysv64" fn vmresume_failed(rflags: u64) -> ! {
+    match vmx_result_from_rflags(rflags) {
+        Ok(()) => log::error!("VMRESUME fell through without reporting a failure"),
+        Err(error) => log::error!("VMRESUME failed: {error}"),
+    }
+
+    halt()
+}
+
+/// Logs `error` as an unrecoverable failure while handling a VM exit and halts.
+fn fatal(error: impl fmt::Display) -> ! {
+    log::error!("failed to handle VM exit: {error}");
+    halt()
+}
+
+/// Halts the processor forever.
+pub fn halt() -> ! {
+    loop {
+        // SAFETY:
+        // Halting the processor has no effect on memory safety.
+        unsafe { asm!("hlt", options(nomem, nostack)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_ignores_successful_entries() {
+        assert_eq!(EntryFailure::decode(33, 0), None);
+        assert_eq!(EntryFailure::decode(10, 0), None);
+    }
+
+    #[test]
+    fn decode_invalid_guest_state() {
+        let exit_reason = EXIT_REASON_ENTRY_FAILURE | 33;
+
+        let failures = [
+            (0, GuestStateFailure::Unspecified),
+            (2, GuestStateFailure::PdpteLoading),
+            (3, GuestStateFailure::NmiInjection),
+            (4, GuestStateFailure::VmcsLinkPointer),
+            (1, GuestStateFailure::Unknown(1)),
+        ];
+        for (qualification, failure) in failures {
+            assert_eq!(
+                EntryFailure::decode(exit_reason, qualification),
+                Some(EntryFailure::InvalidGuestState(failure))
+            );
+        }
+    }
+
+    #[test]
+    fn decode_msr_loading_reports_the_entry_index() {
+        assert_eq!(
+            EntryFailure::decode(EXIT_REASON_ENTRY_FAILURE | 34, 3),
+            Some(EntryFailure::MsrLoading(3))
+        );
+    }
+
+    #[test]
+    fn decode_machine_check_and_other_reasons() {
+        assert_eq!(
+            EntryFailure::decode(EXIT_REASON_ENTRY_FAILURE | 41, 0),
+            Some(EntryFailure::MachineCheck)
+        );
+        assert_eq!(
+            EntryFailure::decode(EXIT_REASON_ENTRY_FAILURE | 10, 0),
+            Some(EntryFailure::Other(ExitReason::Cpuid))
+        );
+    }
+
+    #[test]
+    fn decode_only_uses_the_basic_reason() {
+        // Bits 16-30 of the exit reason carry flags that are not part of the basic reason.
+        assert_eq!(
+            EntryFailure::decode(EXIT_REASON_ENTRY_FAILURE | (1 << 27) | 34, 1),
+            Some(EntryFailure::MsrLoading(1))
+        );
+    }
+
+    #[test]
+    fn cr_access_decodes_moves() {
+        // mov cr4, rcx
+        assert_eq!(
+            ControlRegisterAccess::decode(0x0104),
+            ControlRegisterAccess::MoveTo {
+                control_register: 4,
+                register: 1,
+            }
+        );
+        // mov r15, cr3
+        assert_eq!(
+            ControlRegisterAccess::decode(0x0F13),
+            ControlRegisterAccess::MoveFrom {
+                control_register: 3,
+                register: 15,
+            }
+        );
+    }
+
+    #[test]
+    fn cr_access_decodes_clts_and_lmsw() {
+        assert_eq!(
+            ControlRegisterAccess::decode(0x0020),
+            ControlRegisterAccess::Clts
+        );
+        assert_eq!(
+            ControlRegisterAccess::decode(0xABCD_0030),
+            ControlRegisterAccess::Lmsw { source: 0xABCD }
+        );
+        // The memory-operand flag does not affect the source.
+        assert_eq!(
+            ControlRegisterAccess::decode(0x0001_0070),
+            ControlRegisterAccess::Lmsw { source: 1 }
+        );
+    }
+
+    #[test]
+    fn lmsw_loads_the_low_bits_but_never_clears_pe() {
+        let cr0 = 0x8000_0031;
+
+        assert_eq!(lmsw(cr0, 0), 0x8000_0031);
+        assert_eq!(lmsw(cr0, 0x000E), 0x8000_003F);
+        assert_eq!(lmsw(0x8000_0030, 0x0001), 0x8000_0031);
+        // Bits above the low four are ignored.
+        assert_eq!(lmsw(cr0, 0xFFF0), 0x8000_0031);
+    }
+
+    #[test]
+    fn cr3_loads_mask_the_no_invalidate_bit_with_pcids() {
+        let table = 0x1234_5000;
+        let loads = [
+            (table | 7, 0, (table | 7, true)),
+            (table | 7, CR4_PCIDE, (table | 7, true)),
+            (table | 7 | CR3_NO_INVALIDATE, CR4_PCIDE, (table | 7, false)),
+            (
+                table | CR3_NO_INVALIDATE,
+                CR4_PCIDE | CR4_VMX_ENABLE,
+                (table, false),
+            ),
+        ];
+        for (value, cr4, expected) in loads {
+            assert_eq!(
+                load_cr3(value, cr4),
+                expected,
+                "{value:#x} with CR4 {cr4:#x}"
+            );
+        }
+    }
+}
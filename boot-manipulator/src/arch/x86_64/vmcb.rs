@@ -0,0 +1,329 @@
+//! Layout of the SVM Virtual Machine Control Block.
+
+use core::mem::{offset_of, size_of};
+
+/// The bit in [`ControlArea::intercept_misc1`] intercepting `cpuid`.
+pub const INTERCEPT_CPUID: u32 = 1 << 18;
+/// The bit in [`ControlArea::intercept_misc1`] enabling the MSR permissions map.
+pub const INTERCEPT_MSR_PROT: u32 = 1 << 28;
+/// The bit in [`ControlArea::intercept_misc1`] intercepting shutdown events, such as triple
+/// faults.
+pub const INTERCEPT_SHUTDOWN: u32 = 1 << 31;
+/// The bit in [`ControlArea::intercept_misc2`] intercepting `vmrun`, which must always be set.
+pub const INTERCEPT_VMRUN: u32 = 1;
+
+/// The bit in [`ControlArea::nested_paging`] enabling nested paging.
+pub const NESTED_PAGING_ENABLE: u64 = 1;
+
+/// The bit in [`ControlArea::event_injection`] marking the injected event as valid.
+pub const EVENT_VALID: u64 = 1 << 31;
+/// The bit in [`ControlArea::event_injection`] delivering an error code with the event.
+pub const EVENT_DELIVER_ERROR_CODE: u64 = 1 << 11;
+/// The event type of exceptions in [`ControlArea::event_injection`].
+pub const EVENT_TYPE_EXCEPTION: u64 = 3 << 8;
+
+/// The Virtual Machine Control Block, describing a guest to `vmrun`.
+#[repr(C, align(4096))]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct Vmcb {
+    /// The intercepts and other controls of the guest.
+    pub control: ControlArea,
+    /// The processor state of the guest.
+    pub save: SaveArea,
+}
+
+/// The control area of a [`Vmcb`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ControlArea {
+    /// Intercepts of control register reads (bits 15:0) and writes (bits 31:16).
+    pub intercept_cr: u32,
+    /// Intercepts of debug register reads (bits 15:0) and writes (bits 31:16).
+    pub intercept_dr: u32,
+    /// Intercepts of exception vectors.
+    pub intercept_exceptions: u32,
+    /// Intercepts of instructions and events, such as [`INTERCEPT_CPUID`].
+    pub intercept_misc1: u32,
+    /// Intercepts of instructions, such as [`INTERCEPT_VMRUN`].
+    pub intercept_misc2: u32,
+    /// Further intercepts of instructions.
+    pub intercept_misc3: u32,
+    /// Reserved.
+    pub reserved_0: [u8; 0x24],
+    /// The pause filter threshold.
+    pub pause_filter_threshold: u16,
+    /// The pause filter count.
+    pub pause_filter_count: u16,
+    /// The physical address of the I/O permissions map.
+    pub iopm_base: u64,
+    /// The physical address of the MSR permissions map.
+    pub msrpm_base: u64,
+    /// The offset added to the TSC while the guest runs.
+    pub tsc_offset: u64,
+    /// The address space identifier of the guest, which must not be zero.
+    pub guest_asid: u32,
+    /// The TLB flush requested on the next `vmrun`.
+    pub tlb_control: u8,
+    /// Reserved.
+    pub reserved_1: [u8; 3],
+    /// The virtual interrupt controls.
+    pub virtual_interrupt: u64,
+    /// The interrupt shadow of the guest.
+    pub interrupt_shadow: u64,
+    /// The reason for the last `#VMEXIT`.
+    pub exit_code: u64,
+    /// The first piece of information about the last `#VMEXIT`.
+    pub exit_info_1: u64,
+    /// The second piece of information about the last `#VMEXIT`.
+    pub exit_info_2: u64,
+    /// The event that was being delivered when the last `#VMEXIT` occurred.
+    pub exit_interrupt_info: u64,
+    /// The nested paging controls, such as [`NESTED_PAGING_ENABLE`].
+    pub nested_paging: u64,
+    /// The physical address of the AVIC APIC backing page.
+    pub avic_apic_bar: u64,
+    /// The guest physical address of the GHCB.
+    pub ghcb: u64,
+    /// The event injected on the next `vmrun`.
+    pub event_injection: u64,
+    /// The nested page table root used while nested paging is enabled.
+    pub nested_cr3: u64,
+    /// The LBR virtualization controls.
+    pub lbr_virtualization: u64,
+    /// The fields of the VMCB that are unchanged since the last `vmrun`.
+    pub clean_bits: u32,
+    /// Reserved.
+    pub reserved_2: u32,
+    /// The RIP of the instruction following the one that caused the last `#VMEXIT`.
+    pub next_rip: u64,
+    /// The number of valid bytes in `guest_instruction_bytes`.
+    pub bytes_fetched: u8,
+    /// The bytes of the instruction that caused the last nested page fault.
+    pub guest_instruction_bytes: [u8; 15],
+    /// Reserved, along with the AVIC and later extensions which are not used.
+    pub reserved_3: [u8; 0x320],
+}
+
+/// The state of a segment register in a [`SaveArea`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Segment {
+    /// The selector of the segment.
+    pub selector: u16,
+    /// The attributes of the segment in the VMCB format.
+    pub attributes: u16,
+    /// The limit of the segment in bytes.
+    pub limit: u32,
+    /// The base address of the segment.
+    pub base: u64,
+}
+
+/// The state save area of a [`Vmcb`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct SaveArea {
+    /// The ES segment.
+    pub es: Segment,
+    /// The CS segment.
+    pub cs: Segment,
+    /// The SS segment.
+    pub ss: Segment,
+    /// The DS segment.
+    pub ds: Segment,
+    /// The FS segment.
+    pub fs: Segment,
+    /// The GS segment.
+    pub gs: Segment,
+    /// The GDTR, of which only the base and limit are used.
+    pub gdtr: Segment,
+    /// The LDTR.
+    pub ldtr: Segment,
+    /// The IDTR, of which only the base and limit are used.
+    pub idtr: Segment,
+    /// The TR.
+    pub tr: Segment,
+    /// Reserved.
+    pub reserved_0: [u8; 0x2B],
+    /// The current privilege level.
+    pub cpl: u8,
+    /// Reserved.
+    pub reserved_1: u32,
+    /// `EFER`, which must have SVME set.
+    pub efer: u64,
+    /// Reserved.
+    pub reserved_2: [u8; 0x70],
+    /// CR4.
+    pub cr4: u64,
+    /// CR3.
+    pub cr3: u64,
+    /// CR0.
+    pub cr0: u64,
+    /// DR7.
+    pub dr7: u64,
+    /// DR6.
+    pub dr6: u64,
+    /// RFLAGS.
+    pub rflags: u64,
+    /// RIP.
+    pub rip: u64,
+    /// Reserved.
+    pub reserved_3: [u8; 0x58],
+    /// RSP.
+    pub rsp: u64,
+    /// `S_CET`.
+    pub s_cet: u64,
+    /// The shadow stack pointer.
+    pub ssp: u64,
+    /// `ISST_ADDR`.
+    pub isst_addr: u64,
+    /// RAX.
+    pub rax: u64,
+    /// `STAR`.
+    pub star: u64,
+    /// `LSTAR`.
+    pub lstar: u64,
+    /// `CSTAR`.
+    pub cstar: u64,
+    /// `SFMASK`.
+    pub sfmask: u64,
+    /// `KernelGsBase`.
+    pub kernel_gs_base: u64,
+    /// `SYSENTER_CS`.
+    pub sysenter_cs: u64,
+    /// `SYSENTER_ESP`.
+    pub sysenter_esp: u64,
+    /// `SYSENTER_EIP`.
+    pub sysenter_eip: u64,
+    /// CR2.
+    pub cr2: u64,
+    /// Reserved.
+    pub reserved_4: [u8; 0x20],
+    /// The guest `PAT`.
+    pub g_pat: u64,
+    /// `DBGCTL`.
+    pub dbgctl: u64,
+    /// `BR_FROM`.
+    pub br_from: u64,
+    /// `BR_TO`.
+    pub br_to: u64,
+    /// `LASTEXCPFROM`.
+    pub last_exception_from: u64,
+    /// `LASTEXCPTO`.
+    pub last_exception_to: u64,
+    /// Reserved.
+    pub reserved_5: [u8; 0x968],
+}
+
+const _: () = assert!(size_of::<Vmcb>() == 4096);
+const _: () = assert!(size_of::<ControlArea>() == 0x400);
+const _: () = assert!(offset_of!(ControlArea, pause_filter_threshold) == 0x03C);
+const _: () = assert!(offset_of!(ControlArea, iopm_base) == 0x040);
+const _: () = assert!(offset_of!(ControlArea, guest_asid) == 0x058);
+const _: () = assert!(offset_of!(ControlArea, exit_code) == 0x070);
+const _: () = assert!(offset_of!(ControlArea, nested_paging) == 0x090);
+const _: () = assert!(offset_of!(ControlArea, event_injection) == 0x0A8);
+const _: () = assert!(offset_of!(ControlArea, nested_cr3) == 0x0B0);
+const _: () = assert!(offset_of!(ControlArea, next_rip) == 0x0C8);
+const _: () = assert!(offset_of!(ControlArea, guest_instruction_bytes) == 0x0D1);
+const _: () = assert!(offset_of!(Vmcb, save) == 0x400);
+const _: () = assert!(offset_of!(SaveArea, tr) == 0x090);
+const _: () = assert!(offset_of!(SaveArea, cpl) == 0x0CB);
+const _: () = assert!(offset_of!(SaveArea, efer) == 0x0D0);
+const _: () = assert!(offset_of!(SaveArea, cr4) == 0x148);
+const _: () = assert!(offset_of!(SaveArea, rip) == 0x178);
+const _: () = assert!(offset_of!(SaveArea, rsp) == 0x1D8);
+const _: () = assert!(offset_of!(SaveArea, rax) == 0x1F8);
+const _: () = assert!(offset_of!(SaveArea, kernel_gs_base) == 0x220);
+const _: () = assert!(offset_of!(SaveArea, cr2) == 0x240);
+const _: () = assert!(offset_of!(SaveArea, g_pat) == 0x268);
+const _: () = assert!(offset_of!(SaveArea, last_exception_to) == 0x290);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_area_matches_the_architectural_layout() {
+        let offsets = [
+            (offset_of!(ControlArea, intercept_cr), 0x000),
+            (offset_of!(ControlArea, intercept_dr), 0x004),
+            (offset_of!(ControlArea, intercept_exceptions), 0x008),
+            (offset_of!(ControlArea, intercept_misc1), 0x00C),
+            (offset_of!(ControlArea, intercept_misc2), 0x010),
+            (offset_of!(ControlArea, intercept_misc3), 0x014),
+            (offset_of!(ControlArea, pause_filter_count), 0x03E),
+            (offset_of!(ControlArea, msrpm_base), 0x048),
+            (offset_of!(ControlArea, tsc_offset), 0x050),
+            (offset_of!(ControlArea, tlb_control), 0x05C),
+            (offset_of!(ControlArea, virtual_interrupt), 0x060),
+            (offset_of!(ControlArea, interrupt_shadow), 0x068),
+            (offset_of!(ControlArea, exit_info_1), 0x078),
+            (offset_of!(ControlArea, exit_info_2), 0x080),
+            (offset_of!(ControlArea, exit_interrupt_info), 0x088),
+            (offset_of!(ControlArea, avic_apic_bar), 0x098),
+            (offset_of!(ControlArea, ghcb), 0x0A0),
+            (offset_of!(ControlArea, lbr_virtualization), 0x0B8),
+            (offset_of!(ControlArea, clean_bits), 0x0C0),
+            (offset_of!(ControlArea, bytes_fetched), 0x0D0),
+        ];
+
+        for (index, (actual, expected)) in offsets.into_iter().enumerate() {
+            assert_eq!(actual, expected, "control area field {index}");
+        }
+    }
+
+    #[test]
+    fn save_area_matches_the_architectural_layout() {
+        let offsets = [
+            (offset_of!(SaveArea, es), 0x000),
+            (offset_of!(SaveArea, cs), 0x010),
+            (offset_of!(SaveArea, ss), 0x020),
+            (offset_of!(SaveArea, ds), 0x030),
+            (offset_of!(SaveArea, fs), 0x040),
+            (offset_of!(SaveArea, gs), 0x050),
+            (offset_of!(SaveArea, gdtr), 0x060),
+            (offset_of!(SaveArea, ldtr), 0x070),
+            (offset_of!(SaveArea, idtr), 0x080),
+            (offset_of!(SaveArea, cr3), 0x150),
+            (offset_of!(SaveArea, cr0), 0x158),
+            (offset_of!(SaveArea, dr7), 0x160),
+            (offset_of!(SaveArea, dr6), 0x168),
+            (offset_of!(SaveArea, rflags), 0x170),
+            (offset_of!(SaveArea, s_cet), 0x1E0),
+            (offset_of!(SaveArea, ssp), 0x1E8),
+            (offset_of!(SaveArea, isst_addr), 0x1F0),
+            (offset_of!(SaveArea, star), 0x200),
+            (offset_of!(SaveArea, lstar), 0x208),
+            (offset_of!(SaveArea, cstar), 0x210),
+            (offset_of!(SaveArea, sfmask), 0x218),
+            (offset_of!(SaveArea, sysenter_cs), 0x228),
+            (offset_of!(SaveArea, sysenter_esp), 0x230),
+            (offset_of!(SaveArea, sysenter_eip), 0x238),
+            (offset_of!(SaveArea, dbgctl), 0x270),
+            (offset_of!(SaveArea, br_from), 0x278),
+            (offset_of!(SaveArea, br_to), 0x280),
+            (offset_of!(SaveArea, last_exception_from), 0x288),
+        ];
+
+        for (index, (actual, expected)) in offsets.into_iter().enumerate() {
+            assert_eq!(actual, expected, "save area field {index}");
+        }
+        assert_eq!(size_of::<SaveArea>(), 0x1000 - 0x400);
+    }
+
+    #[test]
+    fn segments_are_16_bytes() {
+        assert_eq!(size_of::<Segment>(), 16);
+        assert_eq!(offset_of!(Segment, selector), 0);
+        assert_eq!(offset_of!(Segment, attributes), 2);
+        assert_eq!(offset_of!(Segment, limit), 4);
+        assert_eq!(offset_of!(Segment, base), 8);
+    }
+
+    #[test]
+    fn exception_injection_encoding() {
+        // A #GP with an error code.
+        let event = 13 | EVENT_TYPE_EXCEPTION | EVENT_DELIVER_ERROR_CODE | EVENT_VALID;
+        assert_eq!(event, 0x8000_0B0D);
+    }
+}
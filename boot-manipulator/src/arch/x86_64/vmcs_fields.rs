@@ -0,0 +1,455 @@
+//! Encodings of the fields of the virtual-machine control structure.
+
+/// A field of the VMCS, identified by its encoding.
+///
+/// 64-bit fields are listed with their full access encoding; the encoding of the high 32 bits is
+/// one greater.
+#[allow(dead_code)] // The table covers every field the VMCS setup may need, not just those in use.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum VmcsField {
+    // 16-bit control fields.
+    /// Virtual-processor identifier.
+    VirtualProcessorId = 0x0000,
+    /// Posted-interrupt notification vector.
+    PostedInterruptNotificationVector = 0x0002,
+    /// EPTP index.
+    EptpIndex = 0x0004,
+
+    // 16-bit guest-state fields.
+    /// Guest ES selector.
+    GuestEsSelector = 0x0800,
+    /// Guest CS selector.
+    GuestCsSelector = 0x0802,
+    /// Guest SS selector.
+    GuestSsSelector = 0x0804,
+    /// Guest DS selector.
+    GuestDsSelector = 0x0806,
+    /// Guest FS selector.
+    GuestFsSelector = 0x0808,
+    /// Guest GS selector.
+    GuestGsSelector = 0x080A,
+    /// Guest LDTR selector.
+    GuestLdtrSelector = 0x080C,
+    /// Guest TR selector.
+    GuestTrSelector = 0x080E,
+    /// Guest interrupt status.
+    GuestInterruptStatus = 0x0810,
+    /// PML index.
+    PmlIndex = 0x0812,
+
+    // 16-bit host-state fields.
+    /// Host ES selector.
+    HostEsSelector = 0x0C00,
+    /// Host CS selector.
+    HostCsSelector = 0x0C02,
+    /// Host SS selector.
+    HostSsSelector = 0x0C04,
+    /// Host DS selector.
+    HostDsSelector = 0x0C06,
+    /// Host FS selector.
+    HostFsSelector = 0x0C08,
+    /// Host GS selector.
+    HostGsSelector = 0x0C0A,
+    /// Host TR selector.
+    HostTrSelector = 0x0C0C,
+
+    // 64-bit control fields.
+    /// Address of I/O bitmap A.
+    IoBitmapA = 0x2000,
+    /// Address of I/O bitmap B.
+    IoBitmapB = 0x2002,
+    /// Address of MSR bitmaps.
+    MsrBitmaps = 0x2004,
+    /// VM-exit MSR-store address.
+    VmExitMsrStoreAddress = 0x2006,
+    /// VM-exit MSR-load address.
+    VmExitMsrLoadAddress = 0x2008,
+    /// VM-entry MSR-load address.
+    VmEntryMsrLoadAddress = 0x200A,
+    /// Executive-VMCS pointer.
+    ExecutiveVmcsPointer = 0x200C,
+    /// PML address.
+    PmlAddress = 0x200E,
+    /// TSC offset.
+    TscOffset = 0x2010,
+    /// Virtual-APIC address.
+    VirtualApicAddress = 0x2012,
+    /// APIC-access address.
+    ApicAccessAddress = 0x2014,
+    /// Posted-interrupt descriptor address.
+    PostedInterruptDescriptorAddress = 0x2016,
+    /// VM-function controls.
+    VmFunctionControls = 0x2018,
+    /// EPT pointer.
+    EptPointer = 0x201A,
+    /// EOI-exit bitmap 0.
+    EoiExitBitmap0 = 0x201C,
+    /// EOI-exit bitmap 1.
+    EoiExitBitmap1 = 0x201E,
+    /// EOI-exit bitmap 2.
+    EoiExitBitmap2 = 0x2020,
+    /// EOI-exit bitmap 3.
+    EoiExitBitmap3 = 0x2022,
+    /// EPTP-list address.
+    EptpListAddress = 0x2024,
+    /// VMREAD-bitmap address.
+    VmreadBitmapAddress = 0x2026,
+    /// VMWRITE-bitmap address.
+    VmwriteBitmapAddress = 0x2028,
+    /// Virtualization-exception information address.
+    VirtualizationExceptionInformationAddress = 0x202A,
+    /// XSS-exiting bitmap.
+    XssExitingBitmap = 0x202C,
+    /// TSC multiplier.
+    TscMultiplier = 0x2032,
+
+    // 64-bit read-only data fields.
+    /// Guest-physical address.
+    GuestPhysicalAddress = 0x2400,
+
+    // 64-bit guest-state fields.
+    /// VMCS link pointer.
+    VmcsLinkPointer = 0x2800,
+    /// Guest IA32_DEBUGCTL.
+    GuestIa32Debugctl = 0x2802,
+    /// Guest IA32_PAT.
+    GuestIa32Pat = 0x2804,
+    /// Guest IA32_EFER.
+    GuestIa32Efer = 0x2806,
+    /// Guest IA32_PERF_GLOBAL_CTRL.
+    GuestIa32PerfGlobalCtrl = 0x2808,
+    /// Guest PDPTE0.
+    GuestPdpte0 = 0x280A,
+    /// Guest PDPTE1.
+    GuestPdpte1 = 0x280C,
+    /// Guest PDPTE2.
+    GuestPdpte2 = 0x280E,
+    /// Guest PDPTE3.
+    GuestPdpte3 = 0x2810,
+    /// Guest IA32_BNDCFGS.
+    GuestIa32Bndcfgs = 0x2812,
+
+    // 64-bit host-state fields.
+    /// Host IA32_PAT.
+    HostIa32Pat = 0x2C00,
+    /// Host IA32_EFER.
+    HostIa32Efer = 0x2C02,
+    /// Host IA32_PERF_GLOBAL_CTRL.
+    HostIa32PerfGlobalCtrl = 0x2C04,
+
+    // 32-bit control fields.
+    /// Pin-based VM-execution controls.
+    PinBasedControls = 0x4000,
+    /// Primary processor-based VM-execution controls.
+    PrimaryProcessorBasedControls = 0x4002,
+    /// Exception bitmap.
+    ExceptionBitmap = 0x4004,
+    /// Page-fault error-code mask.
+    PageFaultErrorCodeMask = 0x4006,
+    /// Page-fault error-code match.
+    PageFaultErrorCodeMatch = 0x4008,
+    /// CR3-target count.
+    Cr3TargetCount = 0x400A,
+    /// Primary VM-exit controls.
+    VmExitControls = 0x400C,
+    /// VM-exit MSR-store count.
+    VmExitMsrStoreCount = 0x400E,
+    /// VM-exit MSR-load count.
+    VmExitMsrLoadCount = 0x4010,
+    /// VM-entry controls.
+    VmEntryControls = 0x4012,
+    /// VM-entry MSR-load count.
+    VmEntryMsrLoadCount = 0x4014,
+    /// VM-entry interruption-information field.
+    VmEntryInterruptionInformation = 0x4016,
+    /// VM-entry exception error code.
+    VmEntryExceptionErrorCode = 0x4018,
+    /// VM-entry instruction length.
+    VmEntryInstructionLength = 0x401A,
+    /// TPR threshold.
+    TprThreshold = 0x401C,
+    /// Secondary processor-based VM-execution controls.
+    SecondaryProcessorBasedControls = 0x401E,
+    /// PAUSE-loop exiting gap.
+    PleGap = 0x4020,
+    /// PAUSE-loop exiting window.
+    PleWindow = 0x4022,
+
+    // 32-bit read-only data fields.
+    /// VM-instruction error.
+    VmInstructionError = 0x4400,
+    /// Exit reason.
+    VmExitReason = 0x4402,
+    /// VM-exit interruption information.
+    VmExitInterruptionInformation = 0x4404,
+    /// VM-exit interruption error code.
+    VmExitInterruptionErrorCode = 0x4406,
+    /// IDT-vectoring information field.
+    IdtVectoringInformation = 0x4408,
+    /// IDT-vectoring error code.
+    IdtVectoringErrorCode = 0x440A,
+    /// VM-exit instruction length.
+    VmExitInstructionLength = 0x440C,
+    /// VM-exit instruction information.
+    VmExitInstructionInformation = 0x440E,
+
+    // 32-bit guest-state fields.
+    /// Guest ES limit.
+    GuestEsLimit = 0x4800,
+    /// Guest CS limit.
+    GuestCsLimit = 0x4802,
+    /// Guest SS limit.
+    GuestSsLimit = 0x4804,
+    /// Guest DS limit.
+    GuestDsLimit = 0x4806,
+    /// Guest FS limit.
+    GuestFsLimit = 0x4808,
+    /// Guest GS limit.
+    GuestGsLimit = 0x480A,
+    /// Guest LDTR limit.
+    GuestLdtrLimit = 0x480C,
+    /// Guest TR limit.
+    GuestTrLimit = 0x480E,
+    /// Guest GDTR limit.
+    GuestGdtrLimit = 0x4810,
+    /// Guest IDTR limit.
+    GuestIdtrLimit = 0x4812,
+    /// Guest ES access rights.
+    GuestEsAccessRights = 0x4814,
+    /// Guest CS access rights.
+    GuestCsAccessRights = 0x4816,
+    /// Guest SS access rights.
+    GuestSsAccessRights = 0x4818,
+    /// Guest DS access rights.
+    GuestDsAccessRights = 0x481A,
+    /// Guest FS access rights.
+    GuestFsAccessRights = 0x481C,
+    /// Guest GS access rights.
+    GuestGsAccessRights = 0x481E,
+    /// Guest LDTR access rights.
+    GuestLdtrAccessRights = 0x4820,
+    /// Guest TR access rights.
+    GuestTrAccessRights = 0x4822,
+    /// Guest interruptibility state.
+    GuestInterruptibilityState = 0x4824,
+    /// Guest activity state.
+    GuestActivityState = 0x4826,
+    /// Guest SMBASE.
+    GuestSmbase = 0x4828,
+    /// Guest IA32_SYSENTER_CS.
+    GuestIa32SysenterCs = 0x482A,
+    /// VMX-preemption timer value.
+    VmxPreemptionTimerValue = 0x482E,
+
+    // 32-bit host-state fields.
+    /// Host IA32_SYSENTER_CS.
+    HostIa32SysenterCs = 0x4C00,
+
+    // Natural-width control fields.
+    /// CR0 guest/host mask.
+    Cr0GuestHostMask = 0x6000,
+    /// CR4 guest/host mask.
+    Cr4GuestHostMask = 0x6002,
+    /// CR0 read shadow.
+    Cr0ReadShadow = 0x6004,
+    /// CR4 read shadow.
+    Cr4ReadShadow = 0x6006,
+    /// CR3-target value 0.
+    Cr3Target0 = 0x6008,
+    /// CR3-target value 1.
+    Cr3Target1 = 0x600A,
+    /// CR3-target value 2.
+    Cr3Target2 = 0x600C,
+    /// CR3-target value 3.
+    Cr3Target3 = 0x600E,
+
+    // Natural-width read-only data fields.
+    /// Exit qualification.
+    ExitQualification = 0x6400,
+    /// I/O RCX.
+    IoRcx = 0x6402,
+    /// I/O RSI.
+    IoRsi = 0x6404,
+    /// I/O RDI.
+    IoRdi = 0x6406,
+    /// I/O RIP.
+    IoRip = 0x6408,
+    /// Guest-linear address.
+    GuestLinearAddress = 0x640A,
+
+    // Natural-width guest-state fields.
+    /// Guest CR0.
+    GuestCr0 = 0x6800,
+    /// Guest CR3.
+    GuestCr3 = 0x6802,
+    /// Guest CR4.
+    GuestCr4 = 0x6804,
+    /// Guest ES base.
+    GuestEsBase = 0x6806,
+    /// Guest CS base.
+    GuestCsBase = 0x6808,
+    /// Guest SS base.
+    GuestSsBase = 0x680A,
+    /// Guest DS base.
+    GuestDsBase = 0x680C,
+    /// Guest FS base.
+    GuestFsBase = 0x680E,
+    /// Guest GS base.
+    GuestGsBase = 0x6810,
+    /// Guest LDTR base.
+    GuestLdtrBase = 0x6812,
+    /// Guest TR base.
+    GuestTrBase = 0x6814,
+    /// Guest GDTR base.
+    GuestGdtrBase = 0x6816,
+    /// Guest IDTR base.
+    GuestIdtrBase = 0x6818,
+    /// Guest DR7.
+    GuestDr7 = 0x681A,
+    /// Guest RSP.
+    GuestRsp = 0x681C,
+    /// Guest RIP.
+    GuestRip = 0x681E,
+    /// Guest RFLAGS.
+    GuestRflags = 0x6820,
+    /// Guest pending debug exceptions.
+    GuestPendingDebugExceptions = 0x6822,
+    /// Guest IA32_SYSENTER_ESP.
+    GuestIa32SysenterEsp = 0x6824,
+    /// Guest IA32_SYSENTER_EIP.
+    GuestIa32SysenterEip = 0x6826,
+
+    // Natural-width host-state fields.
+    /// Host CR0.
+    HostCr0 = 0x6C00,
+    /// Host CR3.
+    HostCr3 = 0x6C02,
+    /// Host CR4.
+    HostCr4 = 0x6C04,
+    /// Host FS base.
+    HostFsBase = 0x6C06,
+    /// Host GS base.
+    HostGsBase = 0x6C08,
+    /// Host TR base.
+    HostTrBase = 0x6C0A,
+    /// Host GDTR base.
+    HostGdtrBase = 0x6C0C,
+    /// Host IDTR base.
+    HostIdtrBase = 0x6C0E,
+    /// Host IA32_SYSENTER_ESP.
+    HostIa32SysenterEsp = 0x6C10,
+    /// Host IA32_SYSENTER_EIP.
+    HostIa32SysenterEip = 0x6C12,
+    /// Host RSP.
+    HostRsp = 0x6C14,
+    /// Host RIP.
+    HostRip = 0x6C16,
+}
+
+impl VmcsField {
+    /// Returns the encoding used to access the field with `vmread` and `vmwrite`.
+    pub const fn encoding(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns the width of the field, as given by bits 14:13 of its encoding.
+    pub const fn width(self) -> VmcsFieldWidth {
+        match (self.encoding() >> 13) & 0b11 {
+            0 => VmcsFieldWidth::Bits16,
+            1 => VmcsFieldWidth::Bits64,
+            2 => VmcsFieldWidth::Bits32,
+            _ => VmcsFieldWidth::Natural,
+        }
+    }
+}
+
+/// The widths of VMCS fields.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum VmcsFieldWidth {
+    /// A 16-bit field.
+    Bits16,
+    /// A 64-bit field.
+    Bits64,
+    /// A 32-bit field.
+    Bits32,
+    /// A field as wide as the processor's linear addresses.
+    Natural,
+}
+
+impl VmcsFieldWidth {
+    /// Returns the mask of the bits defined for fields of this width.
+    pub const fn mask(self) -> u64 {
+        match self {
+            Self::Bits16 => u16::MAX as u64,
+            Self::Bits32 => u32::MAX as u64,
+            Self::Bits64 | Self::Natural => u64::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings_match_the_sdm() {
+        let fields = [
+            (VmcsField::VirtualProcessorId, 0x0000),
+            (VmcsField::GuestEsSelector, 0x0800),
+            (VmcsField::GuestTrSelector, 0x080E),
+            (VmcsField::HostEsSelector, 0x0C00),
+            (VmcsField::IoBitmapA, 0x2000),
+            (VmcsField::MsrBitmaps, 0x2004),
+            (VmcsField::EptPointer, 0x201A),
+            (VmcsField::VmcsLinkPointer, 0x2800),
+            (VmcsField::GuestIa32Efer, 0x2806),
+            (VmcsField::HostIa32Efer, 0x2C02),
+            (VmcsField::PinBasedControls, 0x4000),
+            (VmcsField::VmInstructionError, 0x4400),
+            (VmcsField::VmExitReason, 0x4402),
+            (VmcsField::GuestEsAccessRights, 0x4814),
+            (VmcsField::GuestActivityState, 0x4826),
+            (VmcsField::ExitQualification, 0x6400),
+            (VmcsField::GuestCr0, 0x6800),
+            (VmcsField::GuestRip, 0x681E),
+            (VmcsField::HostCr0, 0x6C00),
+            (VmcsField::HostRip, 0x6C16),
+        ];
+
+        for (field, encoding) in fields {
+            assert_eq!(field.encoding(), encoding, "{field:?}");
+        }
+    }
+
+    #[test]
+    fn width_comes_from_bits_14_13() {
+        assert_eq!(
+            VmcsField::VirtualProcessorId.width(),
+            VmcsFieldWidth::Bits16
+        );
+        assert_eq!(VmcsField::GuestCsSelector.width(), VmcsFieldWidth::Bits16);
+        assert_eq!(VmcsField::HostTrSelector.width(), VmcsFieldWidth::Bits16);
+        assert_eq!(VmcsField::MsrBitmaps.width(), VmcsFieldWidth::Bits64);
+        assert_eq!(VmcsField::VmcsLinkPointer.width(), VmcsFieldWidth::Bits64);
+        assert_eq!(VmcsField::PinBasedControls.width(), VmcsFieldWidth::Bits32);
+        assert_eq!(
+            VmcsField::GuestEsAccessRights.width(),
+            VmcsFieldWidth::Bits32
+        );
+        assert_eq!(
+            VmcsField::ExitQualification.width(),
+            VmcsFieldWidth::Natural
+        );
+        assert_eq!(VmcsField::GuestRip.width(), VmcsFieldWidth::Natural);
+        assert_eq!(VmcsField::HostRsp.width(), VmcsFieldWidth::Natural);
+    }
+
+    #[test]
+    fn width_masks() {
+        assert_eq!(VmcsFieldWidth::Bits16.mask(), 0xFFFF);
+        assert_eq!(VmcsFieldWidth::Bits32.mask(), 0xFFFF_FFFF);
+        assert_eq!(VmcsFieldWidth::Bits64.mask(), u64::MAX);
+        assert_eq!(VmcsFieldWidth::Natural.mask(), u64::MAX);
+    }
+}
@@ -0,0 +1,178 @@
+//! Management of the extended processor state saved by `xsave`, which holds the x87, SSE, AVX,
+//! and later register files.
+//!
+//! The guest's extended state is saved to an [`XsaveArea`] on every VM exit and restored before
+//! the guest resumes, so that host code using those registers cannot corrupt it.
+
+use core::{arch::x86_64::__cpuid_count, ptr::NonNull};
+
+use crate::{
+    arch::x86_64::registers::xcr0::Xcr0,
+    frames::{allocate_frames, deallocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE},
+    spinlock::Spinlock,
+};
+
+/// The CPUID leaf reporting processor features.
+const CPUID_FEATURES: u32 = 0x1;
+/// The bit in ECX of [`CPUID_FEATURES`] reporting support for `xsave` and XCR0.
+const CPUID_FEATURES_ECX_XSAVE: u32 = 1 << 26;
+/// The CPUID leaf enumerating the state components and the size of the XSAVE area.
+const CPUID_XSAVE: u32 = 0xD;
+
+/// The area holding the guest's extended state while the host handles a VM exit.
+static GUEST_AREA: Spinlock<Option<XsaveArea>> = Spinlock::new(None);
+
+/// Returns `true` if the processor supports `xsave` and XCR0.
+pub fn supported() -> bool {
+    __cpuid_count(CPUID_FEATURES, 0).ecx & CPUID_FEATURES_ECX_XSAVE != 0
+}
+
+/// Returns the state components that may be enabled in XCR0.
+///
+/// The processor must support `xsave`.
+pub fn supported_components() -> Xcr0 {
+    let result = __cpuid_count(CPUID_XSAVE, 0);
+    Xcr0::from_bits(u64::from(result.eax) | (u64::from(result.edx) << 32))
+}
+
+/// Returns the number of bytes of an XSAVE area holding every supported state component.
+///
+/// The processor must support `xsave`.
+pub fn area_size() -> usize {
+    __cpuid_count(CPUID_XSAVE, 0).ecx as usize
+}
+
+/// Allocates the area holding the guest's extended state if the processor supports `xsave`.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if the area cannot be allocated.
+pub fn allocate_guest_area() -> Result<(), OutOfMemoryError> {
+    if !supported() {
+        return Ok(());
+    }
+
+    let area = XsaveArea::allocate()?;
+    if let Some(previous) = GUEST_AREA.lock().replace(area) {
+        // SAFETY:
+        // No guest runs while the area is allocated, so the previous area is unused.
+        unsafe { previous.deallocate() }
+    }
+
+    Ok(())
+}
+
+/// Frees the area allocated by [`allocate_guest_area`], if any.
+///
+/// # Safety
+/// No guest may be running, and boot services must not have been exited.
+pub unsafe fn release_guest_area() {
+    if let Some(area) = GUEST_AREA.lock().take() {
+        // SAFETY:
+        // The caller guarantees that no guest is running, so the area is unused.
+        unsafe { area.deallocate() }
+    }
+}
+
+/// Saves the extended state of the guest that caused the current VM exit.
+///
+/// Does nothing if no guest area was allocated.
+pub fn save_guest_state() {
+    if let Some(area) = GUEST_AREA.lock().as_mut() {
+        // SAFETY:
+        // The area is only allocated when `xsave` is supported, in which case the host runs with
+        // CR4.OSXSAVE set.
+        unsafe { area.save() }
+    }
+}
+
+/// Restores the extended state saved by [`save_guest_state`] before the guest resumes.
+///
+/// Does nothing if no guest area was allocated.
+pub fn restore_guest_state() {
+    if let Some(area) = GUEST_AREA.lock().as_ref() {
+        // SAFETY:
+        // The area is only allocated when `xsave` is supported, in which case the host runs with
+        // CR4.OSXSAVE set, and it holds the state saved on the current VM exit.
+        unsafe { area.restore() }
+    }
+}
+
+/// A 64-byte-aligned buffer large enough to hold every supported state component.
+#[derive(Debug, PartialEq, Eq)]
+pub struct XsaveArea {
+    /// The frames holding the area.
+    frames: NonNull<u8>,
+    /// The number of frames holding the area.
+    count: usize,
+}
+
+// SAFETY:
+// The area exclusively owns its frames, which are identity mapped on every processor.
+unsafe impl Send for XsaveArea {}
+
+impl XsaveArea {
+    /// Allocates a zeroed [`XsaveArea`], whose header describes every component as being in its
+    /// initial state.
+    ///
+    /// The processor must support `xsave`.
+    ///
+    /// # Errors
+    /// Returns an [`OutOfMemoryError`] if the frames holding the area cannot be allocated.
+    pub fn allocate() -> Result<Self, OutOfMemoryError> {
+        let count = area_size().div_ceil(FRAME_SIZE);
+        Ok(Self {
+            frames: allocate_frames(count, MemoryKind::Persistent)?,
+            count,
+        })
+    }
+
+    /// Returns the frames holding the area to the firmware.
+    ///
+    /// # Safety
+    /// The area must no longer be in use, and boot services must not have been exited.
+    pub unsafe fn deallocate(self) {
+        // SAFETY:
+        // The frames were allocated by `allocate` and the caller guarantees they are unused.
+        unsafe { deallocate_frames(self.frames, self.count) }
+    }
+
+    /// Saves every state component enabled in XCR0 to the area with `xsave64`.
+    ///
+    /// # Safety
+    /// CR4.OSXSAVE must be set.
+    pub unsafe fn save(&mut self) {
+        // SAFETY:
+        // The caller guarantees that `xsave64` is enabled, and the area is page aligned and
+        // large enough for every supported component.
+        unsafe {
+            core::arch::asm!(
+                "xsave64 [{}]",
+                in(reg) self.frames.as_ptr(),
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags)
+            )
+        }
+    }
+
+    /// Restores every state component enabled in XCR0 from the area with `xrstor64`, loading
+    /// the initial state of the components the area does not hold.
+    ///
+    /// # Safety
+    /// CR4.OSXSAVE must be set, and the area must be zeroed or hold state saved by
+    /// [`XsaveArea::save`].
+    pub unsafe fn restore(&self) {
+        // SAFETY:
+        // The caller guarantees that `xrstor64` is enabled and that the header of the area is
+        // valid.
+        unsafe {
+            core::arch::asm!(
+                "xrstor64 [{}]",
+                in(reg) self.frames.as_ptr(),
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags)
+            )
+        }
+    }
+}
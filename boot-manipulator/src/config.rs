@@ -0,0 +1,677 @@
+//! Configuration of the driver through a configuration file and the load options passed to its
+//! image.
+//!
+//! The configuration file, [`CONFIG_FILE_NAME`], is read from the directory holding the driver
+//! image. Each line is either blank, a comment starting with `#`, or a `key = value` setting.
+//!
+//! The load options are a UCS-2 command line of whitespace-separated options, each of which may be
+//! enclosed in double quotes, and override the settings of the configuration file:
+//!
+//! - `log=<filter>` sets the maximum log level (`off`, `error`, `warn`, `info`, `debug`, `trace`),
+//!   optionally per module, as described in [`crate::log_filter`].
+//! - `serial=<port>` sets the I/O port of the serial port logged to after `ExitBootServices()`,
+//!   either as a number or as one of `com1` to `com4`, and `serial=off` discards records after
+//!   `ExitBootServices()` instead. Records are also discarded if no serial port responds there.
+//! - `serial-baud=<rate>` sets the baud rate of the serial port, which defaults to 115200.
+//! - `hooks=<true|false>` selects whether hooks are installed, and `no-hook` is short for
+//!   `hooks=false`.
+//! - `cpus=<selection>` selects the processors to virtualize (`all` or `bsp-only`).
+//! - `timestamps=<true|false>` selects whether log records are prefixed with the time since the
+//!   driver was loaded.
+//! - `cpu-ids=<true|false>` selects whether log records are prefixed with the ID of the processor
+//!   that logged them.
+//! - `panic=<halt|reboot>` selects whether the machine halts or reboots after a panic.
+//! - `debugcon=<true|false>` selects whether records are written to the debug console at port
+//!   0xE9, if the driver was built with the `debugcon` feature and the port is present.
+//! - `framebuffer=<true|false>` selects whether records are drawn into the framebuffer of the
+//!   Graphics Output Protocol after `ExitBootServices()`, for machines without a serial port.
+//! - `shell=<serial|off>` selects whether the debug shell is offered on the serial port, both
+//!   before the hooks are installed and whenever the guest idles, as described in [`crate::shell`].
+//! - `log-buffer=<KiB>` sets the size of the in-memory ring of recent log records, and
+//!   `log-buffer=0` disables it.
+//!
+//! Every option except `no-hook` is also accepted as a key of the configuration file.
+
+use uefi::{
+    boot,
+    proto::{
+        device_path::DevicePathNodeEnum,
+        loaded_image::LoadedImage,
+        media::{
+            file::{File, FileAttribute, FileMode, RegularFile},
+            fs::SimpleFileSystem,
+        },
+    },
+    CStr16,
+};
+
+use crate::{
+    frames::{allocate_frames, deallocate_frames, MemoryKind, FRAME_SIZE},
+    log_filter::LogFilter,
+    spinlock::Spinlock,
+};
+
+/// The name of the configuration file in the directory holding the driver image.
+pub const CONFIG_FILE_NAME: &str = "boot-manipulator.cfg";
+
+/// The maximum length of the path to the configuration file in UCS-2 characters.
+const MAX_PATH_LENGTH: usize = 256;
+
+/// The maximum size of the configuration file in bytes.
+const MAX_CONFIG_FILE_SIZE: usize = 64 * 1024;
+
+/// The maximum length of a single option in bytes.
+const MAX_OPTION_LENGTH: usize = 256;
+
+/// The configuration in effect, set by [`load`].
+static CONFIG: Spinlock<BootConfig> = Spinlock::new(BootConfig::DEFAULT);
+
+/// The configuration of the driver.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct BootConfig {
+    /// The maximum level of the records that are logged, by module.
+    pub log_filter: LogFilter,
+    /// The I/O port of the serial port logged to after `ExitBootServices()`, or [`None`] if
+    /// records are discarded.
+    pub serial_port: Option<u16>,
+    /// The baud rate of the serial port.
+    pub serial_baud_rate: u32,
+    /// Whether the `ExitBootServices()` and `SetVirtualAddressMap()` hooks are installed.
+    pub install_hooks: bool,
+    /// The processors to virtualize.
+    pub processors: ProcessorSelection,
+    /// Whether log records are prefixed with the time since the driver was loaded.
+    pub timestamps: bool,
+    /// Whether log records are prefixed with the ID of the processor that logged them.
+    pub processor_ids: bool,
+    /// Whether records are written to the debug console.
+    pub debugcon: bool,
+    /// Whether records are drawn into the framebuffer after `ExitBootServices()`.
+    pub framebuffer: bool,
+    /// Whether the debug shell is offered on the serial port.
+    pub shell: bool,
+    /// What happens after a panic.
+    pub panic_action: PanicAction,
+    /// The size in bytes of the in-memory ring of recent log records.
+    pub log_buffer_size: usize,
+}
+
+impl BootConfig {
+    /// The configuration used when no options are given.
+    pub const DEFAULT: Self = Self {
+        log_filter: LogFilter::new(log::LevelFilter::Trace),
+        serial_port: Some(0x3F8),
+        serial_baud_rate: 115_200,
+        install_hooks: true,
+        processors: ProcessorSelection::All,
+        timestamps: false,
+        processor_ids: false,
+        debugcon: false,
+        framebuffer: false,
+        shell: false,
+        panic_action: PanicAction::Halt,
+        log_buffer_size: 16 * 1024,
+    };
+
+    /// Applies the setting `name`, with its `value` if it has one, to this configuration.
+    ///
+    /// Returns `false` if the setting is not recognized or its value is invalid.
+    fn apply(&mut self, name: &str, value: Option<&str>) -> bool {
+        match (name, value) {
+            ("log", Some(spec)) => match spec.parse() {
+                Ok(filter) => {
+                    self.log_filter = filter;
+                    true
+                }
+                Err(error) => {
+                    log::warn!("invalid log filter `{spec}`: {error}");
+                    false
+                }
+            },
+            ("serial", Some("off")) => {
+                self.serial_port = None;
+                true
+            }
+            ("serial", Some(port)) => parse_port(port)
+                .map(|port| self.serial_port = Some(port))
+                .is_some(),
+            ("serial-baud", Some(value)) => value
+                .parse()
+                .map(|baud_rate| self.serial_baud_rate = baud_rate)
+                .is_ok(),
+            ("hooks", Some(value)) => value
+                .parse()
+                .map(|install| self.install_hooks = install)
+                .is_ok(),
+            ("timestamps", Some(value)) => value
+                .parse()
+                .map(|timestamps| self.timestamps = timestamps)
+                .is_ok(),
+            ("cpu-ids", Some(value)) => value
+                .parse()
+                .map(|processor_ids| self.processor_ids = processor_ids)
+                .is_ok(),
+            ("debugcon", Some(value)) => value
+                .parse()
+                .map(|debugcon| self.debugcon = debugcon)
+                .is_ok(),
+            ("framebuffer", Some(value)) => value
+                .parse()
+                .map(|framebuffer| self.framebuffer = framebuffer)
+                .is_ok(),
+            ("shell", Some("serial")) => {
+                self.shell = true;
+                true
+            }
+            ("shell", Some("off")) => {
+                self.shell = false;
+                true
+            }
+            ("panic", Some("halt")) => {
+                self.panic_action = PanicAction::Halt;
+                true
+            }
+            ("panic", Some("reboot")) => {
+                self.panic_action = PanicAction::Reboot;
+                true
+            }
+            ("log-buffer", Some(size)) => size
+                .parse::<usize>()
+                .ok()
+                .and_then(|kibibytes| kibibytes.checked_mul(1024))
+                .map(|size| self.log_buffer_size = size)
+                .is_some(),
+            ("no-hook", None) => {
+                self.install_hooks = false;
+                true
+            }
+            ("cpus", Some("all")) => {
+                self.processors = ProcessorSelection::All;
+                true
+            }
+            ("cpus", Some("bsp-only")) => {
+                self.processors = ProcessorSelection::BootOnly;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The processors the driver virtualizes.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ProcessorSelection {
+    /// Every enabled processor.
+    All,
+    /// Only the bootstrap processor.
+    BootOnly,
+}
+
+/// What happens after a panic.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Halt until the machine is reset manually.
+    Halt,
+    /// Reboot after a delay.
+    Reboot,
+}
+
+/// Reads the configuration file and then the driver's load options, making the resulting
+/// [`BootConfig`] the one in effect.
+///
+/// A missing configuration file is not an error. Settings that cannot be read or parsed are
+/// reported and otherwise ignored.
+pub fn load() -> BootConfig {
+    let mut config = BootConfig::DEFAULT;
+    match boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle()) {
+        Ok(loaded_image) => {
+            if let Err(error) = read_config_file(&loaded_image, &mut config) {
+                log::warn!("failed to read {CONFIG_FILE_NAME}: {error}");
+            }
+
+            let options = loaded_image.load_options_as_bytes().unwrap_or(&[]);
+            parse_load_options(options, &mut config);
+        }
+        Err(error) => log::warn!("failed to read load options: {error}"),
+    }
+
+    *CONFIG.lock() = config;
+    config
+}
+
+/// Parses the configuration file in the directory holding the driver image into `config`.
+///
+/// # Errors
+/// Returns an error if the file exists but cannot be read.
+fn read_config_file(loaded_image: &LoadedImage, config: &mut BootConfig) -> uefi::Result<()> {
+    let mut path = [0u16; MAX_PATH_LENGTH];
+    let Some(path) = config_file_path(loaded_image, &mut path) else {
+        log::debug!("the driver image path is unknown: skipping {CONFIG_FILE_NAME}");
+        return Ok(());
+    };
+    let Some(device) = loaded_image.device() else {
+        return Ok(());
+    };
+
+    let mut file_system = boot::open_protocol_exclusive::<SimpleFileSystem>(device)?;
+    let mut root = file_system.open_volume()?;
+    let file = match root.open(path, FileMode::Read, FileAttribute::empty()) {
+        Ok(file) => file,
+        Err(error) if error.status() == uefi::Status::NOT_FOUND => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    let Some(mut file) = file.into_regular_file() else {
+        return Err(uefi::Status::INVALID_PARAMETER.into());
+    };
+
+    file.set_position(RegularFile::END_OF_FILE)?;
+    let size = usize::try_from(file.get_position()?).unwrap_or(usize::MAX);
+    if size > MAX_CONFIG_FILE_SIZE {
+        log::warn!("{CONFIG_FILE_NAME} is larger than {MAX_CONFIG_FILE_SIZE} bytes: ignoring it");
+        return Ok(());
+    }
+    file.set_position(0)?;
+
+    let frames = size.div_ceil(FRAME_SIZE).max(1);
+    let buffer = allocate_frames(frames, MemoryKind::Scratch)
+        .map_err(|_| uefi::Error::from(uefi::Status::OUT_OF_RESOURCES))?;
+    // SAFETY:
+    // `buffer` points to `frames` freshly allocated frames, which hold at least `size` bytes.
+    let contents = unsafe { core::slice::from_raw_parts_mut(buffer.as_ptr(), size) };
+    let result = file.read(contents).map(|read| {
+        parse_config_file(&contents[..read], config);
+    });
+
+    // SAFETY:
+    // `buffer` was allocated above for `frames` frames and is no longer used.
+    unsafe { deallocate_frames(buffer, frames) };
+    result
+}
+
+/// Writes the null-terminated path of the configuration file, which lies in the same directory as
+/// the driver image, into `buffer`.
+///
+/// Returns [`None`] if the image has no file path or the path does not fit in `buffer`.
+fn config_file_path<'a>(
+    loaded_image: &LoadedImage,
+    buffer: &'a mut [u16; MAX_PATH_LENGTH],
+) -> Option<&'a CStr16> {
+    let mut length = 0;
+    for node in loaded_image.file_path()?.node_iter() {
+        let Ok(DevicePathNodeEnum::MediaFilePath(node)) = node.as_enum() else {
+            continue;
+        };
+
+        for character in node.path_name().into_iter().take_while(|&unit| unit != 0) {
+            *buffer.get_mut(length)? = character;
+            length += 1;
+        }
+    }
+
+    // Everything after the last separator is the image's file name.
+    let directory = buffer[..length]
+        .iter()
+        .rposition(|&unit| unit == u16::from(b'\\'))
+        .map_or(0, |separator| separator + 1);
+
+    let mut length = directory;
+    for character in CONFIG_FILE_NAME.encode_utf16().chain([0]) {
+        *buffer.get_mut(length)? = character;
+        length += 1;
+    }
+
+    CStr16::from_u16_with_nul(&buffer[..length]).ok()
+}
+
+/// Parses `contents`, the UTF-8 text of a configuration file, into `config`.
+///
+/// Malformed lines and unknown settings are reported with their line numbers and otherwise
+/// ignored.
+pub fn parse_config_file(contents: &[u8], config: &mut BootConfig) {
+    for (index, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        let number = index + 1;
+        let Ok(line) = core::str::from_utf8(line) else {
+            log::warn!("{CONFIG_FILE_NAME}:{number}: line is not valid UTF-8");
+            continue;
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            log::warn!("{CONFIG_FILE_NAME}:{number}: expected `key = value`, found `{line}`");
+            continue;
+        };
+
+        let (key, value) = (key.trim(), value.trim());
+        if key == "no-hook" || !config.apply(key, Some(value)) {
+            log::warn!("{CONFIG_FILE_NAME}:{number}: ignoring invalid setting `{key} = {value}`");
+        }
+    }
+}
+
+/// Returns the configuration in effect.
+pub fn current() -> BootConfig {
+    *CONFIG.lock()
+}
+
+/// Returns what happens after a panic.
+///
+/// This does not wait for the configuration to be unlocked, so it may be called while panicking,
+/// and falls back to [`PanicAction::Halt`] if the configuration is locked.
+pub fn panic_action() -> PanicAction {
+    CONFIG
+        .try_lock()
+        .map_or(PanicAction::Halt, |config| config.panic_action)
+}
+
+/// Parses `options`, a little-endian UCS-2 command line, into `config`.
+///
+/// Parsing stops at the first null character. A leading path ending in `.efi`, which the UEFI
+/// shell passes as the name of the image, is skipped.
+pub fn parse_load_options(options: &[u8], config: &mut BootConfig) {
+    let characters = char::decode_utf16(
+        options
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0),
+    )
+    .map(|character| character.unwrap_or(char::REPLACEMENT_CHARACTER));
+
+    let mut options = Options { characters };
+    let mut first = true;
+    while let Some(option) = options.next_option() {
+        let Some(text) = option.as_str() else {
+            log::warn!("ignoring load option longer than {MAX_OPTION_LENGTH} bytes");
+            continue;
+        };
+
+        let is_image_path = first
+            && text.len() >= 4
+            && text.as_bytes()[text.len() - 4..].eq_ignore_ascii_case(b".efi");
+        first = false;
+        if is_image_path {
+            continue;
+        }
+
+        let (name, value) = match text.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (text, None),
+        };
+        if !config.apply(name, value) {
+            log::warn!("ignoring unknown load option `{text}`");
+        }
+    }
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal I/O port, or the name of a standard COM port.
+fn parse_port(port: &str) -> Option<u16> {
+    const COM_PORTS: [(&str, u16); 4] = [
+        ("com1", 0x3F8),
+        ("com2", 0x2F8),
+        ("com3", 0x3E8),
+        ("com4", 0x2E8),
+    ];
+    if let Some(&(_, io_port)) = COM_PORTS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(port))
+    {
+        return Some(io_port);
+    }
+
+    match port.strip_prefix("0x").or_else(|| port.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => port.parse().ok(),
+    }
+}
+
+/// Splits a command line into options.
+struct Options<I> {
+    /// The remaining characters of the command line.
+    characters: I,
+}
+
+impl<I: Iterator<Item = char>> Options<I> {
+    /// Returns the next option, with any double quotes removed, or [`None`] at the end of the
+    /// command line.
+    fn next_option(&mut self) -> Option<OptionBuffer> {
+        let mut option = OptionBuffer::new();
+        let mut started = false;
+        let mut quoted = false;
+
+        for character in self.characters.by_ref() {
+            match character {
+                '"' => {
+                    quoted = !quoted;
+                    started = true;
+                }
+                character if character.is_whitespace() && !quoted => {
+                    if started {
+                        return Some(option);
+                    }
+                }
+                character => {
+                    option.push(character);
+                    started = true;
+                }
+            }
+        }
+
+        started.then_some(option)
+    }
+}
+
+/// A single option, stored without allocating.
+struct OptionBuffer {
+    /// The UTF-8 encoded option.
+    bytes: [u8; MAX_OPTION_LENGTH],
+    /// The number of valid bytes in `bytes`.
+    length: usize,
+    /// Whether the option did not fit in `bytes`.
+    overflowed: bool,
+}
+
+impl OptionBuffer {
+    /// Creates an empty [`OptionBuffer`].
+    const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_OPTION_LENGTH],
+            length: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Appends `character` to the option.
+    fn push(&mut self, character: char) {
+        let length = character.len_utf8();
+        match self.bytes.get_mut(self.length..self.length + length) {
+            Some(bytes) => {
+                character.encode_utf8(bytes);
+                self.length += length;
+            }
+            None => self.overflowed = true,
+        }
+    }
+
+    /// Returns the option, or [`None`] if it did not fit.
+    fn as_str(&self) -> Option<&str> {
+        if self.overflowed {
+            return None;
+        }
+
+        core::str::from_utf8(&self.bytes[..self.length]).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `text` as a little-endian UCS-2 command line.
+    fn ucs2(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    /// Returns the configuration resulting from the load options `text`.
+    fn from_load_options(text: &str) -> BootConfig {
+        let mut config = BootConfig::DEFAULT;
+        parse_load_options(&ucs2(text), &mut config);
+        config
+    }
+
+    #[test]
+    fn load_options_apply_settings() {
+        let config = from_load_options("no-hook serial=com2 cpus=bsp-only log-buffer=4");
+
+        assert!(!config.install_hooks);
+        assert_eq!(config.serial_port, Some(0x2F8));
+        assert_eq!(config.processors, ProcessorSelection::BootOnly);
+        assert_eq!(config.log_buffer_size, 4 * 1024);
+    }
+
+    #[test]
+    fn load_options_skip_the_image_path() {
+        let config = from_load_options(r"fs0:\EFI\Boot\BOOT-MANIPULATOR.EFI   serial=off");
+        assert_eq!(config.serial_port, None);
+
+        // Only the first option may be the image path.
+        let config = from_load_options(r"timestamps=true fs0:\driver.efi");
+        assert_eq!(
+            config,
+            BootConfig {
+                timestamps: true,
+                ..BootConfig::DEFAULT
+            }
+        );
+    }
+
+    #[test]
+    fn load_options_honor_quotes() {
+        let config = from_load_options(r#""fs0:\my driver.efi" "log=info" serial="0x2E8""#);
+
+        assert_eq!(config.log_filter, "info".parse().unwrap());
+        assert_eq!(config.serial_port, Some(0x2E8));
+    }
+
+    #[test]
+    fn load_options_stop_at_the_first_null() {
+        let mut options = ucs2("cpu-ids=true");
+        options.extend_from_slice(&[0, 0]);
+        options.extend_from_slice(&ucs2(" no-hook"));
+
+        let mut config = BootConfig::DEFAULT;
+        parse_load_options(&options, &mut config);
+        assert!(config.processor_ids);
+        assert!(config.install_hooks);
+    }
+
+    #[test]
+    fn load_options_ignore_invalid_options() {
+        let long = format!("log={}", "a".repeat(MAX_OPTION_LENGTH));
+        let config = from_load_options(&format!(
+            "serial=com9 hooks=maybe panic {long} frobnicate debugcon=true"
+        ));
+
+        assert_eq!(
+            config,
+            BootConfig {
+                debugcon: true,
+                ..BootConfig::DEFAULT
+            }
+        );
+    }
+
+    #[test]
+    fn load_options_tolerate_odd_lengths_and_unpaired_surrogates() {
+        let mut options = ucs2("framebuffer=true ");
+        options.extend_from_slice(&0xD800u16.to_le_bytes());
+        options.push(b'x');
+
+        let mut config = BootConfig::DEFAULT;
+        parse_load_options(&options, &mut config);
+        assert!(config.framebuffer);
+    }
+
+    #[test]
+    fn ports_accept_names_and_numbers() {
+        assert_eq!(parse_port("COM1"), Some(0x3F8));
+        assert_eq!(parse_port("com4"), Some(0x2E8));
+        assert_eq!(parse_port("0x3f8"), Some(0x3F8));
+        assert_eq!(parse_port("0X2F8"), Some(0x2F8));
+        assert_eq!(parse_port("1016"), Some(0x3F8));
+        assert_eq!(parse_port("0x10000"), None);
+        assert_eq!(parse_port("com5"), None);
+    }
+
+    /// Returns the configuration resulting from the configuration file `contents`.
+    fn from_config_file(contents: &[u8]) -> BootConfig {
+        let mut config = BootConfig::DEFAULT;
+        parse_config_file(contents, &mut config);
+        config
+    }
+
+    #[test]
+    fn config_file_applies_settings() {
+        let config = from_config_file(
+            b"# Serial logging\n\
+              serial = 0x2F8\n\
+              serial-baud=9600\n\
+              \n\
+              \tpanic =reboot \r\n\
+              shell= serial\n",
+        );
+
+        assert_eq!(
+            config,
+            BootConfig {
+                serial_port: Some(0x2F8),
+                serial_baud_rate: 9600,
+                panic_action: PanicAction::Reboot,
+                shell: true,
+                ..BootConfig::DEFAULT
+            }
+        );
+    }
+
+    #[test]
+    fn config_file_ignores_invalid_lines() {
+        let config = from_config_file(
+            b"no-hook\n\
+              no-hook = true\n\
+              hooks\n\
+              cpus = some\n\
+              \xFF\xFE = 1\n\
+              = true\n\
+              timestamps = true\n",
+        );
+
+        assert_eq!(
+            config,
+            BootConfig {
+                timestamps: true,
+                ..BootConfig::DEFAULT
+            }
+        );
+    }
+
+    #[test]
+    fn config_file_splits_at_the_first_equals_sign() {
+        let config = from_config_file(b"log = info,boot_manipulator::shell=trace");
+        assert_eq!(
+            config.log_filter,
+            "info,boot_manipulator::shell=trace".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn load_options_override_the_config_file() {
+        let mut config = BootConfig::DEFAULT;
+        parse_config_file(b"cpu-ids = true\nhooks = true\n", &mut config);
+        parse_load_options(&ucs2("no-hook"), &mut config);
+
+        assert!(config.processor_ids);
+        assert!(!config.install_hooks);
+    }
+}
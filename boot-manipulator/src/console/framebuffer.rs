@@ -0,0 +1,314 @@
+//! A text console drawn into the linear framebuffer of the Graphics Output Protocol.
+//!
+//! The mode of the framebuffer is queried once while boot services are active, after which the
+//! console only writes to the framebuffer's memory, so it keeps working after `ExitBootServices()`.
+//! The framebuffer is written at its physical address.
+
+use core::{fmt, ptr};
+
+use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
+    proto::console::gop::{GraphicsOutput, PixelFormat},
+    Status,
+};
+
+use crate::{
+    console::{
+        font::{self, GLYPH_HEIGHT, GLYPH_WIDTH},
+        Console, ReadError, WriteError,
+    },
+    exit_boot_services,
+    spinlock::Spinlock,
+};
+
+/// The color text is drawn in, as red, green and blue.
+const FOREGROUND: (u8, u8, u8) = (0xC0, 0xC0, 0xC0);
+/// The color behind the text, as red, green and blue.
+const BACKGROUND: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+/// The number of columns a tab advances to the next multiple of.
+const TAB_WIDTH: usize = 8;
+
+/// The console drawn into the framebuffer, which is unusable until [`initialize`] succeeded.
+pub static FRAMEBUFFER_CONSOLE: Spinlock<FramebufferConsole> =
+    Spinlock::new(FramebufferConsole::new());
+
+/// Saves the current mode of the Graphics Output Protocol for [`FRAMEBUFFER_CONSOLE`], which must
+/// be called while boot services are active.
+///
+/// # Errors
+/// Returns a [`FramebufferError`] if there is no Graphics Output Protocol or its pixels cannot be
+/// written directly.
+pub fn initialize() -> Result<(), FramebufferError> {
+    let handle = boot::get_handle_for_protocol::<GraphicsOutput>()
+        .map_err(|error| FramebufferError::Unavailable(error.status()))?;
+    // SAFETY:
+    // The protocol is only used to read its mode, and is opened without exclusive access so that
+    // the firmware's console keeps drawing through it.
+    let mut gop = unsafe {
+        boot::open_protocol::<GraphicsOutput>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .map_err(|error| FramebufferError::Unavailable(error.status()))?;
+
+    let mode = gop.current_mode_info();
+    let order = match mode.pixel_format() {
+        PixelFormat::Rgb => PixelOrder::Rgb,
+        PixelFormat::Bgr => PixelOrder::Bgr,
+        format => return Err(FramebufferError::UnsupportedPixelFormat(format)),
+    };
+    let (width, height) = mode.resolution();
+    let framebuffer = Framebuffer {
+        base: gop.frame_buffer().as_mut_ptr() as u64,
+        width,
+        height,
+        stride: mode.stride(),
+        order,
+    };
+
+    let mut console = FRAMEBUFFER_CONSOLE.lock();
+    console.framebuffer = Some(framebuffer);
+    console.column = 0;
+    console.row = 0;
+    console.cleared = false;
+
+    Ok(())
+}
+
+/// A text console drawn into the framebuffer, which has no input.
+pub struct FramebufferConsole {
+    /// The framebuffer text is drawn into, or [`None`] before [`initialize`] succeeded.
+    framebuffer: Option<Framebuffer>,
+    /// The column the next character is drawn at.
+    column: usize,
+    /// The row the next character is drawn at.
+    row: usize,
+    /// Whether the screen was cleared of the firmware's output.
+    cleared: bool,
+}
+
+impl FramebufferConsole {
+    /// Creates a [`FramebufferConsole`] without a framebuffer.
+    pub const fn new() -> Self {
+        Self {
+            framebuffer: None,
+            column: 0,
+            row: 0,
+            cleared: false,
+        }
+    }
+
+    /// Draws `character` at the cursor and advances it, handling newlines, carriage returns and
+    /// tabs.
+    fn put(&mut self, framebuffer: &Framebuffer, character: char) {
+        match character {
+            '\n' => self.newline(framebuffer),
+            '\r' => self.column = 0,
+            '\t' => {
+                let column = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < column && self.column < framebuffer.columns() {
+                    self.put(framebuffer, ' ');
+                }
+            }
+            _ => {
+                if self.column >= framebuffer.columns() {
+                    self.newline(framebuffer);
+                }
+
+                framebuffer.draw_glyph(self.column, self.row, font::glyph(character));
+                self.column += 1;
+            }
+        }
+    }
+
+    /// Moves the cursor to the start of the next row, scrolling if it is past the last row.
+    fn newline(&mut self, framebuffer: &Framebuffer) {
+        self.column = 0;
+        if self.row + 1 < framebuffer.rows() {
+            self.row += 1;
+        } else {
+            framebuffer.scroll();
+        }
+    }
+}
+
+impl Default for FramebufferConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    /// Returns `true` once boot services have exited, as the firmware draws its own console into
+    /// the framebuffer until then.
+    fn is_available(&self) -> bool {
+        self.framebuffer.is_some() && exit_boot_services::has_exited()
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        Ok(0)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        let Some(framebuffer) = self.framebuffer else {
+            return Err((WriteError::Unavailable, 0));
+        };
+        if framebuffer.rows() == 0 || framebuffer.columns() == 0 {
+            return Ok(());
+        }
+
+        if !self.cleared {
+            framebuffer.clear();
+            self.cleared = true;
+        }
+
+        for chunk in data.utf8_chunks() {
+            chunk
+                .valid()
+                .chars()
+                .for_each(|character| self.put(&framebuffer, character));
+            if !chunk.invalid().is_empty() {
+                self.put(&framebuffer, char::REPLACEMENT_CHARACTER);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The mode of the framebuffer saved by [`initialize`].
+#[derive(Clone, Copy, Debug)]
+struct Framebuffer {
+    /// The physical address of the first pixel.
+    base: u64,
+    /// The number of visible pixels in a scan line.
+    width: usize,
+    /// The number of scan lines.
+    height: usize,
+    /// The number of pixels between the starts of consecutive scan lines.
+    stride: usize,
+    /// The order of the color channels of a pixel.
+    order: PixelOrder,
+}
+
+impl Framebuffer {
+    /// Returns the number of characters in a row of text.
+    fn columns(&self) -> usize {
+        self.width / GLYPH_WIDTH
+    }
+
+    /// Returns the number of rows of text.
+    fn rows(&self) -> usize {
+        self.height / GLYPH_HEIGHT
+    }
+
+    /// Returns a pointer to the pixel at `x` and `y`.
+    fn pixel(&self, x: usize, y: usize) -> *mut u32 {
+        (self.base as *mut u32).wrapping_add(y * self.stride + x)
+    }
+
+    /// Draws `glyph` into the cell at `column` and `row`.
+    fn draw_glyph(&self, column: usize, row: usize, glyph: &[u8; 8]) {
+        let foreground = self.order.encode(FOREGROUND);
+        let background = self.order.encode(BACKGROUND);
+        for y in 0..GLYPH_HEIGHT {
+            let bits = glyph[y / (GLYPH_HEIGHT / glyph.len())];
+            for x in 0..GLYPH_WIDTH {
+                let color = if bits & (0x80 >> x) != 0 {
+                    foreground
+                } else {
+                    background
+                };
+
+                let pixel = self.pixel(column * GLYPH_WIDTH + x, row * GLYPH_HEIGHT + y);
+                // SAFETY:
+                // The cell lies within the framebuffer reported by the Graphics Output Protocol.
+                unsafe { pixel.write_volatile(color) }
+            }
+        }
+    }
+
+    /// Moves every row of text up by one and clears the last row.
+    fn scroll(&self) {
+        let rows = self.rows();
+        let pixels = (rows - 1) * GLYPH_HEIGHT * self.stride;
+        // SAFETY:
+        // Both ranges lie within the framebuffer reported by the Graphics Output Protocol, and
+        // `ptr::copy` allows them to overlap.
+        unsafe { ptr::copy(self.pixel(0, GLYPH_HEIGHT), self.pixel(0, 0), pixels) }
+
+        self.fill((rows - 1) * GLYPH_HEIGHT, rows * GLYPH_HEIGHT);
+    }
+
+    /// Fills the whole framebuffer with the background color.
+    fn clear(&self) {
+        self.fill(0, self.height);
+    }
+
+    /// Fills the scan lines from `start` up to `end` with the background color.
+    fn fill(&self, start: usize, end: usize) {
+        let background = self.order.encode(BACKGROUND);
+        for y in start..end {
+            for x in 0..self.width {
+                // SAFETY:
+                // The pixel lies within the framebuffer reported by the Graphics Output Protocol.
+                unsafe { self.pixel(x, y).write_volatile(background) }
+            }
+        }
+    }
+}
+
+/// The order of the color channels in the bytes of a 32-bit pixel.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum PixelOrder {
+    /// Red, green, blue and a reserved byte.
+    Rgb,
+    /// Blue, green, red and a reserved byte.
+    Bgr,
+}
+
+impl PixelOrder {
+    /// Returns the pixel value of the color `(red, green, blue)`.
+    fn encode(self, (red, green, blue): (u8, u8, u8)) -> u32 {
+        let bytes = match self {
+            Self::Rgb => [red, green, blue, 0],
+            Self::Bgr => [blue, green, red, 0],
+        };
+
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Various errors that can occur while initializing the [`FramebufferConsole`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramebufferError {
+    /// The Graphics Output Protocol could not be opened.
+    Unavailable(Status),
+    /// The pixels of the current mode are neither RGB nor BGR with 8 bits per channel.
+    UnsupportedPixelFormat(PixelFormat),
+}
+
+impl fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(status) => {
+                write!(f, "failed to open the Graphics Output Protocol: {status}")
+            }
+            Self::UnsupportedPixelFormat(format) => {
+                write!(f, "unsupported framebuffer pixel format {format:?}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for FramebufferError {}
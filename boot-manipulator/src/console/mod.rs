@@ -0,0 +1,377 @@
+//! Console manipulation.
+//!
+//! Consoles are byte streams: writes take UTF-8 and reads produce UTF-8, with special keys
+//! translated to ANSI escape sequences. Every console is registered with [`register`] so that
+//! consumers can enumerate them with [`for_each`].
+
+use core::{error::Error, fmt, time::Duration};
+
+use uefi::{
+    proto::console::text::{Key, ScanCode},
+    CStr16, Status,
+};
+
+use crate::{exit_boot_services, spinlock::Spinlock, time};
+
+mod font;
+pub mod framebuffer;
+pub mod mux;
+
+/// The maximum number of consoles that can be registered.
+const MAX_CONSOLES: usize = 4;
+
+/// The registered consoles.
+static CONSOLES: Spinlock<[Option<&'static Spinlock<dyn Console>>; MAX_CONSOLES]> =
+    Spinlock::new([None; MAX_CONSOLES]);
+
+/// The firmware's text console.
+pub static UEFI_TEXT_CONSOLE: Spinlock<UefiTextConsole> = Spinlock::new(UefiTextConsole::new());
+
+/// A bidirectional byte stream.
+///
+/// Operations that fail after making progress return the error along with the number of bytes
+/// that were transferred before it occurred.
+pub trait Console: Send {
+    /// Returns a short name describing the console, such as `serial`.
+    fn name(&self) -> &'static str;
+
+    /// Returns `true` if the console can currently be used.
+    ///
+    /// Unavailable consoles are skipped by the [`mux::ConsoleMux`] without counting as failures.
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    /// Reads the bytes that are immediately available into `data`, returning their number.
+    ///
+    /// This function does not block: it returns `Ok(0)` if no input is pending.
+    ///
+    /// # Errors
+    /// Returns a [`ReadError`] and the number of bytes read before it occurred if the console
+    /// could not be read.
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)>;
+
+    /// Writes all of `data`.
+    ///
+    /// # Errors
+    /// Returns a [`WriteError`] and the number of bytes written before it occurred if the console
+    /// could not be written.
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)>;
+
+    /// Reads into `data` like [`Console::read`], waiting up to `timeout` for input to arrive.
+    ///
+    /// Without a calibrated counter, input is only polled once.
+    ///
+    /// # Errors
+    /// Returns [`ReadError::TimedOut`] if no input arrived within `timeout`, or a [`ReadError`]
+    /// and the number of bytes read before it occurred if the console could not be read.
+    fn read_timeout(
+        &mut self,
+        data: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, (ReadError, usize)> {
+        let start = time::ticks();
+        loop {
+            let count = self.read(data)?;
+            if count != 0 || data.is_empty() {
+                return Ok(count);
+            }
+
+            let elapsed = time::ticks_to_duration(time::ticks().wrapping_sub(start));
+            if elapsed.is_none_or(|elapsed| elapsed >= timeout) {
+                return Err((ReadError::TimedOut, 0));
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Adds `console` to the consoles enumerated by [`for_each`].
+///
+/// # Errors
+/// Returns [`RegistryFull`] if [`MAX_CONSOLES`] consoles are already registered.
+pub fn register(console: &'static Spinlock<dyn Console>) -> Result<(), RegistryFull> {
+    let mut consoles = CONSOLES.lock();
+    let slot = consoles
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegistryFull)?;
+    *slot = Some(console);
+
+    Ok(())
+}
+
+/// Calls `f` with each registered console, in the order they were registered.
+#[allow(dead_code)]
+pub fn for_each(mut f: impl FnMut(&'static Spinlock<dyn Console>)) {
+    // Copy the registry so that `f` may register consoles itself.
+    registered().into_iter().flatten().for_each(&mut f);
+}
+
+/// Returns a copy of the registry, in which consoles keep the index they were registered at.
+fn registered() -> [Option<&'static Spinlock<dyn Console>>; MAX_CONSOLES] {
+    *CONSOLES.lock()
+}
+
+/// The firmware's text console, which is only usable while boot services are active.
+pub struct UefiTextConsole {
+    /// The bytes of the last key that did not fit into the caller's buffer.
+    pending: KeyBytes,
+}
+
+impl UefiTextConsole {
+    /// Creates a [`UefiTextConsole`] with no pending input.
+    pub const fn new() -> Self {
+        Self {
+            pending: KeyBytes::EMPTY,
+        }
+    }
+}
+
+impl Default for UefiTextConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console for UefiTextConsole {
+    fn name(&self) -> &'static str {
+        "UEFI text"
+    }
+
+    fn is_available(&self) -> bool {
+        !exit_boot_services::has_exited()
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        let mut count = self.pending.take(data);
+        while count < data.len() {
+            if exit_boot_services::has_exited() {
+                return Err((ReadError::Unavailable, count));
+            }
+
+            let key = uefi::system::with_stdin(|stdin| stdin.read_key())
+                .map_err(|error| (ReadError::Device(error.status()), count))?;
+            let Some(key) = key else {
+                break;
+            };
+
+            self.pending = KeyBytes::from_key(key);
+            count += self.pending.take(&mut data[count..]);
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        let mut buffer = Ucs2Buffer::new();
+        // The number of bytes of `data` that were converted into `buffer` or written.
+        let mut converted = 0;
+        // The number of bytes of `data` that were written.
+        let mut written = 0;
+        for chunk in data.utf8_chunks() {
+            let replacement =
+                (!chunk.invalid().is_empty()).then_some(('\u{FFFD}', chunk.invalid()));
+            let characters = chunk
+                .valid()
+                .chars()
+                .map(|character| (character, character.len_utf8()))
+                .chain(replacement.map(|(character, invalid)| (character, invalid.len())));
+            for (character, length) in characters {
+                if !buffer.push(character) {
+                    buffer.flush().map_err(|error| (error, written))?;
+                    written = converted;
+                    buffer.push(character);
+                }
+                converted += length;
+            }
+        }
+
+        buffer.flush().map_err(|error| (error, written))
+    }
+}
+
+/// The bytes a key press is translated to.
+struct KeyBytes {
+    /// The translated bytes.
+    bytes: [u8; 4],
+    /// The offset of the first byte not yet returned.
+    start: usize,
+    /// The number of valid bytes in `bytes`.
+    end: usize,
+}
+
+impl KeyBytes {
+    /// A key press that translates to no bytes.
+    const EMPTY: Self = Self::new(&[]);
+
+    /// Creates a [`KeyBytes`] holding `bytes`, which must be at most 4 bytes long.
+    const fn new(bytes: &[u8]) -> Self {
+        let mut buffer = [0; 4];
+        let mut index = 0;
+        while index < bytes.len() {
+            buffer[index] = bytes[index];
+            index += 1;
+        }
+
+        Self {
+            bytes: buffer,
+            start: 0,
+            end: bytes.len(),
+        }
+    }
+
+    /// Translates `key` to UTF-8, or to the ANSI escape sequence of a special key.
+    ///
+    /// Special keys without an escape sequence translate to no bytes.
+    fn from_key(key: Key) -> Self {
+        let sequence: &[u8] = match key {
+            Key::Printable(character) => {
+                return Self::new(char::from(character).encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            Key::Special(ScanCode::UP) => b"\x1b[A",
+            Key::Special(ScanCode::DOWN) => b"\x1b[B",
+            Key::Special(ScanCode::RIGHT) => b"\x1b[C",
+            Key::Special(ScanCode::LEFT) => b"\x1b[D",
+            Key::Special(ScanCode::HOME) => b"\x1b[H",
+            Key::Special(ScanCode::END) => b"\x1b[F",
+            Key::Special(ScanCode::INSERT) => b"\x1b[2~",
+            Key::Special(ScanCode::DELETE) => b"\x1b[3~",
+            Key::Special(ScanCode::ESCAPE) => b"\x1b",
+            Key::Special(_) => b"",
+        };
+
+        Self::new(sequence)
+    }
+
+    /// Moves as many bytes as fit into `data`, returning their number.
+    fn take(&mut self, data: &mut [u8]) -> usize {
+        let count = data.len().min(self.end - self.start);
+        data[..count].copy_from_slice(&self.bytes[self.start..self.start + count]);
+        self.start += count;
+        count
+    }
+}
+
+/// A null-terminated UCS-2 string written to the firmware's standard output in pieces.
+struct Ucs2Buffer {
+    /// The characters followed by a null terminator.
+    units: [u16; Self::CAPACITY + 1],
+    /// The number of characters in `units`.
+    length: usize,
+}
+
+impl Ucs2Buffer {
+    /// The maximum number of characters written at once.
+    const CAPACITY: usize = 128;
+
+    /// Creates an empty [`Ucs2Buffer`].
+    fn new() -> Self {
+        Self {
+            units: [0; Self::CAPACITY + 1],
+            length: 0,
+        }
+    }
+
+    /// Appends `character`, returning `false` if it does not fit.
+    ///
+    /// Newlines are expanded to carriage return and newline, and characters outside the Basic
+    /// Multilingual Plane as well as null characters are replaced with U+FFFD.
+    fn push(&mut self, character: char) -> bool {
+        let units: &[u16] = match character {
+            '\n' => &[b'\r' as u16, b'\n' as u16],
+            '\0' => &[0xFFFD],
+            _ => &[u16::try_from(u32::from(character)).unwrap_or(0xFFFD)],
+        };
+        let Some(slots) =
+            self.units[..Self::CAPACITY].get_mut(self.length..self.length + units.len())
+        else {
+            return false;
+        };
+
+        slots.copy_from_slice(units);
+        self.length += units.len();
+        true
+    }
+
+    /// Writes the characters to the firmware's standard output and empties the buffer.
+    fn flush(&mut self) -> Result<(), WriteError> {
+        if self.length == 0 {
+            return Ok(());
+        }
+        if exit_boot_services::has_exited() {
+            return Err(WriteError::Unavailable);
+        }
+
+        self.units[self.length] = 0;
+        // SAFETY:
+        // `push` never stores a null character, and the characters are followed by a null
+        // terminator.
+        let string = unsafe { CStr16::from_u16_with_nul_unchecked(&self.units[..=self.length]) };
+        self.length = 0;
+
+        uefi::system::with_stdout(|stdout| stdout.output_string(string))
+            .map_err(|error| WriteError::Device(error.status()))
+    }
+}
+
+/// Various errors that can occur while reading from a [`Console`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ReadError {
+    /// The console is not usable, such as after `ExitBootServices()` or before it was set up.
+    Unavailable,
+    /// No input arrived before the timeout expired.
+    TimedOut,
+    /// A received byte was corrupted by a parity, framing or overrun error and dropped.
+    Corrupted,
+    /// The device reported an error.
+    Device(Status),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable => write!(f, "the console is unavailable"),
+            Self::TimedOut => write!(f, "timed out waiting for console input"),
+            Self::Corrupted => write!(f, "console input was corrupted"),
+            Self::Device(status) => write!(f, "failed to read from the console: {status}"),
+        }
+    }
+}
+
+impl Error for ReadError {}
+
+/// Various errors that can occur while writing to a [`Console`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum WriteError {
+    /// The console is not usable, such as after `ExitBootServices()` or before it was set up.
+    Unavailable,
+    /// The device did not accept the data in time.
+    TimedOut,
+    /// The device reported an error.
+    Device(Status),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable => write!(f, "the console is unavailable"),
+            Self::TimedOut => write!(f, "timed out writing to the console"),
+            Self::Device(status) => write!(f, "failed to write to the console: {status}"),
+        }
+    }
+}
+
+impl Error for WriteError {}
+
+/// The error returned when [`MAX_CONSOLES`] consoles are already registered.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct RegistryFull;
+
+impl fmt::Display for RegistryFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "more than {MAX_CONSOLES} consoles registered")
+    }
+}
+
+impl Error for RegistryFull {}
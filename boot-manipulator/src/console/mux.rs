@@ -0,0 +1,422 @@
+//! A console combining every registered console.
+
+use core::fmt::{self, Write};
+
+use crate::{
+    console::{self, Console, ReadError, WriteError, MAX_CONSOLES},
+    spinlock::Spinlock,
+};
+
+/// The number of consecutive failures after which a console is skipped.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// The consoles a [`ConsoleMux`] multiplexes, indexed like the registry.
+type Consoles = [Option<&'static Spinlock<dyn Console>>; MAX_CONSOLES];
+
+/// The multiplexer over every registered console.
+pub static CONSOLE_MUX: Spinlock<ConsoleMux> = Spinlock::new(ConsoleMux::new());
+
+/// A console writing to every registered console and reading from whichever has input.
+///
+/// Consoles that are unavailable are skipped. A console that fails
+/// [`MAX_CONSECUTIVE_FAILURES`] times in a row is marked degraded and skipped from then on, which
+/// is reported once on the remaining consoles.
+pub struct ConsoleMux {
+    /// The index of the console the next read starts at.
+    next_read: usize,
+    /// The number of consecutive failures of each console.
+    failures: [u32; MAX_CONSOLES],
+    /// Whether each console is skipped after failing repeatedly.
+    degraded: [bool; MAX_CONSOLES],
+}
+
+impl ConsoleMux {
+    /// Creates a [`ConsoleMux`] with every console considered healthy.
+    pub const fn new() -> Self {
+        Self {
+            next_read: 0,
+            failures: [0; MAX_CONSOLES],
+            degraded: [false; MAX_CONSOLES],
+        }
+    }
+
+    /// Records that an operation on the console at `index` succeeded.
+    fn succeeded(&mut self, index: usize) {
+        self.failures[index] = 0;
+    }
+
+    /// Records that an operation on the console at `index` of `consoles`, called `name`, failed
+    /// with `error`, marking it degraded once it has failed too often.
+    fn failed(&mut self, consoles: &Consoles, index: usize, name: &str, error: impl fmt::Display) {
+        self.failures[index] += 1;
+        if self.failures[index] < MAX_CONSECUTIVE_FAILURES {
+            return;
+        }
+
+        self.degraded[index] = true;
+        // The mux is locked, so the warning is written to the remaining consoles directly instead
+        // of being logged.
+        let mut message = Message::new();
+        let _ = writeln!(
+            message,
+            "[WARN]: disabling the {name} console after {MAX_CONSECUTIVE_FAILURES} failures: \
+             {error}"
+        );
+        let _ = self.write_to(consoles, message.as_bytes());
+    }
+
+    /// Reads into `data` from the first of `consoles` with input, starting after the console the
+    /// previous read was satisfied by.
+    fn read_from(
+        &mut self,
+        consoles: &Consoles,
+        data: &mut [u8],
+    ) -> Result<usize, (ReadError, usize)> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        for offset in 0..MAX_CONSOLES {
+            let index = (self.next_read + offset) % MAX_CONSOLES;
+            let Some(console) = consoles[index] else {
+                continue;
+            };
+            if self.degraded[index] {
+                continue;
+            }
+
+            let mut console = console.lock();
+            if !console.is_available() {
+                continue;
+            }
+
+            let name = console.name();
+            let result = console.read(data);
+            drop(console);
+            let count = match result {
+                Ok(count) => {
+                    self.succeeded(index);
+                    count
+                }
+                Err((error, count)) => {
+                    self.failed(consoles, index, name, error);
+                    count
+                }
+            };
+            if count != 0 {
+                self.next_read = index + 1;
+                return Ok(count);
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Writes `data` to every available console of `consoles`, like [`Console::write`].
+    fn write_to(&mut self, consoles: &Consoles, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        let mut first_failure = None;
+        let mut most_written = 0;
+        let mut succeeded = false;
+        for (index, console) in consoles.iter().enumerate() {
+            let Some(console) = console else {
+                continue;
+            };
+            if self.degraded[index] {
+                continue;
+            }
+
+            let mut console = console.lock();
+            if !console.is_available() {
+                continue;
+            }
+
+            let name = console.name();
+            let result = console.write(data);
+            drop(console);
+            match result {
+                Ok(()) => {
+                    self.succeeded(index);
+                    succeeded = true;
+                }
+                Err((error, written)) => {
+                    self.failed(consoles, index, name, error);
+                    first_failure.get_or_insert(error);
+                    most_written = most_written.max(written);
+                }
+            }
+        }
+
+        match first_failure {
+            _ if succeeded => Ok(()),
+            Some(error) => Err((error, most_written)),
+            None => Err((WriteError::Unavailable, 0)),
+        }
+    }
+}
+
+impl Default for ConsoleMux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console for ConsoleMux {
+    fn name(&self) -> &'static str {
+        "multiplexed"
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+        self.read_from(&console::registered(), data)
+    }
+
+    /// Writes `data` to every available console, regardless of failures of the others.
+    ///
+    /// # Errors
+    /// Returns the first failure and the most bytes any console accepted if no console accepted
+    /// all of `data`, and [`WriteError::Unavailable`] if no console is available.
+    fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+        self.write_to(&console::registered(), data)
+    }
+}
+
+/// Buffer holding the warning about a degraded console.
+struct Message {
+    /// The text of the warning.
+    bytes: [u8; 128],
+    /// The number of valid bytes in `bytes`.
+    length: usize,
+}
+
+impl Message {
+    /// Creates an empty [`Message`].
+    fn new() -> Self {
+        Self {
+            bytes: [0; 128],
+            length: 0,
+        }
+    }
+
+    /// Returns the text of the warning.
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let available = self.bytes.len() - self.length;
+        let count = s.len().min(available);
+        self.bytes[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A console recording what is written to it and reading scripted input.
+    struct MockConsole {
+        /// The name of the console.
+        name: &'static str,
+        /// Whether the console can be used.
+        available: bool,
+        /// The input that is read, one chunk per read.
+        input: VecDeque<&'static [u8]>,
+        /// The bytes written to the console.
+        output: Vec<u8>,
+        /// The error every read and write fails with, if any.
+        error: Option<(ReadError, WriteError)>,
+        /// The number of reads and writes attempted.
+        calls: usize,
+    }
+
+    impl Console for MockConsole {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn is_available(&self) -> bool {
+            self.available
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+            self.calls += 1;
+            if let Some((error, _)) = self.error {
+                return Err((error, 0));
+            }
+
+            let Some(chunk) = self.input.pop_front() else {
+                return Ok(0);
+            };
+            data[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+            self.calls += 1;
+            if let Some((_, error)) = self.error {
+                return Err((error, data.len() / 2));
+            }
+
+            self.output.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Returns a healthy, available [`MockConsole`] called `name` that reads `input`.
+    fn mock(name: &'static str, input: &[&'static [u8]]) -> &'static Spinlock<MockConsole> {
+        Box::leak(Box::new(Spinlock::new(MockConsole {
+            name,
+            available: true,
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+            error: None,
+            calls: 0,
+        })))
+    }
+
+    /// Returns a [`MockConsole`] called `name` whose reads and writes always fail.
+    fn failing(name: &'static str) -> &'static Spinlock<MockConsole> {
+        let console = mock(name, &[]);
+        console.lock().error = Some((ReadError::Corrupted, WriteError::TimedOut));
+        console
+    }
+
+    /// Returns the text written to `console`.
+    fn output(console: &Spinlock<MockConsole>) -> String {
+        String::from_utf8(console.lock().output.clone()).unwrap()
+    }
+
+    #[test]
+    fn writes_fan_out_to_every_console() {
+        let (a, b, c) = (mock("a", &[]), mock("b", &[]), mock("c", &[]));
+        let consoles: Consoles = [Some(a), Some(b), None, Some(c)];
+        let mut mux = ConsoleMux::new();
+
+        assert_eq!(mux.write_to(&consoles, b"hello\n"), Ok(()));
+        assert_eq!(mux.write_to(&consoles, b"world\n"), Ok(()));
+
+        for console in [a, b, c] {
+            assert_eq!(output(console), "hello\nworld\n");
+        }
+    }
+
+    #[test]
+    fn unavailable_consoles_are_skipped_without_failing() {
+        let (a, b) = (mock("a", &[b"x"]), mock("b", &[]));
+        b.lock().available = false;
+        let mut mux = ConsoleMux::new();
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES + 1 {
+            assert_eq!(mux.write_to(&[Some(a), Some(b), None, None], b"."), Ok(()));
+        }
+
+        assert_eq!(b.lock().calls, 0);
+        assert!(!mux.degraded[1]);
+        assert_eq!(
+            mux.write_to(&[None, Some(b), None, None], b"."),
+            Err((WriteError::Unavailable, 0))
+        );
+        assert_eq!(
+            mux.write_to(&[None; MAX_CONSOLES], b"."),
+            Err((WriteError::Unavailable, 0))
+        );
+    }
+
+    #[test]
+    fn failing_consoles_do_not_stop_the_others() {
+        let (a, broken) = (mock("a", &[]), failing("broken"));
+        let mut mux = ConsoleMux::new();
+
+        assert_eq!(
+            mux.write_to(&[Some(broken), Some(a), None, None], b"data"),
+            Ok(())
+        );
+        assert_eq!(output(a), "data");
+        assert_eq!(
+            mux.write_to(&[Some(broken), None, None, None], b"data"),
+            Err((WriteError::TimedOut, 2))
+        );
+    }
+
+    #[test]
+    fn repeatedly_failing_consoles_are_degraded_once() {
+        let (a, broken) = (mock("a", &[]), failing("broken"));
+        let consoles: Consoles = [Some(a), Some(broken), None, None];
+        let mut mux = ConsoleMux::new();
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES + 2 {
+            assert_eq!(mux.write_to(&consoles, b"."), Ok(()));
+        }
+
+        assert!(mux.degraded[1]);
+        // The degraded console is no longer tried.
+        assert_eq!(broken.lock().calls, MAX_CONSECUTIVE_FAILURES as usize);
+        assert_eq!(
+            output(a),
+            "...[WARN]: disabling the broken console after 3 failures: timed out writing to the \
+             console\n.."
+        );
+    }
+
+    #[test]
+    fn successes_reset_the_failure_count() {
+        let broken = failing("flaky");
+        let consoles: Consoles = [Some(broken), None, None, None];
+        let mut mux = ConsoleMux::new();
+
+        for _ in 0..3 {
+            for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+                let _ = mux.write_to(&consoles, b".");
+            }
+            broken.lock().error = None;
+            assert_eq!(mux.write_to(&consoles, b"ok"), Ok(()));
+            broken.lock().error = Some((ReadError::Corrupted, WriteError::TimedOut));
+        }
+
+        assert!(!mux.degraded[0]);
+    }
+
+    #[test]
+    fn reads_round_robin_between_consoles() {
+        let a = mock("a", &[b"a1", b"a2"]);
+        let b = mock("b", &[b"b1", b"b2"]);
+        let idle = mock("idle", &[]);
+        let consoles: Consoles = [Some(a), Some(idle), Some(b), None];
+        let mut mux = ConsoleMux::new();
+
+        let mut reads = Vec::new();
+        let mut data = [0; 8];
+        loop {
+            let count = mux.read_from(&consoles, &mut data).unwrap();
+            if count == 0 {
+                break;
+            }
+            reads.push(String::from_utf8(data[..count].to_vec()).unwrap());
+        }
+
+        assert_eq!(reads, ["a1", "b1", "a2", "b2"]);
+        assert_eq!(mux.read_from(&consoles, &mut []), Ok(0));
+    }
+
+    #[test]
+    fn failing_reads_degrade_the_console() {
+        let (a, broken) = (mock("a", &[]), failing("broken"));
+        let consoles: Consoles = [Some(broken), Some(a), None, None];
+        let mut mux = ConsoleMux::new();
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            assert_eq!(mux.read_from(&consoles, &mut [0; 4]), Ok(0));
+        }
+
+        assert!(mux.degraded[0]);
+        assert_eq!(
+            output(a),
+            "[WARN]: disabling the broken console after 3 failures: console input was corrupted\n"
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! Interception of `ExitBootServices()` and capture of the final memory map.
+//!
+//! The boot services table entry is replaced with the architecture's handler, which calls
+//! [`forward`]. Every call captures the current memory map into memory allocated at installation
+//! before chaining to the firmware, so the map in force when boot services exit remains available
+//! to the hypervisor afterwards.
+
+use core::{
+    ffi::c_void,
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+};
+
+use uefi::mem::memory_map::{MemoryMapKey, MemoryMapMeta, MemoryMapRef};
+
+use crate::{
+    arch::exit_boot_services_handler,
+    frames::{allocate_frames, deallocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE},
+    logging,
+    residency::UninstallError,
+};
+
+/// The offset of the CRC32 in a UEFI table header.
+pub const HEADER_CRC32_OFFSET: usize = 16;
+
+/// The number of descriptors reserved beyond the size of the memory map at installation, which
+/// absorbs the growth of the map before `ExitBootServices()` is called.
+const SPARE_DESCRIPTORS: usize = 64;
+
+/// The firmware's `ExitBootServices()`, or null if the hook has not been installed.
+static ORIGINAL: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+/// The patched `ExitBootServices()` pointer in the boot services table.
+static SLOT: AtomicPtr<u64> = AtomicPtr::new(ptr::null_mut());
+
+/// The buffer the memory map is captured into.
+static MAP_BUFFER: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// The size of [`MAP_BUFFER`] in bytes.
+static MAP_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+
+/// The size of the captured memory map in bytes, or 0 if it could not be captured.
+static MAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The size of each descriptor in the captured memory map.
+static DESCRIPTOR_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The version of the descriptors in the captured memory map.
+static DESCRIPTOR_VERSION: AtomicU32 = AtomicU32::new(0);
+/// The key of the captured memory map.
+static MAP_KEY: AtomicUsize = AtomicUsize::new(0);
+/// Whether `ExitBootServices()` has succeeded.
+static EXITED: AtomicBool = AtomicBool::new(false);
+
+/// The signature of `ExitBootServices()`.
+type ExitBootServices = unsafe extern "efiapi" fn(*mut c_void, usize) -> uefi::Status;
+
+/// The state observed by the last successful call to `ExitBootServices()`.
+pub struct ExitContext {
+    /// The key the caller exited boot services with.
+    pub map_key: usize,
+    /// The memory map in force when boot services exited.
+    pub memory_map: MemoryMapRef<'static>,
+}
+
+/// Allocates the memory map buffer and replaces `ExitBootServices()` with
+/// [`exit_boot_services_handler`], returning the address of the patched pointer.
+///
+/// # Errors
+/// Returns [`InstallError::AlreadyInstalled`] if the hook was already installed,
+/// [`InstallError::MemoryMap`] if the size of the memory map cannot be queried, and
+/// [`InstallError::OutOfMemory`] if the capture buffer cannot be allocated.
+pub fn install() -> Result<*const u64, InstallError> {
+    if !ORIGINAL.load(Ordering::Acquire).is_null() {
+        return Err(InstallError::AlreadyInstalled);
+    }
+
+    let system_table = uefi::table::system_table_raw().ok_or(InstallError::MemoryMap)?;
+    // SAFETY:
+    // The system table pointer is valid while boot services are active.
+    let boot_services = unsafe { system_table.as_ref().boot_services };
+
+    let mut map_size = 0;
+    let mut map_key = 0;
+    let mut descriptor_size = 0;
+    let mut descriptor_version = 0;
+    // SAFETY:
+    // The boot services table is valid while boot services are active.
+    let get_memory_map = unsafe { (*boot_services).get_memory_map };
+    // SAFETY:
+    // Boot services are active, and a size of zero makes the firmware only report the required
+    // size.
+    let status = unsafe {
+        get_memory_map(
+            &mut map_size,
+            ptr::null_mut(),
+            &mut map_key,
+            &mut descriptor_size,
+            &mut descriptor_version,
+        )
+    };
+    if status != uefi::Status::BUFFER_TOO_SMALL || descriptor_size == 0 {
+        return Err(InstallError::MemoryMap);
+    }
+
+    let frames = (map_size + SPARE_DESCRIPTORS * descriptor_size).div_ceil(FRAME_SIZE);
+    let buffer =
+        allocate_frames(frames, MemoryKind::Persistent).map_err(InstallError::OutOfMemory)?;
+    MAP_BUFFER.store(buffer.as_ptr(), Ordering::Relaxed);
+    MAP_CAPACITY.store(frames * FRAME_SIZE, Ordering::Relaxed);
+
+    // SAFETY:
+    // The boot services table is valid while boot services are active.
+    let slot = unsafe { &mut (*boot_services).exit_boot_services };
+    ORIGINAL.store(*slot as *mut c_void, Ordering::Release);
+    *slot = exit_boot_services_handler;
+    SLOT.store(ptr::from_mut(slot).cast(), Ordering::Release);
+
+    // SAFETY:
+    // The boot services table begins with its header, which is valid while boot services are
+    // active.
+    unsafe { update_crc32(boot_services.cast()) };
+
+    Ok(ptr::from_mut(slot).cast::<u64>())
+}
+
+/// Returns `true` if the `ExitBootServices()` hook is installed.
+pub fn is_installed() -> bool {
+    !SLOT.load(Ordering::Acquire).is_null()
+}
+
+/// Restores the firmware's `ExitBootServices()` and releases the memory map capture buffer.
+///
+/// # Errors
+/// - Returns [`UninstallError::NotInstalled`] if the hook is not installed.
+/// - Returns [`UninstallError::Overwritten`] if another image replaced the hook, in which case
+///   restoring the firmware's function would also remove that image's hook.
+///
+/// # Safety
+/// Boot services must be active.
+#[allow(dead_code)]
+pub unsafe fn uninstall() -> Result<(), UninstallError> {
+    let slot = SLOT.load(Ordering::Acquire);
+    if slot.is_null() {
+        return Err(UninstallError::NotInstalled);
+    }
+
+    let handler: ExitBootServices = exit_boot_services_handler;
+    // SAFETY:
+    // `SLOT` lies within the boot services table, which is valid while boot services are active.
+    if unsafe { slot.read_volatile() } != handler as usize as u64 {
+        return Err(UninstallError::Overwritten("ExitBootServices"));
+    }
+
+    // SAFETY:
+    // `SLOT` lies within the boot services table, which is valid while boot services are active.
+    unsafe { slot.write_volatile(ORIGINAL.load(Ordering::Acquire) as u64) }
+    SLOT.store(ptr::null_mut(), Ordering::Release);
+    ORIGINAL.store(ptr::null_mut(), Ordering::Release);
+
+    let system_table = uefi::table::system_table_raw().expect("boot services are active");
+    // SAFETY:
+    // The system table pointer is valid while boot services are active.
+    let boot_services = unsafe { system_table.as_ref().boot_services };
+    // SAFETY:
+    // The boot services table begins with its header, which is valid while boot services are
+    // active.
+    unsafe { update_crc32(boot_services.cast()) };
+
+    MAP_SIZE.store(0, Ordering::Release);
+    let capacity = MAP_CAPACITY.swap(0, Ordering::Relaxed);
+    if let Some(buffer) = NonNull::new(MAP_BUFFER.swap(ptr::null_mut(), Ordering::Relaxed)) {
+        // SAFETY:
+        // The buffer was allocated by `install` and, with the hook removed, is no longer used.
+        unsafe { deallocate_frames(buffer, capacity / FRAME_SIZE) }
+    }
+
+    Ok(())
+}
+
+/// Recomputes the CRC32 of the UEFI table with the header at `table` after it was modified.
+///
+/// # Safety
+/// `table` must point to a UEFI table, and boot services must be active.
+pub unsafe fn update_crc32(table: *mut u8) {
+    let system_table = uefi::table::system_table_raw().expect("boot services are active");
+    // SAFETY:
+    // The system table pointer is valid while boot services are active.
+    let boot_services = unsafe { system_table.as_ref().boot_services };
+
+    // SAFETY:
+    // The caller guarantees that `table` begins with a UEFI table header.
+    let size = unsafe { (*table.cast::<uefi::table::Header>()).size };
+    let crc32 = table.wrapping_add(HEADER_CRC32_OFFSET).cast::<u32>();
+
+    // SAFETY:
+    // The CRC32 lies within the header.
+    unsafe { crc32.write(0) }
+    // SAFETY:
+    // Boot services are active, so the boot services table is valid.
+    let calculate_crc32 = unsafe { (*boot_services).calculate_crc32 };
+    let mut value = 0;
+    // SAFETY:
+    // The header reports the size of the table, which is valid for reads.
+    let status = unsafe { calculate_crc32(table.cast(), size as usize, &mut value) };
+    if status.is_success() {
+        // SAFETY:
+        // The CRC32 lies within the header.
+        unsafe { crc32.write(value) }
+    }
+}
+
+/// Returns `true` once `ExitBootServices()` has succeeded through the hook.
+pub fn has_exited() -> bool {
+    EXITED.load(Ordering::Acquire)
+}
+
+/// Captures the memory map and forwards the call to the firmware's `ExitBootServices()`.
+///
+/// A caller whose `map_key` is stale fails with `EFI_INVALID_PARAMETER` and is expected to fetch a
+/// new memory map and retry, at which point the map is captured again.
+///
+/// # Safety
+/// Must only be called in place of `ExitBootServices()` after [`install`] succeeded.
+pub unsafe extern "efiapi" fn forward(image_handle: *mut c_void, map_key: usize) -> uefi::Status {
+    // The firmware's console may be unusable here, so nothing is logged until the call returns and
+    // logging has moved off boot services.
+    capture_memory_map();
+
+    let original = ORIGINAL.load(Ordering::Acquire);
+    // SAFETY:
+    // `ORIGINAL` holds the firmware's `ExitBootServices()`, which has this signature.
+    let original = unsafe { core::mem::transmute::<*mut c_void, ExitBootServices>(original) };
+    // SAFETY:
+    // The arguments are forwarded unchanged from the caller.
+    let status = unsafe { original(image_handle, map_key) };
+    if status.is_success() {
+        EXITED.store(true, Ordering::Release);
+        logging::boot_services_exited();
+    }
+
+    status
+}
+
+/// Copies the current memory map into the capture buffer without allocating.
+fn capture_memory_map() {
+    let Some(system_table) = uefi::table::system_table_raw() else {
+        return;
+    };
+    // SAFETY:
+    // `ExitBootServices()` has not returned, so the system table is valid.
+    let boot_services = unsafe { system_table.as_ref().boot_services };
+
+    let mut map_size = MAP_CAPACITY.load(Ordering::Relaxed);
+    let mut map_key = 0;
+    let mut descriptor_size = 0;
+    let mut descriptor_version = 0;
+    // SAFETY:
+    // `ExitBootServices()` has not returned, so the boot services table is valid.
+    let get_memory_map = unsafe { (*boot_services).get_memory_map };
+    // SAFETY:
+    // `MAP_BUFFER` holds `MAP_CAPACITY` bytes of frames allocated for the capture.
+    let status = unsafe {
+        get_memory_map(
+            &mut map_size,
+            MAP_BUFFER.load(Ordering::Relaxed).cast(),
+            &mut map_key,
+            &mut descriptor_size,
+            &mut descriptor_version,
+        )
+    };
+    if !status.is_success() {
+        MAP_SIZE.store(0, Ordering::Release);
+        return;
+    }
+
+    DESCRIPTOR_SIZE.store(descriptor_size, Ordering::Relaxed);
+    DESCRIPTOR_VERSION.store(descriptor_version, Ordering::Relaxed);
+    MAP_KEY.store(map_key, Ordering::Relaxed);
+    MAP_SIZE.store(map_size, Ordering::Release);
+}
+
+/// Returns the [`ExitContext`] captured by the last call to `ExitBootServices()`.
+///
+/// Returns [`None`] if the memory map could not be captured.
+///
+/// # Safety
+/// Must only be called after `ExitBootServices()` succeeded, once the capture buffer can no
+/// longer change.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+pub unsafe fn exit_context() -> Option<ExitContext> {
+    let map_size = MAP_SIZE.load(Ordering::Acquire);
+    let buffer = MAP_BUFFER.load(Ordering::Relaxed);
+    if map_size == 0 || buffer.is_null() {
+        return None;
+    }
+
+    // SAFETY:
+    // The capture buffer spans `MAP_CAPACITY` bytes, and the caller guarantees it is no longer
+    // written.
+    let buffer =
+        unsafe { core::slice::from_raw_parts(buffer, MAP_CAPACITY.load(Ordering::Relaxed)) };
+    let map_key = MAP_KEY.load(Ordering::Relaxed);
+    let meta = MemoryMapMeta {
+        map_size,
+        desc_size: DESCRIPTOR_SIZE.load(Ordering::Relaxed),
+        // `MemoryMapKey` cannot be constructed outside of `uefi`, so the key is exposed through
+        // `ExitContext::map_key` instead.
+        map_key: MemoryMapKey::default(),
+        desc_version: DESCRIPTOR_VERSION.load(Ordering::Relaxed),
+    };
+
+    Some(ExitContext {
+        map_key,
+        memory_map: MemoryMapRef::new(buffer, meta).ok()?,
+    })
+}
+
+/// Various errors that can occur while installing the `ExitBootServices()` hook.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InstallError {
+    /// The hook was already installed.
+    AlreadyInstalled,
+    /// The size of the memory map could not be queried.
+    MemoryMap,
+    /// The memory map capture buffer could not be allocated.
+    OutOfMemory(OutOfMemoryError),
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInstalled => write!(f, "the ExitBootServices hook is already installed"),
+            Self::MemoryMap => write!(f, "failed to query the size of the memory map"),
+            Self::OutOfMemory(error) => write!(f, "{error}"),
+        }
+    }
+}
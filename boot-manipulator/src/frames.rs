@@ -0,0 +1,184 @@
+//! Allocation of physical frames from the firmware.
+//!
+//! UEFI identity maps all memory before `ExitBootServices`, so the address of a frame is also a
+//! usable pointer to it.
+
+#[cfg(feature = "allocation-tracking")]
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{fmt, ptr::NonNull};
+
+use uefi::boot;
+
+use crate::spinlock::Spinlock;
+
+/// The size of a frame, which is the UEFI page size.
+pub const FRAME_SIZE: usize = boot::PAGE_SIZE;
+
+const _: () = assert!(FRAME_SIZE == 4096);
+
+/// The maximum number of live [`MemoryKind::Persistent`] allocations that are recorded.
+const MAX_PERSISTENT_REGIONS: usize = 64;
+
+/// The live [`MemoryKind::Persistent`] allocations, as their address and number of frames.
+static PERSISTENT_REGIONS: Spinlock<[Option<(u64, usize)>; MAX_PERSISTENT_REGIONS]> =
+    Spinlock::new([None; MAX_PERSISTENT_REGIONS]);
+
+/// The maximum number of live allocations recorded when allocation tracking is enabled.
+#[cfg(feature = "allocation-tracking")]
+const MAX_TRACKED_ALLOCATIONS: usize = 64;
+
+/// The live allocations, as their address and number of frames.
+#[cfg(feature = "allocation-tracking")]
+static ALLOCATIONS: Spinlock<[Option<(u64, usize)>; MAX_TRACKED_ALLOCATIONS]> =
+    Spinlock::new([None; MAX_TRACKED_ALLOCATIONS]);
+
+/// Whether an allocation could not be recorded, after which unknown deallocations are expected.
+#[cfg(feature = "allocation-tracking")]
+static TRACKING_OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
+/// The lifetimes of allocated frames, which determine the memory type they are allocated as.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Memory only used while boot services are active, which the operating system reclaims.
+    Scratch,
+    /// Memory used by the hypervisor after `ExitBootServices`, which the operating system must
+    /// leave untouched.
+    Persistent,
+}
+
+impl MemoryKind {
+    /// Returns the UEFI memory type that frames of this kind are allocated as.
+    fn memory_type(self) -> boot::MemoryType {
+        match self {
+            Self::Scratch => boot::MemoryType::LOADER_DATA,
+            Self::Persistent => boot::MemoryType::RUNTIME_SERVICES_DATA,
+        }
+    }
+}
+
+/// Allocates `count` contiguous zeroed frames of `kind`.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if the frames cannot be allocated.
+pub fn allocate_frames(count: usize, kind: MemoryKind) -> Result<NonNull<u8>, OutOfMemoryError> {
+    let frames = boot::allocate_pages(boot::AllocateType::AnyPages, kind.memory_type(), count)
+        .map_err(|_| OutOfMemoryError { frames: count })?;
+
+    // SAFETY:
+    // `frames` points to `count` freshly allocated frames.
+    unsafe { frames.as_ptr().write_bytes(0, count * FRAME_SIZE) }
+
+    #[cfg(feature = "allocation-tracking")]
+    track_allocation(frames.as_ptr() as u64, count);
+
+    if kind == MemoryKind::Persistent {
+        let mut regions = PERSISTENT_REGIONS.lock();
+        match regions.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some((frames.as_ptr() as u64, count)),
+            None => log::warn!(
+                "persistent region table is full: {:#x} will not be relocated",
+                frames.as_ptr() as u64
+            ),
+        }
+    }
+
+    Ok(frames)
+}
+
+/// Returns the `count` frames at `frames` to the firmware.
+///
+/// # Safety
+/// `frames` must have been returned by [`allocate_frames`] for `count` frames, must not have been
+/// deallocated already, and must no longer be in use. Boot services must not have been exited.
+pub unsafe fn deallocate_frames(frames: NonNull<u8>, count: usize) {
+    #[cfg(feature = "allocation-tracking")]
+    track_deallocation(frames.as_ptr() as u64, count);
+
+    let address = frames.as_ptr() as u64;
+    if let Some(slot) = PERSISTENT_REGIONS
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|(start, _)| start == address))
+    {
+        *slot = None;
+    }
+
+    // SAFETY:
+    // The caller guarantees that `frames` was allocated with `count` frames and is unused.
+    if unsafe { boot::free_pages(frames, count) }.is_err() {
+        log::warn!(
+            "failed to deallocate {count} frames at {:#x}",
+            frames.as_ptr() as u64
+        );
+    }
+}
+
+/// Returns `true` if any of the `size` bytes starting at `address` belong to a live
+/// [`MemoryKind::Persistent`] allocation.
+pub fn overlaps_persistent(address: u64, size: u64) -> bool {
+    PERSISTENT_REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|&(start, count)| {
+            let end = start + (count * FRAME_SIZE) as u64;
+            start < address + size && address < end
+        })
+}
+
+/// Records and logs the allocation of `count` frames at `address`.
+#[cfg(feature = "allocation-tracking")]
+fn track_allocation(address: u64, count: usize) {
+    let mut allocations = ALLOCATIONS.lock();
+    match allocations.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some((address, count)),
+        None => {
+            TRACKING_OVERFLOWED.store(true, Ordering::Relaxed);
+            log::warn!("allocation tracking is full: {address:#x} will not be tracked");
+        }
+    }
+
+    let live = allocations
+        .iter()
+        .flatten()
+        .map(|&(_, count)| count)
+        .sum::<usize>();
+    log::debug!("allocated {count} frames at {address:#x} ({live} frames live)");
+}
+
+/// Removes and logs the allocation of `count` frames at `address`.
+#[cfg(feature = "allocation-tracking")]
+fn track_deallocation(address: u64, count: usize) {
+    let mut allocations = ALLOCATIONS.lock();
+    let slot = allocations
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|(start, _)| start == address));
+    debug_assert!(
+        slot.is_some() || TRACKING_OVERFLOWED.load(Ordering::Relaxed),
+        "deallocating {address:#x}, which is not a live allocation"
+    );
+    if let Some(slot) = slot {
+        debug_assert_eq!(slot.map(|(_, frames)| frames), Some(count));
+        *slot = None;
+    }
+
+    let live = allocations
+        .iter()
+        .flatten()
+        .map(|&(_, count)| count)
+        .sum::<usize>();
+    log::debug!("deallocated {count} frames at {address:#x} ({live} frames live)");
+}
+
+/// The error returned when frames cannot be allocated.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct OutOfMemoryError {
+    /// The number of frames that were requested.
+    pub frames: usize,
+}
+
+impl fmt::Display for OutOfMemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to allocate {} frames", self.frames)
+    }
+}
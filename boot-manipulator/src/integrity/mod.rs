@@ -0,0 +1,580 @@
+//! Tamper-evident record of the driver's code and installed hooks.
+//!
+//! At the end of setup, the executable sections of the driver image are digested and the values
+//! of every installed hook pointer are recorded. The record lives in runtime services memory so
+//! that it remains available to the operating system, and it can be re-verified to detect
+//! corruption of the driver between setup and virtualization.
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use uefi::{boot, proto::loaded_image::LoadedImage, Status};
+
+use crate::frames::{self, MemoryKind, OutOfMemoryError};
+use sha256::{Sha256, DIGEST_SIZE};
+
+pub mod sha256;
+
+/// The magic value identifying an [`IntegrityRecord`].
+pub const RECORD_MAGIC: [u8; 8] = *b"BMINTEG\0";
+
+/// The maximum number of code regions tracked by an [`IntegrityRecord`].
+pub const MAX_REGIONS: usize = 8;
+/// The maximum number of hooks tracked by an [`IntegrityRecord`].
+pub const MAX_HOOKS: usize = 4;
+
+/// Section characteristic flag indicating the section contains executable code.
+const IMAGE_SCN_CNT_CODE: u32 = 0x0000_0020;
+/// Section characteristic flag indicating the section can be executed.
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+
+/// Pointer to the published [`IntegrityRecord`].
+static RECORD: AtomicPtr<IntegrityRecord> = AtomicPtr::new(ptr::null_mut());
+
+/// Record of the driver's code digests and installed hooks.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntegrityRecord {
+    /// Always [`RECORD_MAGIC`].
+    pub magic: [u8; 8],
+    /// The base address of the driver image.
+    pub image_base: u64,
+    /// The size, in bytes, of the driver image.
+    pub image_size: u64,
+    /// The number of valid entries in `regions`.
+    pub region_count: u64,
+    /// Digests of the driver's executable regions.
+    pub regions: [RegionDigest; MAX_REGIONS],
+    /// The number of valid entries in `hooks`.
+    pub hook_count: u64,
+    /// The installed hook pointers.
+    pub hooks: [HookEntry; MAX_HOOKS],
+}
+
+/// Digest of a single region of memory.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegionDigest {
+    /// The address of the region.
+    pub address: u64,
+    /// The size, in bytes, of the region.
+    pub size: u64,
+    /// The SHA-256 digest of the region.
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+/// The expected value of an installed hook pointer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HookEntry {
+    /// The address of the patched pointer.
+    pub slot: u64,
+    /// The value installed into `slot`.
+    pub value: u64,
+}
+
+impl IntegrityRecord {
+    /// Creates an empty [`IntegrityRecord`] describing the image at `image_base`.
+    pub const fn new(image_base: u64, image_size: u64) -> Self {
+        Self {
+            magic: RECORD_MAGIC,
+            image_base,
+            image_size,
+            region_count: 0,
+            regions: [RegionDigest {
+                address: 0,
+                size: 0,
+                digest: [0; DIGEST_SIZE],
+            }; MAX_REGIONS],
+            hook_count: 0,
+            hooks: [HookEntry { slot: 0, value: 0 }; MAX_HOOKS],
+        }
+    }
+
+    /// Returns the recorded region digests.
+    pub fn regions(&self) -> &[RegionDigest] {
+        &self.regions[..self.region_count as usize]
+    }
+
+    /// Returns the recorded hooks.
+    pub fn hooks(&self) -> &[HookEntry] {
+        &self.hooks[..self.hook_count as usize]
+    }
+
+    /// Records the digest of `data`, located at `address`.
+    ///
+    /// Returns `false` if the record has no room for another region.
+    pub fn push_region(&mut self, address: u64, data: &[u8]) -> bool {
+        let Some(entry) = self.regions.get_mut(self.region_count as usize) else {
+            return false;
+        };
+
+        *entry = RegionDigest {
+            address,
+            size: data.len() as u64,
+            digest: Sha256::digest(data),
+        };
+        self.region_count += 1;
+        true
+    }
+
+    /// Records that `value` was installed at `slot`.
+    ///
+    /// Returns `false` if the record has no room for another hook.
+    pub fn push_hook(&mut self, slot: u64, value: u64) -> bool {
+        let Some(entry) = self.hooks.get_mut(self.hook_count as usize) else {
+            return false;
+        };
+
+        *entry = HookEntry { slot, value };
+        self.hook_count += 1;
+        true
+    }
+}
+
+/// Computes and publishes the [`IntegrityRecord`] for the driver image with the given `hooks`.
+///
+/// Each hook is given as the address of the patched pointer.
+///
+/// # Errors
+/// Returns an error if the driver's [`LoadedImage`] protocol could not be opened or the record
+/// could not be allocated.
+pub fn publish(hooks: &[*const u64]) -> Result<u64, PublishError> {
+    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+        .map_err(|error| PublishError::LoadedImage(error.status()))?;
+    let (image_base, image_size) = loaded_image.info();
+
+    // SAFETY:
+    // The firmware guarantees that the loaded image spans `image_size` bytes at `image_base`.
+    let image =
+        unsafe { core::slice::from_raw_parts(image_base.cast::<u8>(), image_size as usize) };
+    // SAFETY:
+    // The caller provides the addresses of installed hook pointers, which are valid for reads.
+    let record = unsafe { build_record(image, hooks) };
+
+    // The record is allocated as persistent memory so that it is relocated along with the rest of
+    // the hypervisor's memory when the operating system calls `SetVirtualAddressMap()`.
+    let record_ptr = frames::allocate_frames(1, MemoryKind::Persistent)
+        .map_err(PublishError::OutOfMemory)?
+        .cast::<IntegrityRecord>();
+
+    // SAFETY:
+    // `record_ptr` points to a freshly allocated frame, which is large enough and sufficiently
+    // aligned to hold an `IntegrityRecord`.
+    unsafe { record_ptr.write(record) }
+    RECORD.store(record_ptr.as_ptr(), Ordering::Release);
+
+    Ok(record_ptr.as_ptr() as u64)
+}
+
+/// Builds the [`IntegrityRecord`] for the PE `image` with the given `hooks`.
+///
+/// # Safety
+/// Each of `hooks` must be valid for reads.
+unsafe fn build_record(image: &[u8], hooks: &[*const u64]) -> IntegrityRecord {
+    let image_base = image.as_ptr() as u64;
+    let mut record = IntegrityRecord::new(image_base, image.len() as u64);
+
+    let sections = executable_sections(image).filter(|&(offset, size)| {
+        offset
+            .checked_add(size)
+            .is_some_and(|end| end <= image.len())
+    });
+
+    let mut found_section = false;
+    for (offset, size) in sections {
+        found_section = true;
+        if !record.push_region(image_base + offset as u64, &image[offset..offset + size]) {
+            log::warn!("integrity record full: skipping code section at offset {offset:#x}");
+        }
+    }
+    if !found_section {
+        record.push_region(image_base, image);
+    }
+
+    for &slot in hooks {
+        // SAFETY:
+        // The caller guarantees that each hook is valid for reads.
+        let value = unsafe { slot.read_volatile() };
+        if !record.push_hook(slot as u64, value) {
+            log::warn!("integrity record full: skipping hook at {slot:p}");
+        }
+    }
+
+    record
+}
+
+/// Recomputes the digests and hook values stored in the published [`IntegrityRecord`].
+///
+/// # Errors
+/// Returns the first mismatch found, or [`IntegrityError::Unpublished`] if no record exists.
+pub fn verify() -> Result<(), IntegrityError> {
+    let record_ptr = RECORD.load(Ordering::Acquire);
+    if record_ptr.is_null() {
+        return Err(IntegrityError::Unpublished);
+    }
+
+    // SAFETY:
+    // `RECORD` only ever holds pointers to initialized records published by `publish()`.
+    let record = unsafe { &*record_ptr };
+
+    // SAFETY:
+    // The regions of a published record are part of the driver image and its hook slots are
+    // installed hook pointers, all of which remain resident.
+    unsafe { verify_record(record) }
+}
+
+/// Recomputes the digests and hook values stored in `record`.
+///
+/// # Errors
+/// Returns the first mismatch found.
+///
+/// # Safety
+/// Every region and hook slot in `record` must be valid for reads.
+unsafe fn verify_record(record: &IntegrityRecord) -> Result<(), IntegrityError> {
+    if record.magic != RECORD_MAGIC {
+        return Err(IntegrityError::RecordCorrupted);
+    }
+
+    for region in record.regions() {
+        // SAFETY:
+        // The caller guarantees that the region is valid for reads.
+        let data = unsafe {
+            core::slice::from_raw_parts(region.address as *const u8, region.size as usize)
+        };
+
+        if Sha256::digest(data) != region.digest {
+            return Err(IntegrityError::RegionMismatch {
+                address: region.address,
+                size: region.size,
+            });
+        }
+    }
+
+    for hook in record.hooks() {
+        // SAFETY:
+        // The caller guarantees that the slot is valid for reads.
+        let found = unsafe { (hook.slot as *const u64).read_volatile() };
+        if found != hook.value {
+            return Err(IntegrityError::HookMismatch {
+                slot: hook.slot,
+                expected: hook.value,
+                found,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns an iterator over the `(offset, size)` of each executable section in the PE `image`.
+fn executable_sections(image: &[u8]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let read_u16 = |offset: usize| {
+        image
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let read_u32 = |offset: usize| {
+        image
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let section_table = read_u32(0x3C).and_then(|pe_offset| {
+        let pe_offset = pe_offset as usize;
+        if image.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+            return None;
+        }
+
+        let section_count = read_u16(pe_offset + 6)? as usize;
+        let optional_header_size = read_u16(pe_offset + 20)? as usize;
+        Some((pe_offset + 24 + optional_header_size, section_count))
+    });
+
+    let (table_offset, section_count) = section_table.unwrap_or((0, 0));
+    (0..section_count).filter_map(move |index| {
+        let header = table_offset + index * 40;
+        let virtual_size = read_u32(header + 8)? as usize;
+        let virtual_address = read_u32(header + 12)? as usize;
+        let characteristics = read_u32(header + 36)?;
+
+        (characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0)
+            .then_some((virtual_address, virtual_size))
+    })
+}
+
+/// Various errors that can occur while publishing the [`IntegrityRecord`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum PublishError {
+    /// The driver's [`LoadedImage`] protocol could not be opened.
+    LoadedImage(Status),
+    /// The frame holding the record could not be allocated.
+    OutOfMemory(OutOfMemoryError),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LoadedImage(status) => write!(f, "failed to open the loaded image: {status}"),
+            Self::OutOfMemory(error) => write!(f, "failed to allocate the record: {error}"),
+        }
+    }
+}
+
+/// Various errors that can occur while verifying the [`IntegrityRecord`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// No [`IntegrityRecord`] has been published.
+    Unpublished,
+    /// The [`IntegrityRecord`] itself has been overwritten.
+    RecordCorrupted,
+    /// The digest of a code region no longer matches.
+    RegionMismatch {
+        /// The address of the region.
+        address: u64,
+        /// The size, in bytes, of the region.
+        size: u64,
+    },
+    /// A hook pointer no longer holds the installed value.
+    HookMismatch {
+        /// The address of the patched pointer.
+        slot: u64,
+        /// The value that was installed.
+        expected: u64,
+        /// The value currently present.
+        found: u64,
+    },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unpublished => write!(f, "integrity record has not been published"),
+            Self::RecordCorrupted => write!(f, "integrity record has been corrupted"),
+            Self::RegionMismatch { address, size } => write!(
+                f,
+                "code region {address:#x}..{:#x} does not match its recorded digest",
+                address + size
+            ),
+            Self::HookMismatch {
+                slot,
+                expected,
+                found,
+            } => write!(
+                f,
+                "hook at {slot:#x} holds {found:#x} instead of the installed {expected:#x}"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The offset of the PE signature in [`pe_image()`].
+    const PE_OFFSET: usize = 0x80;
+    /// The offset and size of the code section in [`pe_image()`].
+    const CODE: (usize, usize) = (0x200, 0x100);
+    /// The offset and size of the data section in [`pe_image()`].
+    const DATA: (usize, usize) = (0x300, 0x80);
+
+    /// Returns a minimal PE image with the given `sections`, as `(offset, size, characteristics)`.
+    fn pe(sections: &[(usize, usize, u32)]) -> Vec<u8> {
+        let mut image = (0..0x400).map(|byte| byte as u8).collect::<Vec<u8>>();
+        image[0x3C..0x40].copy_from_slice(&(PE_OFFSET as u32).to_le_bytes());
+        image[PE_OFFSET..PE_OFFSET + 4].copy_from_slice(b"PE\0\0");
+        image[PE_OFFSET + 6..PE_OFFSET + 8].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        image[PE_OFFSET + 20..PE_OFFSET + 22].copy_from_slice(&0u16.to_le_bytes());
+
+        for (index, &(offset, size, characteristics)) in sections.iter().enumerate() {
+            let header = PE_OFFSET + 24 + index * 40;
+            image[header + 8..header + 12].copy_from_slice(&(size as u32).to_le_bytes());
+            image[header + 12..header + 16].copy_from_slice(&(offset as u32).to_le_bytes());
+            image[header + 36..header + 40].copy_from_slice(&characteristics.to_le_bytes());
+        }
+
+        image
+    }
+
+    /// Returns a PE image with one code section and one data section.
+    fn pe_image() -> Vec<u8> {
+        pe(&[
+            (CODE.0, CODE.1, IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE),
+            (DATA.0, DATA.1, 0x4000_0040),
+        ])
+    }
+
+    #[test]
+    fn finds_executable_sections() {
+        assert_eq!(executable_sections(&pe_image()).collect::<Vec<_>>(), [CODE]);
+
+        let image = pe(&[
+            (0x100, 0x10, IMAGE_SCN_MEM_EXECUTE),
+            (0x200, 0x20, IMAGE_SCN_CNT_CODE),
+        ]);
+        assert_eq!(
+            executable_sections(&image).collect::<Vec<_>>(),
+            [(0x100, 0x10), (0x200, 0x20)]
+        );
+    }
+
+    #[test]
+    fn non_pe_images_have_no_sections() {
+        assert_eq!(executable_sections(&[]).count(), 0);
+        assert_eq!(executable_sections(&[0; 0x100]).count(), 0);
+
+        let mut image = pe_image();
+        image[PE_OFFSET] = b'X';
+        assert_eq!(executable_sections(&image).count(), 0);
+    }
+
+    #[test]
+    fn records_code_sections_and_hooks() {
+        let image = pe_image();
+        let hook = Box::new(0xDEAD_BEEF_u64);
+        let slot: *const u64 = &*hook;
+
+        // SAFETY:
+        // `slot` points to a live `u64`.
+        let record = unsafe { build_record(&image, &[slot]) };
+        let base = image.as_ptr() as u64;
+
+        assert_eq!(record.magic, RECORD_MAGIC);
+        assert_eq!(record.image_base, base);
+        assert_eq!(record.image_size, image.len() as u64);
+        assert_eq!(
+            record.regions(),
+            [RegionDigest {
+                address: base + CODE.0 as u64,
+                size: CODE.1 as u64,
+                digest: Sha256::digest(&image[CODE.0..CODE.0 + CODE.1]),
+            }]
+        );
+        assert_eq!(
+            record.hooks(),
+            [HookEntry {
+                slot: slot as u64,
+                value: 0xDEAD_BEEF,
+            }]
+        );
+    }
+
+    #[test]
+    fn records_the_whole_image_without_usable_sections() {
+        // The only code section lies outside the image.
+        let image = pe(&[(0x380, 0x100, IMAGE_SCN_CNT_CODE)]);
+
+        // SAFETY:
+        // No hooks are read.
+        let record = unsafe { build_record(&image, &[]) };
+
+        assert_eq!(record.regions().len(), 1);
+        assert_eq!(record.regions()[0].address, image.as_ptr() as u64);
+        assert_eq!(record.regions()[0].size, image.len() as u64);
+        assert_eq!(record.regions()[0].digest, Sha256::digest(&image));
+    }
+
+    #[test]
+    fn verifies_an_unmodified_record() {
+        let image = pe_image();
+        let hook = Box::new(0x1234_u64);
+
+        // SAFETY:
+        // The hook points to a live `u64`.
+        let record = unsafe { build_record(&image, &[&*hook]) };
+        // SAFETY:
+        // The recorded region and hook are still alive.
+        assert_eq!(unsafe { verify_record(&record) }, Ok(()));
+    }
+
+    #[test]
+    fn detects_patched_code() {
+        let mut image = pe_image();
+        // SAFETY:
+        // No hooks are read.
+        let record = unsafe { build_record(&image, &[]) };
+
+        // Patching data is not detected.
+        image[DATA.0] ^= 0xFF;
+        // SAFETY:
+        // The recorded region is still alive.
+        assert_eq!(unsafe { verify_record(&record) }, Ok(()));
+
+        image[CODE.0 + CODE.1 - 1] ^= 0xFF;
+        // SAFETY:
+        // The recorded region is still alive.
+        let result = unsafe { verify_record(&record) };
+        assert_eq!(
+            result,
+            Err(IntegrityError::RegionMismatch {
+                address: image.as_ptr() as u64 + CODE.0 as u64,
+                size: CODE.1 as u64,
+            })
+        );
+    }
+
+    #[test]
+    fn detects_replaced_hooks() {
+        let image = pe_image();
+        let mut hook = Box::new(0x1234_u64);
+        let slot: *mut u64 = &mut *hook;
+
+        // SAFETY:
+        // `slot` points to a live `u64`.
+        let record = unsafe { build_record(&image, &[slot]) };
+        // SAFETY:
+        // `slot` points to a live `u64`, which is not otherwise borrowed.
+        unsafe { slot.write(0x5678) }
+
+        // SAFETY:
+        // The recorded region and hook are still alive.
+        let result = unsafe { verify_record(&record) };
+        assert_eq!(
+            result,
+            Err(IntegrityError::HookMismatch {
+                slot: slot as u64,
+                expected: 0x1234,
+                found: 0x5678,
+            })
+        );
+    }
+
+    #[test]
+    fn detects_a_corrupted_record() {
+        let image = pe_image();
+        // SAFETY:
+        // No hooks are read.
+        let mut record = unsafe { build_record(&image, &[]) };
+        record.magic[0] ^= 0xFF;
+
+        // SAFETY:
+        // The recorded region is still alive.
+        let result = unsafe { verify_record(&record) };
+        assert_eq!(result, Err(IntegrityError::RecordCorrupted));
+    }
+
+    #[test]
+    fn record_capacity_is_bounded() {
+        let mut record = IntegrityRecord::new(0x1000, 0x2000);
+
+        for index in 0..MAX_REGIONS {
+            assert!(record.push_region(index as u64, &[index as u8]));
+        }
+        assert!(!record.push_region(0, &[]));
+        assert_eq!(record.regions().len(), MAX_REGIONS);
+
+        for index in 0..MAX_HOOKS {
+            assert!(record.push_hook(index as u64, index as u64));
+        }
+        assert!(!record.push_hook(0, 0));
+        assert_eq!(record.hooks().len(), MAX_HOOKS);
+    }
+
+    #[test]
+    fn record_fits_in_a_frame() {
+        assert!(size_of::<IntegrityRecord>() <= frames::FRAME_SIZE);
+    }
+}
@@ -0,0 +1,244 @@
+//! Per-module filtering of log records.
+//!
+//! A filter is written as comma-separated directives, each of which is either a level, which
+//! applies to every module not matched by another directive, or `target=level`, which applies to
+//! the module `target` and its submodules. For example,
+//! `info,boot_manipulator::arch::x86_64::virtualization=trace` logs the virtualization code at
+//! `trace` and everything else at `info`. Without a bare level, unmatched modules are logged at
+//! `error`.
+
+use core::{fmt, str::FromStr};
+
+use log::LevelFilter;
+
+/// The maximum number of `target=level` directives in a [`LogFilter`].
+const MAX_DIRECTIVES: usize = 8;
+
+/// The maximum length of the target of a directive in bytes.
+const MAX_TARGET_LENGTH: usize = 96;
+
+/// The levels records are logged at, by the module they originate from.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct LogFilter {
+    /// The level of targets not matched by any directive.
+    default: LevelFilter,
+    /// The `target=level` directives.
+    directives: [Option<Directive>; MAX_DIRECTIVES],
+}
+
+impl LogFilter {
+    /// Creates a [`LogFilter`] logging every target up to `level`.
+    pub const fn new(level: LevelFilter) -> Self {
+        Self {
+            default: level,
+            directives: [None; MAX_DIRECTIVES],
+        }
+    }
+
+    /// Returns the level of the directive with the longest target matching `target`, or the
+    /// default level if none matches.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .filter(|directive| directive.matches(target))
+            .max_by_key(|directive| directive.length)
+            .map_or(self.default, |directive| directive.level)
+    }
+
+    /// Returns the most verbose level of any target.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .map(|directive| directive.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = ParseFilterError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::new(LevelFilter::Error);
+        let mut count = 0;
+        for directive in spec.split(',').map(str::trim) {
+            let Some((target, level)) = directive.split_once('=') else {
+                filter.default = parse_level(directive)?;
+                continue;
+            };
+
+            let (target, level) = (target.trim(), parse_level(level.trim())?);
+            if target.is_empty() {
+                return Err(ParseFilterError::EmptyTarget);
+            }
+
+            let mut bytes = [0; MAX_TARGET_LENGTH];
+            bytes
+                .get_mut(..target.len())
+                .ok_or(ParseFilterError::TargetTooLong)?
+                .copy_from_slice(target.as_bytes());
+
+            let slot = filter
+                .directives
+                .get_mut(count)
+                .ok_or(ParseFilterError::TooManyDirectives)?;
+            *slot = Some(Directive {
+                target: bytes,
+                length: target.len(),
+                level,
+            });
+            count += 1;
+        }
+
+        Ok(filter)
+    }
+}
+
+/// A level applying to a module and its submodules.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct Directive {
+    /// The module path, stored without allocating.
+    target: [u8; MAX_TARGET_LENGTH],
+    /// The number of valid bytes in `target`.
+    length: usize,
+    /// The level of records originating from the module.
+    level: LevelFilter,
+}
+
+impl Directive {
+    /// Returns `true` if `target` is this directive's module or one of its submodules.
+    fn matches(&self, target: &str) -> bool {
+        let Some(rest) = target.as_bytes().strip_prefix(&self.target[..self.length]) else {
+            return false;
+        };
+
+        rest.is_empty() || rest.starts_with(b"::")
+    }
+}
+
+/// Parses a level name such as `info` or `off`.
+fn parse_level(level: &str) -> Result<LevelFilter, ParseFilterError> {
+    LevelFilter::from_str(level).map_err(|_| ParseFilterError::InvalidLevel)
+}
+
+/// Various errors that can occur while parsing a [`LogFilter`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ParseFilterError {
+    /// A level is not one of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    InvalidLevel,
+    /// A `target=level` directive has an empty target.
+    EmptyTarget,
+    /// A target is longer than [`MAX_TARGET_LENGTH`] bytes.
+    TargetTooLong,
+    /// There are more than [`MAX_DIRECTIVES`] `target=level` directives.
+    TooManyDirectives,
+}
+
+impl fmt::Display for ParseFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLevel => write!(f, "invalid log level"),
+            Self::EmptyTarget => write!(f, "empty log target"),
+            Self::TargetTooLong => {
+                write!(f, "log target longer than {MAX_TARGET_LENGTH} bytes")
+            }
+            Self::TooManyDirectives => {
+                write!(f, "more than {MAX_DIRECTIVES} log targets")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_levels_set_the_default() {
+        let filter = "warn".parse::<LogFilter>().unwrap();
+        assert_eq!(filter, LogFilter::new(LevelFilter::Warn));
+        assert_eq!(filter.level_for("boot_manipulator"), LevelFilter::Warn);
+
+        // Without a bare level, unmatched targets are logged at `error`.
+        let filter = "uefi=off".parse::<LogFilter>().unwrap();
+        assert_eq!(filter.level_for("boot_manipulator"), LevelFilter::Error);
+    }
+
+    #[test]
+    fn the_longest_matching_target_wins() {
+        let filter = "info, boot_manipulator = debug ,boot_manipulator::arch::x86_64=trace"
+            .parse::<LogFilter>()
+            .unwrap();
+
+        assert_eq!(filter.level_for("uefi"), LevelFilter::Info);
+        assert_eq!(filter.level_for("boot_manipulator"), LevelFilter::Debug);
+        assert_eq!(
+            filter.level_for("boot_manipulator::config"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filter.level_for("boot_manipulator::arch::x86_64::virtualization"),
+            LevelFilter::Trace
+        );
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn targets_match_whole_path_components() {
+        let filter = "off,boot=info".parse::<LogFilter>().unwrap();
+
+        assert_eq!(filter.level_for("boot"), LevelFilter::Info);
+        assert_eq!(filter.level_for("boot::manipulator"), LevelFilter::Info);
+        assert_eq!(filter.level_for("boot_manipulator"), LevelFilter::Off);
+        assert_eq!(filter.level_for("boo"), LevelFilter::Off);
+    }
+
+    #[test]
+    fn later_bare_levels_replace_earlier_ones() {
+        let filter = "trace,x=warn,error".parse::<LogFilter>().unwrap();
+
+        assert_eq!(filter.level_for("y"), LevelFilter::Error);
+        assert_eq!(filter.level_for("x"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let cases = [
+            ("verbose", ParseFilterError::InvalidLevel),
+            ("", ParseFilterError::InvalidLevel),
+            ("info,", ParseFilterError::InvalidLevel),
+            ("x=loud", ParseFilterError::InvalidLevel),
+            ("=info", ParseFilterError::EmptyTarget),
+            (" = info", ParseFilterError::EmptyTarget),
+        ];
+        for (spec, error) in cases {
+            assert_eq!(spec.parse::<LogFilter>(), Err(error), "{spec:?}");
+        }
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let longest = format!("{}=info", "a".repeat(MAX_TARGET_LENGTH));
+        assert!(longest.parse::<LogFilter>().is_ok());
+
+        let too_long = format!("{}=info", "a".repeat(MAX_TARGET_LENGTH + 1));
+        assert_eq!(
+            too_long.parse::<LogFilter>(),
+            Err(ParseFilterError::TargetTooLong)
+        );
+
+        let directives = |count| {
+            (0..count)
+                .map(|index| format!("m{index}=debug"))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert!(directives(MAX_DIRECTIVES).parse::<LogFilter>().is_ok());
+        assert_eq!(
+            directives(MAX_DIRECTIVES + 1).parse::<LogFilter>(),
+            Err(ParseFilterError::TooManyDirectives)
+        );
+    }
+}
@@ -1,49 +1,566 @@
 //! Logging for `boot-manipulator`.
+//!
+//! Records are written to the active logger, which writes them to every registered console
+//! through the [`ConsoleMux`](crate::console::mux::ConsoleMux): the firmware's standard output
+//! while boot services are active, and the debug console, framebuffer and serial port as they
+//! become usable. Every record is also kept in an in-memory ring, which can be replayed with
+//! [`dump_ring`] when no console is usable. Records are filtered by the module they originate
+//! from, according to the [`LogFilter`] in effect.
 
 use core::{
-    fmt::Write,
-    sync::atomic::{AtomicU8, Ordering},
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
+#[cfg(feature = "debugcon")]
+use crate::arch::debugcon::DebugconConsole;
+#[cfg(feature = "serial-logging")]
+use crate::arch::logging::{self as serial, SerialConsole};
 use crate::{
-    arch::logging::{init_transition_logger, TransitionLogger},
-    spinlock::Spinlock,
+    arch,
+    console::{mux::CONSOLE_MUX, Console},
+    exit_boot_services,
+    frames::{allocate_frames, MemoryKind, OutOfMemoryError, FRAME_SIZE},
+    log_filter::LogFilter,
+    ring_buffer::RingBuffer,
+    spinlock::{IrqSpinlock, Spinlock},
+    sync::RwSpinlock,
+    time,
 };
 
-const BOOT_SERVICES: u8 = 0;
-const INITIALIZING: u8 = 1;
-const RUNNING: u8 = 2;
+/// The maximum length of a formatted record kept in the ring; longer records are truncated.
+const MAX_RING_RECORD_LENGTH: usize = 256;
 
-static PROGRAM_STATE: AtomicU8 = AtomicU8::new(BOOT_SERVICES);
+/// The logger every record is written to.
+///
+/// Every record reads the active logger, while it is only replaced a handful of times, so
+/// concurrent records only share a read lock. Interrupts are masked while the lock is held, so
+/// that a handler cannot wait on a writer queued behind the code it interrupted.
+static ACTIVE_LOGGER: RwSpinlock<Sink> = RwSpinlock::new(Sink::Consoles);
 
-static TRANSITION_LOGGER: Spinlock<TransitionLogger> = Spinlock::new(TransitionLogger::new());
+/// The ring holding the most recent records, or [`None`] before [`initialize_ring`] succeeded.
+static RING: IrqSpinlock<Option<RingBuffer>> = IrqSpinlock::new(None);
 
-pub fn initialize_logging(level_filter: log::LevelFilter) {
-    log::set_logger(&Logger).expect("initialize_logging shouldn't be called twice");
-    log::set_max_level(level_filter);
+/// The levels records are logged at, by the module they originate from.
+static FILTER: IrqSpinlock<LogFilter> = IrqSpinlock::new(LogFilter::new(log::LevelFilter::Trace));
+
+/// Whether log records are prefixed with the time since the clock was calibrated.
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Whether log records are prefixed with the ID of the processor that logged them.
+static PROCESSOR_IDS: AtomicBool = AtomicBool::new(false);
+
+/// The debug console, which is registered once it was found.
+#[cfg(feature = "debugcon")]
+static DEBUGCON_CONSOLE: Spinlock<DebugconConsole> = Spinlock::new(DebugconConsole::new());
+
+/// The configured serial port, which is registered once boot services have exited.
+#[cfg(feature = "serial-logging")]
+static SERIAL_CONSOLE: Spinlock<SerialConsole> = Spinlock::new(SerialConsole::new());
+
+/// The logger writing to every registered console.
+static MUX_LOGGER: ConsoleLogger = ConsoleLogger {
+    console: &CONSOLE_MUX,
+};
+
+/// The logger keeping records in the ring.
+static RING_LOGGER: RingBufferLogger = RingBufferLogger;
+
+/// Installs the driver's logger, which logs records according to the filter in effect, initially
+/// every record.
+///
+/// # Errors
+/// Returns [`AlreadyInitialized`] if a logger was already installed, in which case the installed
+/// logger keeps receiving records.
+pub fn initialize() -> Result<(), AlreadyInitialized> {
+    log::set_logger(&Logger).map_err(|_| AlreadyInitialized)?;
+    log::set_max_level(FILTER.lock().max_level());
+
+    Ok(())
+}
+
+/// Logs every module's records up to `level`.
+pub fn set_level(level: log::LevelFilter) {
+    set_filter(LogFilter::new(level));
+}
+
+/// Makes `filter` the filter every subsequent record is checked against.
+pub fn set_filter(filter: LogFilter) {
+    *FILTER.lock() = filter;
+    log::set_max_level(filter.max_level());
+}
+
+/// Allocates a ring of at least `size` bytes that subsequent records are kept in.
+///
+/// A `size` of zero disables the ring. The ring is allocated as persistent memory, so its records
+/// survive `ExitBootServices()`.
+///
+/// # Errors
+/// Returns an [`OutOfMemoryError`] if the ring could not be allocated.
+pub fn initialize_ring(size: usize) -> Result<(), OutOfMemoryError> {
+    let count = size.div_ceil(FRAME_SIZE);
+    if count == 0 {
+        return Ok(());
+    }
+
+    let frames = allocate_frames(count, MemoryKind::Persistent)?;
+    // SAFETY:
+    // `frames` points to `count` freshly allocated frames that are never freed or otherwise
+    // referenced.
+    let buffer = unsafe { core::slice::from_raw_parts_mut(frames.as_ptr(), count * FRAME_SIZE) };
+    *RING.lock() = Some(RingBuffer::new(buffer));
+
+    Ok(())
+}
+
+/// Calls `f` with the records kept in the ring, from oldest to newest.
+///
+/// Each record ends with a newline, but may be passed to `f` in more than one slice.
+#[allow(dead_code)]
+pub fn dump_ring(f: &mut impl FnMut(&[u8])) {
+    if let Some(ring) = RING.lock().as_ref() {
+        ring.for_each(f);
+    }
+}
+
+/// Writes the panic described by `info` and the records kept in the ring where they can be seen,
+/// without waiting for any lock held by the code that panicked.
+///
+/// The panic is kept in the ring, written to every console while boot services are active, and
+/// written to the serial port after the records kept in the ring.
+pub fn panic_output(info: &core::panic::PanicInfo) {
+    let mut ring = RING.try_lock().ok();
+    let ring = ring.as_deref_mut().and_then(Option::as_mut);
+
+    let mut line = LineBuffer {
+        bytes: [0; MAX_RING_RECORD_LENGTH],
+        length: 0,
+    };
+    let _ = writeln!(line, "[PANIC]: {info}");
+
+    if !exit_boot_services::has_exited() {
+        if let Ok(mut mux) = CONSOLE_MUX.try_lock() {
+            let _ = mux.write(line.as_str().as_bytes());
+        }
+    }
+
+    #[cfg(feature = "serial-logging")]
+    serial::force_write(&SERIAL_CONSOLE, |serial_port| {
+        let _ = writeln!(serial_port, "---- buffered log records ----");
+        if let Some(ring) = &ring {
+            ring.for_each(|bytes| {
+                for chunk in bytes.utf8_chunks() {
+                    let _ = serial_port.write_str(chunk.valid());
+                    if !chunk.invalid().is_empty() {
+                        let _ = serial_port.write_char(char::REPLACEMENT_CHARACTER);
+                    }
+                }
+            });
+        }
+        let _ = serial_port.write_str("---- end of buffered log records ----\n");
+        let _ = serial_port.write_str(line.as_str());
+    });
+
+    if let Some(ring) = ring {
+        ring.push(line.as_str().as_bytes());
+    }
+}
+
+/// Selects whether log records are prefixed with the time since the clock was calibrated.
+pub fn set_timestamps(enabled: bool) {
+    TIMESTAMPS.store(enabled, Ordering::Relaxed);
 }
 
-pub fn transition_boot_services() {
-    PROGRAM_STATE.store(INITIALIZING, Ordering::Relaxed);
-    init_transition_logger(&mut TRANSITION_LOGGER.lock());
+/// Selects whether log records are prefixed with the ID of the processor that logged them.
+pub fn set_processor_ids(enabled: bool) {
+    PROCESSOR_IDS.store(enabled, Ordering::Relaxed);
 }
 
+/// Makes `sink` the logger every subsequent record is written to, instead of every registered
+/// console.
+#[allow(dead_code)]
+pub fn set_sink(sink: &'static dyn log::Log) {
+    let interrupts_enabled = arch::interrupts::disable_save();
+    *ACTIVE_LOGGER.write() = Sink::Logger(sink);
+    arch::interrupts::restore(interrupts_enabled);
+}
+
+/// Registers the debug console if it is present.
+///
+/// Returns `false` if the debug console is not present, as on real hardware, or could not be
+/// registered.
+#[cfg(feature = "debugcon")]
+pub fn enable_debugcon() -> bool {
+    if !DEBUGCON_CONSOLE.lock().probe() {
+        return false;
+    }
+
+    crate::console::register(&DEBUGCON_CONSOLE).is_ok()
+}
+
+/// Registers the serial port once boot services have exited, initialized on the configured port.
+///
+/// Nothing is registered if serial logging is disabled or no serial port responds at the
+/// configured port.
+pub fn boot_services_exited() {
+    #[cfg(feature = "serial-logging")]
+    {
+        let config = crate::config::current();
+        let Some(io_port) = config.serial_port else {
+            return;
+        };
+
+        let result = SERIAL_CONSOLE
+            .lock()
+            .initialize(io_port, config.serial_baud_rate);
+        match result {
+            Ok(()) => {
+                if let Err(error) = crate::console::register(&SERIAL_CONSOLE) {
+                    log::warn!("{error}");
+                }
+            }
+            Err(error) => log::warn!("serial logging disabled: {error}"),
+        }
+    }
+}
+
+/// Returns the configured serial port, initializing it if boot services have not exited yet.
+///
+/// Returns [`None`] if serial logging is disabled or no serial port responds at the configured
+/// port.
+pub fn serial_console() -> Option<&'static Spinlock<dyn Console>> {
+    #[cfg(feature = "serial-logging")]
+    {
+        let config = crate::config::current();
+        let io_port = config.serial_port?;
+
+        let mut console = SERIAL_CONSOLE.lock();
+        if !console.is_available() && !exit_boot_services::has_exited() {
+            if let Err(error) = console.initialize(io_port, config.serial_baud_rate) {
+                drop(console);
+                log::warn!("the serial port is unavailable: {error}");
+                return None;
+            }
+        }
+        if console.is_available() {
+            return Some(&SERIAL_CONSOLE);
+        }
+    }
+
+    None
+}
+
+/// The logger installed with the `log` crate, which forwards records to the active logger.
 struct Logger;
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= FILTER.lock().level_for(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
-        match PROGRAM_STATE.load(Ordering::Relaxed) {
-            BOOT_SERVICES => uefi::system::with_stdout(|stdout| {
-                let _ = writeln!(stdout, "[{}]: {}", record.level(), record.args());
-            }),
-            INITIALIZING => TRANSITION_LOGGER.lock().log(record),
-            state => unreachable!("Unreachable program state: {state}"),
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let prefix = Prefix {
+            elapsed: TIMESTAMPS
+                .load(Ordering::Relaxed)
+                .then(time::since_calibration)
+                .flatten(),
+            processor: PROCESSOR_IDS
+                .load(Ordering::Relaxed)
+                .then(arch::processor_id),
         };
+        RING_LOGGER.write(&prefix, record);
+
+        let interrupts_enabled = arch::interrupts::disable_save();
+        let sink = *ACTIVE_LOGGER.read();
+        arch::interrupts::restore(interrupts_enabled);
+
+        match sink {
+            Sink::Consoles => MUX_LOGGER.write(&prefix, record),
+            // Other loggers format the level themselves, so the prefixes can only be passed as
+            // part of the message.
+            Sink::Logger(logger) if prefix.is_empty() => logger.log(record),
+            Sink::Logger(logger) => logger.log(
+                &log::Record::builder()
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .args(format_args!("{prefix}{}", record.args()))
+                    .build(),
+            ),
+        }
     }
 
     fn flush(&self) {}
 }
+
+/// The destination of the records passed to the active logger.
+#[derive(Clone, Copy)]
+enum Sink {
+    /// Every registered console, through [`MUX_LOGGER`].
+    Consoles,
+    /// A logger installed with [`set_sink`].
+    Logger(&'static dyn log::Log),
+}
+
+/// The error returned when a logger was already installed.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct AlreadyInitialized;
+
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a logger was already installed")
+    }
+}
+
+/// The prefixes of a log record.
+struct Prefix {
+    /// The time since the clock was calibrated, if timestamps are enabled and the clock is
+    /// calibrated.
+    elapsed: Option<Duration>,
+    /// The ID of the processor that logged the record if processor IDs are enabled, which is
+    /// [`None`] if it cannot be determined.
+    processor: Option<Option<u32>>,
+}
+
+impl Prefix {
+    /// Returns `true` if no prefix is written.
+    fn is_empty(&self) -> bool {
+        self.elapsed.is_none() && self.processor.is_none()
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(elapsed) = self.elapsed {
+            write!(
+                f,
+                "[{:>4}.{:06}] ",
+                elapsed.as_secs(),
+                elapsed.subsec_micros()
+            )?;
+        }
+
+        match self.processor {
+            Some(Some(id)) => write!(f, "[CPU{id}] "),
+            Some(None) => write!(f, "[CPU?] "),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Logger formatting records into the ring allocated by [`initialize_ring`].
+struct RingBufferLogger;
+
+impl RingBufferLogger {
+    /// Keeps `record`, preceded by `prefix`, in the ring.
+    fn write(&self, prefix: &Prefix, record: &log::Record) {
+        let mut ring = RING.lock();
+        let Some(ring) = ring.as_mut() else {
+            return;
+        };
+
+        let mut line = LineBuffer {
+            bytes: [0; MAX_RING_RECORD_LENGTH],
+            length: 0,
+        };
+        let _ = write_line(&mut line, prefix, record);
+        ring.push(&line.bytes[..line.length]);
+    }
+}
+
+/// Writes `record` as a single line, in the form `<prefix>[LEVEL]: message`.
+fn write_line(f: &mut impl fmt::Write, prefix: &Prefix, record: &log::Record) -> fmt::Result {
+    writeln!(f, "{prefix}[{}]: {}", record.level(), record.args())
+}
+
+/// A fixed-size buffer a record is formatted into, which silently truncates once full.
+struct LineBuffer {
+    /// The storage of the buffer.
+    bytes: [u8; MAX_RING_RECORD_LENGTH],
+    /// The number of bytes written.
+    length: usize,
+}
+
+impl LineBuffer {
+    /// Returns the formatted text, without a character truncation may have split.
+    fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.length];
+        match core::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.length);
+        self.bytes[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count;
+
+        Ok(())
+    }
+}
+
+/// Logger writing records to a [`Console`].
+struct ConsoleLogger {
+    /// The console records are written to.
+    console: &'static Spinlock<dyn Console>,
+}
+
+impl ConsoleLogger {
+    /// Writes `record`, preceded by `prefix`, to the console.
+    fn write(&self, prefix: &Prefix, record: &log::Record) {
+        // Once boot services have exited, records may be logged from interrupt handlers, which
+        // must not wait on the lock held by the code they interrupted. Before then, the firmware's
+        // console may rely on interrupts, and no interrupt handler of the driver logs.
+        let interrupts_enabled =
+            exit_boot_services::has_exited() && arch::interrupts::disable_save();
+        // Every processor logs through the same console, so waiters back off to leave the lock's
+        // cache line to the holder.
+        let mut console = self.console.lock_with_backoff();
+        let _ = write_line(&mut ConsoleWriter(&mut *console), prefix, record);
+        drop(console);
+        arch::interrupts::restore(interrupts_enabled);
+    }
+}
+
+/// Adapter formatting text into a [`Console`].
+struct ConsoleWriter<'a>(&'a mut dyn Console);
+
+impl fmt::Write for ConsoleWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the line a record of `level` with `message` is written as, preceded by `prefix`.
+    fn line(prefix: Prefix, level: log::Level, message: &str) -> String {
+        let mut line = String::new();
+        write_line(
+            &mut line,
+            &prefix,
+            &log::Record::builder()
+                .level(level)
+                .args(format_args!("{message}"))
+                .build(),
+        )
+        .unwrap();
+        line
+    }
+
+    #[test]
+    fn records_without_prefixes_keep_the_plain_format() {
+        let prefix = Prefix {
+            elapsed: None,
+            processor: None,
+        };
+
+        assert!(prefix.is_empty());
+        assert_eq!(line(prefix, log::Level::Info, "hello"), "[INFO]: hello\n");
+    }
+
+    #[test]
+    fn prefixes_precede_the_level() {
+        let prefix = Prefix {
+            elapsed: Some(Duration::from_micros(12_345_678)),
+            processor: Some(Some(3)),
+        };
+
+        assert!(!prefix.is_empty());
+        assert_eq!(
+            line(prefix, log::Level::Warn, "vmlaunch failed"),
+            "[  12.345678] [CPU3] [WARN]: vmlaunch failed\n"
+        );
+    }
+
+    #[test]
+    fn prefixes_are_individually_optional() {
+        let timestamp = Prefix {
+            elapsed: Some(Duration::from_millis(1500)),
+            processor: None,
+        };
+        let processor = Prefix {
+            elapsed: None,
+            processor: Some(Some(0)),
+        };
+        let unknown_processor = Prefix {
+            elapsed: None,
+            processor: Some(None),
+        };
+
+        assert_eq!(
+            line(timestamp, log::Level::Debug, "a"),
+            "[   1.500000] [DEBUG]: a\n"
+        );
+        assert_eq!(
+            line(processor, log::Level::Error, "b"),
+            "[CPU0] [ERROR]: b\n"
+        );
+        assert_eq!(
+            line(unknown_processor, log::Level::Trace, "c"),
+            "[CPU?] [TRACE]: c\n"
+        );
+    }
+
+    #[test]
+    fn ring_lines_are_truncated_at_a_character_boundary() {
+        let mut buffer = LineBuffer {
+            bytes: [0; MAX_RING_RECORD_LENGTH],
+            length: 0,
+        };
+        let message = "é".repeat(MAX_RING_RECORD_LENGTH);
+        let prefix = Prefix {
+            elapsed: None,
+            processor: Some(Some(1)),
+        };
+        let _ = write_line(
+            &mut buffer,
+            &prefix,
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .args(format_args!("{message}"))
+                .build(),
+        );
+
+        assert_eq!(buffer.length, MAX_RING_RECORD_LENGTH);
+        assert!(buffer.as_str().starts_with("[CPU1] [INFO]: éé"));
+        assert!(buffer.as_str().len() < MAX_RING_RECORD_LENGTH);
+    }
+
+    /// Returns the metadata of a record of `level` from `target`.
+    fn metadata(level: log::Level, target: &str) -> log::Metadata<'_> {
+        log::Metadata::builder().level(level).target(target).build()
+    }
+
+    #[test]
+    fn second_initialization_keeps_the_installed_logger() {
+        // No other test installs a logger, so the first call installs the driver's.
+        assert_eq!(initialize(), Ok(()));
+        set_filter("warn,boot_manipulator::shell=trace".parse().unwrap());
+
+        assert_eq!(initialize(), Err(AlreadyInitialized));
+
+        // The installed logger still follows the filter, which the failed call left in place.
+        let logger = log::logger();
+        assert!(logger.enabled(&metadata(log::Level::Warn, "boot_manipulator")));
+        assert!(!logger.enabled(&metadata(log::Level::Info, "boot_manipulator")));
+        assert!(logger.enabled(&metadata(log::Level::Trace, "boot_manipulator::shell")));
+        assert_eq!(log::max_level(), log::LevelFilter::Trace);
+
+        set_level(log::LevelFilter::Error);
+        assert_eq!(initialize(), Err(AlreadyInitialized));
+        assert!(!log::logger().enabled(&metadata(log::Level::Warn, "boot_manipulator")));
+        assert_eq!(log::max_level(), log::LevelFilter::Error);
+    }
+}
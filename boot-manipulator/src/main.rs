@@ -1,101 +1,250 @@
 //! UEFI boot manipulation tool.
 
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use core::{fmt, ptr};
+use core::fmt;
 
-use arch::{exit_boot_services_handler, virtualization};
+use uefi::{
+    boot,
+    mem::memory_map::{MemoryMap, MemoryType},
+    proto::pi::mp::MpServices,
+};
 
+use arch::{nested, virtualization};
+use config::{PanicAction, ProcessorSelection};
+
+mod acpi;
 mod arch;
+mod config;
 pub mod console;
+mod exit_boot_services;
+mod frames;
+mod integrity;
+mod log_filter;
 mod logging;
+mod residency;
+mod ring_buffer;
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+mod shell;
 mod spinlock;
-
-static mut EXIT_BOOT_SERVICES_PTR: unsafe extern "efiapi" fn(
-    *mut core::ffi::c_void,
-    usize,
-) -> uefi::Status = placeholder;
+mod sync;
+mod time;
+mod virtual_address_map;
+mod watchdog;
 
 #[uefi::entry]
 fn entry_point() -> uefi::Status {
-    logging::initialize_logging(log::LevelFilter::Trace);
+    let registered = console::register(&console::UEFI_TEXT_CONSOLE);
+    if let Err(error) = logging::initialize() {
+        log::warn!("{error}");
+    }
+    if let Err(error) = registered {
+        log::warn!("{error}");
+    }
+    let config = config::load();
+    logging::set_filter(config.log_filter);
+    if let Err(error) = logging::initialize_ring(config.log_buffer_size) {
+        log::warn!("failed to allocate the log ring: {error}");
+    }
+    time::calibrate();
+    logging::set_timestamps(config.timestamps);
+    logging::set_processor_ids(config.processor_ids);
+    #[cfg(feature = "debugcon")]
+    if config.debugcon && !logging::enable_debugcon() {
+        log::debug!("the debug console is not available");
+    }
+    if config.framebuffer {
+        match console::framebuffer::initialize() {
+            Ok(()) => {
+                if let Err(error) = console::register(&console::framebuffer::FRAMEBUFFER_CONSOLE) {
+                    log::warn!("{error}");
+                }
+            }
+            Err(error) => log::warn!("failed to set up the framebuffer console: {error}"),
+        }
+    }
+    let watchdog_disabled = watchdog::disable();
+    if config.shell {
+        match logging::serial_console() {
+            Some(console) => shell::run(console),
+            None => log::warn!("the debug shell requires a serial port"),
+        }
+    }
 
     match setup() {
         Ok(()) => {}
         Err(error) => {
             log::error!("{error}");
+            if watchdog_disabled {
+                watchdog::restore();
+            }
+            #[cfg(feature = "test-exit")]
+            arch::debug_exit::exit_qemu(arch::debug_exit::QemuExitCode::Failure);
             uefi::boot::stall(10_000_000);
             return uefi::Status::LOAD_ERROR;
         }
     }
 
     log::info!("boot-manipulator successfully loaded");
+    #[cfg(feature = "test-exit")]
+    arch::debug_exit::exit_qemu(arch::debug_exit::QemuExitCode::Success);
 
     uefi::Status::SUCCESS
 }
 
 fn setup() -> Result<(), DriverSetupError> {
+    if let Some(parent) = nested::detect() {
+        log::info!("running nested under {parent}");
+    }
+
     if !virtualization::is_supported() {
         return Err(DriverSetupError::VirtualizationUnsupported);
     }
 
-    virtualization::allocate_basic_memory();
+    log_processor_count();
+
+    if !config::current().install_hooks {
+        log::info!("hooks disabled by load options");
+        return Ok(());
+    }
 
-    setup_boot_services_interception();
+    virtualization::allocate_basic_memory().map_err(DriverSetupError::OutOfMemory)?;
+
+    let exit_slot = exit_boot_services::install().map_err(DriverSetupError::Hook)?;
+    let relocation_slot =
+        virtual_address_map::install().map_err(DriverSetupError::RelocationHook)?;
+
+    if let Err(error) = residency::protect() {
+        log::warn!("failed to install the unload handler: {error}");
+    }
+
+    match integrity::publish(&[exit_slot, relocation_slot]) {
+        Ok(address) => log::info!("integrity record published at {address:#x}"),
+        Err(error) => log::warn!("failed to publish integrity record: {error}"),
+    }
 
     Ok(())
 }
 
+/// Logs the number of enabled processors, taken from MP services when the firmware provides them
+/// and from the ACPI MADT otherwise.
+fn log_processor_count() {
+    let from_mp_services = boot::get_handle_for_protocol::<MpServices>()
+        .and_then(boot::open_protocol_exclusive::<MpServices>)
+        .and_then(|mp_services| mp_services.get_number_of_processors());
+
+    let (count, source) = match from_mp_services {
+        Ok(count) => (count.enabled, "MP services"),
+        Err(_) => match acpi::processor_count() {
+            Ok(count) => (count, "the ACPI MADT"),
+            Err(error) => {
+                log::warn!("failed to determine the number of processors: {error}");
+                return;
+            }
+        },
+    };
+
+    log::info!("{count} processors enabled according to {source}");
+    if count > 1 && config::current().processors == ProcessorSelection::All {
+        log::warn!(
+            "only the boot processor is virtualized; {} processors will run unintercepted",
+            count - 1
+        );
+    }
+}
+
 /// Various errors that can occur while setting up the driver.
 pub enum DriverSetupError {
     /// Virtualization is not supported on this processor.
     VirtualizationUnsupported,
+    /// The memory needed for virtualization could not be allocated.
+    OutOfMemory(frames::OutOfMemoryError),
+    /// The `ExitBootServices()` hook could not be installed.
+    Hook(exit_boot_services::InstallError),
+    /// The `SetVirtualAddressMap()` hook could not be installed.
+    RelocationHook(virtual_address_map::InstallError),
 }
 
 impl fmt::Display for DriverSetupError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::VirtualizationUnsupported => write!(f, "virtualization is not supported"),
+            Self::OutOfMemory(error) => write!(f, "{error}"),
+            Self::Hook(error) => write!(f, "{error}"),
+            Self::RelocationHook(error) => write!(f, "{error}"),
         }
     }
 }
 
-fn setup_boot_services_interception() {
-    let system_table_ptr = uefi::table::system_table_raw()
-        .map(|ptr| ptr.as_ptr())
-        .unwrap_or(ptr::null_mut());
-
-    let boot_services_table_ptr = unsafe { (*system_table_ptr).boot_services };
-    let exit_boot_services_func = unsafe { &mut ((*boot_services_table_ptr).exit_boot_services) };
-
-    unsafe { EXIT_BOOT_SERVICES_PTR = *exit_boot_services_func };
-    *exit_boot_services_func = exit_boot_services_handler;
-}
-
 /// # Safety
 /// - This function must not be called if virtualization is not supported.
 /// - This function must only be called once, and only after boot services have exited.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
 unsafe extern "C" fn setup_virtualization() -> ! {
-    logging::transition_boot_services();
+    match integrity::verify() {
+        Ok(()) => log::info!("driver integrity verified"),
+        Err(error) => log::error!("driver integrity check failed: {error}"),
+    }
 
-    virtualization::enable_support();
-    log::info!("VMX successfully entered");
+    // SAFETY:
+    // Boot services have exited, so the captured memory map no longer changes.
+    match unsafe { exit_boot_services::exit_context() } {
+        Some(context) => {
+            log::debug!("boot services exited with map key {:#x}", context.map_key);
+            log_memory_map(&context.memory_map);
+        }
+        None => log::warn!("the final memory map was not captured"),
+    }
 
-    virtualization::setup_virtual_machine_state();
-    log::info!("Virtual Machine state initialized");
+    let result = virtualization::enable_support()
+        .inspect(|()| log::info!("VMX successfully entered"))
+        .and_then(|()| virtualization::setup_virtual_machine_state())
+        .inspect(|()| log::info!("Virtual Machine state initialized"))
+        .and_then(|()| virtualization::launch_virtual_machine());
+    let Err(error) = result;
+    log::error!("failed to initialize virtualization: {error}");
+
+    // Boot services have exited, so the released frames cannot be returned to the firmware; they
+    // remain runtime services data and are lost to the operating system.
+    let frames = virtualization::teardown_processor();
+    log::info!("virtualization torn down, releasing {frames:?}");
 
     loop {}
 }
 
-unsafe extern "efiapi" fn placeholder(_: *mut core::ffi::c_void, _: usize) -> uefi::Status {
-    panic!("exit_boot_services placeholder reached")
+/// Logs a summary of the memory map in force when boot services exited.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+fn log_memory_map(memory_map: &impl MemoryMap) {
+    let top_of_ram = memory_map
+        .entries()
+        .filter(|descriptor| descriptor.ty == MemoryType::CONVENTIONAL)
+        .map(|descriptor| descriptor.phys_start + descriptor.page_count * boot::PAGE_SIZE as u64)
+        .max()
+        .unwrap_or(0);
+
+    log::info!(
+        "final memory map has {} descriptors, with usable memory up to {top_of_ram:#x}",
+        memory_map.len()
+    );
 }
 
+/// How long a panic stays on screen before the machine reboots with `panic=reboot`.
+const PANIC_REBOOT_DELAY: core::time::Duration = core::time::Duration::from_secs(10);
+
 #[cfg_attr(not(test), panic_handler)]
 #[allow(unused)]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
-    log::error!("{info}");
+    logging::panic_output(info);
+
+    if config::panic_action() == PanicAction::Reboot {
+        time::busy_wait(PANIC_REBOOT_DELAY);
+        if exit_boot_services::has_exited() {
+            arch::reset();
+        }
+
+        uefi::runtime::reset(uefi::runtime::ResetType::COLD, uefi::Status::ABORTED, None);
+    }
 
     loop {}
 }
@@ -0,0 +1,94 @@
+//! Keeping the driver image resident while its hooks are installed.
+//!
+//! The installed hooks point into the driver image, so unloading the image while they remain
+//! installed would leave the firmware calling freed memory.
+
+use core::fmt;
+
+use uefi::{
+    boot::{self, MemoryType},
+    proto::loaded_image::LoadedImage,
+    Handle, Status,
+};
+
+use crate::{exit_boot_services, virtual_address_map};
+
+/// Logs the subsystem the driver was loaded as and installs an unload handler that refuses to
+/// unload the driver while its hooks are installed.
+///
+/// # Errors
+/// Returns an error if the driver's [`LoadedImage`] protocol could not be opened.
+pub fn protect() -> uefi::Result<()> {
+    let mut loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
+
+    match loaded_image.code_type() {
+        MemoryType::RUNTIME_SERVICES_CODE => log::info!("loaded as a runtime driver"),
+        MemoryType::BOOT_SERVICES_CODE => {
+            log::warn!("loaded as a boot services driver: hooks will not survive ExitBootServices");
+        }
+        MemoryType::LOADER_CODE => {
+            log::warn!("loaded as an application: the image is unloaded once it returns");
+        }
+        other => log::warn!("loaded with unexpected code type {other:?}"),
+    }
+
+    // SAFETY:
+    // `unload` only inspects the state of the hooks and may be called at any time.
+    unsafe { loaded_image.set_unload(unload) };
+
+    Ok(())
+}
+
+/// Removes every installed hook, after which the driver may be unloaded.
+///
+/// # Errors
+/// Returns an [`UninstallError`] if a hook could not be removed. Hooks removed before the failure
+/// stay removed.
+///
+/// # Safety
+/// Boot services must be active, and the hooks must not be executing.
+#[allow(dead_code)]
+pub unsafe fn uninstall() -> Result<(), UninstallError> {
+    // SAFETY:
+    // The caller guarantees that boot services are active.
+    match unsafe { virtual_address_map::uninstall() } {
+        Ok(()) | Err(UninstallError::NotInstalled) => {}
+        Err(error) => return Err(error),
+    }
+
+    // SAFETY:
+    // The caller guarantees that boot services are active.
+    match unsafe { exit_boot_services::uninstall() } {
+        Ok(()) | Err(UninstallError::NotInstalled) => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Refuses to unload the driver while any of its hooks are installed.
+extern "efiapi" fn unload(_: Handle) -> Status {
+    if exit_boot_services::is_installed() || virtual_address_map::is_installed() {
+        return Status::ACCESS_DENIED;
+    }
+
+    Status::SUCCESS
+}
+
+/// Various errors that can occur while removing a hook.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum UninstallError {
+    /// The hook is not installed.
+    NotInstalled,
+    /// Another image replaced the hook of the given service.
+    Overwritten(&'static str),
+}
+
+impl fmt::Display for UninstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInstalled => write!(f, "the hook is not installed"),
+            Self::Overwritten(service) => {
+                write!(f, "the {service} hook was replaced by another image")
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! A fixed-size ring of variable-length records, which drops whole records when full.
+
+/// The size of the header preceding each record, which holds its length.
+const HEADER_SIZE: usize = 2;
+
+/// A ring of variable-length records stored in a fixed buffer.
+///
+/// When a new record does not fit, the oldest records are dropped whole until it does, so a record
+/// is never partially overwritten.
+pub struct RingBuffer {
+    /// The storage of the ring.
+    buffer: &'static mut [u8],
+    /// The offset of the header of the oldest record.
+    head: usize,
+    /// The number of bytes in use, including headers.
+    used: usize,
+}
+
+impl RingBuffer {
+    /// Creates an empty [`RingBuffer`] storing its records in `buffer`.
+    pub fn new(buffer: &'static mut [u8]) -> Self {
+        Self {
+            buffer,
+            head: 0,
+            used: 0,
+        }
+    }
+
+    /// Appends `record`, dropping the oldest records until it fits.
+    ///
+    /// Records longer than the ring can hold are truncated.
+    pub fn push(&mut self, record: &[u8]) {
+        let capacity = self.buffer.len();
+        if capacity <= HEADER_SIZE {
+            return;
+        }
+
+        let length = record
+            .len()
+            .min(capacity - HEADER_SIZE)
+            .min(usize::from(u16::MAX));
+        let record = &record[..length];
+
+        while capacity - self.used < HEADER_SIZE + length {
+            self.drop_oldest();
+        }
+
+        let tail = (self.head + self.used) % capacity;
+        self.write_at(tail, &(length as u16).to_le_bytes());
+        self.write_at((tail + HEADER_SIZE) % capacity, record);
+        self.used += HEADER_SIZE + length;
+    }
+
+    /// Calls `f` with the contents of each record from oldest to newest.
+    ///
+    /// A record that wraps around the end of the buffer is passed as two consecutive slices.
+    pub fn for_each(&self, mut f: impl FnMut(&[u8])) {
+        let capacity = self.buffer.len();
+        let mut offset = self.head;
+        let mut remaining = self.used;
+        while remaining != 0 {
+            let length = usize::from(self.read_length(offset));
+            let start = (offset + HEADER_SIZE) % capacity;
+            let first = length.min(capacity - start);
+            f(&self.buffer[start..start + first]);
+            if first < length {
+                f(&self.buffer[..length - first]);
+            }
+
+            offset = (start + length) % capacity;
+            remaining -= HEADER_SIZE + length;
+        }
+    }
+
+    /// Removes the oldest record.
+    fn drop_oldest(&mut self) {
+        let length = usize::from(self.read_length(self.head));
+        self.head = (self.head + HEADER_SIZE + length) % self.buffer.len();
+        self.used -= HEADER_SIZE + length;
+    }
+
+    /// Reads the length stored in the header at `offset`.
+    fn read_length(&self, offset: usize) -> u16 {
+        let capacity = self.buffer.len();
+        u16::from_le_bytes([self.buffer[offset], self.buffer[(offset + 1) % capacity]])
+    }
+
+    /// Copies `bytes` into the buffer starting at `offset`, wrapping around its end.
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) {
+        let first = bytes.len().min(self.buffer.len() - offset);
+        self.buffer[offset..offset + first].copy_from_slice(&bytes[..first]);
+        self.buffer[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty [`RingBuffer`] with room for `capacity` bytes.
+    fn ring(capacity: usize) -> RingBuffer {
+        RingBuffer::new(Box::leak(vec![0; capacity].into_boxed_slice()))
+    }
+
+    /// Returns the slices passed to [`RingBuffer::for_each`].
+    fn slices(ring: &RingBuffer) -> Vec<Vec<u8>> {
+        let mut slices = Vec::new();
+        ring.for_each(|slice| slices.push(slice.to_vec()));
+        slices
+    }
+
+    #[test]
+    fn records_are_returned_oldest_first() {
+        let mut ring = ring(16);
+        assert!(slices(&ring).is_empty());
+
+        ring.push(b"abc");
+        ring.push(b"");
+        ring.push(b"de");
+        assert_eq!(slices(&ring), [&b"abc"[..], b"", b"de"]);
+    }
+
+    #[test]
+    fn full_rings_drop_whole_records() {
+        let mut ring = ring(10);
+        ring.push(b"aaaa");
+        ring.push(b"bbbb");
+        assert_eq!(ring.used, 6);
+        assert_eq!(slices(&ring).concat(), b"bbbb");
+
+        // The new record fits in the space freed at the start of the buffer.
+        ring.push(b"cc");
+        assert_eq!(slices(&ring).concat(), b"bbbbcc");
+    }
+
+    #[test]
+    fn headers_wrap_around_the_end() {
+        let mut ring = ring(10);
+        ring.push(b"aaa");
+        ring.push(b"bb");
+        ring.push(b"ccccc");
+
+        // The header of the last record occupies the final and first bytes of the buffer.
+        assert_eq!(ring.head, 9);
+        assert_eq!(slices(&ring), [b"ccccc"]);
+    }
+
+    #[test]
+    fn records_wrap_around_the_end_as_two_slices() {
+        let mut ring = ring(10);
+        ring.push(b"a");
+        ring.push(b"bb");
+        ring.push(b"ccc");
+
+        assert_eq!(slices(&ring), [&b"bb"[..], b"c", b"cc"]);
+        assert_eq!(ring.used, 9);
+    }
+
+    #[test]
+    fn long_records_are_truncated() {
+        let mut ring = ring(8);
+        ring.push(b"xy");
+        ring.push(b"0123456789");
+
+        assert_eq!(slices(&ring).concat(), b"012345");
+        assert_eq!(ring.used, 8);
+    }
+
+    #[test]
+    fn tiny_rings_hold_nothing() {
+        let mut ring = ring(HEADER_SIZE);
+        ring.push(b"a");
+        assert!(slices(&ring).is_empty());
+    }
+}
@@ -0,0 +1,578 @@
+//! A minimal interactive shell on the serial port for inspecting the hypervisor.
+//!
+//! The shell is offered when the driver is loaded with `shell=serial`: once before the hooks are
+//! installed, and afterwards whenever [`MAGIC`] is received on the serial port while a
+//! virtualized processor idles in `hlt`. Lines are only edited with backspace, and are parsed
+//! without allocating. The commands are:
+//!
+//! - `help` lists the commands.
+//! - `state` reports the state of the driver and of the virtualization support.
+//! - `registers` dumps the segment registers of the current processor.
+//! - `vmcs <encoding>` reads the field with `encoding` from the current VMCS.
+//! - `msr <address> [force]` reads a model-specific register.
+//! - `mem <physical> <length> [force]` dumps physical memory.
+//! - `log <level>` sets the maximum level of every module's records.
+//! - `exit` leaves the shell.
+//!
+//! Numbers are decimal, or hexadecimal if prefixed with `0x`. Reads that are likely to fault or to
+//! have side effects are refused unless `force` is given.
+
+use core::fmt::{self, Write};
+
+use crate::{
+    arch::{
+        self,
+        inspect::{self, CHUNK_LENGTH},
+    },
+    console::Console,
+    exit_boot_services, logging,
+    spinlock::Spinlock,
+    virtual_address_map,
+};
+
+/// The bytes that summon the shell while a processor idles: Ctrl-B three times.
+pub const MAGIC: &[u8] = b"\x02\x02\x02";
+
+/// The prompt written before every line.
+const PROMPT: &str = "boot-manipulator> ";
+
+/// The maximum length of a line in bytes; further characters are ignored.
+const MAX_LINE_LENGTH: usize = 128;
+
+/// The ASCII backspace character.
+const BACKSPACE: u8 = 0x08;
+/// The ASCII delete character, which most terminals send for the backspace key.
+const DELETE: u8 = 0x7F;
+
+/// The overview written by `help`.
+const HELP: &str = "\
+help                          list the commands
+state                         report the state of the driver
+registers                     dump the segment registers
+vmcs <encoding>               read a field of the current VMCS
+msr <address> [force]         read a model-specific register
+mem <physical> <length> [force]
+                              dump physical memory
+log <level>                   set the maximum log level
+exit                          leave the shell
+";
+
+/// The number of bytes of [`MAGIC`] received so far, which is locked by the processor polling the
+/// serial port or running the shell.
+static MAGIC_RECEIVED: Spinlock<usize> = Spinlock::new(0);
+
+/// Checks the serial port for [`MAGIC`] and runs the shell once it was received.
+///
+/// Called whenever the guest executes `hlt`. Does nothing if another processor is already polling
+/// or running the shell.
+pub fn idle() {
+    let Ok(mut received) = MAGIC_RECEIVED.try_lock() else {
+        return;
+    };
+    let Some(console) = logging::serial_console() else {
+        return;
+    };
+
+    let mut byte = [0];
+    loop {
+        let result = console.lock().read(&mut byte);
+        if !matches!(result, Ok(1)) {
+            return;
+        }
+
+        *received = match byte[0] {
+            byte if byte == MAGIC[*received] => *received + 1,
+            byte if byte == MAGIC[0] => 1,
+            _ => 0,
+        };
+        if *received == MAGIC.len() {
+            *received = 0;
+            run(console);
+            return;
+        }
+    }
+}
+
+/// Runs the shell on `console` until `exit` is entered.
+pub fn run(console: &Spinlock<dyn Console>) {
+    let mut output = Output(console);
+    let _ = writeln!(
+        output,
+        "\nboot-manipulator shell, `help` lists the commands"
+    );
+
+    let mut editor = LineEditor::new();
+    loop {
+        let _ = output.write_str(PROMPT);
+        let line = editor.read_line(console);
+        let result = match parse(line) {
+            Ok(Command::Exit) => return,
+            Ok(command) => execute(command, &mut output),
+            Err(ParseError::Empty) => Ok(()),
+            Err(error) => writeln!(output, "{error}"),
+        };
+        if result.is_err() {
+            return;
+        }
+    }
+}
+
+/// Executes `command`, writing its results to `output`.
+fn execute(command: Command, output: &mut dyn Write) -> fmt::Result {
+    match command {
+        Command::Help => output.write_str(HELP),
+        Command::State => write_state(output),
+        Command::Registers => inspect::write_registers(output),
+        Command::Vmcs(encoding) => match inspect::read_vmcs_field(encoding) {
+            Ok(value) => writeln!(output, "{encoding:#06x}: {value:#x}"),
+            Err(error) => writeln!(output, "{error}"),
+        },
+        Command::Msr { address, force } => match inspect::read_msr(address, force) {
+            Ok(value) => writeln!(output, "{address:#x}: {value:#018x}"),
+            Err(error) => writeln!(output, "{error}"),
+        },
+        Command::Mem {
+            physical,
+            length,
+            force,
+        } => {
+            let mut result = Ok(());
+            let read = inspect::read_physical(physical, length, force, &mut |address, bytes| {
+                if result.is_ok() {
+                    result = write_hex_line(output, address, bytes);
+                }
+            });
+            match read {
+                Ok(()) => result,
+                Err(error) => writeln!(output, "{error}"),
+            }
+        }
+        Command::Log(level) => {
+            logging::set_level(level);
+            writeln!(output, "logging every module up to {level}")
+        }
+        Command::Exit => Ok(()),
+    }
+}
+
+/// Writes the state of the driver and of the virtualization support to `output`.
+fn write_state(output: &mut dyn Write) -> fmt::Result {
+    match arch::processor_id() {
+        Some(id) => writeln!(output, "processor: {id}")?,
+        None => writeln!(output, "processor: unknown")?,
+    }
+    writeln!(
+        output,
+        "ExitBootServices() hook installed: {}",
+        exit_boot_services::is_installed()
+    )?;
+    writeln!(
+        output,
+        "SetVirtualAddressMap() hook installed: {}",
+        virtual_address_map::is_installed()
+    )?;
+    writeln!(
+        output,
+        "boot services exited: {}",
+        exit_boot_services::has_exited()
+    )?;
+    writeln!(output, "maximum log level: {}", log::max_level())?;
+
+    inspect::write_state(output)
+}
+
+/// Writes `bytes`, read at `address`, as a line of hexadecimal and printable ASCII.
+fn write_hex_line(output: &mut dyn Write, address: u64, bytes: &[u8]) -> fmt::Result {
+    write!(output, "{address:016x}:")?;
+    for byte in bytes {
+        write!(output, " {byte:02x}")?;
+    }
+    for _ in bytes.len()..CHUNK_LENGTH {
+        output.write_str("   ")?;
+    }
+
+    output.write_str("  |")?;
+    for &byte in bytes {
+        let character = if byte.is_ascii_graphic() || byte == b' ' {
+            byte as char
+        } else {
+            '.'
+        };
+        output.write_char(character)?;
+    }
+    output.write_str("|\n")
+}
+
+/// A command entered into the shell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    /// Lists the commands.
+    Help,
+    /// Reports the state of the driver.
+    State,
+    /// Dumps the segment registers.
+    Registers,
+    /// Reads the field with the encoding from the current VMCS.
+    Vmcs(u32),
+    /// Reads a model-specific register.
+    Msr {
+        /// The address of the register.
+        address: u32,
+        /// Whether registers not known to be implemented are read.
+        force: bool,
+    },
+    /// Dumps physical memory.
+    Mem {
+        /// The physical address of the first byte.
+        physical: u64,
+        /// The number of bytes.
+        length: u64,
+        /// Whether long reads and reads of memory-mapped I/O are allowed.
+        force: bool,
+    },
+    /// Sets the maximum level of every module's records.
+    Log(log::LevelFilter),
+    /// Leaves the shell.
+    Exit,
+}
+
+/// Parses `line` into a [`Command`].
+///
+/// # Errors
+/// Returns a [`ParseError`] if `line` is empty or is not a valid command.
+fn parse(line: &str) -> Result<Command, ParseError<'_>> {
+    let mut words = line.split_ascii_whitespace();
+    let command = match words.next().ok_or(ParseError::Empty)? {
+        "help" => Command::Help,
+        "state" => Command::State,
+        "registers" => Command::Registers,
+        "vmcs" => Command::Vmcs(parse_number(&mut words, "field encoding")?),
+        "msr" => Command::Msr {
+            address: parse_number(&mut words, "MSR address")?,
+            force: parse_force(&mut words)?,
+        },
+        "mem" => Command::Mem {
+            physical: parse_number(&mut words, "physical address")?,
+            length: parse_number(&mut words, "length")?,
+            force: parse_force(&mut words)?,
+        },
+        "log" => {
+            let level = words.next().ok_or(ParseError::MissingArgument("level"))?;
+            Command::Log(level.parse().map_err(|_| ParseError::InvalidLevel(level))?)
+        }
+        "exit" => Command::Exit,
+        name => return Err(ParseError::UnknownCommand(name)),
+    };
+
+    match words.next() {
+        Some(word) => Err(ParseError::UnexpectedArgument(word)),
+        None => Ok(command),
+    }
+}
+
+/// Parses the next word of `words` as a number called `name`.
+///
+/// # Errors
+/// Returns a [`ParseError`] if there is no next word, or it is not a number that fits in `T`.
+fn parse_number<'a, T: TryFrom<u64>>(
+    words: &mut impl Iterator<Item = &'a str>,
+    name: &'static str,
+) -> Result<T, ParseError<'a>> {
+    let word = words.next().ok_or(ParseError::MissingArgument(name))?;
+    let value = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+
+    value
+        .ok()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or(ParseError::InvalidNumber(word))
+}
+
+/// Parses the optional `force` argument from `words`.
+///
+/// # Errors
+/// Returns [`ParseError::UnexpectedArgument`] if the next word is something else.
+fn parse_force<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<bool, ParseError<'a>> {
+    match words.next() {
+        None => Ok(false),
+        Some("force") => Ok(true),
+        Some(word) => Err(ParseError::UnexpectedArgument(word)),
+    }
+}
+
+/// Various errors that can occur while parsing a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParseError<'a> {
+    /// The line holds no command.
+    Empty,
+    /// The command is not known.
+    UnknownCommand(&'a str),
+    /// The named argument is missing.
+    MissingArgument(&'static str),
+    /// The argument is not a number, or is out of range.
+    InvalidNumber(&'a str),
+    /// The argument is not a log level.
+    InvalidLevel(&'a str),
+    /// The command takes no further arguments.
+    UnexpectedArgument(&'a str),
+}
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "no command given"),
+            Self::UnknownCommand(name) => {
+                write!(f, "unknown command `{name}`, `help` lists the commands")
+            }
+            Self::MissingArgument(name) => write!(f, "missing {name}"),
+            Self::InvalidNumber(word) => write!(f, "`{word}` is not a valid number"),
+            Self::InvalidLevel(word) => write!(f, "`{word}` is not a log level"),
+            Self::UnexpectedArgument(word) => write!(f, "unexpected argument `{word}`"),
+        }
+    }
+}
+
+/// Reads lines from a console, echoing printable characters and handling backspace.
+struct LineEditor {
+    /// The characters of the current line.
+    buffer: [u8; MAX_LINE_LENGTH],
+    /// The number of characters in `buffer`.
+    length: usize,
+    /// Whether the previous line ended with a carriage return, so that a following line feed is
+    /// part of the same line ending.
+    after_carriage_return: bool,
+}
+
+impl LineEditor {
+    /// Creates a [`LineEditor`] without input.
+    fn new() -> Self {
+        Self {
+            buffer: [0; MAX_LINE_LENGTH],
+            length: 0,
+            after_carriage_return: false,
+        }
+    }
+
+    /// Reads a line from `console`, waiting until it is terminated by a carriage return or a line
+    /// feed.
+    fn read_line(&mut self, console: &Spinlock<dyn Console>) -> &str {
+        self.length = 0;
+        loop {
+            let mut byte = [0];
+            let result = console.lock().read(&mut byte);
+            if !matches!(result, Ok(1)) {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let after_carriage_return = core::mem::replace(&mut self.after_carriage_return, false);
+            match byte[0] {
+                b'\n' if after_carriage_return => {}
+                b'\r' | b'\n' => {
+                    self.after_carriage_return = byte[0] == b'\r';
+                    let _ = console.lock().write(b"\r\n");
+                    break;
+                }
+                BACKSPACE | DELETE if self.length != 0 => {
+                    self.length -= 1;
+                    let _ = console.lock().write(b"\x08 \x08");
+                }
+                byte @ (b' '..=b'~') if self.length < MAX_LINE_LENGTH => {
+                    self.buffer[self.length] = byte;
+                    self.length += 1;
+                    let _ = console.lock().write(&[byte]);
+                }
+                _ => {}
+            }
+        }
+
+        // Only printable ASCII is accepted into the buffer.
+        core::str::from_utf8(&self.buffer[..self.length]).unwrap_or_default()
+    }
+}
+
+/// Writer to a console that ends lines with a carriage return and a line feed, as terminals
+/// attached to a serial port expect.
+struct Output<'a>(&'a Spinlock<dyn Console>);
+
+impl Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut console = self.0.lock();
+        for (index, line) in s.split('\n').enumerate() {
+            if index != 0 {
+                console.write(b"\r\n").map_err(|_| fmt::Error)?;
+            }
+            console.write(line.as_bytes()).map_err(|_| fmt::Error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::console::{ReadError, WriteError};
+
+    /// A console replaying scripted input and recording its output.
+    struct ScriptedConsole {
+        /// The bytes that remain to be read.
+        input: VecDeque<u8>,
+        /// The bytes written so far.
+        output: Vec<u8>,
+    }
+
+    impl ScriptedConsole {
+        /// Creates a [`ScriptedConsole`] that reads `input`.
+        fn new(input: &[u8]) -> Spinlock<Self> {
+            Spinlock::new(Self {
+                input: input.iter().copied().collect(),
+                output: Vec::new(),
+            })
+        }
+    }
+
+    impl Console for ScriptedConsole {
+        fn name(&self) -> &'static str {
+            "scripted"
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> Result<usize, (ReadError, usize)> {
+            let count = data.len().min(self.input.len());
+            for (slot, byte) in data.iter_mut().zip(self.input.drain(..count)) {
+                *slot = byte;
+            }
+            Ok(count)
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), (WriteError, usize)> {
+            self.output.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        let commands = [
+            ("help", Command::Help),
+            ("  state  ", Command::State),
+            ("registers", Command::Registers),
+            ("vmcs 0x681e", Command::Vmcs(0x681E)),
+            (
+                "msr 0xC0000080",
+                Command::Msr {
+                    address: 0xC000_0080,
+                    force: false,
+                },
+            ),
+            (
+                "msr 16 force",
+                Command::Msr {
+                    address: 16,
+                    force: true,
+                },
+            ),
+            (
+                "mem 0X1000\t64",
+                Command::Mem {
+                    physical: 0x1000,
+                    length: 64,
+                    force: false,
+                },
+            ),
+            (
+                "mem 0xFEE00000 4096 force",
+                Command::Mem {
+                    physical: 0xFEE0_0000,
+                    length: 4096,
+                    force: true,
+                },
+            ),
+            ("log debug", Command::Log(log::LevelFilter::Debug)),
+            ("exit", Command::Exit),
+        ];
+
+        for (line, command) in commands {
+            assert_eq!(parse(line), Ok(command), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let errors = [
+            ("", ParseError::Empty),
+            ("   ", ParseError::Empty),
+            ("halt", ParseError::UnknownCommand("halt")),
+            ("HELP", ParseError::UnknownCommand("HELP")),
+            ("vmcs", ParseError::MissingArgument("field encoding")),
+            ("vmcs 0xZZ", ParseError::InvalidNumber("0xZZ")),
+            ("msr 0x100000000", ParseError::InvalidNumber("0x100000000")),
+            ("msr -1", ParseError::InvalidNumber("-1")),
+            ("msr 0x10 please", ParseError::UnexpectedArgument("please")),
+            ("mem 0x1000", ParseError::MissingArgument("length")),
+            (
+                "mem 0x1000 16 force now",
+                ParseError::UnexpectedArgument("now"),
+            ),
+            ("log", ParseError::MissingArgument("level")),
+            ("log loud", ParseError::InvalidLevel("loud")),
+            ("exit now", ParseError::UnexpectedArgument("now")),
+        ];
+
+        for (line, error) in errors {
+            assert_eq!(parse(line), Err(error), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn hex_lines_pad_short_chunks() {
+        let mut line = String::new();
+        write_hex_line(&mut line, 0x1000, b"Hi\x00 ~").unwrap();
+
+        let padding = "   ".repeat(CHUNK_LENGTH - 5);
+        assert_eq!(
+            line,
+            format!("0000000000001000: 48 69 00 20 7e{padding}  |Hi. ~|\n")
+        );
+    }
+
+    #[test]
+    fn line_editor_handles_backspace_and_line_endings() {
+        let console =
+            ScriptedConsole::new(b"mrs\x7F\x08sr 16\r\nhelp\x01\x7F\x7F\x7F\x7F\x7Fexit\n");
+        let mut editor = LineEditor::new();
+
+        assert_eq!(editor.read_line(&console), "msr 16");
+        // The line feed following the carriage return does not end an empty line.
+        assert_eq!(editor.read_line(&console), "exit");
+        assert!(console.lock().input.is_empty());
+
+        let output = String::from_utf8(console.lock().output.clone()).unwrap();
+        assert_eq!(
+            output,
+            "mrs\x08 \x08\x08 \x08sr 16\r\nhelp\x08 \x08\x08 \x08\x08 \x08\x08 \x08exit\r\n"
+        );
+    }
+
+    #[test]
+    fn line_editor_ignores_characters_past_the_limit() {
+        let mut input = vec![b'a'; MAX_LINE_LENGTH + 8];
+        input.push(b'\r');
+        let console = ScriptedConsole::new(&input);
+
+        let line = LineEditor::new().read_line(&console).len();
+        assert_eq!(line, MAX_LINE_LENGTH);
+    }
+
+    #[test]
+    fn output_ends_lines_for_terminals() {
+        let console = ScriptedConsole::new(b"");
+        write!(Output(&console), "a\nb\n").unwrap();
+
+        assert_eq!(console.lock().output, b"a\r\nb\r\n");
+    }
+}
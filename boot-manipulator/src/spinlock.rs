@@ -1,70 +1,217 @@
 //! Simple spinlock implementation.
+//!
+//! Locks are ticket locks: each processor waiting for a lock takes a ticket and is granted the
+//! lock in the order its ticket was taken, so that no processor is starved by others repeatedly
+//! reacquiring the lock.
+//!
+//! [`IrqSpinlock`] additionally masks interrupts while held, and must be used for data that is
+//! also accessed from interrupt or VM exit handlers: if such a handler tried to take a
+//! [`Spinlock`] held by the code it interrupted, the processor would wait on itself forever. Data
+//! that is never touched from those contexts uses the plain [`Spinlock`], which leaves interrupts
+//! alone and so does not delay them.
+//!
+//! With the `debug-locks` feature, each lock records the processor holding it and where it was
+//! acquired, and a processor locking a lock it already holds panics instead of deadlocking.
 
 use core::{
     cell::UnsafeCell,
     error, fmt,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicU32, Ordering},
 };
+#[cfg(feature = "debug-locks")]
+pub use owner::Owner;
+
+/// The maximum number of spins between checks of the ticket being served with backoff.
+const MAX_BACKOFF_SPINS: u32 = 64;
 
 /// The locking component of a [`Spinlock`].
 #[derive(Debug)]
 pub struct RawSpinlock {
-    /// The lock.
-    lock: AtomicBool,
+    /// The next ticket to hand out.
+    next_ticket: AtomicU32,
+    /// The ticket that currently holds the lock, or [`RawSpinlock::next_ticket`] if the lock is
+    /// not held.
+    now_serving: AtomicU32,
+    /// The processor holding the lock.
+    #[cfg(feature = "debug-locks")]
+    owner: Owner,
 }
 
 impl RawSpinlock {
     /// Creates a new [`RawSpinlock`] in the unlocked state.
     pub const fn new() -> Self {
         Self {
-            lock: AtomicBool::new(false),
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            #[cfg(feature = "debug-locks")]
+            owner: Owner::new(),
         }
     }
 
     /// Locks the [`RawSpinlock`], spinning until the lock is acquired.
     ///
     /// This function does not return until the lock has been acquired.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor already holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
     pub fn lock(&self) {
-        let mut was_locked = self.lock.load(Ordering::Relaxed);
-
-        loop {
-            if !was_locked {
-                match self
-                    .lock
-                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-                {
-                    Ok(_) => break,
-                    Err(state) => was_locked = state,
-                }
-            }
+        self.acquire(false);
+    }
+
+    /// Locks the [`RawSpinlock`] like [`RawSpinlock::lock`], but checks whether the lock was
+    /// granted exponentially less often while waiting, which reduces traffic on heavily contended
+    /// locks.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor already holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn lock_with_backoff(&self) {
+        self.acquire(true);
+    }
+
+    /// Takes a ticket and waits until it is served, backing off between checks if `backoff`.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    fn acquire(&self, backoff: bool) {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
 
-            core::hint::spin_loop();
+        let mut spins = 1;
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            #[cfg(feature = "debug-locks")]
+            self.owner.check_deadlock();
+
+            for _ in 0..spins {
+                core::hint::spin_loop();
+            }
+            if backoff {
+                spins = (spins * 2).min(MAX_BACKOFF_SPINS);
+            }
         }
+
+        #[cfg(feature = "debug-locks")]
+        self.owner.record();
     }
 
     /// Attempts to lock the [`RawSpinlock`].
     ///
-    /// This function does not spin or block.
+    /// This function does not spin or block. A ticket is only taken if it would be served
+    /// immediately, so a failed attempt does not hold up later waiters.
     ///
     /// # Errors
     /// If the [`RawSpinlock`] was already locked, then this calll will return an [`Err`].
+    #[cfg_attr(feature = "debug-locks", track_caller)]
     pub fn try_lock(&self) -> Result<(), SpinlockAcquisitionError> {
-        if !self.lock.load(Ordering::Relaxed)
-            && self
-                .lock
-                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
+        let serving = self.now_serving.load(Ordering::Acquire);
+        if self
+            .next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
         {
+            #[cfg(feature = "debug-locks")]
+            self.owner.record();
             Ok(())
         } else {
             Err(SpinlockAcquisitionError)
         }
     }
 
+    /// Returns `true` if the [`RawSpinlock`] is currently locked.
+    ///
+    /// The lock may be acquired or released by another processor as soon as this returns.
+    pub fn is_locked(&self) -> bool {
+        self.now_serving.load(Ordering::Relaxed) != self.next_ticket.load(Ordering::Relaxed)
+    }
+
     /// Unlocks the [`RawSpinlock`].
     pub fn unlock(&self) {
-        self.lock.store(false, Ordering::Release);
+        #[cfg(feature = "debug-locks")]
+        self.owner.clear();
+
+        self.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Tracking of the holder of a lock for the `debug-locks` feature.
+#[cfg(feature = "debug-locks")]
+mod owner {
+    use core::{
+        panic::Location,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    };
+
+    /// The value of [`Owner::processor`] when no identifiable processor holds the lock.
+    const NO_OWNER: u64 = u64::MAX;
+
+    /// The processor holding a lock and where it acquired the lock.
+    #[derive(Debug)]
+    pub struct Owner {
+        /// The ID of the processor holding the lock, or [`NO_OWNER`].
+        processor: AtomicU64,
+        /// Where the lock was acquired by its current holder, or null if it is not held.
+        location: AtomicPtr<Location<'static>>,
+    }
+
+    impl Owner {
+        /// Creates an [`Owner`] of a lock that is not held.
+        pub const fn new() -> Self {
+            Self {
+                processor: AtomicU64::new(NO_OWNER),
+                location: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+
+        /// Records the current processor and the caller's location as the holder of the lock.
+        #[track_caller]
+        pub fn record(&self) {
+            let processor = crate::arch::processor_id().map_or(NO_OWNER, u64::from);
+            self.processor.store(processor, Ordering::Relaxed);
+            self.location.store(
+                ptr::from_ref(Location::caller()).cast_mut(),
+                Ordering::Relaxed,
+            );
+        }
+
+        /// Records that the lock is no longer held.
+        pub fn clear(&self) {
+            self.processor.store(NO_OWNER, Ordering::Relaxed);
+            self.location.store(ptr::null_mut(), Ordering::Relaxed);
+        }
+
+        /// Panics if the current processor holds the lock, which it would otherwise wait on
+        /// forever.
+        #[track_caller]
+        pub fn check_deadlock(&self) {
+            let Some(processor) = crate::arch::processor_id() else {
+                return;
+            };
+            if self.processor.load(Ordering::Relaxed) != u64::from(processor) {
+                return;
+            }
+
+            let location = self.location.load(Ordering::Relaxed);
+            // SAFETY:
+            // `location` is either null or was derived from a `&'static Location<'static>`.
+            match unsafe { location.as_ref() } {
+                Some(holder) => panic!(
+                    "deadlock: processor {processor} locked a spinlock at {} that it acquired at \
+                     {holder}",
+                    Location::caller()
+                ),
+                None => panic!(
+                    "deadlock: processor {processor} locked a spinlock at {} that it already \
+                     holds",
+                    Location::caller()
+                ),
+            }
+        }
     }
 }
 
@@ -113,6 +260,10 @@ impl<T: ?Sized> Spinlock<T> {
     /// This function will spin until the lock is available. Upon returning, this context is the
     /// only context with the lock held. A RAII guard is returned to allow for scoped unlock of the
     /// [`Spinlock`].
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor already holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
     pub fn lock(&self) -> SpinlockGuard<T> {
         self.lock.lock();
 
@@ -122,6 +273,20 @@ impl<T: ?Sized> Spinlock<T> {
         }
     }
 
+    /// Acquires the [`Spinlock`] like [`Spinlock::lock`], backing off exponentially while waiting.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor already holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn lock_with_backoff(&self) -> SpinlockGuard<'_, T> {
+        self.lock.lock_with_backoff();
+
+        SpinlockGuard {
+            lock: &self.lock,
+            value: &self.value,
+        }
+    }
+
     /// Attempts to acquire this [`Spinlock`].
     ///
     /// If the lock could not be acquired, then [`Err`] is returned. Otherwise, a RAII guard is
@@ -132,6 +297,7 @@ impl<T: ?Sized> Spinlock<T> {
     /// # Errors
     /// If the [`Spinlock`] could not be acquire because it is already locked, then this call will
     /// return an [`Err`].
+    #[cfg_attr(feature = "debug-locks", track_caller)]
     pub fn try_lock(&self) -> Result<SpinlockGuard<T>, SpinlockAcquisitionError> {
         self.lock.try_lock().map(|()| SpinlockGuard {
             lock: &self.lock,
@@ -139,9 +305,17 @@ impl<T: ?Sized> Spinlock<T> {
         })
     }
 
+    /// Returns `true` if this [`Spinlock`] is currently locked.
+    ///
+    /// The lock may be acquired or released by another processor as soon as this returns.
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
     /// Method that makes unlocking a mutex more explicit.
     pub fn unlock(guard: SpinlockGuard<T>) {
-        guard.lock.unlock()
+        drop(guard)
     }
 
     /// Returns a mutable reference to the underlying data.
@@ -205,6 +379,113 @@ impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
     }
 }
 
+/// A [`Spinlock`] that masks interrupts on the current processor while it is held.
+pub struct IrqSpinlock<T: ?Sized> {
+    /// The lock protecting the value.
+    lock: Spinlock<T>,
+}
+
+impl<T> IrqSpinlock<T> {
+    /// Creates a new [`IrqSpinlock`] in an unlocked state ready for use.
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: Spinlock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    /// Masks interrupts and acquires the [`IrqSpinlock`], spinning until the lock is available.
+    ///
+    /// Interrupts are restored to their prior state when the returned guard is dropped.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor already holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts_enabled = crate::arch::interrupts::disable_save();
+
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+            interrupts_enabled,
+        }
+    }
+
+    /// Masks interrupts and acquires the [`IrqSpinlock`] like [`IrqSpinlock::lock`], backing off
+    /// exponentially while waiting.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor already holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    #[allow(dead_code)]
+    pub fn lock_with_backoff(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts_enabled = crate::arch::interrupts::disable_save();
+
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.lock.lock_with_backoff()),
+            interrupts_enabled,
+        }
+    }
+
+    /// Attempts to acquire this [`IrqSpinlock`], masking interrupts while it is held.
+    ///
+    /// This function does not block.
+    ///
+    /// # Errors
+    /// If the [`IrqSpinlock`] could not be acquired because it is already locked, then this call
+    /// will return an [`Err`] and interrupts are left unchanged.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn try_lock(&self) -> Result<IrqSpinlockGuard<'_, T>, SpinlockAcquisitionError> {
+        let interrupts_enabled = crate::arch::interrupts::disable_save();
+        match self.lock.try_lock() {
+            Ok(guard) => Ok(IrqSpinlockGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled,
+            }),
+            Err(error) => {
+                crate::arch::interrupts::restore(interrupts_enabled);
+                Err(error)
+            }
+        }
+    }
+}
+
+/// A RAII implementation of a "scoped lock" implemented using an [`IrqSpinlock`]. When this
+/// structure is dropped, the [`IrqSpinlock`] is unlocked and interrupts are restored.
+///
+/// This structure is created by the [`IrqSpinlock::lock()`] and [`IrqSpinlock::try_lock()`]
+/// methods.
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    /// The guard of the underlying [`Spinlock`].
+    guard: ManuallyDrop<SpinlockGuard<'a, T>>,
+    /// Whether interrupts were enabled before the lock was acquired.
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY:
+        // `guard` is not used again, and is released before interrupts are restored so that an
+        // interrupt handler never finds the lock held by the code it interrupted.
+        unsafe { ManuallyDrop::drop(&mut self.guard) }
+        crate::arch::interrupts::restore(self.interrupts_enabled);
+    }
+}
+
 /// Represents the failure to acquire a [`Spinlock`].
 #[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SpinlockAcquisitionError;
@@ -216,3 +497,162 @@ impl fmt::Display for SpinlockAcquisitionError {
 }
 
 impl error::Error for SpinlockAcquisitionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_lock_fails_while_locked() {
+        let lock = Spinlock::new(5);
+        assert!(!lock.is_locked());
+
+        let guard = lock.try_lock().unwrap();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_err());
+
+        drop(guard);
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.try_lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn failed_attempts_do_not_take_tickets() {
+        let lock = Spinlock::new(());
+        let guard = lock.lock();
+        for _ in 0..3 {
+            assert!(lock.try_lock().is_err());
+        }
+        drop(guard);
+
+        // A ticket taken by a failed attempt would never be served, leaving the lock held.
+        assert!(!lock.is_locked());
+        drop(lock.lock());
+    }
+
+    #[test]
+    fn explicit_unlock_releases_once() {
+        let lock = Spinlock::new(0);
+        Spinlock::unlock(lock.lock());
+        assert!(!lock.is_locked());
+
+        let guard = lock.try_lock().unwrap();
+        assert!(lock.is_locked());
+        drop(guard);
+    }
+
+    #[test]
+    fn guards_give_access_to_the_value() {
+        let lock = Spinlock::new(Vec::new());
+        lock.lock().push(1);
+        lock.lock_with_backoff().push(2);
+        lock.try_lock().unwrap().push(3);
+
+        assert_eq!(lock.into_inner(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn get_mut_does_not_lock() {
+        let mut lock = Spinlock::new(1);
+        *lock.get_mut() += 1;
+
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test]
+    fn tickets_wrap_around() {
+        let lock = Spinlock::new(());
+        lock.lock.next_ticket.store(u32::MAX, Ordering::Relaxed);
+        lock.lock.now_serving.store(u32::MAX, Ordering::Relaxed);
+
+        drop(lock.try_lock().unwrap());
+        drop(lock.lock());
+        assert!(!lock.is_locked());
+        assert_eq!(lock.lock.now_serving.load(Ordering::Relaxed), 1);
+    }
+
+    /// Returns the number of tickets taken for `lock` that have not been served, including the
+    /// holder's.
+    fn tickets_outstanding<T>(lock: &Spinlock<T>) -> u32 {
+        let next = lock.lock.next_ticket.load(Ordering::Relaxed);
+        next.wrapping_sub(lock.lock.now_serving.load(Ordering::Relaxed))
+    }
+
+    /// Waits until `count` tickets for `lock` are outstanding.
+    fn wait_for_tickets<T>(lock: &Spinlock<T>, count: u32) {
+        while tickets_outstanding(lock) != count {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn waiters_are_served_in_ticket_order() {
+        const THREADS: usize = 6;
+
+        let lock = Spinlock::new(Vec::new());
+        std::thread::scope(|scope| {
+            let guard = lock.lock();
+            for index in 0..THREADS {
+                let lock = &lock;
+                scope.spawn(move || {
+                    if index % 2 == 0 {
+                        lock.lock().push(index);
+                    } else {
+                        lock.lock_with_backoff().push(index);
+                    }
+                });
+                wait_for_tickets(lock, index as u32 + 2);
+            }
+            drop(guard);
+        });
+
+        assert_eq!(lock.into_inner(), (0..THREADS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn contending_threads_share_the_lock_evenly() {
+        const THREADS: usize = 4;
+        const ACQUISITIONS: usize = 400;
+
+        // The total is deliberately not atomic: the lock alone keeps increments from being lost.
+        let lock = Spinlock::new(0);
+        let counts = std::thread::scope(|scope| {
+            let guard = lock.lock();
+            let threads = (0..THREADS)
+                .map(|_| {
+                    let lock = &lock;
+                    scope.spawn(move || {
+                        let mut count = 0;
+                        loop {
+                            let mut total = lock.lock();
+                            if *total == ACQUISITIONS {
+                                return count;
+                            }
+                            *total += 1;
+                            count += 1;
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // Start measuring once every thread is queued, so that none gets a head start.
+            wait_for_tickets(&lock, THREADS as u32 + 1);
+            drop(guard);
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(counts.iter().sum::<usize>(), ACQUISITIONS);
+        let fair_share = ACQUISITIONS / THREADS;
+        for count in counts {
+            assert!(
+                count.abs_diff(fair_share) <= fair_share / 2,
+                "unfair acquisition counts"
+            );
+        }
+    }
+}
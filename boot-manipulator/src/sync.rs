@@ -0,0 +1,552 @@
+//! Synchronization primitives for statics that are initialized once or mostly read.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+#[cfg(feature = "debug-locks")]
+use crate::spinlock::Owner;
+use crate::spinlock::SpinlockAcquisitionError;
+
+/// The bit of an [`RwSpinlock`]'s state set while a writer holds the lock.
+const WRITER: u32 = 1 << 31;
+/// The bit of an [`RwSpinlock`]'s state set while a writer waits for the lock, which keeps new
+/// readers out.
+const WRITER_WAITING: u32 = 1 << 30;
+/// The bits of an [`RwSpinlock`]'s state counting the readers holding the lock.
+const READERS: u32 = WRITER_WAITING - 1;
+
+/// The [`OnceCell`] holds no value and is not being initialized.
+const UNINITIALIZED: u8 = 0;
+/// A processor is writing the value of the [`OnceCell`].
+const INITIALIZING: u8 = 1;
+/// The [`OnceCell`] holds its value.
+const INITIALIZED: u8 = 2;
+
+/// A cell that is written at most once and can then be read from any processor.
+pub struct OnceCell<T> {
+    /// Whether the value has been written.
+    state: AtomicU8,
+    /// The value, which is initialized once `state` is [`INITIALIZED`].
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY:
+// The value is only written once, before any reference to it is handed out, so sharing the cell
+// only shares `T`, which must therefore be `Sync`. The value may be written by any processor, so
+// `T` must also be `Send`.
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+// SAFETY:
+// Nothing about `OnceCell<T>` changes whether it is safe to send `T` across threads.
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    /// Creates an empty [`OnceCell`].
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINITIALIZED),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, or [`None`] if it has not been written yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != INITIALIZED {
+            return None;
+        }
+
+        // SAFETY:
+        // The value is never written again once `state` is `INITIALIZED`.
+        let value = unsafe { &*self.value.get() };
+        // SAFETY:
+        // The value was written before `state` was set to `INITIALIZED`.
+        Some(unsafe { value.assume_init_ref() })
+    }
+
+    /// Writes `value` if the cell is empty.
+    ///
+    /// # Errors
+    /// Returns `value` if the cell already holds a value or is being initialized.
+    #[allow(dead_code)]
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(
+                UNINITIALIZED,
+                INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        // SAFETY:
+        // Only the processor that moved `state` to `INITIALIZING` writes the value, and no
+        // reference to it exists until `state` is `INITIALIZED`.
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(INITIALIZED, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Returns the value, initializing it with `init` if the cell is empty.
+    ///
+    /// If another processor is initializing the cell, this spins until it has finished. If `init`
+    /// panics, the cell is left being initialized forever.
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    pub fn get_or_init(&self, init: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+
+        match self.state.compare_exchange(
+            UNINITIALIZED,
+            INITIALIZING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // SAFETY:
+                // Only the processor that moved `state` to `INITIALIZING` writes the value, and no
+                // reference to it exists until `state` is `INITIALIZED`.
+                unsafe { (*self.value.get()).write(init()) };
+                self.state.store(INITIALIZED, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != INITIALIZED {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+
+        // SAFETY:
+        // `state` is `INITIALIZED`, so the value is never written again.
+        let value = unsafe { &*self.value.get() };
+        // SAFETY:
+        // `state` is `INITIALIZED`, so the value was written.
+        unsafe { value.assume_init_ref() }
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceCell").field(&self.get()).finish()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == INITIALIZED {
+            // SAFETY:
+            // `state` is `INITIALIZED`, so the value was written, and it is not used again.
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+/// A value that is initialized by `init` the first time it is accessed.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+pub struct Lazy<T, F = fn() -> T> {
+    /// The value, once initialized.
+    cell: OnceCell<T>,
+    /// The function producing the value.
+    init: F,
+}
+
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+impl<T, F> Lazy<T, F> {
+    /// Creates a [`Lazy`] that is initialized by `init` the first time it is accessed.
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init,
+        }
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.cell.get_or_init(&self.init)
+    }
+}
+
+/// A reader-writer spinlock, which lets any number of readers or a single writer hold the lock.
+///
+/// Writers are preferred: once a writer is waiting, new readers wait until it has released the
+/// lock, so a steady stream of readers cannot starve writers.
+///
+/// With the `debug-locks` feature, a processor that waits on a lock it holds for writing panics
+/// instead of deadlocking. A processor that holds the lock for reading and then waits to write
+/// is not detected.
+pub struct RwSpinlock<T: ?Sized> {
+    /// The writer bits and the number of readers.
+    state: AtomicU32,
+    /// The processor holding the lock for writing.
+    #[cfg(feature = "debug-locks")]
+    writer: Owner,
+    /// The value protected by the lock.
+    value: UnsafeCell<T>,
+}
+
+// SAFETY:
+// Nothing about `RwSpinlock<T>` changes whether it is safe to send `T` across threads.
+unsafe impl<T: ?Sized + Send> Send for RwSpinlock<T> {}
+
+// SAFETY:
+// Readers on several processors may share `&T`, so `T` must be `Sync`, and a writer on any
+// processor may take `&mut T`, so `T` must be `Send`.
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinlock<T> {}
+
+impl<T> RwSpinlock<T> {
+    /// Creates a new [`RwSpinlock`] in an unlocked state ready for use.
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            #[cfg(feature = "debug-locks")]
+            writer: Owner::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwSpinlock<T> {
+    /// Acquires the lock for reading, spinning while a writer holds or waits for it.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor holds the lock for
+    /// writing.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn read(&self) -> RwSpinlockReadGuard<'_, T> {
+        loop {
+            if let Ok(guard) = self.try_read() {
+                return guard;
+            }
+
+            #[cfg(feature = "debug-locks")]
+            self.writer.check_deadlock();
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Attempts to acquire the lock for reading.
+    ///
+    /// This function does not block.
+    ///
+    /// # Errors
+    /// Returns an [`Err`] if a writer holds or waits for the lock.
+    pub fn try_read(&self) -> Result<RwSpinlockReadGuard<'_, T>, SpinlockAcquisitionError> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & (WRITER | WRITER_WAITING) != 0 || state & READERS == READERS {
+                return Err(SpinlockAcquisitionError);
+            }
+
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(RwSpinlockReadGuard { lock: self }),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /// Acquires the lock for writing, spinning until every reader and writer has released it.
+    ///
+    /// # Panics
+    /// With the `debug-locks` feature, panics if the current processor holds the lock for
+    /// writing.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn write(&self) -> RwSpinlockWriteGuard<'_, T> {
+        loop {
+            if let Ok(guard) = self.try_write() {
+                return guard;
+            }
+
+            // Keep new readers out until this writer has acquired the lock.
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            #[cfg(feature = "debug-locks")]
+            self.writer.check_deadlock();
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Attempts to acquire the lock for writing.
+    ///
+    /// This function does not block.
+    ///
+    /// # Errors
+    /// Returns an [`Err`] if a reader or writer holds the lock.
+    #[cfg_attr(feature = "debug-locks", track_caller)]
+    pub fn try_write(&self) -> Result<RwSpinlockWriteGuard<'_, T>, SpinlockAcquisitionError> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & !WRITER_WAITING != 0 {
+            return Err(SpinlockAcquisitionError);
+        }
+
+        // Acquiring clears `WRITER_WAITING`; other waiting writers set it again as they spin.
+        self.state
+            .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .map_err(|_| SpinlockAcquisitionError)?;
+        #[cfg(feature = "debug-locks")]
+        self.writer.record();
+
+        Ok(RwSpinlockWriteGuard { lock: self })
+    }
+}
+
+/// A RAII guard holding an [`RwSpinlock`] for reading, which releases it when dropped.
+pub struct RwSpinlockReadGuard<'a, T: ?Sized> {
+    /// The lock held for reading.
+    lock: &'a RwSpinlock<T>,
+}
+
+impl<T: ?Sized> Deref for RwSpinlockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY:
+        // The lock is held for reading, so no writer can access the value.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinlockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// A RAII guard holding an [`RwSpinlock`] for writing, which releases it when dropped.
+pub struct RwSpinlockWriteGuard<'a, T: ?Sized> {
+    /// The lock held for writing.
+    lock: &'a RwSpinlock<T>,
+}
+
+impl<T: ?Sized> Deref for RwSpinlockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY:
+        // The lock is held for writing, so this guard has exclusive access to the value.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinlockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY:
+        // The lock is held for writing, so this guard has exclusive access to the value.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinlockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "debug-locks")]
+        self.lock.writer.clear();
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
+        thread,
+    };
+
+    use super::*;
+
+    /// The number of threads racing in the concurrency tests.
+    const THREADS: usize = 8;
+
+    #[test]
+    fn set_only_succeeds_once() {
+        let cell = OnceCell::new();
+        assert_eq!(cell.get(), None);
+
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(cell.get(), Some(&1));
+        assert_eq!(*cell.get_or_init(|| 3), 1);
+    }
+
+    #[test]
+    fn concurrent_get_or_init_initializes_once() {
+        let cell = OnceCell::new();
+        let calls = AtomicUsize::new(0);
+        let barrier = Barrier::new(THREADS);
+
+        let values = thread::scope(|scope| {
+            let threads = (0..THREADS)
+                .map(|index| {
+                    let (cell, calls, barrier) = (&cell, &calls, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        cell.get_or_init(|| {
+                            calls.fetch_add(1, Ordering::Relaxed);
+                            // Give the other threads time to find the cell being initialized.
+                            thread::yield_now();
+                            index
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let winner = cell.get().unwrap();
+        assert!(values.iter().all(|&value| core::ptr::eq(value, winner)));
+    }
+
+    #[test]
+    fn concurrent_set_has_one_winner() {
+        let cell = OnceCell::new();
+        let barrier = Barrier::new(THREADS);
+
+        let successes = thread::scope(|scope| {
+            let threads = (0..THREADS)
+                .map(|index| {
+                    let (cell, barrier) = (&cell, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        cell.set(index).is_ok()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|&success| success)
+                .count()
+        });
+
+        assert_eq!(successes, 1);
+        assert!(cell.get().is_some());
+    }
+
+    #[test]
+    fn lazy_runs_init_on_first_access() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static VALUE: Lazy<usize> = Lazy::new(|| CALLS.fetch_add(1, Ordering::Relaxed) + 41);
+
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| assert_eq!(*VALUE, 41));
+            }
+        });
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn dropping_the_cell_drops_its_value() {
+        let value = Arc::new(());
+
+        let cell = OnceCell::new();
+        cell.set(Arc::clone(&value)).unwrap();
+        assert_eq!(Arc::strong_count(&value), 2);
+        drop(cell);
+        assert_eq!(Arc::strong_count(&value), 1);
+
+        // An empty cell has nothing to drop.
+        drop(OnceCell::<Arc<()>>::new());
+    }
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwSpinlock::new(1);
+
+        let first = lock.try_read().unwrap();
+        let second = lock.read();
+        assert_eq!((*first, *second), (1, 1));
+        assert!(lock.try_write().is_err());
+        drop((first, second));
+
+        let mut writer = lock.try_write().unwrap();
+        *writer += 1;
+        assert!(lock.try_read().is_err());
+        assert!(lock.try_write().is_err());
+        drop(writer);
+
+        assert_eq!(*lock.read(), 2);
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn waiting_writers_keep_new_readers_out() {
+        let lock = RwSpinlock::new(0);
+
+        thread::scope(|scope| {
+            let reader = lock.read();
+            let writer = scope.spawn(|| *lock.write() = 1);
+
+            while lock.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+                thread::yield_now();
+            }
+            assert!(lock.try_read().is_err());
+            assert_eq!(*reader, 0);
+
+            drop(reader);
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn readers_never_observe_partial_writes() {
+        const WRITES: usize = 200;
+
+        let lock = RwSpinlock::new((0, 0));
+        thread::scope(|scope| {
+            for _ in 0..THREADS / 2 {
+                scope.spawn(|| {
+                    for _ in 0..WRITES {
+                        let mut pair = lock.write();
+                        pair.0 += 1;
+                        thread::yield_now();
+                        pair.1 += 1;
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..WRITES {
+                        let pair = lock.read();
+                        assert_eq!(pair.0, pair.1);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(*lock.read(), (THREADS / 2 * WRITES, THREADS / 2 * WRITES));
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,197 @@
+//! Monotonic time measured by the architecture's counter, which keeps running after
+//! `ExitBootServices()`.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use uefi::{boot, runtime};
+
+use crate::arch;
+
+/// The length of the interval the counter is calibrated over, in microseconds.
+const CALIBRATION_PERIOD_US: u64 = 10_000;
+/// The number of nanoseconds in a second.
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+/// The rate of the counter, or 0 if it has not been calibrated.
+static TICKS_PER_SECOND: AtomicU64 = AtomicU64::new(0);
+/// The value of the counter when it was calibrated.
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Measures the rate of the counter against `Stall()`, which must be called while boot services
+/// are active.
+pub fn calibrate() {
+    if !arch::time::is_invariant() {
+        log::warn!("the counter is not invariant: time measurements may drift");
+    }
+
+    let start = ticks();
+    boot::stall(CALIBRATION_PERIOD_US as usize);
+    let end = ticks();
+
+    let ticks_per_second = (end - start) * (1_000_000 / CALIBRATION_PERIOD_US);
+    EPOCH.store(start, Ordering::Relaxed);
+    TICKS_PER_SECOND.store(ticks_per_second, Ordering::Release);
+
+    match runtime::get_time() {
+        Ok(time) => log::debug!("counter runs at {ticks_per_second} Hz, calibrated at {time}"),
+        Err(_) => log::debug!("counter runs at {ticks_per_second} Hz"),
+    }
+}
+
+/// Returns the current value of the counter.
+pub fn ticks() -> u64 {
+    arch::time::read_counter()
+}
+
+/// Returns the rate of the counter, or 0 if it has not been calibrated.
+pub fn ticks_per_second() -> u64 {
+    TICKS_PER_SECOND.load(Ordering::Acquire)
+}
+
+/// Converts a difference of `ticks` into a [`Duration`].
+///
+/// Returns [`None`] if the counter has not been calibrated.
+pub fn ticks_to_duration(ticks: u64) -> Option<Duration> {
+    duration_from_ticks(ticks, ticks_per_second())
+}
+
+/// Converts a difference of `ticks` of a counter running at `ticks_per_second` into a
+/// [`Duration`], rounded down to the nanosecond.
+///
+/// Returns [`None`] if `ticks_per_second` is 0.
+fn duration_from_ticks(ticks: u64, ticks_per_second: u64) -> Option<Duration> {
+    if ticks_per_second == 0 {
+        return None;
+    }
+
+    let seconds = ticks / ticks_per_second;
+    let nanoseconds = u128::from(ticks % ticks_per_second) * NANOSECONDS_PER_SECOND
+        / u128::from(ticks_per_second);
+    Some(Duration::new(seconds, nanoseconds as u32))
+}
+
+/// Returns the number of ticks of a counter running at `ticks_per_second` that covers at least
+/// `duration`, saturating at [`u64::MAX`].
+fn ticks_from_duration(duration: Duration, ticks_per_second: u64) -> u64 {
+    let ticks = duration
+        .as_nanos()
+        .saturating_mul(u128::from(ticks_per_second))
+        .div_ceil(NANOSECONDS_PER_SECOND);
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Spins for `duration`, which requires neither boot nor runtime services.
+///
+/// Returns immediately if the counter has not been calibrated.
+pub fn busy_wait(duration: Duration) {
+    let wait = ticks_from_duration(duration, ticks_per_second());
+    let start = ticks();
+    while ticks().wrapping_sub(start) < wait {
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the time elapsed since the counter was calibrated.
+///
+/// Returns [`None`] if the counter has not been calibrated.
+pub fn since_calibration() -> Option<Duration> {
+    ticks_to_duration(ticks().saturating_sub(EPOCH.load(Ordering::Relaxed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_round_down_to_the_nanosecond() {
+        assert_eq!(duration_from_ticks(0, 3), Some(Duration::ZERO));
+        assert_eq!(
+            duration_from_ticks(1, 3),
+            Some(Duration::from_nanos(333_333_333))
+        );
+        assert_eq!(
+            duration_from_ticks(2, 3),
+            Some(Duration::from_nanos(666_666_666))
+        );
+        assert_eq!(
+            duration_from_ticks(7_500_000_001, 2_500_000_000),
+            Some(Duration::new(3, 0))
+        );
+        assert_eq!(
+            duration_from_ticks(3_000_000_123, 1_000_000_000),
+            Some(Duration::new(3, 123))
+        );
+    }
+
+    #[test]
+    fn durations_of_large_tick_counts_do_not_overflow() {
+        assert_eq!(
+            duration_from_ticks(u64::MAX, 1),
+            Some(Duration::from_secs(u64::MAX))
+        );
+        assert_eq!(
+            duration_from_ticks(u64::MAX, u64::MAX),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            duration_from_ticks(u64::MAX - 1, u64::MAX),
+            Some(Duration::from_nanos(999_999_999))
+        );
+        assert_eq!(
+            duration_from_ticks(u64::MAX, 3_000_000_000),
+            Some(Duration::new(6_148_914_691, 236_517_205))
+        );
+    }
+
+    #[test]
+    fn uncalibrated_counters_have_no_duration() {
+        assert_eq!(duration_from_ticks(0, 0), None);
+        assert_eq!(duration_from_ticks(u64::MAX, 0), None);
+    }
+
+    #[test]
+    fn waits_round_up_to_whole_ticks() {
+        assert_eq!(ticks_from_duration(Duration::ZERO, 3), 0);
+        assert_eq!(ticks_from_duration(Duration::from_nanos(1), 3), 1);
+        assert_eq!(ticks_from_duration(Duration::from_nanos(333_333_333), 3), 1);
+        assert_eq!(ticks_from_duration(Duration::from_nanos(333_333_334), 3), 2);
+        assert_eq!(
+            ticks_from_duration(Duration::from_millis(10), 2_500_000_000),
+            25_000_000
+        );
+    }
+
+    #[test]
+    fn waits_saturate_instead_of_overflowing() {
+        assert_eq!(ticks_from_duration(Duration::MAX, u64::MAX), u64::MAX);
+        assert_eq!(
+            ticks_from_duration(Duration::from_secs(u64::MAX / 2), 3),
+            u64::MAX
+        );
+        assert_eq!(
+            ticks_from_duration(Duration::from_secs(u64::MAX), 1),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn waits_on_uncalibrated_counters_are_empty() {
+        assert_eq!(ticks_from_duration(Duration::ZERO, 0), 0);
+        assert_eq!(ticks_from_duration(Duration::MAX, 0), 0);
+    }
+
+    #[test]
+    fn conversions_round_trip_whole_ticks() {
+        // Above 1 GHz, a tick is shorter than the nanoseconds a `Duration` is counted in.
+        for ticks_per_second in [1, 3, 1_000_000, 999_999_937, 1_000_000_000] {
+            for ticks in [0, 1, 2, 999, ticks_per_second, 10 * ticks_per_second + 1] {
+                let duration = duration_from_ticks(ticks, ticks_per_second).unwrap();
+                let back = ticks_from_duration(duration, ticks_per_second);
+                assert_eq!(back, ticks, "{ticks} at {ticks_per_second} Hz");
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Interception of `SetVirtualAddressMap()` and translation of hypervisor memory to the virtual
+//! addresses assigned by the operating system.
+//!
+//! The hook runs once, when the operating system relocates the runtime services. It records the
+//! virtual addresses of the descriptors covering hypervisor-owned frames, forwards the call, and
+//! then restores the relocated firmware function in the runtime services table, since the driver's
+//! own code is not converted by the firmware.
+
+use core::{
+    ffi::c_void,
+    fmt, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use uefi::mem::memory_map::{MemoryAttribute, MemoryDescriptor};
+
+use crate::{
+    exit_boot_services::{self, HEADER_CRC32_OFFSET},
+    frames,
+    residency::UninstallError,
+    spinlock::Spinlock,
+};
+
+/// The maximum number of descriptors referencing hypervisor memory that can be translated.
+const MAX_TRANSLATIONS: usize = 32;
+
+/// The firmware's `SetVirtualAddressMap()`, or null if the hook has not been installed.
+static ORIGINAL: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// The physical address of the runtime services table whose `SetVirtualAddressMap()` was
+/// replaced.
+///
+/// The firmware converts the system table's pointer to the runtime services table during
+/// `SetVirtualAddressMap()`, so the physical address is saved at installation.
+static RUNTIME_SERVICES: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// The physical address of the patched `SetVirtualAddressMap()` pointer.
+static SLOT: AtomicPtr<u64> = AtomicPtr::new(ptr::null_mut());
+
+/// The virtual mappings of the descriptors covering hypervisor memory, filled in once the
+/// operating system has called `SetVirtualAddressMap()`.
+static TRANSLATIONS: Spinlock<[Option<Translation>; MAX_TRANSLATIONS]> =
+    Spinlock::new([None; MAX_TRANSLATIONS]);
+
+/// The signature of `SetVirtualAddressMap()`.
+type SetVirtualAddressMap =
+    unsafe extern "efiapi" fn(usize, usize, u32, *mut MemoryDescriptor) -> uefi::Status;
+
+/// The virtual mapping of a range of physical memory.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct Translation {
+    /// The first physical address of the range.
+    physical: u64,
+    /// The virtual address `physical` is mapped at.
+    virtual_address: u64,
+    /// The size of the range in bytes.
+    size: u64,
+}
+
+/// Replaces `SetVirtualAddressMap()` with [`hook`], returning the address of the patched pointer.
+///
+/// # Errors
+/// Returns [`InstallError::AlreadyInstalled`] if the hook was already installed and
+/// [`InstallError::MissingRuntimeServices`] if the system table has no runtime services table.
+pub fn install() -> Result<*const u64, InstallError> {
+    if !ORIGINAL.load(Ordering::Acquire).is_null() {
+        return Err(InstallError::AlreadyInstalled);
+    }
+
+    let system_table =
+        uefi::table::system_table_raw().ok_or(InstallError::MissingRuntimeServices)?;
+    // SAFETY:
+    // The system table pointer is valid while boot services are active.
+    let runtime_services = unsafe { system_table.as_ref().runtime_services };
+    if runtime_services.is_null() {
+        return Err(InstallError::MissingRuntimeServices);
+    }
+
+    // SAFETY:
+    // The runtime services table is valid for the lifetime of the system.
+    let slot = unsafe { &mut (*runtime_services).set_virtual_address_map };
+    ORIGINAL.store(*slot as *mut c_void, Ordering::Release);
+    RUNTIME_SERVICES.store(runtime_services.cast(), Ordering::Release);
+    *slot = hook;
+    SLOT.store(ptr::from_mut(slot).cast(), Ordering::Release);
+
+    // SAFETY:
+    // The runtime services table begins with its header, and boot services are active.
+    unsafe { exit_boot_services::update_crc32(runtime_services.cast()) };
+
+    Ok(ptr::from_mut(slot).cast::<u64>())
+}
+
+/// Returns `true` if the `SetVirtualAddressMap()` hook is installed.
+pub fn is_installed() -> bool {
+    !SLOT.load(Ordering::Acquire).is_null()
+}
+
+/// Restores the firmware's `SetVirtualAddressMap()`.
+///
+/// # Errors
+/// - Returns [`UninstallError::NotInstalled`] if the hook is not installed.
+/// - Returns [`UninstallError::Overwritten`] if another image replaced the hook, in which case
+///   restoring the firmware's function would also remove that image's hook.
+///
+/// # Safety
+/// Boot services must be active.
+#[allow(dead_code)]
+pub unsafe fn uninstall() -> Result<(), UninstallError> {
+    let slot = SLOT.load(Ordering::Acquire);
+    if slot.is_null() {
+        return Err(UninstallError::NotInstalled);
+    }
+
+    let handler: SetVirtualAddressMap = hook;
+    // SAFETY:
+    // `SLOT` lies within the runtime services table, which is valid for the lifetime of the
+    // system.
+    if unsafe { slot.read_volatile() } != handler as usize as u64 {
+        return Err(UninstallError::Overwritten("SetVirtualAddressMap"));
+    }
+
+    // SAFETY:
+    // `SLOT` lies within the runtime services table, which is valid for the lifetime of the
+    // system.
+    unsafe { slot.write_volatile(ORIGINAL.load(Ordering::Acquire) as u64) }
+    SLOT.store(ptr::null_mut(), Ordering::Release);
+    ORIGINAL.store(ptr::null_mut(), Ordering::Release);
+
+    // SAFETY:
+    // The runtime services table begins with its header, and the caller guarantees that boot
+    // services are active.
+    unsafe { exit_boot_services::update_crc32(RUNTIME_SERVICES.load(Ordering::Acquire)) };
+
+    Ok(())
+}
+
+/// Records the virtual mappings of hypervisor memory, forwards the call to the firmware's
+/// `SetVirtualAddressMap()`, and restores the relocated firmware function in the runtime services
+/// table.
+///
+/// # Safety
+/// Must only be called in place of `SetVirtualAddressMap()` after [`install`] succeeded.
+unsafe extern "efiapi" fn hook(
+    map_size: usize,
+    descriptor_size: usize,
+    descriptor_version: u32,
+    virtual_map: *mut MemoryDescriptor,
+) -> uefi::Status {
+    let original = ORIGINAL.load(Ordering::Acquire);
+    // SAFETY:
+    // `ORIGINAL` holds the firmware's `SetVirtualAddressMap()`, which has this signature.
+    let original = unsafe { core::mem::transmute::<*mut c_void, SetVirtualAddressMap>(original) };
+
+    let count = map_size.checked_div(descriptor_size).unwrap_or(0);
+    let descriptor = |index: usize| {
+        // SAFETY:
+        // The caller provides `count` descriptors spaced `descriptor_size` bytes apart.
+        let descriptor = unsafe { virtual_map.byte_add(index * descriptor_size) };
+        // SAFETY:
+        // `descriptor` points to one of the caller's descriptors.
+        unsafe { &*descriptor }
+    };
+
+    let mut translations = [None; MAX_TRANSLATIONS];
+    let mut referencing = 0;
+    for descriptor in (0..count).map(descriptor) {
+        let size = descriptor.page_count * uefi::boot::PAGE_SIZE as u64;
+        if !descriptor.att.contains(MemoryAttribute::RUNTIME)
+            || !frames::overlaps_persistent(descriptor.phys_start, size)
+        {
+            continue;
+        }
+
+        referencing += 1;
+        let translation = Translation {
+            physical: descriptor.phys_start,
+            virtual_address: descriptor.virt_start,
+            size,
+        };
+        match translations.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(translation),
+            None => log::warn!(
+                "translation table is full: {:#x} will not be translated",
+                descriptor.phys_start
+            ),
+        }
+    }
+
+    // SAFETY:
+    // The arguments are forwarded unchanged from the caller.
+    let status = unsafe { original(map_size, descriptor_size, descriptor_version, virtual_map) };
+    if status.is_error() {
+        log::warn!("SetVirtualAddressMap failed: {status:?}");
+        return status;
+    }
+    *TRANSLATIONS.lock() = translations;
+
+    // The firmware cannot convert the pointer to this hook, so the table is pointed back at the
+    // relocated firmware function rather than at driver code without a virtual mapping.
+    let original = original as usize as u64;
+    let relocated = (0..count).map(descriptor).find_map(|descriptor| {
+        let size = descriptor.page_count * uefi::boot::PAGE_SIZE as u64;
+        (descriptor.phys_start..descriptor.phys_start + size)
+            .contains(&original)
+            .then(|| original - descriptor.phys_start + descriptor.virt_start)
+    });
+    match relocated {
+        // SAFETY:
+        // The runtime services table is only accessed by the operating system through virtual
+        // addresses once this call returns.
+        Some(relocated) => unsafe { restore(relocated) },
+        None => log::warn!("SetVirtualAddressMap is not covered by the virtual address map"),
+    }
+
+    log::info!(
+        "{referencing} of {count} virtual address map descriptors reference hypervisor memory"
+    );
+
+    status
+}
+
+/// Points `SetVirtualAddressMap()` in the runtime services table at `relocated` and recomputes the
+/// table's CRC32.
+///
+/// # Safety
+/// The runtime services table must be accessible at its physical address and not in use.
+unsafe fn restore(relocated: u64) {
+    let table = RUNTIME_SERVICES.load(Ordering::Acquire);
+
+    // SAFETY:
+    // `SLOT` lies within the runtime services table, which the caller guarantees is accessible and
+    // not in use.
+    unsafe { SLOT.load(Ordering::Acquire).write_volatile(relocated) }
+
+    // SAFETY:
+    // The runtime services table begins with its header.
+    let size = unsafe { (*table.cast::<uefi::table::Header>()).size } as usize;
+    let crc32 = table.wrapping_add(HEADER_CRC32_OFFSET).cast::<u32>();
+    // SAFETY:
+    // The CRC32 lies within the header.
+    unsafe { crc32.write(0) }
+    // SAFETY:
+    // The header reports the size of the table, which is valid for reads.
+    let bytes = unsafe { core::slice::from_raw_parts(table, size) };
+    // SAFETY:
+    // The CRC32 lies within the header.
+    unsafe { crc32.write(crc32_ieee(bytes)) }
+}
+
+/// Computes the CRC32 of `bytes` used by UEFI table headers, as boot services are no longer
+/// available to compute it.
+fn crc32_ieee(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1))
+        })
+    })
+}
+
+/// Returns the virtual address the operating system assigned to the hypervisor memory at
+/// `physical`.
+///
+/// Returns [`None`] if `SetVirtualAddressMap()` has not been called or `physical` does not lie in
+/// hypervisor memory with a virtual mapping.
+#[allow(dead_code)]
+pub fn to_virtual(physical: u64) -> Option<u64> {
+    TRANSLATIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find_map(|translation| {
+            (translation.physical..translation.physical + translation.size)
+                .contains(&physical)
+                .then(|| physical - translation.physical + translation.virtual_address)
+        })
+}
+
+/// Various errors that can occur while installing the `SetVirtualAddressMap()` hook.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum InstallError {
+    /// The hook was already installed.
+    AlreadyInstalled,
+    /// The system table does not reference a runtime services table.
+    MissingRuntimeServices,
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInstalled => {
+                write!(f, "the SetVirtualAddressMap hook is already installed")
+            }
+            Self::MissingRuntimeServices => write!(f, "the runtime services table is missing"),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Control of the firmware watchdog timer, which resets the machine if boot takes too long.
+
+use uefi::boot;
+
+/// The timeout the firmware arms the watchdog timer with when starting an image, in seconds.
+const DEFAULT_TIMEOUT: usize = 5 * 60;
+
+/// The watchdog code logged when the re-armed watchdog timer expires, the first value available
+/// to images rather than the firmware.
+const WATCHDOG_CODE: u64 = 0x1_0000;
+
+/// Disables the firmware watchdog timer so that slow setup or debugging cannot reset the machine.
+///
+/// Returns `true` if the watchdog timer was disabled.
+pub fn disable() -> bool {
+    match boot::set_watchdog_timer(0, WATCHDOG_CODE, None) {
+        Ok(()) => {
+            log::debug!("watchdog timer disabled");
+            true
+        }
+        Err(error) => {
+            log::debug!("failed to disable the watchdog timer: {:?}", error.status());
+            false
+        }
+    }
+}
+
+/// Re-arms the firmware watchdog timer with its default timeout after [`disable`] succeeded.
+pub fn restore() {
+    match boot::set_watchdog_timer(DEFAULT_TIMEOUT, WATCHDOG_CODE, None) {
+        Ok(()) => log::debug!("watchdog timer re-armed for {DEFAULT_TIMEOUT} seconds"),
+        Err(error) => log::debug!("failed to re-arm the watchdog timer: {:?}", error.status()),
+    }
+}
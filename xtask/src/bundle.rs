@@ -0,0 +1,261 @@
+//! Assembly of triage bundles for failed (or explicitly requested) runs.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Artifacts produced by a run that are copied into the bundle when present.
+pub const ARTIFACTS: &[&str] = &["serial.log", "debugcon.log"];
+
+/// Name of the file containing the crash records extracted from the serial log.
+pub const CRASH_RECORDS: &str = "crash-records.txt";
+/// Name of the file containing the exact QEMU command line.
+pub const QEMU_ARGV: &str = "qemu-argv.txt";
+/// Name of the file containing the xtask configuration snapshot.
+pub const CONFIGURATION: &str = "configuration.txt";
+
+/// Line markers that identify crash or diagnostic records in the serial stream.
+const CRASH_MARKERS: &[&str] = &["[ERROR]", "panicked at", "VMCS", "VM-instruction error"];
+
+/// The information needed to assemble a triage bundle.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BundleInputs {
+    /// The short reason for the bundle, used in the bundle's name.
+    pub reason: String,
+    /// The QEMU command line, if QEMU was launched.
+    pub qemu_argv: Option<Vec<OsString>>,
+    /// Snapshot of the xtask configuration used for the run.
+    pub configuration: String,
+}
+
+/// Assembles a triage bundle named `<timestamp>-<reason>` under `run_directory/failures/`.
+///
+/// Every artifact in [`ARTIFACTS`] that exists in `run_directory` is copied into the bundle,
+/// alongside the QEMU command line, the configuration snapshot, and any crash records extracted
+/// from the serial log.
+///
+/// # Errors
+/// Returns an [`io::Error`] if the bundle directory cannot be created or an artifact cannot be
+/// copied.
+pub fn assemble_bundle(
+    run_directory: &Path,
+    timestamp: u64,
+    inputs: &BundleInputs,
+) -> Result<PathBuf, io::Error> {
+    let bundle_directory = run_directory
+        .join("failures")
+        .join(bundle_name(timestamp, &inputs.reason));
+    fs::create_dir_all(&bundle_directory)?;
+
+    for &artifact in ARTIFACTS {
+        let source = run_directory.join(artifact);
+        if source.is_file() {
+            fs::copy(&source, bundle_directory.join(artifact))?;
+        }
+    }
+
+    let serial_log = run_directory.join("serial.log");
+    if serial_log.is_file() {
+        let contents = fs::read(&serial_log)?;
+        let records = extract_crash_records(&String::from_utf8_lossy(&contents));
+        if !records.is_empty() {
+            fs::write(bundle_directory.join(CRASH_RECORDS), records)?;
+        }
+    }
+
+    if let Some(argv) = inputs.qemu_argv.as_ref() {
+        fs::write(bundle_directory.join(QEMU_ARGV), format_argv(argv))?;
+    }
+
+    fs::write(bundle_directory.join(CONFIGURATION), &inputs.configuration)?;
+
+    Ok(bundle_directory)
+}
+
+/// Returns the name of a bundle created at `timestamp` for `reason`.
+pub fn bundle_name(timestamp: u64, reason: &str) -> String {
+    let reason = reason
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+
+    format!("{timestamp}-{reason}")
+}
+
+/// Returns the lines of `serial_output` that look like crash or diagnostic records.
+pub fn extract_crash_records(serial_output: &str) -> String {
+    serial_output
+        .lines()
+        .filter(|line| CRASH_MARKERS.iter().any(|marker| line.contains(marker)))
+        .fold(String::new(), |mut records, line| {
+            records.push_str(line);
+            records.push('\n');
+            records
+        })
+}
+
+/// Formats `argv` as a single line, quoting arguments that contain whitespace.
+pub fn format_argv(argv: &[OsString]) -> String {
+    let mut line = argv
+        .iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.contains(char::is_whitespace) {
+                format!("'{arg}'")
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    line.push('\n');
+    line
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+pub fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the system's temporary directory that is removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Creates an empty temporary directory unique to `name` and this process.
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("xtask-bundle-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn bundle_name_replaces_non_alphanumeric_characters() {
+        assert_eq!(bundle_name(42, "timeout"), "42-timeout");
+        assert_eq!(
+            bundle_name(1700000000, "qemu exited/abnormally"),
+            "1700000000-qemu-exited-abnormally"
+        );
+        assert_eq!(bundle_name(0, ""), "0-");
+    }
+
+    #[test]
+    fn extract_crash_records_keeps_only_marked_lines() {
+        let serial = "\
+[INFO] starting
+[ERROR] failed to enable VMX
+panicked at src/main.rs:10:5
+[DEBUG] VMCS revision 4
+VM-instruction error: 7
+[TRACE] unrelated
+";
+
+        assert_eq!(
+            extract_crash_records(serial),
+            "\
+[ERROR] failed to enable VMX
+panicked at src/main.rs:10:5
+[DEBUG] VMCS revision 4
+VM-instruction error: 7
+"
+        );
+        assert_eq!(extract_crash_records("[INFO] all good\n"), "");
+    }
+
+    #[test]
+    fn format_argv_quotes_arguments_with_whitespace() {
+        let argv = [
+            "qemu-system-x86_64",
+            "-drive",
+            "file=my disk.img",
+            "-nographic",
+        ]
+        .map(OsString::from);
+
+        assert_eq!(
+            format_argv(&argv),
+            "qemu-system-x86_64 -drive 'file=my disk.img' -nographic\n"
+        );
+        assert_eq!(format_argv(&[]), "\n");
+    }
+
+    #[test]
+    fn assemble_bundle_copies_present_artifacts() {
+        let run_directory = TempDir::new("artifacts");
+        fs::write(
+            run_directory.0.join("serial.log"),
+            "[INFO] booting\n[ERROR] vmlaunch failed\n",
+        )
+        .unwrap();
+        fs::write(run_directory.0.join("debugcon.log"), "debugcon\n").unwrap();
+        fs::write(run_directory.0.join("disk.img"), "image").unwrap();
+
+        let inputs = BundleInputs {
+            reason: "qemu failed".to_owned(),
+            qemu_argv: Some(vec![OsString::from("qemu"), OsString::from("-s")]),
+            configuration: "configuration\n".to_owned(),
+        };
+        let bundle = assemble_bundle(&run_directory.0, 7, &inputs).unwrap();
+
+        assert_eq!(
+            bundle,
+            run_directory.0.join("failures").join("7-qemu-failed")
+        );
+        assert_eq!(
+            fs::read_to_string(bundle.join("serial.log")).unwrap(),
+            "[INFO] booting\n[ERROR] vmlaunch failed\n"
+        );
+        assert_eq!(
+            fs::read_to_string(bundle.join("debugcon.log")).unwrap(),
+            "debugcon\n"
+        );
+        assert!(!bundle.join("disk.img").exists());
+        assert_eq!(
+            fs::read_to_string(bundle.join(CRASH_RECORDS)).unwrap(),
+            "[ERROR] vmlaunch failed\n"
+        );
+        assert_eq!(
+            fs::read_to_string(bundle.join(QEMU_ARGV)).unwrap(),
+            "qemu -s\n"
+        );
+        assert_eq!(
+            fs::read_to_string(bundle.join(CONFIGURATION)).unwrap(),
+            "configuration\n"
+        );
+    }
+
+    #[test]
+    fn assemble_bundle_without_serial_log_or_qemu() {
+        let run_directory = TempDir::new("empty");
+
+        let inputs = BundleInputs {
+            reason: "build".to_owned(),
+            qemu_argv: None,
+            configuration: String::new(),
+        };
+        let bundle = assemble_bundle(&run_directory.0, 1, &inputs).unwrap();
+
+        assert!(bundle.is_dir());
+        assert!(!bundle.join(CRASH_RECORDS).exists());
+        assert!(!bundle.join(QEMU_ARGV).exists());
+        assert_eq!(fs::read_to_string(bundle.join(CONFIGURATION)).unwrap(), "");
+    }
+}
@@ -1,18 +1,44 @@
 //! Command line parsing and command construction.
 
-use std::path::PathBuf;
+use std::{ffi::OsString, path::PathBuf};
+
+use crate::message::MessageFormat;
 
 /// The action to carry out.
 pub enum Action {
     /// Builds `boot-manipulator` and `boot-manipulator-cli`.
-    Build(BuildArguments),
+    Build(BuildPlan),
     /// Build and run `boot-manipulator`.
     Run {
         /// Arguments necessary to build `boot-manipulator` and `boot-manipulator-cli`.
         build_arguments: BuildArguments,
         /// Arguments necessary to run `boot-manipulator`.
         run_arguments: RunArguments,
+        /// The number of seconds after which QEMU is killed, if limited.
+        timeout: Option<u64>,
     },
+    /// Build and run `boot-manipulator`, checking that it loads successfully.
+    Test {
+        /// Arguments necessary to build `boot-manipulator` and `boot-manipulator-cli`.
+        build_arguments: BuildArguments,
+        /// Arguments necessary to run `boot-manipulator`.
+        run_arguments: RunArguments,
+        /// Arguments controlling how the test is carried out.
+        test_arguments: TestArguments,
+    },
+    /// Removes run artifacts and, optionally, build outputs.
+    Clean(CleanArguments),
+    /// Reports the section sizes of `boot-manipulator`.
+    Size(SizeArguments),
+}
+
+/// The set of builds requested by the `build` subcommand.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct BuildPlan {
+    /// The builds to carry out, one per architecture.
+    pub targets: Vec<BuildArguments>,
+    /// The maximum number of builds to run concurrently.
+    pub jobs: usize,
 }
 
 /// Arguments necessary to determine how to build `boot-manipulator`.
@@ -20,72 +46,324 @@ pub enum Action {
 pub struct BuildArguments {
     /// The architecture for which `boot-manipulator` should be built.
     pub arch: Arch,
-    /// Whether `boot-manipulator` should be built in release mode.
-    pub release: bool,
+    /// The cargo profile with which `boot-manipulator` should be built.
+    pub profile: String,
     /// The features that `boot-manipulator` should have enabled.
     pub features: Vec<Feature>,
+    /// The standard library crates to build from source with `-Z build-std`, if requested.
+    pub build_std: Option<String>,
+    /// Additional flags passed to rustc through `RUSTFLAGS`.
+    pub rustflags: Vec<String>,
 }
 
 /// Arguments necessary to determine how to run `boot-manipulator`.
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct RunArguments {
-    /// The path to the OVMF code file used to run UEFI.
-    pub ovmf_code: PathBuf,
-    /// The path to the OVMF vars file used to run UEFI.
-    pub ovmf_vars: PathBuf,
+    /// The path to the OVMF code file used to run UEFI, if explicitly provided.
+    pub ovmf_code: Option<PathBuf>,
+    /// The path to the OVMF vars file used to run UEFI, if explicitly provided.
+    pub ovmf_vars: Option<PathBuf>,
+    /// Whether OVMF may be downloaded if it cannot be found locally.
+    pub no_download: bool,
+    /// Whether the writable copy of the OVMF vars file is kept between runs.
+    pub persist_vars: bool,
+    /// The path to which serial output should be logged, if not the default.
+    pub serial_log: Option<PathBuf>,
+    /// The number of virtual processors QEMU should provide.
+    pub smp: u16,
+    /// Additional arguments passed to QEMU after the built-in arguments.
+    pub qemu_args: Vec<OsString>,
+    /// Whether a triage bundle should be assembled even if the run succeeds.
+    pub bundle_on_success: bool,
+    /// Whether to boot from a GPT disk image instead of QEMU's virtual FAT directory.
+    pub disk_image: bool,
+    /// Whether writing `startup.nsh` into the FAT directory should be skipped.
+    pub no_startup_script: bool,
+    /// Whether the existing `boot-manipulator` binary is reused instead of rebuilding it.
+    pub skip_build: bool,
+    /// The port on which QEMU should start halted with a GDB stub, if requested.
+    pub gdb: Option<u16>,
+    /// The accelerator QEMU should use.
+    pub accel: Accel,
+    /// The QEMU machine type, if not the architecture's default.
+    pub machine: Option<String>,
+    /// Whether to run Secure Boot capable OVMF with SMM enabled.
+    pub secure_boot: bool,
+}
+
+/// Arguments necessary to determine how to test `boot-manipulator`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct TestArguments {
+    /// The number of seconds to wait for `boot-manipulator` to report success.
+    pub timeout: u64,
+}
+
+/// Arguments necessary to determine what to clean.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CleanArguments {
+    /// The architecture whose artifacts should be removed, or `None` for every architecture.
+    pub arch: Option<Arch>,
+    /// Whether the cargo target directories for the UEFI targets should also be removed.
+    pub all: bool,
+}
+
+/// Arguments necessary to determine what to measure.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct SizeArguments {
+    /// The binary whose sections should be measured.
+    pub target: SizeTarget,
+    /// The image size, in bytes, above which the measurement fails.
+    pub max_size: Option<u64>,
+}
+
+/// The binary measured by the `size` subcommand.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum SizeTarget {
+    /// Build `boot-manipulator` and measure the result.
+    Build(BuildArguments),
+    /// Measure an existing binary.
+    Binary(PathBuf),
 }
 
 /// Parses arguments to construct an [`Action`].
 pub fn get_action() -> Action {
     let mut matches = command_parser().get_matches();
+    if let Some(format) = matches.remove_one::<MessageFormat>("message-format") {
+        crate::message::set_format(format);
+    }
+
     let (subcommand_name, mut subcommand_matches) =
         matches.remove_subcommand().expect("subcommand required");
     match subcommand_name.as_str() {
-        "build" => Action::Build(parse_build_arguments(&mut subcommand_matches)),
+        "build" => Action::Build(parse_build_plan(&mut subcommand_matches)),
         "run" => {
             let build_arguments = parse_build_arguments(&mut subcommand_matches);
-            let run_arguments = parse_run_arguments(&mut subcommand_matches);
+            let mut run_arguments =
+                parse_run_arguments(&mut subcommand_matches, build_arguments.arch);
+            run_arguments.gdb = subcommand_matches.remove_one::<u16>("gdb");
+            run_arguments.skip_build = subcommand_matches
+                .remove_one::<bool>("skip-build")
+                .unwrap_or(false);
+            let timeout = subcommand_matches.remove_one::<u64>("timeout");
 
             Action::Run {
                 build_arguments,
                 run_arguments,
+                timeout,
             }
         }
+        "test" => {
+            let build_arguments = parse_build_arguments(&mut subcommand_matches);
+            let run_arguments = parse_run_arguments(&mut subcommand_matches, build_arguments.arch);
+            let test_arguments = parse_test_arguments(&mut subcommand_matches);
+
+            Action::Test {
+                build_arguments,
+                run_arguments,
+                test_arguments,
+            }
+        }
+        "clean" => {
+            let arch = subcommand_matches.remove_one::<Arch>("arch");
+            let all = subcommand_matches
+                .remove_one::<bool>("all")
+                .unwrap_or(false);
+
+            Action::Clean(CleanArguments { arch, all })
+        }
+        "size" => {
+            let target = match subcommand_matches.remove_one::<PathBuf>("binary") {
+                Some(binary) => SizeTarget::Binary(binary),
+                None => SizeTarget::Build(parse_build_arguments(&mut subcommand_matches)),
+            };
+            let max_size = subcommand_matches.remove_one::<u64>("max-size");
+
+            Action::Size(SizeArguments { target, max_size })
+        }
         name => unreachable!("unexpected subcommand {name:?}"),
     }
 }
 
+fn parse_build_plan(matches: &mut clap::ArgMatches) -> BuildPlan {
+    let mut arches = Vec::new();
+    for name in matches
+        .remove_many::<String>("arch")
+        .expect("arch is a required argument")
+    {
+        let selected = match name.as_str() {
+            "all" => <Arch as clap::ValueEnum>::value_variants(),
+            name => <Arch as clap::ValueEnum>::value_variants()
+                .iter()
+                .find(|arch| arch.as_str() == name)
+                .map(core::slice::from_ref)
+                .expect("arch values are validated by clap"),
+        };
+
+        for &arch in selected {
+            if !arches.contains(&arch) {
+                arches.push(arch);
+            }
+        }
+    }
+
+    let jobs = matches
+        .remove_one::<usize>("jobs")
+        .expect("jobs has a default value");
+    let targets = parse_build_options(matches, &arches);
+
+    BuildPlan { targets, jobs }
+}
+
 fn parse_build_arguments(matches: &mut clap::ArgMatches) -> BuildArguments {
     let arch = matches
         .remove_one::<Arch>("arch")
         .expect("arch is a required argument");
+
+    parse_build_options(matches, &[arch])
+        .pop()
+        .expect("one set of build arguments is returned per architecture")
+}
+
+/// Parses the arguments shared by every subcommand that builds, returning the resulting
+/// [`BuildArguments`] for each of `arches`.
+fn parse_build_options(matches: &mut clap::ArgMatches, arches: &[Arch]) -> Vec<BuildArguments> {
     let release = matches.remove_one::<bool>("release").unwrap_or(false);
+    let profile = match matches.remove_one::<String>("profile") {
+        Some(profile) => profile,
+        None if release => "release".to_owned(),
+        None => "dev".to_owned(),
+    };
     let features = matches
         .remove_many::<Feature>("features")
         .map(|features| features.collect::<Vec<Feature>>())
         .unwrap_or(Vec::new());
+    let build_std = matches.remove_one::<String>("build-std");
+    let rustflags = matches
+        .remove_many::<String>("rustflag")
+        .map(|flags| flags.collect::<Vec<String>>())
+        .unwrap_or_default();
 
-    BuildArguments {
-        arch,
-        release,
-        features,
+    arches
+        .iter()
+        .map(|&arch| {
+            validate_features(arch, &features).unwrap_or_else(|error| error.exit());
+
+            BuildArguments {
+                arch,
+                profile: profile.clone(),
+                features: features.clone(),
+                build_std: build_std.clone(),
+                rustflags: rustflags.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Checks that every one of `features` is supported on `arch`.
+///
+/// # Errors
+/// Returns a [`clap::Error`] naming the first of `features` that is not supported on `arch`.
+fn validate_features(arch: Arch, features: &[Feature]) -> Result<(), clap::Error> {
+    match features.iter().find(|feature| !feature.is_supported(arch)) {
+        Some(feature) => Err(clap::Error::raw(
+            clap::error::ErrorKind::InvalidValue,
+            format!(
+                "feature '{}' is not supported on {}\n",
+                feature.as_str(),
+                arch.as_str()
+            ),
+        )),
+        None => Ok(()),
     }
 }
 
-fn parse_run_arguments(matches: &mut clap::ArgMatches) -> RunArguments {
-    let ovmf_code = matches
-        .remove_one("ovmf-code")
-        .expect("ovmf-code is required");
-    let ovmf_vars = matches
-        .remove_one("ovmf-vars")
-        .expect("ovmf-vars is required");
+fn parse_run_arguments(matches: &mut clap::ArgMatches, arch: Arch) -> RunArguments {
+    let ovmf_code = matches.remove_one("ovmf-code");
+    let ovmf_vars = matches.remove_one("ovmf-vars");
+    let no_download = matches.remove_one::<bool>("no-download").unwrap_or(false);
+    let persist_vars = matches.remove_one::<bool>("persist-vars").unwrap_or(false);
+    let serial_log = matches.remove_one("serial-log");
+    let smp = matches
+        .remove_one::<u16>("smp")
+        .expect("smp has a default value");
+    let qemu_args = matches
+        .remove_many::<OsString>("qemu-arg")
+        .map(|args| args.collect::<Vec<OsString>>())
+        .unwrap_or_default();
+    let bundle_on_success = matches
+        .remove_one::<bool>("bundle-on-success")
+        .unwrap_or(false);
+    let disk_image = matches.remove_one::<bool>("disk-image").unwrap_or(false);
+    let no_startup_script = matches
+        .remove_one::<bool>("no-startup-script")
+        .unwrap_or(false);
+    let accel = matches
+        .remove_one::<Accel>("accel")
+        .expect("accel has a default value");
+    let machine = matches.remove_one::<String>("machine");
+    let secure_boot = matches.remove_one::<bool>("secure-boot").unwrap_or(false);
+
+    if secure_boot {
+        validate_secure_boot(arch, machine.as_deref()).unwrap_or_else(|error| error.exit());
+    }
 
     RunArguments {
         ovmf_code,
         ovmf_vars,
+        no_download,
+        persist_vars,
+        serial_log,
+        smp,
+        qemu_args,
+        bundle_on_success,
+        disk_image,
+        no_startup_script,
+        skip_build: false,
+        gdb: None,
+        accel,
+        machine,
+        secure_boot,
     }
 }
 
+/// Checks that Secure Boot can be used on `arch` with `machine`, or the default machine of `arch`
+/// if [`None`].
+///
+/// # Errors
+/// Returns a [`clap::Error`] if `arch` is not an `x86` architecture or the machine is not a
+/// version of q35, as only those provide the SMM that Secure Boot capable OVMF requires.
+fn validate_secure_boot(arch: Arch, machine: Option<&str>) -> Result<(), clap::Error> {
+    if !matches!(arch, Arch::X86 | Arch::X86_64) {
+        return Err(clap::Error::raw(
+            clap::error::ErrorKind::ArgumentConflict,
+            format!("--secure-boot is not supported on {}\n", arch.as_str()),
+        ));
+    }
+
+    let machine = machine.unwrap_or(arch.default_machine());
+    if !is_q35(machine) {
+        return Err(clap::Error::raw(
+            clap::error::ErrorKind::ArgumentConflict,
+            format!("--secure-boot requires a q35 machine, but '{machine}' was selected\n"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns whether `machine` names a version of QEMU's q35 machine type.
+fn is_q35(machine: &str) -> bool {
+    machine == "q35" || machine.starts_with("pc-q35-")
+}
+
+fn parse_test_arguments(matches: &mut clap::ArgMatches) -> TestArguments {
+    let timeout = matches
+        .remove_one::<u64>("timeout")
+        .expect("timeout has a default value");
+
+    TestArguments { timeout }
+}
+
 /// Returns the clap command parser.
 fn command_parser() -> clap::Command {
     let arch_arg = clap::Arg::new("arch")
@@ -94,93 +372,398 @@ fn command_parser() -> clap::Command {
         .required(true);
 
     let release_arg = clap::Arg::new("release")
-        .help("Build boot-manipulator in release mode")
+        .help("Build boot-manipulator in release mode (alias for --profile release)")
         .long("release")
         .short('r')
         .action(clap::ArgAction::SetTrue);
 
+    let profile_arg = clap::Arg::new("profile")
+        .help("The cargo profile with which to build boot-manipulator [default: dev]")
+        .long("profile")
+        .value_name("NAME")
+        .conflicts_with("release");
+
     let features_arg = clap::Arg::new("features")
         .help("List of features to active for boot-manipulator")
         .long("features")
         .short('F')
+        .value_parser(clap::builder::EnumValueParser::<Feature>::new())
         .value_delimiter(',')
         .action(clap::ArgAction::Append);
 
+    let build_std_arg = clap::Arg::new("build-std")
+        .help("Build the standard library crates from source using a nightly toolchain")
+        .long("build-std")
+        .value_name("CRATES")
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("core,alloc");
+
+    let rustflag_arg = clap::Arg::new("rustflag")
+        .help("Additional flag passed to rustc through RUSTFLAGS")
+        .long("rustflag")
+        .value_name("FLAG")
+        .allow_hyphen_values(true)
+        .action(clap::ArgAction::Append);
+
+    let build_arch_values = <Arch as clap::ValueEnum>::value_variants()
+        .iter()
+        .map(Arch::as_str)
+        .chain(["all"]);
+    let build_arch_arg = clap::Arg::new("arch")
+        .help(
+            "The architectures for which boot-manipulator and boot-manipulator-cli should be \
+             built, or all of them",
+        )
+        .long("arch")
+        .value_parser(clap::builder::PossibleValuesParser::new(build_arch_values))
+        .action(clap::ArgAction::Append)
+        .required(true);
+
+    let jobs_arg = clap::Arg::new("jobs")
+        .help("The maximum number of builds to run concurrently")
+        .long("jobs")
+        .short('j')
+        .value_parser(clap::value_parser!(usize))
+        .default_value("2");
+
     let build_subcommand = clap::Command::new("build")
         .about("Builds boot-manipulator and boot-manipulator-cli")
-        .arg(arch_arg.clone().help(
-            "The architecture for which boot-manipulator and boot-manipulator-cli should be built",
-        ))
+        .arg(build_arch_arg)
+        .arg(jobs_arg)
         .arg(release_arg.clone())
-        .arg(features_arg.clone());
+        .arg(profile_arg.clone())
+        .arg(features_arg.clone())
+        .arg(build_std_arg.clone())
+        .arg(rustflag_arg.clone());
 
     let ovmf_code_arg = clap::Arg::new("ovmf-code")
+        .help("The OVMF code file to use instead of the discovered one")
         .long("ovmf-code")
         .short('c')
-        .value_parser(clap::builder::PathBufValueParser::new())
-        .required(true);
+        .value_parser(clap::builder::PathBufValueParser::new());
 
     let ovmf_vars_arg = clap::Arg::new("ovmf-vars")
+        .help("The OVMF vars file to use instead of the discovered one")
         .long("ovmf-vars")
         .short('v')
-        .value_parser(clap::builder::PathBufValueParser::new())
-        .required(true);
+        .value_parser(clap::builder::PathBufValueParser::new());
+
+    let no_download_arg = clap::Arg::new("no-download")
+        .help("Do not download OVMF if it cannot be found locally")
+        .long("no-download")
+        .action(clap::ArgAction::SetTrue);
+
+    let persist_vars_arg = clap::Arg::new("persist-vars")
+        .help("Keep the writable copy of the OVMF vars file so boot variables survive between runs")
+        .long("persist-vars")
+        .action(clap::ArgAction::SetTrue);
+
+    let serial_log_arg = clap::Arg::new("serial-log")
+        .help("The file to which serial output is logged [default: run/<arch>/serial.log]")
+        .long("serial-log")
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new());
+
+    let smp_arg = clap::Arg::new("smp")
+        .help("The number of virtual processors QEMU should provide")
+        .long("smp")
+        .value_name("N")
+        .value_parser(clap::value_parser!(u16).range(1..=255))
+        .default_value("4");
+
+    let qemu_arg_arg = clap::Arg::new("qemu-arg")
+        .help("Additional argument passed to QEMU after the built-in arguments")
+        .long("qemu-arg")
+        .value_name("ARG")
+        .value_parser(clap::builder::OsStringValueParser::new())
+        .allow_hyphen_values(true)
+        .action(clap::ArgAction::Append);
+
+    let bundle_on_success_arg = clap::Arg::new("bundle-on-success")
+        .help("Assemble a triage bundle even if the run succeeds")
+        .long("bundle-on-success")
+        .action(clap::ArgAction::SetTrue);
+
+    let disk_image_arg = clap::Arg::new("disk-image")
+        .help("Boot from a GPT disk image instead of QEMU's virtual FAT directory")
+        .long("disk-image")
+        .action(clap::ArgAction::SetTrue);
+
+    let gdb_arg = clap::Arg::new("gdb")
+        .help("Start QEMU halted with a GDB stub listening on PORT")
+        .long("gdb")
+        .value_name("PORT")
+        .value_parser(clap::value_parser!(u16))
+        .num_args(0..=1)
+        .default_missing_value("1234");
+
+    let no_startup_script_arg = clap::Arg::new("no-startup-script")
+        .help("Do not write a startup.nsh that starts boot-manipulator from the EFI shell")
+        .long("no-startup-script")
+        .action(clap::ArgAction::SetTrue);
+
+    let skip_build_arg = clap::Arg::new("skip-build")
+        .help("Reuse the existing boot-manipulator binary instead of rebuilding it")
+        .long("skip-build")
+        .action(clap::ArgAction::SetTrue);
+
+    let accel_arg = clap::Arg::new("accel")
+        .help("The accelerator QEMU should use")
+        .long("accel")
+        .value_parser(clap::builder::EnumValueParser::<Accel>::new())
+        .default_value("auto");
+
+    let machine_arg = clap::Arg::new("machine")
+        .help("The QEMU machine type, such as q35 or pc [default: q35, or virt on aarch64]")
+        .long("machine")
+        .value_name("NAME");
+
+    let secure_boot_arg = clap::Arg::new("secure-boot")
+        .help("Use Secure Boot capable OVMF with SMM enabled (x86 and x86_64 only, requires a q35 machine)")
+        .long("secure-boot")
+        .action(clap::ArgAction::SetTrue);
+
+    let run_timeout_arg = clap::Arg::new("timeout")
+        .help("The number of seconds after which QEMU is killed [default: no limit]")
+        .long("timeout")
+        .short('t')
+        .value_parser(clap::value_parser!(u64));
 
     let run_subcommand = clap::Command::new("run")
         .about("Runs boot-manipulator using QEMU")
-        .arg(arch_arg.help("The architecutre for which boot-manipulator should be built and run"))
+        .arg(
+            arch_arg
+                .clone()
+                .help("The architecutre for which boot-manipulator should be built and run"),
+        )
+        .arg(release_arg.clone())
+        .arg(profile_arg.clone())
+        .arg(features_arg.clone())
+        .arg(build_std_arg.clone())
+        .arg(rustflag_arg.clone())
+        .arg(ovmf_code_arg.clone())
+        .arg(ovmf_vars_arg.clone())
+        .arg(no_download_arg.clone())
+        .arg(persist_vars_arg.clone())
+        .arg(serial_log_arg.clone())
+        .arg(smp_arg.clone())
+        .arg(qemu_arg_arg.clone())
+        .arg(bundle_on_success_arg.clone())
+        .arg(disk_image_arg.clone())
+        .arg(no_startup_script_arg.clone())
+        .arg(accel_arg.clone())
+        .arg(machine_arg.clone())
+        .arg(secure_boot_arg.clone())
+        .arg(gdb_arg)
+        .arg(skip_build_arg)
+        .arg(run_timeout_arg);
+
+    let timeout_arg = clap::Arg::new("timeout")
+        .help("The number of seconds to wait for boot-manipulator to report success")
+        .long("timeout")
+        .short('t')
+        .value_parser(clap::value_parser!(u64))
+        .default_value("60");
+
+    let size_subcommand = clap::Command::new("size")
+        .about("Reports the section sizes of the built boot-manipulator binary")
+        .arg(
+            arch_arg
+                .clone()
+                .help("The architecture for which boot-manipulator should be built and measured")
+                .required(false)
+                .required_unless_present("binary"),
+        )
+        .arg(release_arg.clone())
+        .arg(profile_arg.clone())
+        .arg(features_arg.clone())
+        .arg(build_std_arg.clone())
+        .arg(rustflag_arg.clone())
+        .arg(
+            clap::Arg::new("binary")
+                .help("Measure this EFI binary instead of building boot-manipulator")
+                .long("binary")
+                .value_name("PATH")
+                .value_parser(clap::builder::PathBufValueParser::new())
+                .conflicts_with_all([
+                    "arch",
+                    "release",
+                    "profile",
+                    "features",
+                    "build-std",
+                    "rustflag",
+                ]),
+        )
+        .arg(
+            clap::Arg::new("max-size")
+                .help("Fail if the total image size exceeds BYTES")
+                .long("max-size")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64)),
+        );
+
+    let test_subcommand = clap::Command::new("test")
+        .about("Runs boot-manipulator headless using QEMU and checks that it loads successfully")
+        .arg(
+            arch_arg.help("The architecture for which boot-manipulator should be built and tested"),
+        )
         .arg(release_arg)
+        .arg(profile_arg)
         .arg(features_arg)
+        .arg(build_std_arg)
+        .arg(rustflag_arg)
         .arg(ovmf_code_arg)
-        .arg(ovmf_vars_arg);
+        .arg(ovmf_vars_arg)
+        .arg(no_download_arg)
+        .arg(persist_vars_arg)
+        .arg(serial_log_arg)
+        .arg(smp_arg)
+        .arg(qemu_arg_arg)
+        .arg(bundle_on_success_arg)
+        .arg(disk_image_arg)
+        .arg(no_startup_script_arg)
+        .arg(accel_arg)
+        .arg(machine_arg)
+        .arg(secure_boot_arg)
+        .arg(timeout_arg);
+
+    let clean_subcommand = clap::Command::new("clean")
+        .about("Removes run artifacts and generated FAT directories")
+        .arg(
+            clap::Arg::new("arch")
+                .help("Only remove the artifacts of this architecture")
+                .long("arch")
+                .value_parser(clap::builder::EnumValueParser::<Arch>::new()),
+        )
+        .arg(
+            clap::Arg::new("all")
+                .help("Also remove the cargo target directories for the UEFI targets")
+                .long("all")
+                .action(clap::ArgAction::SetTrue),
+        );
+
+    let message_format_arg = clap::Arg::new("message-format")
+        .help("The format in which results are reported")
+        .long("message-format")
+        .value_parser(clap::builder::EnumValueParser::<MessageFormat>::new())
+        .default_value("human")
+        .global(true);
 
     clap::Command::new("xtask")
         .about("Developer utility for running various tasks in boot-manipulator")
+        .arg(message_format_arg)
         .subcommand(build_subcommand)
         .subcommand(run_subcommand)
+        .subcommand(test_subcommand)
+        .subcommand(clean_subcommand)
+        .subcommand(size_subcommand)
         .subcommand_required(true)
         .arg_required_else_help(true)
 }
 
 /// Various features supported by `boot-manipulator`.
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
-pub enum Feature {}
+pub enum Feature {
+    /// Log to the serial port once boot services have been exited.
+    SerialLogging,
+    /// Report the result of loading through QEMU's `isa-debug-exit` device.
+    TestExit,
+    /// Map guest memory missing from the EPT identity map on demand instead of panicking.
+    LazyEpt,
+    /// Log every frame allocation and deallocation, checking for double frees.
+    AllocationTracking,
+    /// Log to QEMU's debug console at port 0xE9 when enabled by the `debugcon` boot option.
+    Debugcon,
+    /// Track the holder of every spinlock, panicking when a processor relocks a lock it holds.
+    DebugLocks,
+}
 
 impl Feature {
     /// Returns the [`Feature`] in is textual representation.
     pub fn as_str(&self) -> &'static str {
         match self {
-            _ => unreachable!(),
+            Self::SerialLogging => "serial-logging",
+            Self::TestExit => "test-exit",
+            Self::LazyEpt => "lazy-ept",
+            Self::AllocationTracking => "allocation-tracking",
+            Self::Debugcon => "debugcon",
+            Self::DebugLocks => "debug-locks",
+        }
+    }
+
+    /// Returns whether the [`Feature`] is supported when building for `arch`.
+    pub fn is_supported(&self, arch: Arch) -> bool {
+        match self {
+            Self::SerialLogging | Self::TestExit | Self::LazyEpt | Self::Debugcon => {
+                arch == Arch::X86_64
+            }
+            Self::AllocationTracking | Self::DebugLocks => true,
         }
     }
 }
 
+impl clap::ValueEnum for Feature {
+    fn value_variants<'a>() -> &'a [Self] {
+        static FEATURES: &[Feature] = &[
+            Feature::SerialLogging,
+            Feature::TestExit,
+            Feature::LazyEpt,
+            Feature::AllocationTracking,
+            Feature::Debugcon,
+            Feature::DebugLocks,
+        ];
+
+        FEATURES
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.as_str()))
+    }
+}
+
 /// The architectures supported by `boot-manipulator`.
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub enum Arch {
+    /// The `x86` architecture.
+    X86,
     /// The `x86_64` architecture.
     X86_64,
+    /// The `aarch64` architecture.
+    Aarch64,
 }
 
 impl Arch {
     /// Returns the [`Arch`] as its rustc target triple.
     pub fn as_target_triple(&self) -> &'static str {
         match self {
+            Self::X86 => "i686-unknown-uefi",
             Self::X86_64 => "x86_64-unknown-uefi",
+            Self::Aarch64 => "aarch64-unknown-uefi",
+        }
+    }
+
+    /// Returns the QEMU machine type used for the [`Arch`] unless another is selected.
+    pub fn default_machine(&self) -> &'static str {
+        match self {
+            Self::X86 | Self::X86_64 => "q35",
+            Self::Aarch64 => "virt",
         }
     }
 
     /// Returns the [`Arch`] as its textual representation.
     pub fn as_str(&self) -> &'static str {
         match self {
+            Self::X86 => "x86",
             Self::X86_64 => "x86_64",
+            Self::Aarch64 => "aarch64",
         }
     }
 }
 
 impl clap::ValueEnum for Arch {
     fn value_variants<'a>() -> &'a [Self] {
-        static ARCHES: &[Arch] = &[Arch::X86_64];
+        static ARCHES: &[Arch] = &[Arch::X86, Arch::X86_64, Arch::Aarch64];
 
         ARCHES
     }
@@ -189,3 +772,148 @@ impl clap::ValueEnum for Arch {
         Some(clap::builder::PossibleValue::new(self.as_str()))
     }
 }
+
+/// The accelerators QEMU can use to run `boot-manipulator`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Accel {
+    /// Use KVM if it is available, otherwise fall back to TCG.
+    Auto,
+    /// Use KVM.
+    Kvm,
+    /// Use QEMU's software emulation.
+    Tcg,
+}
+
+impl Accel {
+    /// Returns the [`Accel`] as its textual representation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Kvm => "kvm",
+            Self::Tcg => "tcg",
+        }
+    }
+}
+
+impl clap::ValueEnum for Accel {
+    fn value_variants<'a>() -> &'a [Self] {
+        static ACCELS: &[Accel] = &[Accel::Auto, Accel::Kvm, Accel::Tcg];
+
+        ACCELS
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `args` as an xtask command line, returning the matches of its subcommand.
+    fn subcommand_matches(args: &[&str]) -> Result<clap::ArgMatches, clap::Error> {
+        let mut matches = command_parser()
+            .try_get_matches_from(std::iter::once("xtask").chain(args.iter().copied()))?;
+        let (_, subcommand_matches) = matches.remove_subcommand().expect("subcommand required");
+        Ok(subcommand_matches)
+    }
+
+    #[test]
+    fn features_split_on_commas() {
+        let mut matches =
+            subcommand_matches(&["run", "--arch", "x86_64", "-F", "lazy-ept,debugcon"]).unwrap();
+
+        assert_eq!(
+            parse_build_arguments(&mut matches).features,
+            [Feature::LazyEpt, Feature::Debugcon]
+        );
+    }
+
+    #[test]
+    fn repeated_features_accumulate() {
+        let mut matches = subcommand_matches(&[
+            "test",
+            "--arch",
+            "x86_64",
+            "--features",
+            "test-exit",
+            "--features",
+            "debug-locks,allocation-tracking",
+            "-F",
+            "serial-logging",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            parse_build_arguments(&mut matches).features,
+            [
+                Feature::TestExit,
+                Feature::DebugLocks,
+                Feature::AllocationTracking,
+                Feature::SerialLogging
+            ]
+        );
+    }
+
+    #[test]
+    fn no_features_is_empty() {
+        let mut matches = subcommand_matches(&["run", "--arch", "aarch64"]).unwrap();
+
+        assert!(parse_build_arguments(&mut matches).features.is_empty());
+    }
+
+    #[test]
+    fn unknown_features_are_rejected() {
+        let error = subcommand_matches(&["run", "--arch", "x86_64", "-F", "lazy-ept,nonexistent"])
+            .unwrap_err();
+
+        assert_eq!(error.kind(), clap::error::ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn target_incompatible_features_are_rejected() {
+        let error =
+            validate_features(Arch::Aarch64, &[Feature::DebugLocks, Feature::LazyEpt]).unwrap_err();
+
+        assert_eq!(error.kind(), clap::error::ErrorKind::InvalidValue);
+        assert!(error
+            .to_string()
+            .contains("'lazy-ept' is not supported on aarch64"));
+    }
+
+    #[test]
+    fn target_compatible_features_are_accepted() {
+        assert!(validate_features(
+            Arch::Aarch64,
+            &[Feature::DebugLocks, Feature::AllocationTracking]
+        )
+        .is_ok());
+        assert!(
+            validate_features(Arch::X86_64, <Feature as clap::ValueEnum>::value_variants()).is_ok()
+        );
+    }
+
+    #[test]
+    fn secure_boot_accepts_q35_on_x86() {
+        assert!(validate_secure_boot(Arch::X86_64, None).is_ok());
+        assert!(validate_secure_boot(Arch::X86_64, Some("q35")).is_ok());
+        assert!(validate_secure_boot(Arch::X86, Some("pc-q35-8.2")).is_ok());
+    }
+
+    #[test]
+    fn secure_boot_rejects_other_machines() {
+        let error = validate_secure_boot(Arch::X86_64, Some("pc")).unwrap_err();
+
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn secure_boot_rejects_aarch64() {
+        for machine in [None, Some("virt"), Some("q35")] {
+            let error = validate_secure_boot(Arch::Aarch64, machine).unwrap_err();
+
+            assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+    }
+}
@@ -1,30 +1,86 @@
 //! Helper crate for building and testing `boot-manipulator`.
 
 use std::{
-    ffi::OsString,
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
     fmt::{self, Display},
-    io,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    process::ExitCode,
+    process::{ExitCode, Stdio},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use cli::{get_action, Action, Arch, BuildArguments, Feature, RunArguments};
+use bundle::BundleInputs;
+use cli::{
+    get_action, Accel, Action, Arch, BuildArguments, BuildPlan, CleanArguments, Feature,
+    RunArguments, SizeArguments, SizeTarget,
+};
+use message::{status, BuildArtifact, MessageFormat, RunEvent};
+use ovmf::Firmware;
 
+pub mod bundle;
 pub mod cli;
+pub mod message;
+pub mod ovmf;
+pub mod pe;
+pub mod process;
+pub mod size;
 
 fn main() -> ExitCode {
     match get_action() {
-        Action::Build(arguments) => match build_boot_manipulator(arguments) {
-            Ok(path) => println!("boot-manipulator located at \"{}\"", path.display()),
-            Err(error) => {
-                eprintln!("{error}");
+        Action::Build(plan) => {
+            if !build_all(plan) {
                 return ExitCode::FAILURE;
             }
-        },
+        }
         Action::Run {
             build_arguments,
             run_arguments,
-        } => match run(build_arguments, run_arguments) {
+            timeout,
+        } => {
+            let mode = QemuMode::Interactive {
+                timeout: timeout.map(Duration::from_secs),
+            };
+
+            match run(build_arguments, run_arguments, mode) {
+                Ok(()) => {}
+                Err(error) => {
+                    eprintln!("{error}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Action::Test {
+            build_arguments,
+            run_arguments,
+            test_arguments,
+        } => {
+            let mode = QemuMode::Test {
+                timeout: Duration::from_secs(test_arguments.timeout),
+            };
+
+            match run(build_arguments, run_arguments, mode) {
+                Ok(()) => status!("boot-manipulator test passed"),
+                Err(error) => {
+                    eprintln!("{error}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Action::Clean(arguments) => match clean(arguments) {
+            Ok(()) => {}
+            Err(error) => {
+                eprintln!("error while cleaning: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Action::Size(arguments) => match size(arguments) {
             Ok(()) => {}
             Err(error) => {
                 eprintln!("{error}");
@@ -36,15 +92,179 @@ fn main() -> ExitCode {
     ExitCode::SUCCESS
 }
 
-fn build_boot_manipulator(arguments: BuildArguments) -> Result<PathBuf, BuildError> {
+/// Removes the run artifacts and, if requested, the cargo target directories selected by
+/// `arguments`.
+///
+/// # Errors
+/// Returns an [`io::Error`] if a directory could not be removed or would require following a
+/// symbolic link out of the workspace.
+fn clean(arguments: CleanArguments) -> Result<(), io::Error> {
+    let arches = match arguments.arch {
+        Some(arch) => vec![arch],
+        None => <Arch as clap::ValueEnum>::value_variants().to_vec(),
+    };
+
+    let mut directories = match arguments.arch {
+        Some(arch) => vec![run_directory(arch)],
+        None => vec![PathBuf::from("run")],
+    };
+    if arguments.all {
+        directories.extend(
+            arches
+                .iter()
+                .map(|arch| Path::new("target").join(arch.as_target_triple())),
+        );
+    }
+
+    let workspace = std::env::current_dir()?.canonicalize()?;
+    for directory in directories {
+        let metadata = match std::fs::symlink_metadata(&directory) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+
+        if metadata.file_type().is_symlink() || !directory.canonicalize()?.starts_with(&workspace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "refusing to remove \"{}\": it leads outside of the workspace",
+                    directory.display()
+                ),
+            ));
+        }
+
+        status!("Removing \"{}\"", directory.display());
+        std::fs::remove_dir_all(&directory)?;
+    }
+
+    Ok(())
+}
+
+/// Reports the section sizes of the binary selected by `arguments`, building it if necessary.
+///
+/// # Errors
+/// Returns a [`SizeError`] if the binary could not be built, read, or parsed, or if its image
+/// size exceeds the requested maximum.
+fn size(arguments: SizeArguments) -> Result<(), SizeError> {
+    let binary = match arguments.target {
+        SizeTarget::Build(build_arguments) => build_boot_manipulator(build_arguments)?.binary,
+        SizeTarget::Binary(binary) => binary,
+    };
+
+    let image = std::fs::read(&binary).map_err(SizeError::ReadFailed)?;
+    let report = size::SizeReport::measure(binary, &image).map_err(SizeError::ParseFailed)?;
+
+    match message::format() {
+        MessageFormat::Human => println!("{report}"),
+        MessageFormat::Json => message::emit_json(&report),
+    }
+
+    match arguments.max_size {
+        Some(max_size) if u64::from(report.image_size) > max_size => Err(SizeError::TooLarge {
+            size: report.image_size,
+            max_size,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Various errors that can occur while measuring a binary.
+#[derive(Debug)]
+enum SizeError {
+    /// An error occurred while building `boot-manipulator`.
+    BuildFailed(BuildError),
+    /// The binary could not be read.
+    ReadFailed(io::Error),
+    /// The binary is not a PE image.
+    ParseFailed(object::Error),
+    /// The image size exceeds the requested maximum.
+    TooLarge {
+        /// The image size of the binary.
+        size: u32,
+        /// The maximum permitted image size.
+        max_size: u64,
+    },
+}
+
+impl From<BuildError> for SizeError {
+    fn from(value: BuildError) -> Self {
+        Self::BuildFailed(value)
+    }
+}
+
+impl Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BuildFailed(error) => write!(f, "{error}"),
+            Self::ReadFailed(error) => write!(f, "error while reading binary: {error}"),
+            Self::ParseFailed(error) => write!(f, "error while parsing binary: {error}"),
+            Self::TooLarge { size, max_size } => write!(
+                f,
+                "image size of {size} bytes exceeds the maximum of {max_size} bytes"
+            ),
+        }
+    }
+}
+
+/// Carries out every build in `plan`, running up to `plan.jobs` builds concurrently, and prints a
+/// summary of the results.
+///
+/// Returns `true` if every build succeeded.
+fn build_all(plan: BuildPlan) -> bool {
+    let jobs = plan.jobs.clamp(1, plan.targets.len().max(1));
+    let queue = Mutex::new(plan.targets.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let Some((index, arguments)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+
+                let target = arguments.arch.as_target_triple();
+                let result = build_boot_manipulator(arguments);
+                results.lock().unwrap().push((index, target, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(index, _, _)| index);
+
+    let mut success = true;
+    for (_, target, result) in results {
+        match result {
+            Ok(artifact) => match message::format() {
+                MessageFormat::Human => println!(
+                    "{target}: boot-manipulator located at \"{}\"",
+                    artifact.binary.display()
+                ),
+                MessageFormat::Json => message::emit_json(&artifact),
+            },
+            Err(error) => {
+                eprintln!("{target}: {error}");
+                success = false;
+            }
+        }
+    }
+
+    success
+}
+
+fn build_boot_manipulator(arguments: BuildArguments) -> Result<BuildArtifact, BuildError> {
+    check_toolchain(&arguments).map_err(BuildError::ToolchainUnavailable)?;
+
     let mut cmd = std::process::Command::new("cargo");
+    if arguments.build_std.is_some() {
+        cmd.arg("+nightly");
+    }
     cmd.arg("build");
     cmd.args(["--package", "boot-manipulator"]);
 
     cmd.args(["--target", arguments.arch.as_target_triple()]);
-    if arguments.release {
-        cmd.arg("--release");
-    }
+    cmd.args(["--profile", &arguments.profile]);
 
     if !arguments.features.is_empty() {
         let features = arguments
@@ -57,54 +277,466 @@ fn build_boot_manipulator(arguments: BuildArguments) -> Result<PathBuf, BuildErr
         cmd.args(["--features", &features]);
     }
 
+    if let Some(crates) = &arguments.build_std {
+        cmd.arg(format!("-Zbuild-std={crates}"));
+    }
+
+    if !arguments.rustflags.is_empty() {
+        let mut rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
+        for flag in &arguments.rustflags {
+            if !rustflags.is_empty() {
+                rustflags.push(' ');
+            }
+            rustflags.push_str(flag);
+        }
+
+        cmd.env("RUSTFLAGS", rustflags);
+    }
+
+    run_cmd(cmd)?;
+
+    existing_build(arguments)
+}
+
+/// Returns the artifact left by a previous build of `boot-manipulator` with `arguments`, without
+/// running `cargo`.
+///
+/// # Errors
+/// Returns [`BuildError::NotBuilt`] if no binary exists at the expected location, or another
+/// [`BuildError`] if the binary cannot be read or is not a valid EFI image for the target.
+fn existing_build(arguments: BuildArguments) -> Result<BuildArtifact, BuildError> {
+    let binary_location = binary_path(arguments.arch, &arguments.profile);
+
+    let image = std::fs::read(&binary_location).map_err(|error| match error.kind() {
+        io::ErrorKind::NotFound => BuildError::NotBuilt(binary_location.clone()),
+        _ => BuildError::ReadFailed(error),
+    })?;
+    pe::validate(&image, arguments.arch, pe::Subsystem::EfiRuntimeDriver)
+        .map_err(|error| BuildError::InvalidBinary(binary_location.clone(), error))?;
+
+    Ok(BuildArtifact {
+        binary: binary_location,
+        target: arguments.arch.as_target_triple(),
+        profile: arguments.profile,
+        features: arguments.features.iter().map(Feature::as_str).collect(),
+    })
+}
+
+/// Returns the path at which cargo places `boot-manipulator` when built for `arch` with `profile`.
+fn binary_path(arch: Arch, profile: &str) -> PathBuf {
     let mut binary_location = PathBuf::with_capacity(50);
     binary_location.push("target");
-    binary_location.push(arguments.arch.as_target_triple());
-    if arguments.release {
-        binary_location.push("release");
-    } else {
-        binary_location.push("debug");
-    }
+    binary_location.push(arch.as_target_triple());
+    binary_location.push(profile_directory(profile));
     binary_location.push("boot-manipulator.efi");
+    binary_location
+}
 
-    run_cmd(cmd)?;
+/// Returns the name of the directory under `target/<triple>/` into which cargo places the output of
+/// `profile`.
+fn profile_directory(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// Checks that the toolchain needed to build `boot-manipulator` with `arguments` is installed.
+///
+/// # Errors
+/// Returns [`ToolchainError::NightlyRequired`] if `-Z build-std` was requested but no nightly
+/// toolchain is available, or [`ToolchainError::TargetNotInstalled`] if the standard library for
+/// the target is not installed.
+fn check_toolchain(arguments: &BuildArguments) -> Result<(), ToolchainError> {
+    if arguments.build_std.is_some() {
+        // The standard library is built from source, so only a nightly toolchain is needed.
+        return if nightly_available() {
+            Ok(())
+        } else {
+            Err(ToolchainError::NightlyRequired)
+        };
+    }
 
-    Ok(binary_location)
+    let target = arguments.arch.as_target_triple();
+    if !target_installed(target) {
+        return Err(ToolchainError::TargetNotInstalled(target));
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if `rustc +nightly` runs and reports a nightly version.
+fn nightly_available() -> bool {
+    std::process::Command::new("rustc")
+        .args(["+nightly", "--version"])
+        .output()
+        .is_ok_and(|output| {
+            output.status.success() && String::from_utf8_lossy(&output.stdout).contains("nightly")
+        })
+}
+
+/// Various ways in which the toolchain needed to build `boot-manipulator` can be unavailable.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum ToolchainError {
+    /// The standard library for the target is not installed.
+    TargetNotInstalled(&'static str),
+    /// `-Z build-std` was requested but no nightly toolchain is installed.
+    NightlyRequired,
+}
+
+impl Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TargetNotInstalled(target) => write!(
+                f,
+                "target {target} is not installed; install it with `rustup target add {target}`"
+            ),
+            Self::NightlyRequired => write!(
+                f,
+                "--build-std requires a nightly toolchain; install it with `rustup toolchain \
+                 install nightly --component rust-src`"
+            ),
+        }
+    }
+}
+
+/// Returns `false` if `rustup` reports that the standard library for `target` is not installed.
+///
+/// If `rustup` is unavailable, the target is assumed to be installed and any problem is left for
+/// `cargo` to report.
+fn target_installed(target: &str) -> bool {
+    let output = std::process::Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.trim() == target),
+        _ => true,
+    }
 }
 
 #[derive(Debug)]
-struct BuildError(RunCommandError);
+enum BuildError {
+    /// The toolchain needed for the build is not installed.
+    ToolchainUnavailable(ToolchainError),
+    /// An error occurred while running `cargo`.
+    CommandFailed(RunCommandError),
+    /// No binary from a previous build exists at the expected location.
+    NotBuilt(PathBuf),
+    /// The built binary could not be read.
+    ReadFailed(io::Error),
+    /// The built binary is not a valid EFI image for the target.
+    InvalidBinary(PathBuf, pe::PeError),
+}
 
 impl From<RunCommandError> for BuildError {
     fn from(value: RunCommandError) -> Self {
-        Self(value)
+        Self::CommandFailed(value)
     }
 }
 
 impl Display for BuildError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "error while building boot-manipulator: {}", self.0)
+        match self {
+            Self::ToolchainUnavailable(error) => {
+                write!(f, "error while building boot-manipulator: {error}")
+            }
+            Self::CommandFailed(error) => {
+                write!(f, "error while building boot-manipulator: {error}")
+            }
+            Self::NotBuilt(path) => write!(
+                f,
+                "no boot-manipulator build found at \"{}\"; run without --skip-build first",
+                path.display()
+            ),
+            Self::ReadFailed(error) => {
+                write!(f, "error while reading built boot-manipulator: {error}")
+            }
+            Self::InvalidBinary(path, error) => write!(
+                f,
+                "built boot-manipulator at \"{}\" is invalid: {error}",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// The line printed by `boot-manipulator` once it has been successfully loaded.
+const SUCCESS_MARKER: &str = "boot-manipulator successfully loaded";
+
+fn run(
+    build_arguments: BuildArguments,
+    run_arguments: RunArguments,
+    mode: QemuMode,
+) -> Result<(), RunError> {
+    let arch = build_arguments.arch;
+    let bundle_on_success = run_arguments.bundle_on_success;
+    let configuration = format!("{build_arguments:#?}\n{run_arguments:#?}\n{mode:#?}\n");
+
+    let mut qemu_argv = None;
+    let result = build_and_run(build_arguments, run_arguments, mode, &mut qemu_argv);
+
+    let reason = match &result {
+        Ok(()) if !bundle_on_success => return result,
+        Ok(()) => "success",
+        Err(error) => error.reason(),
+    };
+
+    let inputs = BundleInputs {
+        reason: reason.to_owned(),
+        qemu_argv,
+        configuration,
+    };
+    match bundle::assemble_bundle(&run_directory(arch), bundle::current_timestamp(), &inputs) {
+        Ok(path) => {
+            status!("triage bundle located at \"{}\"", path.display());
+            message::emit_json(&RunEvent::BundleAssembled { path: &path });
+        }
+        Err(error) => eprintln!("error while assembling triage bundle: {error}"),
     }
+
+    result
 }
 
-fn run(build_arguments: BuildArguments, run_arguments: RunArguments) -> Result<(), RunError> {
+/// Builds `boot-manipulator` and runs it in QEMU, recording the QEMU command line in
+/// `qemu_argv`.
+fn build_and_run(
+    build_arguments: BuildArguments,
+    run_arguments: RunArguments,
+    mode: QemuMode,
+    qemu_argv: &mut Option<Vec<OsString>>,
+) -> Result<(), RunError> {
     let arch = build_arguments.arch;
+    let debugcon = build_arguments.features.contains(&Feature::Debugcon);
 
-    let boot_manipulator = build_boot_manipulator(build_arguments)?;
-    let fat_directory = build_fat_directory(arch, boot_manipulator, &[], &[])
-        .map_err(RunError::BuildFatDirectoryError)?;
+    let mut firmware = preflight(&build_arguments, &run_arguments)?;
+    firmware.vars = prepare_vars(arch, &firmware.vars, run_arguments.persist_vars)
+        .map_err(RunError::VarsCopyError)?;
 
-    run_qemu(arch, &fat_directory, run_arguments)?;
+    let artifact = if run_arguments.skip_build {
+        existing_build(build_arguments)?
+    } else {
+        build_boot_manipulator(build_arguments)?
+    };
+    message::emit_json(&RunEvent::BuildFinished(&artifact));
+    let boot_manipulator = artifact.binary;
+    if let Some(port) = run_arguments.gdb {
+        prepare_gdb(arch, &boot_manipulator, port).map_err(RunError::GdbSetupError)?;
+    }
+
+    let startup_script = |path: &[&str]| {
+        if run_arguments.no_startup_script {
+            Ok(None)
+        } else {
+            startup_script(&boot_manipulator, path).map(Some)
+        }
+    };
+
+    let boot_drive = if run_arguments.disk_image {
+        let startup_script =
+            startup_script(&DISK_IMAGE_DRIVER_PATH).map_err(RunError::BuildDiskImageError)?;
+        let image = build_disk_image(arch, &boot_manipulator, startup_script.as_deref())
+            .map_err(RunError::BuildDiskImageError)?;
+        BootDrive::DiskImage(image)
+    } else {
+        let startup_script = startup_script(&["EFI", "BOOT", boot_file_name(arch)])
+            .map_err(RunError::BuildFatDirectoryError)?;
+        let additional_binary_files = startup_script
+            .as_ref()
+            .map(|script| (script.as_bytes(), STARTUP_SCRIPT))
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let fat_directory =
+            build_fat_directory(arch, boot_manipulator, &[], &additional_binary_files)
+                .map_err(RunError::BuildFatDirectoryError)?;
+        if startup_script.is_none() {
+            match std::fs::remove_file(fat_directory.join(STARTUP_SCRIPT)) {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(RunError::BuildFatDirectoryError(error)),
+            }
+        }
+
+        BootDrive::FatDirectory(fat_directory)
+    };
+
+    run_qemu(
+        arch,
+        &boot_drive,
+        &firmware,
+        &run_arguments,
+        debugcon,
+        mode,
+        qemu_argv,
+    )?;
 
     Ok(())
 }
 
+/// Writes a GDB script for debugging `executable_path` to `run/<arch>/gdbinit` and prints
+/// instructions for attaching to QEMU's GDB stub on `port`.
+fn prepare_gdb(arch: Arch, executable_path: &Path, port: u16) -> Result<(), io::Error> {
+    let run_directory = run_directory(arch);
+    std::fs::create_dir_all(&run_directory)?;
+
+    let executable_path = std::fs::canonicalize(executable_path)?;
+    let script_path = run_directory.join("gdbinit");
+    std::fs::write(
+        &script_path,
+        format!(
+            "symbol-file {}\ntarget remote :{port}\n",
+            executable_path.display()
+        ),
+    )?;
+
+    status!("QEMU will start halted, waiting for GDB on port {port}");
+    status!("attach with: target remote :{port}");
+    status!("or run: gdb -x {}", script_path.display());
+    status!(
+        "UEFI relocates the driver when loading it; if breakpoints do not resolve, reload the \
+         symbols with `add-symbol-file {} <image base>`",
+        executable_path.display()
+    );
+
+    Ok(())
+}
+
+/// Checks that QEMU, the OVMF firmware, and the Rust toolchain needed to run `boot-manipulator`
+/// are all available, returning the located firmware.
+///
+/// # Errors
+/// Returns a [`PreflightError`] listing every missing requirement.
+fn preflight(
+    build_arguments: &BuildArguments,
+    run_arguments: &RunArguments,
+) -> Result<Firmware, PreflightError> {
+    let arch = build_arguments.arch;
+    let mut problems = Vec::new();
+
+    let qemu = qemu_binary(arch);
+    if find_in_path(qemu).is_none() {
+        problems.push(format!("QEMU binary \"{qemu}\" was not found on PATH"));
+    }
+
+    if let Err(error) = check_toolchain(build_arguments) {
+        problems.push(error.to_string());
+    }
+
+    let firmware = ovmf::locate(
+        arch,
+        run_arguments.ovmf_code.clone(),
+        run_arguments.ovmf_vars.clone(),
+        run_arguments.secure_boot,
+        !run_arguments.no_download,
+    );
+    match &firmware {
+        Ok(firmware) => {
+            for path in [&firmware.code, &firmware.vars] {
+                if let Err(error) = std::fs::File::open(path) {
+                    problems.push(format!(
+                        "OVMF file \"{}\" is not readable: {error}",
+                        path.display()
+                    ));
+                }
+            }
+        }
+        Err(error) => problems.push(error.to_string()),
+    }
+
+    match firmware {
+        Ok(firmware) if problems.is_empty() => Ok(firmware),
+        _ => Err(PreflightError { problems }),
+    }
+}
+
+/// Copies the OVMF vars file at `source` into `run/<arch>/OVMF_VARS.fd`, returning the path to
+/// the writable copy.
+///
+/// If `persist` is true, an existing copy is kept so that boot variables survive between runs,
+/// unless `source` has been modified since the copy was made.
+///
+/// # Errors
+/// Returns an [`io::Error`] if the copy could not be made.
+fn prepare_vars(arch: Arch, source: &Path, persist: bool) -> Result<PathBuf, io::Error> {
+    let run_directory = run_directory(arch);
+    std::fs::create_dir_all(&run_directory)?;
+
+    let copy = run_directory.join("OVMF_VARS.fd");
+    if persist {
+        let source_modified = std::fs::metadata(source)?.modified()?;
+        if let Ok(copy_modified) = std::fs::metadata(&copy).and_then(|m| m.modified()) {
+            if copy_modified >= source_modified {
+                return Ok(copy);
+            }
+        }
+    }
+
+    // Installed firmware is often read-only, and `std::fs::copy` would preserve that.
+    std::fs::write(&copy, std::fs::read(source)?)?;
+
+    Ok(copy)
+}
+
+/// Returns the path to the first file named `name` in the directories listed in `PATH`.
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|directory| directory.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Returns the name of the QEMU binary used to run `arch`.
+fn qemu_binary(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86 => "qemu-system-i386",
+        Arch::Aarch64 => "qemu-system-aarch64",
+        Arch::X86_64 => "qemu-system-x86_64",
+    }
+}
+
+/// The requirements found to be missing by [`preflight`].
+#[derive(Debug)]
+struct PreflightError {
+    /// A description of each missing requirement.
+    problems: Vec<String>,
+}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot run boot-manipulator:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the directory in which run artifacts for `arch` are stored.
+fn run_directory(arch: Arch) -> PathBuf {
+    let mut run_directory = PathBuf::with_capacity(50);
+    run_directory.push("run");
+    run_directory.push(arch.as_str());
+    run_directory
+}
+
 #[derive(Debug)]
 enum RunError {
+    /// Something required to run `boot-manipulator` is missing.
+    PreflightFailed(PreflightError),
+    /// An error occurred while copying the OVMF vars file.
+    VarsCopyError(std::io::Error),
     /// An error occurred while building `boot_manipulator`.
     BuildFailed(BuildError),
     /// An error occurred while building the FAT directory.
     BuildFatDirectoryError(std::io::Error),
+    /// An error occurred while building the disk image.
+    BuildDiskImageError(std::io::Error),
+    /// An error occurred while writing the GDB script.
+    GdbSetupError(std::io::Error),
     /// An error occurred while running QEMU.
     QemuError(QemuError),
 }
@@ -115,120 +747,520 @@ impl From<BuildError> for RunError {
     }
 }
 
+impl From<PreflightError> for RunError {
+    fn from(value: PreflightError) -> Self {
+        Self::PreflightFailed(value)
+    }
+}
+
 impl From<QemuError> for RunError {
     fn from(value: QemuError) -> Self {
         Self::QemuError(value)
     }
 }
 
+impl RunError {
+    /// Returns a short description of the failure suitable for naming a triage bundle.
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::PreflightFailed(_) => "preflight-failed",
+            Self::VarsCopyError(_) => "vars-copy-failed",
+            Self::BuildFailed(_) => "build-failed",
+            Self::BuildFatDirectoryError(_) => "fat-directory-failed",
+            Self::BuildDiskImageError(_) => "disk-image-failed",
+            Self::GdbSetupError(_) => "gdb-setup-failed",
+            Self::QemuError(QemuError::CommandFailed(_)) => "qemu-failed",
+            Self::QemuError(QemuError::MarkerNotFound) => "marker-not-found",
+            Self::QemuError(QemuError::TimedOut(_)) => "timed-out",
+            Self::QemuError(QemuError::GuestFailed) => "guest-failed",
+        }
+    }
+}
+
 impl Display for RunError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::PreflightFailed(error) => error.fmt(f),
+            Self::VarsCopyError(error) => write!(f, "error while copying OVMF vars: {error}"),
             Self::BuildFailed(error) => error.fmt(f),
             Self::BuildFatDirectoryError(error) => {
                 write!(f, "error while building FAT directory: {error}")
             }
+            Self::BuildDiskImageError(error) => {
+                write!(f, "error while building disk image: {error}")
+            }
+            Self::GdbSetupError(error) => write!(f, "error while writing GDB script: {error}"),
             Self::QemuError(error) => error.fmt(f),
         }
     }
 }
 
+/// How QEMU should be run.
+#[derive(Clone, Copy, Debug)]
+enum QemuMode {
+    /// QEMU runs with a display, and the serial port is connected to FIFOs under
+    /// `run/<arch>/outputs`.
+    Interactive {
+        /// How long QEMU may run before it is killed, if limited.
+        timeout: Option<Duration>,
+    },
+    /// QEMU runs headless, and its serial output is scanned for [`SUCCESS_MARKER`].
+    Test {
+        /// How long to wait for [`SUCCESS_MARKER`] before giving up.
+        timeout: Duration,
+    },
+}
+
+/// The drive from which QEMU boots `boot-manipulator`.
+#[derive(Clone, Debug)]
+enum BootDrive {
+    /// A host directory exposed through QEMU's virtual FAT driver.
+    FatDirectory(PathBuf),
+    /// A raw GPT disk image containing an EFI System Partition.
+    DiskImage(PathBuf),
+}
+
 fn run_qemu(
     arch: Arch,
-    fat_directory: &Path,
-    run_arguments: RunArguments,
+    boot_drive: &BootDrive,
+    firmware: &Firmware,
+    run_arguments: &RunArguments,
+    debugcon: bool,
+    mode: QemuMode,
+    qemu_argv: &mut Option<Vec<OsString>>,
 ) -> Result<(), QemuError> {
-    let name = match arch {
-        Arch::X86_64 => "qemu-system-x86_64",
-    };
-
-    let mut cmd = std::process::Command::new(name);
+    let mut cmd = std::process::Command::new(qemu_binary(arch));
 
     // Disable unnecessary devices
     cmd.arg("-nodefaults");
 
     cmd.args(["-boot", "menu=on,splash-time=0"]);
+    let use_kvm = match run_arguments.accel {
+        Accel::Kvm => true,
+        Accel::Tcg => false,
+        Accel::Auto => kvm_available(arch),
+    };
+
     match arch {
-        Arch::X86_64 => {
+        Arch::X86 | Arch::X86_64 => {
             // Target fairly modern cpu and machine
-            cmd.args(["-machine", "q35"]);
-            cmd.args(["-cpu", "max"]);
+            let mut machine = run_arguments
+                .machine
+                .clone()
+                .unwrap_or_else(|| arch.default_machine().to_owned());
+            if run_arguments.secure_boot {
+                // Secure Boot capable OVMF builds require SMM to protect the variable store.
+                machine.push_str(",smm=on");
+                cmd.args(["-global", "driver=cfi.pflash01,property=secure,value=on"]);
+            }
+            cmd.arg("-machine").arg(machine);
+            if use_kvm {
+                cmd.arg("-enable-kvm");
+                cmd.args(["-cpu", "max"]);
+            } else {
+                eprintln!(
+                    "warning: KVM is not in use; falling back to TCG, which is slow and only \
+                     emulates VMX because it is explicitly requested"
+                );
+                cmd.args(["-accel", "tcg"]);
+                cmd.args(["-cpu", "max,+vmx"]);
+            }
 
             // Allocate a little memory.
             cmd.args(["-m", "512M"]);
 
+            // Provide multiple processors so that the APs are exercised.
+            cmd.arg("-smp").arg(run_arguments.smp.to_string());
+
             // Use VGA graphics as the windowing interface.
             cmd.args(["-vga", "std"]);
 
-            if std::env::consts::OS == "linux" {
+            // Allow the guest to exit QEMU with a status code.
+            cmd.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
+        }
+        Arch::Aarch64 => {
+            let machine = run_arguments
+                .machine
+                .clone()
+                .unwrap_or_else(|| arch.default_machine().to_owned());
+            cmd.arg("-machine").arg(machine);
+            if use_kvm {
                 cmd.arg("-enable-kvm");
+                cmd.args(["-cpu", "host"]);
+            } else {
+                cmd.args(["-accel", "tcg"]);
+                cmd.args(["-cpu", "cortex-a72"]);
             }
+
+            // Allocate a little memory.
+            cmd.args(["-m", "512M"]);
+
+            // Provide multiple processors so that the secondary cores are exercised.
+            cmd.arg("-smp").arg(run_arguments.smp.to_string());
+
+            // The virt machine has no legacy VGA, so provide a simple framebuffer and input.
+            cmd.args(["-device", "ramfb"]);
+            cmd.args(["-device", "qemu-xhci"]);
+            cmd.args(["-device", "usb-kbd"]);
         }
     }
 
+    // Exit instead of rebooting so that triple faults are reported rather than looped.
+    cmd.arg("-no-reboot");
+
     // Use OVMF code file.
     let mut ovmf_code_arg = OsString::from("if=pflash,format=raw,readonly=on,file=");
-    ovmf_code_arg.push(run_arguments.ovmf_code);
+    ovmf_code_arg.push(&firmware.code);
     cmd.arg("-drive").arg(ovmf_code_arg);
 
-    // Use OVMF vars file.
-    let mut ovmf_vars_arg = OsString::from("if=pflash,format=raw,readonly=on,file=");
-    ovmf_vars_arg.push(run_arguments.ovmf_vars);
+    // Use the writable copy of the OVMF vars file.
+    let mut ovmf_vars_arg = OsString::from("if=pflash,format=raw,readonly=off,file=");
+    ovmf_vars_arg.push(&firmware.vars);
     cmd.arg("-drive").arg(ovmf_vars_arg);
 
-    // Use the given `fat_directory`.
-    let mut fat_drive_arg = OsString::from("format=raw,file=fat:rw:");
-    fat_drive_arg.push(fat_directory);
-    cmd.arg("-drive").arg(fat_drive_arg);
+    // Use the given `boot_drive`.
+    let mut boot_drive_arg = OsString::from("format=raw,file=");
+    match boot_drive {
+        BootDrive::FatDirectory(fat_directory) => {
+            boot_drive_arg.push("fat:rw:");
+            boot_drive_arg.push(fat_directory);
+        }
+        BootDrive::DiskImage(image) => boot_drive_arg.push(image),
+    }
+    cmd.arg("-drive").arg(boot_drive_arg);
 
     let mut outputs_path = PathBuf::with_capacity(50);
     outputs_path.push("run");
     outputs_path.push(arch.as_str());
     outputs_path.push("outputs");
 
-    #[cfg(unix)]
-    {
-        let mode = nix::sys::stat::Mode::from_bits(0o666).unwrap();
+    let serial_log = run_arguments
+        .serial_log
+        .clone()
+        .unwrap_or_else(|| run_directory(arch).join("serial.log"));
+    if let Some(parent) = serial_log.parent() {
+        std::fs::create_dir_all(parent).map_err(RunCommandError::from)?;
+    }
+    // Truncate the log so that stale output can't be mistaken for output from this boot.
+    std::fs::File::create(&serial_log).map_err(RunCommandError::from)?;
 
-        match nix::unistd::mkfifo(&outputs_path.join("serial.in"), mode) {
-            Ok(()) => {}
-            Err(error) if error == nix::errno::Errno::EEXIST => {}
-            Err(error) => todo!("{error}"),
+    let mut serial_arg = OsString::new();
+    match mode {
+        QemuMode::Interactive { .. } => {
+            #[cfg(unix)]
+            {
+                std::fs::create_dir_all(&outputs_path).map_err(RunCommandError::from)?;
+                let mode = nix::sys::stat::Mode::from_bits(0o666).unwrap();
+
+                for fifo in ["serial.in", "serial.out"] {
+                    match nix::unistd::mkfifo(&outputs_path.join(fifo), mode) {
+                        Ok(()) | Err(nix::errno::Errno::EEXIST) => {}
+                        Err(error) => {
+                            return Err(RunCommandError::from(io::Error::from(error)).into())
+                        }
+                    }
+                }
+
+                serial_arg.push("pipe,id=serial0,path=");
+                serial_arg.push(outputs_path.join("serial"));
+            }
+
+            #[cfg(not(unix))]
+            serial_arg.push("null,id=serial0");
         }
+        QemuMode::Test { .. } => {
+            cmd.args(["-display", "none"]);
+            serial_arg.push("stdio,id=serial0");
+        }
+    }
 
-        match nix::unistd::mkfifo(&outputs_path.join("serial.out"), mode) {
-            Ok(()) => {}
-            Err(error) if error == nix::errno::Errno::EEXIST => {}
-            Err(error) => todo!("{error}"),
+    // Log all serial output regardless of where the serial port is connected.
+    serial_arg.push(",logfile=");
+    serial_arg.push(&serial_log);
+    serial_arg.push(",logappend=off");
+    cmd.arg("-chardev").arg(serial_arg);
+    cmd.args(["-serial", "chardev:serial0"]);
+
+    // Log the debug console, which the driver only writes to when built with `debugcon`.
+    let debugcon_log = debugcon.then(|| run_directory(arch).join("debugcon.log"));
+    if let Some(debugcon_log) = &debugcon_log {
+        let mut debugcon_arg = OsString::from("file:");
+        debugcon_arg.push(debugcon_log);
+        cmd.arg("-debugcon").arg(debugcon_arg);
+    }
+
+    // Start halted and wait for a debugger to attach.
+    if let Some(port) = run_arguments.gdb {
+        cmd.arg("-gdb").arg(format!("tcp::{port}"));
+        cmd.arg("-S");
+    }
+
+    // Extra arguments come last so that they can override the built-in arguments.
+    cmd.args(&run_arguments.qemu_args);
+
+    *qemu_argv = Some(
+        std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(OsStr::to_os_string)
+            .collect(),
+    );
+    if let Some(argv) = qemu_argv.as_ref() {
+        message::emit_json(&RunEvent::QemuStarted {
+            argv: argv
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+        });
+    }
+
+    let result = match mode {
+        QemuMode::Interactive { timeout } => {
+            let result = run_qemu_interactive(cmd, timeout);
+
+            #[cfg(unix)]
+            let result = result.and(
+                std::fs::remove_file(outputs_path.join("serial.in"))
+                    .and_then(|()| std::fs::remove_file(outputs_path.join("serial.out")))
+                    .map_err(|error| QemuError::from(RunCommandError::from(error))),
+            );
+
+            result
         }
+        QemuMode::Test { timeout } => run_qemu_test(cmd, timeout),
+    };
 
-        cmd.args(["-serial", "pipe:run/x86_64/outputs/serial"]);
+    status!("serial output logged to \"{}\"", serial_log.display());
+    if let Some(debugcon_log) = debugcon_log {
+        status!(
+            "debug console output logged to \"{}\"",
+            debugcon_log.display()
+        );
     }
 
-    run_cmd(cmd)?;
+    result
+}
 
-    #[cfg(unix)]
-    {
-        std::fs::remove_file(&outputs_path.join("serial.in")).unwrap();
-        std::fs::remove_file(&outputs_path.join("serial.out")).unwrap();
+/// Returns `true` if `/dev/kvm` exists and can be opened for reading and writing, and the host
+/// can run `arch` natively.
+fn kvm_available(arch: Arch) -> bool {
+    let native = match arch {
+        Arch::X86 | Arch::X86_64 => std::env::consts::ARCH == "x86_64",
+        Arch::Aarch64 => std::env::consts::ARCH == "aarch64",
+    };
+
+    native
+        && std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+}
+
+/// Runs QEMU until it exits or `timeout` expires.
+fn run_qemu_interactive(
+    mut cmd: std::process::Command,
+    timeout: Option<Duration>,
+) -> Result<(), QemuError> {
+    status!("Running command: {cmd:?}");
+    // Without a timeout, QEMU stays in the terminal's foreground process group so that it can use
+    // the terminal for its serial port or monitor.
+    let mut child = process::spawn(&mut cmd, timeout.is_some()).map_err(RunCommandError::from)?;
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    match process::wait(&mut child, deadline) {
+        Ok(Some(status)) => {
+            message::emit_json(&RunEvent::QemuExited {
+                code: status.code(),
+            });
+            interpret_exit_status(status.code(), Ok(()))
+        }
+        Ok(None) => {
+            let status = process::kill(&mut child);
+            message::emit_json(&RunEvent::QemuExited {
+                code: status.and_then(|status| status.code()),
+            });
+            Err(QemuError::TimedOut(timeout.unwrap_or_default()))
+        }
+        Err(error) => {
+            process::kill(&mut child);
+            Err(QemuError::from(RunCommandError::from(error)))
+        }
     }
+}
 
-    Ok(())
+/// Interprets QEMU's exit `code`, returning `clean_exit` if QEMU exited normally without the guest
+/// reporting a status.
+fn interpret_exit_status(
+    code: Option<i32>,
+    clean_exit: Result<(), QemuError>,
+) -> Result<(), QemuError> {
+    match code {
+        Some(code) => match QemuExitCode::from_exit_status(code) {
+            Some(QemuExitCode::Success) => Ok(()),
+            Some(QemuExitCode::Failure) => Err(QemuError::GuestFailed),
+            None if code == 0 => clean_exit,
+            None => Err(QemuError::from(RunCommandError::CommandFailed {
+                code: Some(code),
+            })),
+        },
+        None => Err(QemuError::from(RunCommandError::CommandFailed {
+            code: None,
+        })),
+    }
+}
+
+/// Runs QEMU until [`SUCCESS_MARKER`] appears on its standard output, QEMU exits, or `timeout`
+/// expires.
+fn run_qemu_test(mut cmd: std::process::Command, timeout: Duration) -> Result<(), QemuError> {
+    cmd.stdin(Stdio::null());
+    cmd.stdout(Stdio::piped());
+
+    status!("Running command: {cmd:?}");
+    let mut child = process::spawn(&mut cmd, true).map_err(RunCommandError::from)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        for line in BufReader::new(stdout).split(b'\n') {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(line) => {
+                let line = String::from_utf8_lossy(&line);
+                status!("{line}");
+                if line.contains(SUCCESS_MARKER) {
+                    break Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => break Err(QemuError::TimedOut(timeout)),
+            Err(RecvTimeoutError::Disconnected) => {
+                break match process::wait(&mut child, None) {
+                    Ok(status) => interpret_exit_status(
+                        status.and_then(|status| status.code()),
+                        Err(QemuError::MarkerNotFound),
+                    ),
+                    Err(error) => Err(QemuError::from(RunCommandError::from(error))),
+                };
+            }
+        }
+    };
+
+    if let Some(status) = process::kill(&mut child) {
+        message::emit_json(&RunEvent::QemuExited {
+            code: status.code(),
+        });
+    }
+    let _ = reader.join();
+
+    result
+}
+
+/// The status reported by the guest through the `isa-debug-exit` device.
+///
+/// QEMU exits with `(value << 1) | 1` when `value` is written to the device.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum QemuExitCode {
+    /// The guest wrote `0x10`, indicating success.
+    Success,
+    /// The guest wrote `0x11`, indicating failure.
+    Failure,
+}
+
+impl QemuExitCode {
+    /// The value the guest writes to indicate success.
+    pub const SUCCESS_VALUE: i32 = 0x10;
+    /// The value the guest writes to indicate failure.
+    pub const FAILURE_VALUE: i32 = 0x11;
+
+    /// Returns the [`QemuExitCode`] corresponding to QEMU's exit status `code`, if the exit was
+    /// caused by the guest writing to the `isa-debug-exit` device.
+    pub fn from_exit_status(code: i32) -> Option<Self> {
+        if code == (Self::SUCCESS_VALUE << 1) | 1 {
+            Some(Self::Success)
+        } else if code == (Self::FAILURE_VALUE << 1) | 1 {
+            Some(Self::Failure)
+        } else {
+            None
+        }
+    }
 }
 
 /// Various errors that can occur while running QEMU.
 #[derive(Debug)]
-pub struct QemuError(RunCommandError);
+pub enum QemuError {
+    /// An error occurred while running the QEMU command.
+    CommandFailed(RunCommandError),
+    /// QEMU exited without printing the success marker.
+    MarkerNotFound,
+    /// QEMU did not finish before the timeout expired.
+    TimedOut(Duration),
+    /// The guest reported failure through the `isa-debug-exit` device.
+    GuestFailed,
+}
 
 impl From<RunCommandError> for QemuError {
     fn from(value: RunCommandError) -> Self {
-        Self(value)
+        Self::CommandFailed(value)
     }
 }
 
 impl fmt::Display for QemuError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "error while running QEMU: {}", self.0)
+        match self {
+            Self::CommandFailed(error) => write!(f, "error while running QEMU: {error}"),
+            Self::MarkerNotFound => {
+                write!(f, "QEMU exited without printing \"{SUCCESS_MARKER}\"")
+            }
+            Self::GuestFailed => write!(f, "boot-manipulator reported failure"),
+            Self::TimedOut(timeout) => write!(
+                f,
+                "QEMU was killed after {} seconds without finishing",
+                timeout.as_secs()
+            ),
+        }
+    }
+}
+
+/// The name of the script run by the EFI shell on startup.
+const STARTUP_SCRIPT: &str = "startup.nsh";
+
+/// Returns the contents of a `startup.nsh` that starts the `boot-manipulator` binary at
+/// `executable_path`, stored at `path` on the first file system, from the EFI shell.
+///
+/// Drivers are loaded with `load`, while applications are executed directly.
+///
+/// # Errors
+/// Returns an [`io::Error`] if `executable_path` cannot be read or is not a PE image.
+fn startup_script(executable_path: &Path, path: &[&str]) -> Result<String, io::Error> {
+    let image = std::fs::read(executable_path)?;
+    let subsystem = pe::subsystem(&image)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+
+    let boot_file = format!("fs0:\\{}", path.join("\\"));
+    let command = match subsystem {
+        pe::Subsystem::EfiBootServiceDriver | pe::Subsystem::EfiRuntimeDriver => {
+            format!("load {boot_file}")
+        }
+        pe::Subsystem::EfiApplication | pe::Subsystem::Other(_) => boot_file,
+    };
+
+    Ok(format!("@echo -off\r\n{command}\r\n"))
+}
+
+/// Returns the name under which the removable media boot file for `arch` is stored.
+fn boot_file_name(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86 => "BOOTIA32.EFI",
+        Arch::Aarch64 => "BOOTAA64.EFI",
+        Arch::X86_64 => "BOOTX64.EFI",
     }
 }
 
@@ -250,11 +1282,15 @@ pub fn build_fat_directory(
         std::fs::create_dir_all(&boot_directory)?;
     }
 
-    let boot_file_name = match arch {
-        Arch::X86_64 => "BOOTX64.EFI",
-    };
+    let boot_file = boot_directory.join(boot_file_name(arch));
 
-    std::fs::copy(executable_path, boot_directory.join(boot_file_name))?;
+    let executable_modified = std::fs::metadata(&executable_path)?.modified()?;
+    let up_to_date = std::fs::metadata(&boot_file)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|copy_modified| copy_modified >= executable_modified);
+    if !up_to_date {
+        std::fs::copy(executable_path, boot_file)?;
+    }
 
     for &(file, name) in additional_files {
         std::fs::copy(file, fat_directory.join(name))?;
@@ -267,11 +1303,258 @@ pub fn build_fat_directory(
     Ok(fat_directory)
 }
 
+/// The size, in bytes, of a logical block in the disk image.
+const DISK_IMAGE_BLOCK_SIZE: u64 = 512;
+/// The minimum size, in bytes, of the EFI System Partition in the disk image.
+///
+/// FAT32 requires at least 65525 clusters, so this is the smallest size that can be formatted as
+/// FAT32 with 512 byte clusters.
+const MIN_ESP_SIZE: u64 = 64 * 1024 * 1024;
+/// The space, in bytes, reserved in the disk image for the GPT structures and alignment.
+const GPT_OVERHEAD: u64 = 2 * 1024 * 1024;
+
+/// The location of `boot-manipulator` within the disk image's EFI System Partition.
+///
+/// The firmware's boot manager does not start drivers as the removable media boot file, so the
+/// driver is kept out of `EFI\BOOT`. OVMF then falls back to its built-in EFI shell, which runs
+/// `startup.nsh` to load the driver.
+const DISK_IMAGE_DRIVER_PATH: [&str; 3] = ["EFI", "boot-manipulator", "boot-manipulator.efi"];
+
+/// Builds a GPT disk image with a single FAT32 EFI System Partition containing `executable_path`
+/// and, if provided, a `startup.nsh` containing `startup_script`.
+///
+/// The image is only rebuilt if `executable_path` has been modified since the image was last
+/// built or the image's startup script differs from `startup_script`.
+///
+/// # Errors
+/// Returns an [`io::Error`] if `executable_path` cannot be read or the image cannot be written.
+pub fn build_disk_image(
+    arch: Arch,
+    executable_path: &Path,
+    startup_script: Option<&str>,
+) -> Result<PathBuf, std::io::Error> {
+    let image_path = run_directory(arch).join("disk.img");
+
+    let executable_modified = std::fs::metadata(executable_path)?.modified()?;
+    let image_newer = std::fs::metadata(&image_path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|image_modified| image_modified >= executable_modified);
+    if image_newer
+        && read_disk_image_file(&image_path, &DISK_IMAGE_DRIVER_PATH).is_ok()
+        && read_disk_image_file(&image_path, &[STARTUP_SCRIPT])
+            .ok()
+            .as_deref()
+            == startup_script.map(str::as_bytes)
+    {
+        return Ok(image_path);
+    }
+
+    let executable = std::fs::read(executable_path)?;
+    write_disk_image(&image_path, &executable, startup_script)?;
+
+    Ok(image_path)
+}
+
+/// Writes a GPT disk image to `image_path` with a single FAT32 EFI System Partition containing
+/// `executable` at [`DISK_IMAGE_DRIVER_PATH`] and, if provided, a `startup.nsh` containing
+/// `startup_script`.
+///
+/// # Errors
+/// Returns an [`io::Error`] if the image cannot be written.
+fn write_disk_image(
+    image_path: &Path,
+    executable: &[u8],
+    startup_script: Option<&str>,
+) -> Result<(), io::Error> {
+    let esp_size = (executable.len() as u64 * 2)
+        .next_multiple_of(1024 * 1024)
+        .max(MIN_ESP_SIZE);
+    let image_size = esp_size + GPT_OVERHEAD;
+
+    if let Some(parent) = image_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut image = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image_path)?;
+    image.set_len(image_size)?;
+
+    let block_count = u32::try_from(image_size / DISK_IMAGE_BLOCK_SIZE - 1).unwrap_or(u32::MAX);
+    gpt::mbr::ProtectiveMBR::with_lb_size(block_count).overwrite_lba0(&mut image)?;
+
+    let esp_start = {
+        let mut disk = gpt::GptConfig::new()
+            .initialized(false)
+            .writable(true)
+            .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+            .create_from_device(Box::new(&mut image), None)?;
+        disk.update_partitions(BTreeMap::new())?;
+
+        let id = disk.add_partition("EFI System", esp_size, gpt::partition_types::EFI, 0, None)?;
+        let esp_start = disk
+            .partitions()
+            .get(&id)
+            .ok_or_else(|| io::Error::other("EFI System Partition missing"))?
+            .bytes_start(gpt::disk::LogicalBlockSize::Lb512)?;
+        disk.write()?;
+
+        esp_start
+    };
+
+    let mut esp = PartitionSlice::new(&mut image, esp_start, esp_size)?;
+    fatfs::format_volume(
+        &mut esp,
+        fatfs::FormatVolumeOptions::new()
+            .fat_type(fatfs::FatType::Fat32)
+            .bytes_per_cluster(DISK_IMAGE_BLOCK_SIZE as u32)
+            .volume_label(*b"BOOT-MANIP "),
+    )?;
+
+    let filesystem = fatfs::FileSystem::new(&mut esp, fatfs::FsOptions::new())?;
+    {
+        let root = filesystem.root_dir();
+        let [directories @ .., file_name] = DISK_IMAGE_DRIVER_PATH;
+        let mut directory = root.clone();
+        for name in directories {
+            directory = directory.create_dir(name)?;
+        }
+
+        let mut driver = directory.create_file(file_name)?;
+        driver.truncate()?;
+        driver.write_all(executable)?;
+        driver.flush()?;
+
+        if let Some(startup_script) = startup_script {
+            let mut script = root.create_file(STARTUP_SCRIPT)?;
+            script.truncate()?;
+            script.write_all(startup_script.as_bytes())?;
+            script.flush()?;
+        }
+    }
+    filesystem.unmount()?;
+
+    Ok(())
+}
+
+/// Reads the file at `path` from the EFI System Partition of the disk image at `image_path`.
+///
+/// # Errors
+/// Returns an [`io::Error`] if the image does not contain an EFI System Partition or the file
+/// cannot be read.
+fn read_disk_image_file(image_path: &Path, path: &[&str]) -> Result<Vec<u8>, io::Error> {
+    let mut image = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image_path)?;
+
+    let (esp_start, esp_size) = {
+        let disk = gpt::GptConfig::new()
+            .writable(false)
+            .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+            .open_from_device(Box::new(&mut image))?;
+        let esp = disk
+            .partitions()
+            .values()
+            .find(|partition| partition.part_type_guid == gpt::partition_types::EFI)
+            .ok_or_else(|| io::Error::other("EFI System Partition missing"))?;
+
+        (
+            esp.bytes_start(gpt::disk::LogicalBlockSize::Lb512)?,
+            esp.bytes_len(gpt::disk::LogicalBlockSize::Lb512)?,
+        )
+    };
+
+    let mut esp = PartitionSlice::new(&mut image, esp_start, esp_size)?;
+    let filesystem = fatfs::FileSystem::new(&mut esp, fatfs::FsOptions::new())?;
+
+    let mut contents = Vec::new();
+    filesystem
+        .root_dir()
+        .open_file(&path.join("/"))?
+        .read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// A window onto a partition within a disk image.
+#[derive(Debug)]
+struct PartitionSlice<'a> {
+    /// The disk image containing the partition.
+    image: &'a mut File,
+    /// The offset, in bytes, of the start of the partition.
+    start: u64,
+    /// The size, in bytes, of the partition.
+    size: u64,
+    /// The current offset, in bytes, relative to the start of the partition.
+    position: u64,
+}
+
+impl<'a> PartitionSlice<'a> {
+    /// Creates a new [`PartitionSlice`] covering `size` bytes starting at `start`.
+    fn new(image: &'a mut File, start: u64, size: u64) -> Result<Self, io::Error> {
+        image.seek(SeekFrom::Start(start))?;
+
+        Ok(Self {
+            image,
+            start,
+            size,
+            position: 0,
+        })
+    }
+
+    /// Returns the number of bytes between the current position and the end of the partition.
+    fn remaining(&self) -> usize {
+        usize::try_from(self.size.saturating_sub(self.position)).unwrap_or(usize::MAX)
+    }
+}
+
+impl Read for PartitionSlice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        let read = self.image.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for PartitionSlice<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        let written = self.image.write(&buf[..len])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.image.flush()
+    }
+}
+
+impl Seek for PartitionSlice<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+        };
+
+        let position = position
+            .filter(|&position| position <= self.size)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek out of bounds"))?;
+
+        self.image.seek(SeekFrom::Start(self.start + position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
 /// Runs a [`Command`][c], handling non-zero exit codes and other failures.
 ///
 /// [c]: std::process::Command
 pub fn run_cmd(mut cmd: std::process::Command) -> Result<(), RunCommandError> {
-    println!("Running command: {cmd:?}");
+    status!("Running command: {cmd:?}");
 
     let status = cmd.status()?;
     if !status.success() {
@@ -312,3 +1595,65 @@ impl Display for RunCommandError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_directory_maps_builtin_profiles() {
+        assert_eq!(profile_directory("dev"), "debug");
+        assert_eq!(profile_directory("test"), "debug");
+        assert_eq!(profile_directory("release"), "release");
+        assert_eq!(profile_directory("bench"), "release");
+    }
+
+    #[test]
+    fn profile_directory_keeps_custom_profiles() {
+        assert_eq!(profile_directory("release-lto"), "release-lto");
+        assert_eq!(profile_directory("ci"), "ci");
+    }
+
+    #[test]
+    fn binary_path_for_each_target() {
+        let cases = [
+            (Arch::X86, "i686-unknown-uefi"),
+            (Arch::X86_64, "x86_64-unknown-uefi"),
+            (Arch::Aarch64, "aarch64-unknown-uefi"),
+        ];
+        for (arch, triple) in cases {
+            for (profile, directory) in [("dev", "debug"), ("release", "release"), ("ci", "ci")] {
+                assert_eq!(
+                    binary_path(arch, profile),
+                    Path::new("target")
+                        .join(triple)
+                        .join(directory)
+                        .join("boot-manipulator.efi")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn disk_image_contains_driver_and_startup_script() {
+        let image_path =
+            std::env::temp_dir().join(format!("xtask-disk-image-{}.img", std::process::id()));
+        let driver = (0..=255).cycle().take(70_000).collect::<Vec<u8>>();
+        let script = "@echo -off\r\nload fs0:\\EFI\\boot-manipulator\\boot-manipulator.efi\r\n";
+
+        write_disk_image(&image_path, &driver, Some(script)).unwrap();
+        let startup = read_disk_image_file(&image_path, &[STARTUP_SCRIPT]);
+        let stored_driver = read_disk_image_file(&image_path, &DISK_IMAGE_DRIVER_PATH);
+        let boot_file = read_disk_image_file(&image_path, &["EFI", "BOOT", "BOOTX64.EFI"]);
+
+        write_disk_image(&image_path, &driver, None).unwrap();
+        let missing_startup = read_disk_image_file(&image_path, &[STARTUP_SCRIPT]);
+        let _ = std::fs::remove_file(&image_path);
+
+        assert_eq!(startup.unwrap(), script.as_bytes());
+        assert_eq!(stored_driver.unwrap(), driver);
+        // The driver must not be picked up as the removable media boot file.
+        assert!(boot_file.is_err());
+        assert!(missing_startup.is_err());
+    }
+}
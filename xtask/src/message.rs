@@ -0,0 +1,115 @@
+//! Machine-readable reporting of xtask results.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::Serialize;
+
+/// The format selected for xtask's output.
+static MESSAGE_FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+
+/// The formats in which xtask can report its results.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Human readable messages.
+    #[default]
+    Human,
+    /// One JSON object per line.
+    Json,
+}
+
+impl MessageFormat {
+    /// Returns the [`MessageFormat`] as its textual representation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Human => "human",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl clap::ValueEnum for MessageFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        static FORMATS: &[MessageFormat] = &[MessageFormat::Human, MessageFormat::Json];
+
+        FORMATS
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.as_str()))
+    }
+}
+
+/// Sets the [`MessageFormat`] used for the remainder of the process.
+///
+/// Only the first call has any effect.
+pub fn set_format(format: MessageFormat) {
+    let _ = MESSAGE_FORMAT.set(format);
+}
+
+/// Returns the [`MessageFormat`] in use.
+pub fn format() -> MessageFormat {
+    MESSAGE_FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Prints a human readable status message.
+///
+/// In [`MessageFormat::Json`] mode, status messages are printed to standard error so that
+/// standard output only contains JSON.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        match $crate::message::format() {
+            $crate::message::MessageFormat::Human => println!($($arg)*),
+            $crate::message::MessageFormat::Json => eprintln!($($arg)*),
+        }
+    };
+}
+pub(crate) use status;
+
+/// The result of building `boot-manipulator`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+pub struct BuildArtifact {
+    /// The path to the built binary.
+    pub binary: PathBuf,
+    /// The rustc target triple for which the binary was built.
+    pub target: &'static str,
+    /// The cargo profile used to build the binary.
+    pub profile: String,
+    /// The features enabled for the build.
+    pub features: Vec<&'static str>,
+}
+
+/// Events emitted while running `boot-manipulator`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum RunEvent<'a> {
+    /// `boot-manipulator` has been built.
+    BuildFinished(&'a BuildArtifact),
+    /// QEMU has been launched.
+    QemuStarted {
+        /// The QEMU command line.
+        argv: Vec<String>,
+    },
+    /// QEMU has exited.
+    QemuExited {
+        /// The exit code of QEMU, or `None` if it was terminated by a signal.
+        code: Option<i32>,
+    },
+    /// A triage bundle has been assembled.
+    BundleAssembled {
+        /// The path to the bundle directory.
+        path: &'a Path,
+    },
+}
+
+/// Prints `value` as a single line of JSON if [`MessageFormat::Json`] is in use.
+pub fn emit_json<T: Serialize>(value: &T) {
+    if format() == MessageFormat::Json {
+        match serde_json::to_string(value) {
+            Ok(line) => println!("{line}"),
+            Err(error) => eprintln!("error while serializing message: {error}"),
+        }
+    }
+}
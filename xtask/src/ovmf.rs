@@ -0,0 +1,307 @@
+//! Discovery and download of the OVMF firmware used to run UEFI.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{cli::Arch, message::status, run_cmd, RunCommandError};
+
+/// The prebuilt OVMF release downloaded when no firmware is installed.
+const PREBUILT_RELEASE: &str = "edk2-stable202408-r1";
+
+/// The location from which [`PREBUILT_RELEASE`] is downloaded.
+const PREBUILT_URL: &str = "https://github.com/rust-osdev/ovmf-prebuilt/releases/download";
+
+/// The paths to the OVMF code and vars files.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Firmware {
+    /// The path to the OVMF code file.
+    pub code: PathBuf,
+    /// The path to the OVMF vars file.
+    pub vars: PathBuf,
+}
+
+/// Returns the `(code, vars)` pairs checked for an installed copy of OVMF for `arch`.
+///
+/// If `secure_boot` is true, only Secure Boot capable builds with enrolled keys are returned.
+pub fn candidate_paths(arch: Arch, secure_boot: bool) -> Vec<(PathBuf, PathBuf)> {
+    if secure_boot {
+        return secure_boot_candidate_paths(arch);
+    }
+
+    let pairs: &[(&str, &str)] = match arch {
+        Arch::X86 => &[
+            (
+                "/usr/share/OVMF/OVMF32_CODE_4M.secboot.fd",
+                "/usr/share/OVMF/OVMF32_VARS_4M.fd",
+            ),
+            (
+                "/usr/share/edk2/ovmf-ia32/OVMF_CODE.fd",
+                "/usr/share/edk2/ovmf-ia32/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/edk2/ia32/OVMF_CODE.fd",
+                "/usr/share/edk2/ia32/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/edk2-ovmf/ia32/OVMF_CODE.fd",
+                "/usr/share/edk2-ovmf/ia32/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/qemu/edk2-i386-code.fd",
+                "/usr/share/qemu/edk2-i386-vars.fd",
+            ),
+        ],
+        Arch::X86_64 => &[
+            (
+                "/usr/share/OVMF/OVMF_CODE_4M.fd",
+                "/usr/share/OVMF/OVMF_VARS_4M.fd",
+            ),
+            (
+                "/usr/share/OVMF/OVMF_CODE.fd",
+                "/usr/share/OVMF/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+                "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+                "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+            ),
+            (
+                "/usr/share/edk2/x64/OVMF_CODE.fd",
+                "/usr/share/edk2/x64/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+                "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/qemu/edk2-x86_64-code.fd",
+                "/usr/share/qemu/edk2-i386-vars.fd",
+            ),
+        ],
+        Arch::Aarch64 => &[
+            (
+                "/usr/share/AAVMF/AAVMF_CODE.fd",
+                "/usr/share/AAVMF/AAVMF_VARS.fd",
+            ),
+            (
+                "/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw",
+                "/usr/share/edk2/aarch64/vars-template-pflash.raw",
+            ),
+            (
+                "/usr/share/edk2/aarch64/QEMU_CODE.fd",
+                "/usr/share/edk2/aarch64/QEMU_VARS.fd",
+            ),
+            (
+                "/usr/share/qemu/edk2-aarch64-code.fd",
+                "/usr/share/qemu/edk2-arm-vars.fd",
+            ),
+        ],
+    };
+
+    let mut candidates = pairs
+        .iter()
+        .map(|&(code, vars)| (PathBuf::from(code), PathBuf::from(vars)))
+        .collect::<Vec<_>>();
+
+    let cache = prebuilt_directory(arch);
+    candidates.push((cache.join("code.fd"), cache.join("vars.fd")));
+
+    candidates
+}
+
+/// Returns the `(code, vars)` pairs checked for an installed Secure Boot capable copy of OVMF for
+/// `arch`.
+fn secure_boot_candidate_paths(arch: Arch) -> Vec<(PathBuf, PathBuf)> {
+    let pairs: &[(&str, &str)] = match arch {
+        Arch::X86 => &[(
+            "/usr/share/OVMF/OVMF32_CODE_4M.secboot.fd",
+            "/usr/share/OVMF/OVMF32_VARS_4M.fd",
+        )],
+        Arch::X86_64 => &[
+            (
+                "/usr/share/OVMF/OVMF_CODE_4M.secboot.fd",
+                "/usr/share/OVMF/OVMF_VARS_4M.ms.fd",
+            ),
+            (
+                "/usr/share/OVMF/OVMF_CODE.secboot.fd",
+                "/usr/share/OVMF/OVMF_VARS.ms.fd",
+            ),
+            (
+                "/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd",
+                "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd",
+            ),
+            (
+                "/usr/share/edk2/x64/OVMF_CODE.secboot.4m.fd",
+                "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+            ),
+            (
+                "/usr/share/qemu/edk2-x86_64-secure-code.fd",
+                "/usr/share/qemu/edk2-i386-vars.fd",
+            ),
+        ],
+        Arch::Aarch64 => &[(
+            "/usr/share/AAVMF/AAVMF_CODE.ms.fd",
+            "/usr/share/AAVMF/AAVMF_VARS.ms.fd",
+        )],
+    };
+
+    pairs
+        .iter()
+        .map(|&(code, vars)| (PathBuf::from(code), PathBuf::from(vars)))
+        .collect()
+}
+
+/// Locates the OVMF firmware for `arch`.
+///
+/// Explicitly provided paths take precedence over discovered ones. If no installed firmware can
+/// be found and `allow_download` is true, a prebuilt copy is downloaded into `target/ovmf/`. The
+/// prebuilt firmware does not support Secure Boot, so it is never downloaded if `secure_boot` is
+/// true.
+///
+/// # Errors
+/// Returns [`OvmfError::NotFound`] listing every path checked if no firmware could be found, or
+/// [`OvmfError::DownloadFailed`] if downloading the prebuilt firmware failed.
+pub fn locate(
+    arch: Arch,
+    code: Option<PathBuf>,
+    vars: Option<PathBuf>,
+    secure_boot: bool,
+    allow_download: bool,
+) -> Result<Firmware, OvmfError> {
+    if let (Some(code), Some(vars)) = (code.as_ref(), vars.as_ref()) {
+        return Ok(Firmware {
+            code: code.clone(),
+            vars: vars.clone(),
+        });
+    }
+
+    let candidates = candidate_paths(arch, secure_boot);
+    let found = candidates
+        .iter()
+        .find(|(code, vars)| code.is_file() && vars.is_file())
+        .cloned();
+
+    let (found_code, found_vars) = match found {
+        Some(pair) => pair,
+        None if allow_download && !secure_boot => {
+            download(arch).map_err(OvmfError::DownloadFailed)?
+        }
+        None => {
+            return Err(OvmfError::NotFound {
+                checked: candidates,
+            })
+        }
+    };
+
+    Ok(Firmware {
+        code: code.unwrap_or(found_code),
+        vars: vars.unwrap_or(found_vars),
+    })
+}
+
+/// Returns the directory into which the prebuilt firmware for `arch` is extracted.
+fn prebuilt_directory(arch: Arch) -> PathBuf {
+    let mut directory = PathBuf::with_capacity(50);
+    directory.push("target");
+    directory.push("ovmf");
+    directory.push(format!("{PREBUILT_RELEASE}-bin"));
+    directory.push(match arch {
+        Arch::X86 => "ia32",
+        Arch::Aarch64 => "aarch64",
+        Arch::X86_64 => "x64",
+    });
+    directory
+}
+
+/// Downloads and extracts the prebuilt firmware into `target/ovmf/`.
+fn download(arch: Arch) -> Result<(PathBuf, PathBuf), DownloadError> {
+    let ovmf_directory = Path::new("target").join("ovmf");
+    std::fs::create_dir_all(&ovmf_directory)?;
+
+    let archive_name = format!("{PREBUILT_RELEASE}-bin.tar.xz");
+    let archive = ovmf_directory.join(&archive_name);
+
+    status!(
+        "Downloading OVMF {PREBUILT_RELEASE} into \"{}\"",
+        ovmf_directory.display()
+    );
+
+    let mut curl = std::process::Command::new("curl");
+    curl.args(["--fail", "--location", "--silent", "--show-error"]);
+    curl.arg("--output").arg(&archive);
+    curl.arg(format!("{PREBUILT_URL}/{PREBUILT_RELEASE}/{archive_name}"));
+    run_cmd(curl)?;
+
+    let mut tar = std::process::Command::new("tar");
+    tar.arg("-xJf").arg(&archive);
+    tar.arg("-C").arg(&ovmf_directory);
+    run_cmd(tar)?;
+
+    let directory = prebuilt_directory(arch);
+    Ok((directory.join("code.fd"), directory.join("vars.fd")))
+}
+
+/// Various errors that can occur while locating the OVMF firmware.
+#[derive(Debug)]
+pub enum OvmfError {
+    /// No firmware was found and downloading was disabled.
+    NotFound {
+        /// The `(code, vars)` pairs that were checked.
+        checked: Vec<(PathBuf, PathBuf)>,
+    },
+    /// Downloading the prebuilt firmware failed.
+    DownloadFailed(DownloadError),
+}
+
+impl fmt::Display for OvmfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { checked } => {
+                writeln!(f, "OVMF firmware not found; checked:")?;
+                for (code, vars) in checked {
+                    writeln!(f, "    \"{}\" and \"{}\"", code.display(), vars.display())?;
+                }
+                write!(
+                    f,
+                    "pass --ovmf-code and --ovmf-vars, or remove --no-download to fetch it"
+                )
+            }
+            Self::DownloadFailed(error) => write!(f, "error while downloading OVMF: {error}"),
+        }
+    }
+}
+
+/// Various errors that can occur while downloading the prebuilt firmware.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// An error occurred while creating the download directory.
+    Io(io::Error),
+    /// An error occurred while fetching or extracting the archive.
+    Command(RunCommandError),
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<RunCommandError> for DownloadError {
+    fn from(value: RunCommandError) -> Self {
+        Self::Command(value)
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{error}"),
+            Self::Command(error) => write!(f, "{error}"),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Validation of the PE headers of built EFI binaries.
+
+use std::fmt;
+
+use crate::cli::Arch;
+
+/// The `IMAGE_FILE_MACHINE_I386` machine type.
+const MACHINE_I386: u16 = 0x014c;
+/// The `IMAGE_FILE_MACHINE_AMD64` machine type.
+const MACHINE_AMD64: u16 = 0x8664;
+/// The `IMAGE_FILE_MACHINE_ARM64` machine type.
+const MACHINE_ARM64: u16 = 0xaa64;
+
+/// The optional header magic of a PE32 image.
+const PE32_MAGIC: u16 = 0x010b;
+/// The optional header magic of a PE32+ image.
+const PE32_PLUS_MAGIC: u16 = 0x020b;
+
+/// The offset of the subsystem field within the optional header, which is identical for PE32 and
+/// PE32+ images.
+const SUBSYSTEM_OFFSET: usize = 68;
+
+/// The subsystems an EFI image can declare.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Subsystem {
+    /// `IMAGE_SUBSYSTEM_EFI_APPLICATION`.
+    EfiApplication,
+    /// `IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER`.
+    EfiBootServiceDriver,
+    /// `IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER`.
+    EfiRuntimeDriver,
+    /// Any other subsystem.
+    Other(u16),
+}
+
+impl Subsystem {
+    /// Returns the [`Subsystem`] corresponding to the PE subsystem `value`.
+    pub fn from_value(value: u16) -> Self {
+        match value {
+            10 => Self::EfiApplication,
+            11 => Self::EfiBootServiceDriver,
+            12 => Self::EfiRuntimeDriver,
+            value => Self::Other(value),
+        }
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EfiApplication => write!(f, "EFI application"),
+            Self::EfiBootServiceDriver => write!(f, "EFI boot service driver"),
+            Self::EfiRuntimeDriver => write!(f, "EFI runtime driver"),
+            Self::Other(value) => write!(f, "subsystem {value}"),
+        }
+    }
+}
+
+/// Checks that `image` is a PE image for `arch` declaring the `expected` subsystem.
+///
+/// # Errors
+/// Returns a [`PeError`] describing the first mismatch found.
+pub fn validate(image: &[u8], arch: Arch, expected: Subsystem) -> Result<(), PeError> {
+    let pe_offset = pe_header_offset(image)?;
+
+    let (expected_machine, expected_magic) = match arch {
+        Arch::X86 => (MACHINE_I386, PE32_MAGIC),
+        Arch::X86_64 => (MACHINE_AMD64, PE32_PLUS_MAGIC),
+        Arch::Aarch64 => (MACHINE_ARM64, PE32_PLUS_MAGIC),
+    };
+
+    let machine = read_u16(image, pe_offset + 4)?;
+    if machine != expected_machine {
+        return Err(PeError::MachineMismatch {
+            expected: expected_machine,
+            found: machine,
+        });
+    }
+
+    let magic = read_u16(image, pe_offset + 24)?;
+    if magic != expected_magic {
+        return Err(PeError::FormatMismatch {
+            expected: expected_magic,
+            found: magic,
+        });
+    }
+
+    let subsystem = subsystem(image)?;
+    if subsystem != expected {
+        return Err(PeError::SubsystemMismatch {
+            expected,
+            found: subsystem,
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the [`Subsystem`] declared by the PE `image`.
+///
+/// # Errors
+/// Returns a [`PeError`] if `image` is not a PE image.
+pub fn subsystem(image: &[u8]) -> Result<Subsystem, PeError> {
+    let optional_header = pe_header_offset(image)? + 24;
+    read_u16(image, optional_header + SUBSYSTEM_OFFSET).map(Subsystem::from_value)
+}
+
+/// Returns the offset of the `PE\0\0` signature in `image`.
+fn pe_header_offset(image: &[u8]) -> Result<usize, PeError> {
+    if image.get(..2) != Some(b"MZ") {
+        return Err(PeError::MissingDosHeader);
+    }
+
+    let pe_offset = read_u32(image, 0x3C)? as usize;
+    if image.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+        return Err(PeError::MissingPeSignature);
+    }
+
+    Ok(pe_offset)
+}
+
+/// Reads a little-endian [`u16`] at `offset` in `image`.
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, PeError> {
+    image
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or(PeError::Truncated)
+}
+
+/// Reads a little-endian [`u32`] at `offset` in `image`.
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, PeError> {
+    image
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or(PeError::Truncated)
+}
+
+/// Various ways in which an image can fail validation.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum PeError {
+    /// The image is too small to contain its headers.
+    Truncated,
+    /// The image does not start with an `MZ` header.
+    MissingDosHeader,
+    /// The image does not contain a `PE\0\0` signature.
+    MissingPeSignature,
+    /// The image was built for a different machine.
+    MachineMismatch {
+        /// The machine type expected for the architecture.
+        expected: u16,
+        /// The machine type of the image.
+        found: u16,
+    },
+    /// The image is PE32 where PE32+ was expected, or vice versa.
+    FormatMismatch {
+        /// The optional header magic expected for the architecture.
+        expected: u16,
+        /// The optional header magic of the image.
+        found: u16,
+    },
+    /// The image declares the wrong subsystem.
+    SubsystemMismatch {
+        /// The expected subsystem.
+        expected: Subsystem,
+        /// The subsystem of the image.
+        found: Subsystem,
+    },
+}
+
+impl fmt::Display for PeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "image is truncated"),
+            Self::MissingDosHeader => write!(f, "image does not start with an MZ header"),
+            Self::MissingPeSignature => write!(f, "image has no PE signature"),
+            Self::MachineMismatch { expected, found } => write!(
+                f,
+                "image has machine type {found:#06x}, expected {expected:#06x}"
+            ),
+            Self::FormatMismatch { expected, found } => write!(
+                f,
+                "image has optional header magic {found:#06x}, expected {expected:#06x}"
+            ),
+            Self::SubsystemMismatch { expected, found } => write!(
+                f,
+                "image is an {found}, expected an {expected} (is the /subsystem link argument \
+                 missing?)"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The offset at which [`minimal_image`] places the PE signature.
+    const PE_OFFSET: usize = 0x80;
+
+    /// Builds the headers of a PE image with `machine`, optional header `magic`, and `subsystem`.
+    fn minimal_image(machine: u16, magic: u16, subsystem: u16) -> Vec<u8> {
+        let optional_header = PE_OFFSET + 24;
+        let mut image = vec![0; optional_header + 240];
+
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3C..0x40].copy_from_slice(&(PE_OFFSET as u32).to_le_bytes());
+        image[PE_OFFSET..PE_OFFSET + 4].copy_from_slice(b"PE\0\0");
+        image[PE_OFFSET + 4..PE_OFFSET + 6].copy_from_slice(&machine.to_le_bytes());
+        image[optional_header..optional_header + 2].copy_from_slice(&magic.to_le_bytes());
+        image[optional_header + SUBSYSTEM_OFFSET..optional_header + SUBSYSTEM_OFFSET + 2]
+            .copy_from_slice(&subsystem.to_le_bytes());
+
+        image
+    }
+
+    #[test]
+    fn parses_subsystem() {
+        let cases = [
+            (10, Subsystem::EfiApplication),
+            (11, Subsystem::EfiBootServiceDriver),
+            (12, Subsystem::EfiRuntimeDriver),
+            (3, Subsystem::Other(3)),
+        ];
+        for (value, expected) in cases {
+            let image = minimal_image(MACHINE_AMD64, PE32_PLUS_MAGIC, value);
+
+            assert_eq!(subsystem(&image), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn accepts_matching_images() {
+        let cases = [
+            (Arch::X86, MACHINE_I386, PE32_MAGIC),
+            (Arch::X86_64, MACHINE_AMD64, PE32_PLUS_MAGIC),
+            (Arch::Aarch64, MACHINE_ARM64, PE32_PLUS_MAGIC),
+        ];
+        for (arch, machine, magic) in cases {
+            let image = minimal_image(machine, magic, 12);
+
+            assert_eq!(validate(&image, arch, Subsystem::EfiRuntimeDriver), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_machine() {
+        let image = minimal_image(MACHINE_ARM64, PE32_PLUS_MAGIC, 12);
+
+        assert_eq!(
+            validate(&image, Arch::X86_64, Subsystem::EfiRuntimeDriver),
+            Err(PeError::MachineMismatch {
+                expected: MACHINE_AMD64,
+                found: MACHINE_ARM64,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_format() {
+        let image = minimal_image(MACHINE_AMD64, PE32_MAGIC, 12);
+
+        assert_eq!(
+            validate(&image, Arch::X86_64, Subsystem::EfiRuntimeDriver),
+            Err(PeError::FormatMismatch {
+                expected: PE32_PLUS_MAGIC,
+                found: PE32_MAGIC,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_subsystem() {
+        let image = minimal_image(MACHINE_AMD64, PE32_PLUS_MAGIC, 10);
+
+        assert_eq!(
+            validate(&image, Arch::X86_64, Subsystem::EfiRuntimeDriver),
+            Err(PeError::SubsystemMismatch {
+                expected: Subsystem::EfiRuntimeDriver,
+                found: Subsystem::EfiApplication,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_dos_header() {
+        let mut image = minimal_image(MACHINE_AMD64, PE32_PLUS_MAGIC, 12);
+        image[0] = b'X';
+
+        assert_eq!(subsystem(&image), Err(PeError::MissingDosHeader));
+        assert_eq!(subsystem(&[]), Err(PeError::MissingDosHeader));
+    }
+
+    #[test]
+    fn rejects_missing_pe_signature() {
+        let mut image = minimal_image(MACHINE_AMD64, PE32_PLUS_MAGIC, 12);
+        image[PE_OFFSET + 1] = b'X';
+
+        assert_eq!(subsystem(&image), Err(PeError::MissingPeSignature));
+
+        // A signature offset past the end of the image cannot hold a signature either.
+        image[0x3C..0x40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(subsystem(&image), Err(PeError::MissingPeSignature));
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        assert_eq!(subsystem(b"MZ"), Err(PeError::Truncated));
+
+        let image = minimal_image(MACHINE_AMD64, PE32_PLUS_MAGIC, 12);
+        let truncated = &image[..PE_OFFSET + 24 + SUBSYSTEM_OFFSET + 1];
+        assert_eq!(subsystem(truncated), Err(PeError::Truncated));
+        assert_eq!(
+            validate(
+                &image[..PE_OFFSET + 5],
+                Arch::X86_64,
+                Subsystem::EfiRuntimeDriver
+            ),
+            Err(PeError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Supervision of the QEMU child process.
+
+use std::{
+    io,
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a child is polled while waiting for it to exit.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Spawns `cmd`, in its own process group if `isolate` is set.
+///
+/// An isolated child is kept out of the terminal's foreground process group, so that it can be
+/// killed along with any processes it starts, but it is stopped if it touches the terminal. On
+/// Unix, `SIGINT` received by xtask is forwarded to an isolated child's process group. A child that
+/// is not isolated receives `SIGINT` from the terminal directly, and xtask ignores it so that it
+/// can clean up once the child exits.
+///
+/// # Errors
+/// Returns an [`io::Error`] if the child could not be spawned.
+pub fn spawn(cmd: &mut Command, isolate: bool) -> Result<Child, io::Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        if isolate {
+            cmd.process_group(0);
+        }
+        unix::install_sigint_forwarding();
+    }
+
+    let child = cmd.spawn()?;
+
+    #[cfg(unix)]
+    if isolate {
+        unix::set_forwarding_target(child.id());
+    }
+
+    Ok(child)
+}
+
+/// Waits for `child` to exit, giving up at `deadline` if one is given.
+///
+/// Returns `None` if `deadline` passed before the child exited.
+///
+/// # Errors
+/// Returns an [`io::Error`] if the status of the child could not be queried.
+pub fn wait(child: &mut Child, deadline: Option<Instant>) -> Result<Option<ExitStatus>, io::Error> {
+    let result = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break None;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    #[cfg(unix)]
+    if result.is_some() {
+        unix::set_forwarding_target(0);
+    }
+
+    Ok(result)
+}
+
+/// Kills `child`, along with every other process in its process group if it was isolated, then
+/// reaps it.
+pub fn kill(child: &mut Child) -> Option<ExitStatus> {
+    #[cfg(unix)]
+    {
+        unix::set_forwarding_target(0);
+        if let Ok(id) = i32::try_from(child.id()) {
+            let pid = nix::unistd::Pid::from_raw(id);
+            if nix::unistd::getpgid(Some(pid)) == Ok(pid) {
+                let _ = nix::sys::signal::killpg(pid, nix::sys::signal::Signal::SIGKILL);
+            }
+        }
+    }
+
+    let _ = child.kill();
+    child.wait().ok()
+}
+
+/// Forwarding of `SIGINT` to the QEMU process group.
+#[cfg(unix)]
+mod unix {
+    use std::sync::{
+        atomic::{AtomicI32, Ordering},
+        Once,
+    };
+
+    use nix::{
+        sys::signal::{killpg, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        unistd::Pid,
+    };
+
+    /// The process group to which `SIGINT` is forwarded, or 0 if there is none.
+    static FORWARDING_TARGET: AtomicI32 = AtomicI32::new(0);
+
+    /// Sets the process group to which `SIGINT` is forwarded, or clears it if `id` is 0.
+    pub fn set_forwarding_target(id: u32) {
+        FORWARDING_TARGET.store(i32::try_from(id).unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// Installs the `SIGINT` handler, if it has not already been installed.
+    pub fn install_sigint_forwarding() {
+        static INSTALLED: Once = Once::new();
+
+        INSTALLED.call_once(|| {
+            let action = SigAction::new(
+                SigHandler::Handler(forward_sigint),
+                SaFlags::SA_RESTART,
+                SigSet::empty(),
+            );
+
+            // SAFETY:
+            // `forward_sigint` only performs async-signal-safe operations: an atomic load and a
+            // call to `kill`.
+            if let Err(error) = unsafe { sigaction(Signal::SIGINT, &action) } {
+                eprintln!("warning: failed to install SIGINT handler: {error}");
+            }
+        });
+    }
+
+    /// Forwards `SIGINT` to the current forwarding target, if there is one.
+    extern "C" fn forward_sigint(_: nix::libc::c_int) {
+        let id = FORWARDING_TARGET.load(Ordering::SeqCst);
+        if id > 0 {
+            let _ = killpg(Pid::from_raw(id), Signal::SIGINT);
+        }
+    }
+}
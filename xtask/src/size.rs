@@ -0,0 +1,92 @@
+//! Measurement of the sections of built EFI binaries.
+
+use std::{fmt, path::PathBuf};
+
+use object::{
+    read::pe::{ImageNtHeaders, ImageOptionalHeader, PeFile},
+    FileKind, LittleEndian,
+};
+use serde::Serialize;
+
+/// The sizes of a single section of a PE image.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+pub struct SectionSize {
+    /// The name of the section.
+    pub name: String,
+    /// The number of bytes the section occupies in the file.
+    pub raw_size: u32,
+    /// The number of bytes the section occupies once loaded.
+    pub virtual_size: u32,
+}
+
+/// The sizes of the sections of a PE image.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+pub struct SizeReport {
+    /// The path to the measured binary.
+    pub binary: PathBuf,
+    /// The sections of the image, in the order of the section table.
+    pub sections: Vec<SectionSize>,
+    /// The number of bytes the image occupies once loaded, as declared by its optional header.
+    pub image_size: u32,
+}
+
+impl SizeReport {
+    /// Measures the PE `image` read from `binary`.
+    ///
+    /// # Errors
+    /// Returns an [`object::Error`] if `image` is not a PE32 or PE32+ image.
+    pub fn measure(binary: PathBuf, image: &[u8]) -> Result<Self, object::Error> {
+        match FileKind::parse(image)? {
+            FileKind::Pe32 => measure_pe::<object::pe::ImageNtHeaders32>(binary, image),
+            // Parsing any other kind of file as PE32+ reports why it is not a PE image.
+            _ => measure_pe::<object::pe::ImageNtHeaders64>(binary, image),
+        }
+    }
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.binary.display())?;
+        writeln!(f, "{:<10} {:>12} {:>12}", "section", "raw", "virtual")?;
+        for section in &self.sections {
+            writeln!(
+                f,
+                "{:<10} {:>12} {:>12}",
+                section.name, section.raw_size, section.virtual_size
+            )?;
+        }
+        write!(f, "total image size: {} bytes", self.image_size)
+    }
+}
+
+/// Measures the PE `image` read from `binary`, whose NT headers are of type `Pe`.
+fn measure_pe<Pe: ImageNtHeaders>(
+    binary: PathBuf,
+    image: &[u8],
+) -> Result<SizeReport, object::Error> {
+    let file = PeFile::<Pe>::parse(image)?;
+
+    let sections = file
+        .section_table()
+        .iter()
+        .map(|section| {
+            let name = section.raw_name();
+            let name = name
+                .iter()
+                .position(|&byte| byte == 0)
+                .map_or(name, |end| &name[..end]);
+
+            SectionSize {
+                name: String::from_utf8_lossy(name).into_owned(),
+                raw_size: section.size_of_raw_data.get(LittleEndian),
+                virtual_size: section.virtual_size.get(LittleEndian),
+            }
+        })
+        .collect();
+
+    Ok(SizeReport {
+        binary,
+        sections,
+        image_size: file.nt_headers().optional_header().size_of_image(),
+    })
+}